@@ -0,0 +1,56 @@
+//! Times typechecking generated programs of increasing size the way `uplp
+//! typecheck` does, to show that it scales roughly linearly with the length of
+//! the program.
+//!
+//! Run with `cargo bench --bench typecheck`, optionally followed by `-- <lines>`
+//! for the largest program to check.
+
+use std::{env, fmt::Write, time::Instant};
+
+use untitled_programming_language_project::check_types_all;
+
+fn main() {
+    let max_lines = env::args()
+        .skip(1)
+        .find_map(|a| a.parse().ok())
+        .unwrap_or(20_000);
+    println!("{:>8} {:>10} {:>12}", "lines", "time (ms)", "µs per line");
+    let mut lines = max_lines;
+    let mut sizes = Vec::new();
+    while lines >= 1_000 {
+        sizes.push(lines);
+        lines /= 2;
+    }
+    for lines in sizes.into_iter().rev() {
+        let program = generate(lines / 4);
+        let lines = program.lines().count();
+        let start = Instant::now();
+        check_types_all(&program).expect("generated program should typecheck");
+        let elapsed = start.elapsed();
+        println!(
+            "{:>8} {:>10} {:>12.1}",
+            lines,
+            elapsed.as_millis(),
+            elapsed.as_secs_f64() * 1e6 / lines as f64
+        );
+    }
+}
+
+/// A program of `4 * n + 2` lines, each of whose definitions uses the one
+/// before, with lambdas, records, lists, conditionals & pattern matching.
+fn generate(n: usize) -> String {
+    let mut program = String::from("let f0 = |x: Int| |y: Int| x + y in\n");
+    for i in 1..=n {
+        let prev = i - 1;
+        writeln!(
+            program,
+            "let f{i} = |x| |y| if x < y then f{prev} x y else y + 1 in\n\
+             let p{i} = {{ left = f{i} 1 2, right = [f{i} 3 4] }} in\n\
+             let g{i} = |r| match r.right with | [] -> r.left | h :: _ -> h end in\n\
+             let v{i} = g{i} p{i} in"
+        )
+        .unwrap();
+    }
+    writeln!(program, "v{n}").unwrap();
+    program
+}
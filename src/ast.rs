@@ -1,36 +1,101 @@
-use std::fmt::Debug;
+use std::{
+    fmt::{self, Debug, Display},
+    rc::Rc,
+};
 
-use crate::{interner, typ::Type, values::Val};
+use crate::{
+    builtins::{self, Builtin},
+    interner,
+    typ::{Measure, Type},
+    values::Val,
+};
 
 #[derive(PartialEq)]
 pub enum RawExpr {
     Ascribed(Box<RawExpr>, Type),
     App(Box<RawExpr>, Box<RawExpr>),
+    Constructor(interner::Id),
+    Field(Box<RawExpr>, interner::Id),
+    Index(Box<RawExpr>, Box<RawExpr>),
+    /// `instance Class Type = methods in body`.
+    Instance(interner::Id, interner::Id, Box<RawExpr>, Box<RawExpr>),
     Lambda(interner::Id, Option<Type>, Box<RawExpr>),
     Let(bool, interner::Id, Box<RawExpr>, Box<RawExpr>),
     Literal(Val),
+    /// An expression along with its byte offset in the source.
+    Located(usize, Box<RawExpr>),
+    /// A `Num` literal in some unit of measure, e.g. `3.0<m>`.
+    Measured(f64, Measure),
     IfThenElse(Box<RawExpr>, Box<RawExpr>, Box<RawExpr>),
+    Match(Box<RawExpr>, Vec<(Pattern, RawExpr)>),
+    Propagate(Box<RawExpr>),
+    QualifiedVar(interner::Id),
     Var(interner::Id),
     Op(Box<RawExpr>, BinaryOp, Box<RawExpr>),
+    Record(Vec<(interner::Id, RawExpr)>),
+    TypeDecl(DataDecl, Box<RawExpr>),
+    /// `type Name = new T in body`.
+    NewtypeDecl(Newtype, Box<RawExpr>),
+    /// `body`, which may only be evaluated once the variable the contract
+    /// is about satisfies the predicate.
+    Refined(Contract, Box<RawExpr>, Box<RawExpr>),
+    /// `unit name in body`.
+    UnitDecl(interner::Id, Box<RawExpr>),
 }
 
 impl RawExpr {
+    pub fn make_located(location: usize, e: Box<RawExpr>) -> Box<Self> {
+        Box::new(Self::Located(location, e))
+    }
+
     pub fn make_app(fnc: Box<RawExpr>, args: Vec<RawExpr>) -> Box<Self> {
         args.into_iter()
             .fold(fnc, |f, arg| Box::new(Self::App(f, Box::new(arg))))
     }
 
-    pub fn make_lambda(args: Vec<(RawIdent, Option<Type>)>, body: Box<RawExpr>) -> Box<Self> {
+    /// Desugar a lambda with several arguments to nested lambdas, each of
+    /// whose bodies first checks its argument's refinement, if it has one.
+    pub fn make_lambda(
+        args: Vec<(RawIdent, Option<Type>, Option<Refinement>)>,
+        body: Box<RawExpr>,
+    ) -> Box<Self> {
         args.into_iter()
             .rev()
-            .map(|(r, ty)| (interner::Id::new(r.0), ty))
-            .fold(body, |body, (id, ty)| Box::new(Self::Lambda(id, ty, body)))
+            .map(|(r, ty, refinement)| (interner::Id::new(r.0), ty, refinement))
+            .fold(body, |body, (id, ty, refinement)| {
+                let body = Self::make_refined(id, refinement, body);
+                Box::new(Self::Lambda(id, ty, body))
+            })
+    }
+
+    /// Ascribe `t` to `e`. If `t` is refined, the value is bound to a name
+    /// users can't write while its refinement is checked, and to `it` within
+    /// the predicate alone, so that the predicate can refer to it without
+    /// shadowing any `it` in the rest of the program.
+    pub fn make_ascribed(e: Box<RawExpr>, t: Type, refinement: Option<Refinement>) -> Box<Self> {
+        let e = Box::new(RawExpr::Ascribed(e, t));
+        match refinement {
+            Some(mut r) => {
+                let value = interner::Id::new("<ascribed>");
+                let it = interner::Id::new("it");
+                r.predicate = Box::new(Self::Let(
+                    false,
+                    it,
+                    Box::new(RawExpr::Var(value)),
+                    r.predicate,
+                ));
+                let body = Self::make_refined(value, Some(r), Box::new(RawExpr::Var(value)));
+                Box::new(Self::Let(false, value, e, body))
+            }
+            None => e,
+        }
     }
 
     pub fn make_let(
         rec: bool,
         ident: RawIdent,
         ann: Option<Type>,
+        refinement: Option<Refinement>,
         binding: Box<RawExpr>,
         body: Box<RawExpr>,
     ) -> Box<Self> {
@@ -38,31 +103,191 @@ impl RawExpr {
             Some(ann) => Box::new(RawExpr::Ascribed(binding, ann)),
             None => binding,
         };
-        Box::new(Self::Let(rec, interner::Id::new(ident.0), binding, body))
+        let id = interner::Id::new(ident.0);
+        let body = Self::make_refined(id, refinement, body);
+        Box::new(Self::Let(rec, id, binding, body))
+    }
+
+    /// Check `refinement` of the variable `var`, which has just been bound,
+    /// before evaluating `body`.
+    fn make_refined(
+        var: interner::Id,
+        refinement: Option<Refinement>,
+        body: Box<RawExpr>,
+    ) -> Box<Self> {
+        match refinement {
+            Some(r) => {
+                let contract = Contract {
+                    var,
+                    source: r.source,
+                    location: r.location,
+                };
+                Box::new(Self::Refined(contract, r.predicate, body))
+            }
+            None => body,
+        }
     }
 
     pub fn make_var(ident: RawIdent) -> Box<Self> {
         Box::new(Self::Var(interner::Id::new(ident.0)))
     }
+
+    pub fn make_constructor(ident: RawIdent) -> Box<Self> {
+        Box::new(Self::Constructor(ident.into()))
+    }
+
+    /// Desugar `l :: r` to an application of the `::` constructor.
+    pub fn make_cons(l: RawExpr, r: RawExpr) -> Box<Self> {
+        let cons = Box::new(Self::Constructor(*builtins::CONS));
+        Self::make_app(cons, vec![l, r])
+    }
+
+    /// Desugar `[a, b, ..]` to `a :: b :: .. :: []`.
+    pub fn make_list(items: Vec<Box<RawExpr>>) -> Box<Self> {
+        let nil = Box::new(Self::Constructor(*builtins::NIL));
+        items
+            .into_iter()
+            .rev()
+            .fold(nil, |list, item| Self::make_cons(*item, *list))
+    }
+
+    /// Desugar `#{k1 => v1, k2 => v2, ..}` to insertions into `Map.empty`, so
+    /// that later entries replace earlier ones with the same key.
+    pub fn make_map(entries: Vec<(Box<RawExpr>, Box<RawExpr>)>) -> Box<Self> {
+        entries
+            .into_iter()
+            .fold(Self::make_builtin(Builtin::MapEmpty), |map, (k, v)| {
+                Self::make_app(Self::make_builtin(Builtin::MapInsert), vec![*k, *v, *map])
+            })
+    }
+
+    /// Desugar `#{a, b, ..}` to insertions into `Set.empty`.
+    pub fn make_set(items: Vec<Box<RawExpr>>) -> Box<Self> {
+        items
+            .into_iter()
+            .fold(Self::make_builtin(Builtin::SetEmpty), |set, item| {
+                Self::make_app(Self::make_builtin(Builtin::SetInsert), vec![*item, *set])
+            })
+    }
+
+    fn make_builtin(b: Builtin) -> Box<Self> {
+        Box::new(Self::QualifiedVar(interner::Id::new(b.name())))
+    }
+
+    pub fn make_instance(
+        class: RawIdent,
+        typ: RawIdent,
+        methods: Box<RawExpr>,
+        body: Box<RawExpr>,
+    ) -> Box<Self> {
+        Box::new(Self::Instance(class.into(), typ.into(), methods, body))
+    }
+
+    pub fn make_match(scrutinee: Box<RawExpr>, arms: Vec<(Pattern, Box<RawExpr>)>) -> Box<Self> {
+        let arms = arms.into_iter().map(|(p, e)| (p, *e)).collect();
+        Box::new(Self::Match(scrutinee, arms))
+    }
+
+    pub fn make_type_decl(
+        name: RawIdent,
+        params: Vec<RawIdent>,
+        variants: Vec<(RawIdent, Vec<Type>)>,
+        body: Box<RawExpr>,
+    ) -> Box<Self> {
+        let variants = variants
+            .into_iter()
+            .map(|(name, fields)| Variant {
+                name: name.into(),
+                fields,
+            })
+            .collect();
+        let decl = DataDecl {
+            name: name.into(),
+            params: params.into_iter().map(Into::into).collect(),
+            variants,
+        };
+        Box::new(Self::TypeDecl(decl, body))
+    }
+
+    pub fn make_newtype_decl(name: RawIdent, typ: Type, body: Box<RawExpr>) -> Box<Self> {
+        let newtype = Newtype {
+            name: name.into(),
+            typ,
+        };
+        Box::new(Self::NewtypeDecl(newtype, body))
+    }
+
+    pub fn make_unit_decl(name: RawIdent, body: Box<RawExpr>) -> Box<Self> {
+        Box::new(Self::UnitDecl(name.into(), body))
+    }
+
+    pub fn make_qualified_var(ident: RawIdent) -> Box<Self> {
+        Box::new(Self::QualifiedVar(ident.into()))
+    }
+
+    pub fn make_record(fields: Vec<(RawIdent, Box<RawExpr>)>) -> Box<Self> {
+        let fields = fields
+            .into_iter()
+            .map(|(f, e)| (interner::Id::new(f.0), *e))
+            .collect();
+        Box::new(Self::Record(fields))
+    }
+
+    pub fn make_field(record: Box<RawExpr>, field: RawIdent) -> Box<Self> {
+        Box::new(Self::Field(record, interner::Id::new(field.0)))
+    }
+
+    pub fn make_index(array: Box<RawExpr>, index: Box<RawExpr>) -> Box<Self> {
+        Box::new(Self::Index(array, index))
+    }
 }
 
 impl Debug for RawExpr {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
+        crate::with_large_stack(|| match self {
             RawExpr::Ascribed(e, t) => write!(f, "{e:?} : {t:?}"),
             RawExpr::App(fnc, a) => write!(f, "({fnc:?} {a:?})"),
+            RawExpr::Constructor(c) => write!(f, "{c:?}"),
+            RawExpr::Field(r, field) => write!(f, "{r:?}.{field:?}"),
+            RawExpr::Index(a, i) => write!(f, "{a:?}[{i:?}]"),
+            RawExpr::Instance(class, typ, methods, body) => {
+                write!(f, "instance {class:?} {typ:?} = {methods:?} in {body:?}")
+            }
             RawExpr::Lambda(id, ty, body) => write!(f, "|{id:?}: {ty:?}| {body:?}"),
             RawExpr::Let(rec, i, bnd, body) => {
                 let rec_txt = if *rec { "rec " } else { "" };
                 write!(f, "(let {rec_txt}{i:?} = {bnd:?} in {body:?})")
             }
             RawExpr::Literal(v) => write!(f, "{v}"),
+            RawExpr::Located(_, e) => write!(f, "{e:?}"),
+            RawExpr::Measured(n, m) => write!(f, "{}<{m}>", Val::Num(*n)),
             RawExpr::IfThenElse(cond, then, els) => {
                 write!(f, "if {cond:?} then {then:?} else {els:?}")
             }
+            RawExpr::Match(e, arms) => {
+                write!(f, "match {e:?} with")?;
+                for (p, e) in arms {
+                    write!(f, " | {p} -> {e:?}")?;
+                }
+                write!(f, " end")
+            }
             RawExpr::Op(l, op, r) => write!(f, "({l:?} {op:?} {r:?})"),
+            RawExpr::Propagate(e) => write!(f, "{e:?}?"),
+            RawExpr::QualifiedVar(i) => write!(f, "{i:?}"),
+            RawExpr::Record(fields) => {
+                write!(f, "{{")?;
+                for (idx, (field, e)) in fields.iter().enumerate() {
+                    let sep = if idx == 0 { "" } else { ", " };
+                    write!(f, "{sep}{field:?} = {e:?}")?;
+                }
+                write!(f, "}}")
+            }
+            RawExpr::TypeDecl(decl, body) => write!(f, "{decl:?} in {body:?}"),
+            RawExpr::NewtypeDecl(newtype, body) => write!(f, "{newtype:?} in {body:?}"),
+            RawExpr::Refined(_, predicate, body) => write!(f, "where {predicate:?} in {body:?}"),
+            RawExpr::UnitDecl(name, body) => write!(f, "unit {name:?} in {body:?}"),
             RawExpr::Var(i) => write!(f, "{i:?}"),
-        }
+        })
     }
 }
 
@@ -70,19 +295,70 @@ impl Debug for RawExpr {
 pub enum Expr {
     Ascribed(Box<Expr>, Type),
     App(Box<Expr>, Box<Expr>),
+    Builtin(Builtin),
+    /// A data constructor, along with the number of arguments it takes.
+    Constructor(interner::Id, usize),
+    Field(Box<Expr>, interner::Id),
+    /// Indexing into an array, i.e. `array[index]`.
+    Index(Box<Expr>, Box<Expr>),
+    /// The declaration of an instance of a type class, whose methods are
+    /// given by a record.
+    Instance(Instance, Box<Expr>, Box<Expr>),
     Lambda(interner::Id, Option<Type>, Box<Expr>),
     Let(bool, interner::Id, Box<Expr>, Box<Expr>),
     Literal(Val),
+    /// An expression along with its byte offset in the source, which errors
+    /// in it are reported at.
+    Located(usize, Box<Expr>),
+    /// A `Num` literal in some unit of measure. Units only exist while
+    /// typechecking, so this evaluates to the plain `Num`.
+    Measured(f64, Measure),
     IfThenElse(Box<Expr>, Box<Expr>, Box<Expr>),
+    Match(Box<Expr>, Vec<(Pattern, Expr)>),
+    /// Evaluates to the contents of an `Ok` or `Some`, or returns the `Err` or
+    /// `None` from the enclosing function.
+    Propagate(Box<Expr>),
     Var(interner::Id, usize),
     Op(Box<Expr>, BinaryOp, Box<Expr>),
+    Record(Vec<(interner::Id, Expr)>),
+    TypeDecl(DataDecl, Box<Expr>),
+    NewtypeDecl(Newtype, Box<Expr>),
+    /// The function which wraps a value of a newtype's underlying type in the
+    /// newtype. Newtypes only exist while typechecking, so this evaluates to
+    /// the identity function.
+    Wrap(interner::Id),
+    /// `Name.unwrap`, the inverse of `Wrap`.
+    Unwrap(interner::Id),
+    /// Evaluates the predicate, failing if it's false, and then the body.
+    /// The variable the contract is about is always the innermost one bound.
+    Refined(Contract, Box<Expr>, Box<Expr>),
+}
+
+impl Expr {
+    /// The expression without any locations around it.
+    pub fn unlocated(&self) -> &Expr {
+        match self {
+            Expr::Located(_, e) => e.unlocated(),
+            e => e,
+        }
+    }
 }
 
 impl Debug for Expr {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
+        crate::with_large_stack(|| match self {
             Expr::Ascribed(e, t) => write!(f, "{e:?} : {t:?}"),
             Expr::App(fnc, a) => write!(f, "{fnc:?} {a:?}"),
+            Expr::Builtin(b) => write!(f, "{}", b.name()),
+            Expr::Constructor(c, _) => write!(f, "{}", c.name()),
+            Expr::Field(r, field) => write!(f, "{r:?}.{}", field.name()),
+            Expr::Index(a, i) => write!(f, "{a:?}[{i:?}]"),
+            Expr::Instance(instance, methods, body) => write!(
+                f,
+                "instance {} {} = {methods:?} in {body:?}",
+                instance.class,
+                instance.typ.name()
+            ),
             Expr::Lambda(id, ty, body) => write!(f, "|{:?}: {ty:?}| {body:?}", id.name()),
             Expr::Let(rec, id, bnd, body) => {
                 let rec = if *rec { "rec " } else { "" };
@@ -90,13 +366,351 @@ impl Debug for Expr {
                 write!(f, "let {rec}{id} {bnd:?} in {body:?}")
             }
             Expr::Literal(v) => write!(f, "{v}"),
+            Expr::Located(_, e) => write!(f, "{e:?}"),
+            Expr::Measured(n, m) => write!(f, "{}<{m}>", Val::Num(*n)),
             Expr::IfThenElse(cond, thn, els) => write!(f, "if {cond:?} then {thn:?} else {els:?}"),
+            Expr::Match(e, arms) => {
+                write!(f, "match {e:?} with")?;
+                for (p, e) in arms {
+                    write!(f, " | {p} -> {e:?}")?;
+                }
+                write!(f, " end")
+            }
             Expr::Op(l, op, r) => write!(f, "({l:?} {op:?} {r:?})"),
+            Expr::Propagate(e) => write!(f, "{e:?}?"),
+            Expr::Record(fields) => {
+                write!(f, "{{")?;
+                for (idx, (field, e)) in fields.iter().enumerate() {
+                    let sep = if idx == 0 { "" } else { ", " };
+                    write!(f, "{sep}{} = {e:?}", field.name())?;
+                }
+                write!(f, "}}")
+            }
+            Expr::TypeDecl(decl, body) => write!(f, "{decl:?} in {body:?}"),
+            Expr::NewtypeDecl(newtype, body) => write!(f, "{newtype:?} in {body:?}"),
+            Expr::Wrap(n) => write!(f, "{}", n.name()),
+            Expr::Unwrap(n) => write!(f, "{}.unwrap", n.name()),
+            Expr::Refined(_, predicate, body) => write!(f, "where {predicate:?} in {body:?}"),
             Expr::Var(id, _) => write!(f, "{}", id.name()),
+        })
+    }
+}
+
+/// An `Expr` in which every node is annotated with its inferred type.
+#[derive(Clone, PartialEq)]
+pub struct TypedExpr {
+    pub kind: TypedExprKind,
+    pub typ: Type,
+}
+
+/// The nodes of a `TypedExpr`, which mirror those of an `Expr`.
+#[derive(Clone, PartialEq)]
+pub enum TypedExprKind {
+    Ascribed(Box<TypedExpr>, Type),
+    App(Box<TypedExpr>, Box<TypedExpr>),
+    Builtin(Builtin),
+    Constructor(interner::Id, usize),
+    Field(Box<TypedExpr>, interner::Id),
+    Index(Box<TypedExpr>, Box<TypedExpr>),
+    Instance(Instance, Box<TypedExpr>, Box<TypedExpr>),
+    Lambda(interner::Id, Option<Type>, Box<TypedExpr>),
+    Let(bool, interner::Id, Box<TypedExpr>, Box<TypedExpr>),
+    Literal(Val),
+    Located(usize, Box<TypedExpr>),
+    Measured(f64, Measure),
+    IfThenElse(Box<TypedExpr>, Box<TypedExpr>, Box<TypedExpr>),
+    Match(Box<TypedExpr>, Vec<(Pattern, TypedExpr)>),
+    Propagate(Box<TypedExpr>),
+    Var(interner::Id, usize),
+    Op(Box<TypedExpr>, BinaryOp, Box<TypedExpr>),
+    Record(Vec<(interner::Id, TypedExpr)>),
+    TypeDecl(DataDecl, Box<TypedExpr>),
+    NewtypeDecl(Newtype, Box<TypedExpr>),
+    Wrap(interner::Id),
+    Unwrap(interner::Id),
+    Refined(Contract, Box<TypedExpr>, Box<TypedExpr>),
+}
+
+impl Debug for TypedExpr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.kind {
+            TypedExprKind::Located(_, e) => write!(f, "{e:?}"),
+            kind => write!(f, "({kind:?} : {})", self.typ),
         }
     }
 }
 
+impl Debug for TypedExprKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        crate::with_large_stack(|| match self {
+            TypedExprKind::Ascribed(e, t) => write!(f, "{e:?} : {t:?}"),
+            TypedExprKind::App(fnc, a) => write!(f, "{fnc:?} {a:?}"),
+            TypedExprKind::Builtin(b) => write!(f, "{}", b.name()),
+            TypedExprKind::Constructor(c, _) => write!(f, "{}", c.name()),
+            TypedExprKind::Field(r, field) => write!(f, "{r:?}.{}", field.name()),
+            TypedExprKind::Index(a, i) => write!(f, "{a:?}[{i:?}]"),
+            TypedExprKind::Instance(instance, methods, body) => write!(
+                f,
+                "instance {} {} = {methods:?} in {body:?}",
+                instance.class,
+                instance.typ.name()
+            ),
+            TypedExprKind::Lambda(id, ty, body) => {
+                write!(f, "|{:?}: {ty:?}| {body:?}", id.name())
+            }
+            TypedExprKind::Let(rec, id, bnd, body) => {
+                let rec = if *rec { "rec " } else { "" };
+                let id = id.name();
+                write!(f, "let {rec}{id} {bnd:?} in {body:?}")
+            }
+            TypedExprKind::Literal(v) => write!(f, "{v}"),
+            TypedExprKind::Located(_, e) => write!(f, "{e:?}"),
+            TypedExprKind::Measured(n, m) => write!(f, "{}<{m}>", Val::Num(*n)),
+            TypedExprKind::IfThenElse(cond, thn, els) => {
+                write!(f, "if {cond:?} then {thn:?} else {els:?}")
+            }
+            TypedExprKind::Match(e, arms) => {
+                write!(f, "match {e:?} with")?;
+                for (p, e) in arms {
+                    write!(f, " | {p} -> {e:?}")?;
+                }
+                write!(f, " end")
+            }
+            TypedExprKind::Op(l, op, r) => write!(f, "{l:?} {op:?} {r:?}"),
+            TypedExprKind::Propagate(e) => write!(f, "{e:?}?"),
+            TypedExprKind::Record(fields) => {
+                write!(f, "{{")?;
+                for (idx, (field, e)) in fields.iter().enumerate() {
+                    let sep = if idx == 0 { "" } else { ", " };
+                    write!(f, "{sep}{} = {e:?}", field.name())?;
+                }
+                write!(f, "}}")
+            }
+            TypedExprKind::TypeDecl(decl, body) => write!(f, "{decl:?} in {body:?}"),
+            TypedExprKind::NewtypeDecl(newtype, body) => write!(f, "{newtype:?} in {body:?}"),
+            TypedExprKind::Wrap(n) => write!(f, "{}", n.name()),
+            TypedExprKind::Unwrap(n) => write!(f, "{}.unwrap", n.name()),
+            TypedExprKind::Refined(_, predicate, body) => {
+                write!(f, "where {predicate:?} in {body:?}")
+            }
+            TypedExprKind::Var(id, _) => write!(f, "{}", id.name()),
+        })
+    }
+}
+
+/// The declaration of an algebraic data type.
+#[derive(Clone, PartialEq, Eq)]
+pub struct DataDecl {
+    pub name: interner::Id,
+    /// The type parameters, which the variants' fields refer to as
+    /// `Type::Var`s.
+    pub params: Vec<interner::Id>,
+    pub variants: Vec<Variant>,
+}
+
+/// The declaration of a newtype, a nominal type whose values are represented
+/// by those of another type, `typ`, but which doesn't mix with it.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Newtype {
+    pub name: interner::Id,
+    pub typ: Type,
+}
+
+/// A predicate refining the type of a variable, as written after `where` in
+/// an annotation, e.g. `n >= 0` in `|n: Num where n >= 0| ...`.
+pub struct Refinement {
+    pub predicate: Box<RawExpr>,
+    pub source: Rc<str>,
+    pub location: usize,
+}
+
+/// A requirement that a variable satisfies a predicate whenever it's bound,
+/// which is checked at runtime.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Contract {
+    pub var: interner::Id,
+    /// The predicate as it's written in the source, which violations are
+    /// reported with.
+    pub source: Rc<str>,
+    /// The location of the predicate, which is blamed for violations.
+    pub location: usize,
+}
+
+/// A type class. These are built in, but users may declare their own instances
+/// of them.
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+pub enum Class {
+    /// Types whose values can be compared with `==`.
+    Eq,
+    /// Types whose values can be arithmetically combined.
+    Num,
+    /// Types whose values are ordered, and so can be compared with `<` etc.
+    Ord,
+    /// Types whose values can be converted to strings with `Show.show`.
+    Show,
+}
+
+impl Class {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "Eq" => Some(Class::Eq),
+            "Num" => Some(Class::Num),
+            "Ord" => Some(Class::Ord),
+            "Show" => Some(Class::Show),
+            _ => None,
+        }
+    }
+
+    /// The names of the class's methods, which are the fields of the records
+    /// which instances provide.
+    pub fn methods(&self) -> &'static [&'static str] {
+        match self {
+            Class::Eq => &["eq"],
+            Class::Num => &["add", "div", "mul", "sub"],
+            Class::Ord => &["compare"],
+            Class::Show => &["show"],
+        }
+    }
+}
+
+impl Display for Class {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{self:?}")
+    }
+}
+
+/// A user-declared instance of a type class for a data type.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Instance {
+    pub class: Class,
+    pub typ: interner::Id,
+    /// The constructors of `typ`, which are used to find the instance for
+    /// values within others which are compared or shown structurally.
+    pub constructors: Vec<interner::Id>,
+}
+
+/// One of the constructors of an algebraic data type.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Variant {
+    pub name: interner::Id,
+    pub fields: Vec<Type>,
+}
+
+impl Debug for DataDecl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "type {}", self.name.name())?;
+        for p in self.params.iter() {
+            write!(f, " {}", p.name())?;
+        }
+        write!(f, " =")?;
+        for (idx, v) in self.variants.iter().enumerate() {
+            let sep = if idx == 0 { "" } else { " |" };
+            write!(f, "{sep} {}", v.name.name())?;
+            for t in v.fields.iter() {
+                write!(f, " ({t})")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Debug for Newtype {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "type {} = new {}", self.name.name(), self.typ)
+    }
+}
+
+#[derive(Clone, PartialEq)]
+pub enum Pattern {
+    Constructor(interner::Id, Vec<Pattern>),
+    /// A newtype's constructor applied to a pattern for its underlying value.
+    /// Newtypes are erased, so this matches whatever the inner pattern does.
+    Newtype(interner::Id, Box<Pattern>),
+    Literal(Val),
+    Var(interner::Id),
+    Wildcard,
+}
+
+// Literal patterns are only ever built from source literals, so they never
+// contain the `NaN`s which stop `Val` from being `Eq`.
+impl Eq for Pattern {}
+
+impl Pattern {
+    pub fn make_constructor(ident: RawIdent, args: Vec<Pattern>) -> Self {
+        Pattern::Constructor(ident.into(), args)
+    }
+
+    pub fn make_var(ident: RawIdent) -> Self {
+        Pattern::Var(ident.into())
+    }
+
+    pub fn make_cons(head: Pattern, tail: Pattern) -> Self {
+        Pattern::Constructor(*builtins::CONS, vec![head, tail])
+    }
+
+    /// Desugar `[a, b, ..]` to `a :: b :: .. :: []`.
+    pub fn make_list(items: Vec<Pattern>) -> Self {
+        let nil = Pattern::Constructor(*builtins::NIL, Vec::new());
+        items
+            .into_iter()
+            .rev()
+            .fold(nil, |list, item| Self::make_cons(item, list))
+    }
+
+    /// The variables bound by the pattern, from left to right.
+    pub fn bindings(&self) -> Vec<interner::Id> {
+        match self {
+            Pattern::Constructor(_, args) => args.iter().flat_map(Pattern::bindings).collect(),
+            Pattern::Newtype(_, p) => p.bindings(),
+            Pattern::Var(id) => vec![*id],
+            Pattern::Literal(_) | Pattern::Wildcard => Vec::new(),
+        }
+    }
+}
+
+impl Display for Pattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Pattern::Constructor(c, _) if *c == *builtins::NIL => write!(f, "[]"),
+            Pattern::Constructor(c, args) if *c == *builtins::CONS && args.len() == 2 => {
+                match &args[0] {
+                    Pattern::Constructor(c, _) if *c == *builtins::CONS => {
+                        write!(f, "({}) :: {}", args[0], args[1])
+                    }
+                    _ => write!(f, "{} :: {}", args[0], args[1]),
+                }
+            }
+            Pattern::Constructor(c, args) => {
+                write!(f, "{}", c.name())?;
+                args.iter().try_for_each(|a| write_argument(f, a))
+            }
+            Pattern::Newtype(n, p) => {
+                write!(f, "{}", n.name())?;
+                write_argument(f, p)
+            }
+            Pattern::Literal(v) => write!(f, "{v}"),
+            Pattern::Var(id) => write!(f, "{}", id.name()),
+            Pattern::Wildcard => write!(f, "_"),
+        }
+    }
+}
+
+/// Write `p` as the argument of a constructor, in parentheses if it's applied
+/// to arguments itself.
+fn write_argument(f: &mut fmt::Formatter<'_>, p: &Pattern) -> fmt::Result {
+    match p {
+        Pattern::Constructor(_, args) if !args.is_empty() => write!(f, " ({p})"),
+        Pattern::Newtype(..) => write!(f, " ({p})"),
+        _ => write!(f, " {p}"),
+    }
+}
+
+impl Debug for Pattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{self}")
+    }
+}
+
 #[derive(Clone, Hash, PartialEq, Eq)]
 pub struct RawIdent(String);
 
@@ -106,6 +720,18 @@ impl<'a> From<&'a str> for RawIdent {
     }
 }
 
+impl From<RawIdent> for interner::Id {
+    fn from(r: RawIdent) -> Self {
+        interner::Id::new(r.0)
+    }
+}
+
+impl From<i64> for RawExpr {
+    fn from(i: i64) -> Self {
+        RawExpr::Literal(Val::Int(i))
+    }
+}
+
 impl From<f64> for RawExpr {
     fn from(n: f64) -> Self {
         RawExpr::Literal(Val::Num(n))
@@ -119,7 +745,26 @@ pub enum BinaryOp {
     Add,
     Sub,
     Eq,
+    Lt,
+    Le,
+    Gt,
+    Ge,
     And,
+    BitAnd,
+    BitOr,
+    BitXor,
+    Shl,
+    Shr,
+}
+
+impl BinaryOp {
+    /// Whether the operator works on the bits of `Int`s.
+    pub fn is_bitwise(&self) -> bool {
+        matches!(
+            self,
+            BinaryOp::BitAnd | BinaryOp::BitOr | BinaryOp::BitXor | BinaryOp::Shl | BinaryOp::Shr
+        )
+    }
 }
 
 impl Debug for BinaryOp {
@@ -130,7 +775,16 @@ impl Debug for BinaryOp {
             BinaryOp::Add => "+",
             BinaryOp::Sub => "-",
             BinaryOp::Eq => "==",
+            BinaryOp::Lt => "<",
+            BinaryOp::Le => "<=",
+            BinaryOp::Gt => ">",
+            BinaryOp::Ge => ">=",
             BinaryOp::And => "&&",
+            BinaryOp::BitAnd => "&&&",
+            BinaryOp::BitOr => "|||",
+            BinaryOp::BitXor => "^^^",
+            BinaryOp::Shl => "<<<",
+            BinaryOp::Shr => ">>>",
         };
         write!(f, "{}", w)
     }
@@ -0,0 +1,508 @@
+//! Arbitrary-precision integers & exact rationals, for programs which can't
+//! tolerate the rounding of `Num`.
+
+use std::{
+    cmp::Ordering,
+    fmt,
+    ops::{Add, Mul, Neg, Sub},
+};
+
+/// The base of a `BigInt`'s digits. Using a power of ten makes converting to &
+/// from decimal trivial.
+const BASE: u64 = 1_000_000_000;
+const DECIMAL_DIGITS_PER_DIGIT: usize = 9;
+
+/// An arbitrary-precision integer.
+#[derive(Clone, Default, PartialEq, Eq, Hash)]
+pub struct BigInt {
+    /// Always `false` for zero, so that each integer has one representation.
+    negative: bool,
+    /// The digits of the magnitude in base `BASE`, least significant first,
+    /// without any trailing zeros.
+    digits: Vec<u32>,
+}
+
+impl BigInt {
+    pub fn zero() -> Self {
+        Self::default()
+    }
+
+    pub fn one() -> Self {
+        Self::from(1)
+    }
+
+    fn from_parts(negative: bool, mut digits: Vec<u32>) -> Self {
+        trim(&mut digits);
+        let negative = negative && !digits.is_empty();
+        Self { negative, digits }
+    }
+
+    /// Parse an integer written in decimal, with an optional leading `-`.
+    pub fn parse(s: &str) -> Option<Self> {
+        let (negative, s) = match s.strip_prefix('-') {
+            Some(s) => (true, s),
+            None => (false, s),
+        };
+        if s.is_empty() || !s.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+        let digits = s
+            .as_bytes()
+            .rchunks(DECIMAL_DIGITS_PER_DIGIT)
+            .map(|chunk| chunk.iter().fold(0, |n, b| n * 10 + u32::from(b - b'0')))
+            .collect();
+        Some(Self::from_parts(negative, digits))
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.digits.is_empty()
+    }
+
+    pub fn is_negative(&self) -> bool {
+        self.negative
+    }
+
+    pub fn abs(&self) -> Self {
+        Self::from_parts(false, self.digits.clone())
+    }
+
+    /// Convert to an `i64`, or return `None` if it's out of range.
+    pub fn to_i64(&self) -> Option<i64> {
+        if self.digits.len() > 3 {
+            return None;
+        }
+        let magnitude = self
+            .digits
+            .iter()
+            .rev()
+            .fold(0i128, |n, d| n * BASE as i128 + i128::from(*d));
+        i64::try_from(if self.negative { -magnitude } else { magnitude }).ok()
+    }
+
+    /// Convert to the nearest `f64`, which is infinite if it's out of range.
+    pub fn to_f64(&self) -> f64 {
+        let magnitude = self
+            .digits
+            .iter()
+            .rev()
+            .fold(0.0, |n, d| n * BASE as f64 + f64::from(*d));
+        if self.negative {
+            -magnitude
+        } else {
+            magnitude
+        }
+    }
+
+    /// Divide by `other`, truncating towards zero, and return the quotient &
+    /// remainder, or `None` if `other` is zero. The remainder has the same
+    /// sign as `self`.
+    pub fn checked_div_rem(&self, other: &Self) -> Option<(Self, Self)> {
+        if other.is_zero() {
+            return None;
+        }
+        let (quotient, remainder) = div_rem_digits(&self.digits, &other.digits);
+        Some((
+            Self::from_parts(self.negative != other.negative, quotient),
+            Self::from_parts(self.negative, remainder),
+        ))
+    }
+
+    /// The greatest common divisor of `self` & `other`, which is never
+    /// negative.
+    pub fn gcd(&self, other: &Self) -> Self {
+        let (mut a, mut b) = (self.digits.clone(), other.digits.clone());
+        while !b.is_empty() {
+            let (_, r) = div_rem_digits(&a, &b);
+            a = std::mem::replace(&mut b, r);
+        }
+        Self::from_parts(false, a)
+    }
+}
+
+impl From<i64> for BigInt {
+    fn from(n: i64) -> Self {
+        let mut magnitude = n.unsigned_abs();
+        let mut digits = Vec::new();
+        while magnitude > 0 {
+            digits.push((magnitude % BASE) as u32);
+            magnitude /= BASE;
+        }
+        Self::from_parts(n < 0, digits)
+    }
+}
+
+impl Ord for BigInt {
+    fn cmp(&self, other: &Self) -> Ordering {
+        match (self.negative, other.negative) {
+            (false, true) => Ordering::Greater,
+            (true, false) => Ordering::Less,
+            (false, false) => cmp_digits(&self.digits, &other.digits),
+            (true, true) => cmp_digits(&other.digits, &self.digits),
+        }
+    }
+}
+
+impl PartialOrd for BigInt {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Neg for &BigInt {
+    type Output = BigInt;
+
+    fn neg(self) -> BigInt {
+        BigInt::from_parts(!self.negative, self.digits.clone())
+    }
+}
+
+impl Add for &BigInt {
+    type Output = BigInt;
+
+    fn add(self, other: &BigInt) -> BigInt {
+        if self.negative == other.negative {
+            return BigInt::from_parts(self.negative, add_digits(&self.digits, &other.digits));
+        }
+        // The signs differ, so subtract the smaller magnitude from the larger
+        // and take the sign of the larger.
+        match cmp_digits(&self.digits, &other.digits) {
+            Ordering::Less => {
+                BigInt::from_parts(other.negative, sub_digits(&other.digits, &self.digits))
+            }
+            _ => BigInt::from_parts(self.negative, sub_digits(&self.digits, &other.digits)),
+        }
+    }
+}
+
+impl Sub for &BigInt {
+    type Output = BigInt;
+
+    fn sub(self, other: &BigInt) -> BigInt {
+        self + &-other
+    }
+}
+
+impl Mul for &BigInt {
+    type Output = BigInt;
+
+    fn mul(self, other: &BigInt) -> BigInt {
+        BigInt::from_parts(
+            self.negative != other.negative,
+            mul_digits(&self.digits, &other.digits),
+        )
+    }
+}
+
+impl fmt::Display for BigInt {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let Some((most, rest)) = self.digits.split_last() else {
+            return write!(f, "0");
+        };
+        if self.negative {
+            write!(f, "-")?;
+        }
+        write!(f, "{most}")?;
+        rest.iter()
+            .rev()
+            .try_for_each(|d| write!(f, "{d:0width$}", width = DECIMAL_DIGITS_PER_DIGIT))
+    }
+}
+
+impl fmt::Debug for BigInt {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{self}")
+    }
+}
+
+/// Remove any trailing zeros from `digits`.
+fn trim(digits: &mut Vec<u32>) {
+    while digits.last() == Some(&0) {
+        digits.pop();
+    }
+}
+
+fn cmp_digits(l: &[u32], r: &[u32]) -> Ordering {
+    l.len()
+        .cmp(&r.len())
+        .then_with(|| l.iter().rev().cmp(r.iter().rev()))
+}
+
+fn add_digits(l: &[u32], r: &[u32]) -> Vec<u32> {
+    let (l, r) = if l.len() >= r.len() { (l, r) } else { (r, l) };
+    let mut sum = Vec::with_capacity(l.len() + 1);
+    let mut carry = 0;
+    for (idx, d) in l.iter().enumerate() {
+        let s = u64::from(*d) + u64::from(r.get(idx).copied().unwrap_or(0)) + carry;
+        sum.push((s % BASE) as u32);
+        carry = s / BASE;
+    }
+    if carry > 0 {
+        sum.push(carry as u32);
+    }
+    sum
+}
+
+/// Subtract `r` from `l`, which must be at least as large.
+fn sub_digits(l: &[u32], r: &[u32]) -> Vec<u32> {
+    let mut difference = Vec::with_capacity(l.len());
+    let mut borrow = 0;
+    for (idx, d) in l.iter().enumerate() {
+        let d = u64::from(*d);
+        let s = u64::from(r.get(idx).copied().unwrap_or(0)) + borrow;
+        if d >= s {
+            difference.push((d - s) as u32);
+            borrow = 0;
+        } else {
+            difference.push((d + BASE - s) as u32);
+            borrow = 1;
+        }
+    }
+    trim(&mut difference);
+    difference
+}
+
+fn mul_digits(l: &[u32], r: &[u32]) -> Vec<u32> {
+    let mut product = vec![0u64; l.len() + r.len()];
+    for (i, ld) in l.iter().enumerate() {
+        // Each digit of `product` & the carry stay below `BASE`, so this
+        // never exceeds `BASE * BASE - 1`.
+        let mut carry = 0;
+        for (j, rd) in r.iter().enumerate() {
+            let p = product[i + j] + u64::from(*ld) * u64::from(*rd) + carry;
+            product[i + j] = p % BASE;
+            carry = p / BASE;
+        }
+        product[i + r.len()] = carry;
+    }
+    let mut product = product.into_iter().map(|d| d as u32).collect();
+    trim(&mut product);
+    product
+}
+
+/// Divide `l` by `r`, which must be non-zero, returning the quotient &
+/// remainder.
+fn div_rem_digits(l: &[u32], r: &[u32]) -> (Vec<u32>, Vec<u32>) {
+    let mut quotient = vec![0; l.len()];
+    let mut remainder = Vec::new();
+    for (idx, d) in l.iter().enumerate().rev() {
+        remainder.insert(0, *d);
+        trim(&mut remainder);
+        // Binary search for the largest digit `q` with `r * q <= remainder`.
+        let (mut lo, mut hi) = (0, BASE as u32 - 1);
+        while lo < hi {
+            let mid = lo + (hi - lo).div_ceil(2);
+            match cmp_digits(&mul_digits(r, &[mid]), &remainder) {
+                Ordering::Greater => hi = mid - 1,
+                _ => lo = mid,
+            }
+        }
+        remainder = sub_digits(&remainder, &mul_digits(r, &[lo]));
+        quotient[idx] = lo;
+    }
+    trim(&mut quotient);
+    (quotient, remainder)
+}
+
+/// An exact rational number.
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct Rational {
+    /// Shares no factors with `denominator`.
+    numerator: BigInt,
+    /// Always positive.
+    denominator: BigInt,
+}
+
+impl Rational {
+    /// Build `numerator / denominator` in lowest terms, or return `None` if
+    /// `denominator` is zero.
+    pub fn new(numerator: BigInt, denominator: BigInt) -> Option<Self> {
+        if denominator.is_zero() {
+            return None;
+        }
+        let gcd = numerator.gcd(&denominator);
+        let (mut numerator, _) = numerator.checked_div_rem(&gcd)?;
+        let (mut denominator, _) = denominator.checked_div_rem(&gcd)?;
+        if denominator.is_negative() {
+            numerator = -&numerator;
+            denominator = -&denominator;
+        }
+        Some(Self {
+            numerator,
+            denominator,
+        })
+    }
+
+    pub fn from_integer(n: BigInt) -> Self {
+        Self {
+            numerator: n,
+            denominator: BigInt::one(),
+        }
+    }
+
+    /// Parse a number written in decimal, with an optional leading `-` and
+    /// an optional fractional part, such as `-0.25`.
+    pub fn parse(s: &str) -> Option<Self> {
+        let Some((whole, fraction)) = s.split_once('.') else {
+            return BigInt::parse(s).map(Self::from_integer);
+        };
+        if fraction.starts_with('-') {
+            return None;
+        }
+        let numerator = BigInt::parse(&format!("{whole}{fraction}"))?;
+        let denominator = BigInt::parse(&format!("1{}", "0".repeat(fraction.len())))?;
+        Self::new(numerator, denominator)
+    }
+
+    pub fn numerator(&self) -> &BigInt {
+        &self.numerator
+    }
+
+    pub fn denominator(&self) -> &BigInt {
+        &self.denominator
+    }
+
+    /// Divide by `other`, or return `None` if it's zero.
+    pub fn checked_div(&self, other: &Self) -> Option<Self> {
+        Self::new(
+            &self.numerator * &other.denominator,
+            &self.denominator * &other.numerator,
+        )
+    }
+
+    /// Convert to the nearest `f64`, rounding twice: once for each of the
+    /// numerator & denominator.
+    pub fn to_f64(&self) -> f64 {
+        self.numerator.to_f64() / self.denominator.to_f64()
+    }
+}
+
+impl Ord for Rational {
+    fn cmp(&self, other: &Self) -> Ordering {
+        // Denominators are positive, so cross-multiplying keeps the order.
+        (&self.numerator * &other.denominator).cmp(&(&other.numerator * &self.denominator))
+    }
+}
+
+impl PartialOrd for Rational {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Add for &Rational {
+    type Output = Rational;
+
+    fn add(self, other: &Rational) -> Rational {
+        Rational::new(
+            &(&self.numerator * &other.denominator) + &(&other.numerator * &self.denominator),
+            &self.denominator * &other.denominator,
+        )
+        .expect("denominators are non-zero")
+    }
+}
+
+impl Sub for &Rational {
+    type Output = Rational;
+
+    fn sub(self, other: &Rational) -> Rational {
+        Rational::new(
+            &(&self.numerator * &other.denominator) - &(&other.numerator * &self.denominator),
+            &self.denominator * &other.denominator,
+        )
+        .expect("denominators are non-zero")
+    }
+}
+
+impl Mul for &Rational {
+    type Output = Rational;
+
+    fn mul(self, other: &Rational) -> Rational {
+        Rational::new(
+            &self.numerator * &other.numerator,
+            &self.denominator * &other.denominator,
+        )
+        .expect("denominators are non-zero")
+    }
+}
+
+/// Shows whole numbers like integers, and others as `numerator/denominator`.
+impl fmt::Display for Rational {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.denominator == BigInt::one() {
+            write!(f, "{}", self.numerator)
+        } else {
+            write!(f, "{}/{}", self.numerator, self.denominator)
+        }
+    }
+}
+
+impl fmt::Debug for Rational {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{self}")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use quickcheck::quickcheck;
+
+    fn big(n: i128) -> BigInt {
+        BigInt::parse(&n.to_string()).unwrap()
+    }
+
+    quickcheck! {
+        fn parsing_then_showing_is_identity(n: i128) -> bool {
+            big(n).to_string() == n.to_string()
+        }
+    }
+
+    quickcheck! {
+        fn from_i64_agrees_with_parsing(n: i64) -> bool {
+            BigInt::from(n) == big(n.into()) && BigInt::from(n).to_i64() == Some(n)
+        }
+    }
+
+    quickcheck! {
+        fn arithmetic_agrees_with_i128(l: i64, r: i64) -> bool {
+            let (l, r) = (i128::from(l), i128::from(r));
+            let div_rem = big(l).checked_div_rem(&big(r));
+            &big(l) + &big(r) == big(l + r)
+                && &big(l) - &big(r) == big(l - r)
+                && &big(l) * &big(r) == big(l * r)
+                && big(l).cmp(&big(r)) == l.cmp(&r)
+                && div_rem == (r != 0).then(|| (big(l / r), big(l % r)))
+        }
+    }
+
+    quickcheck! {
+        fn division_inverts_multiplication(q: u128, d: u128, r: u128) -> bool {
+            let parse = |n: u128| BigInt::parse(&n.to_string()).unwrap();
+            if d == 0 {
+                return true;
+            }
+            let (q, d, r) = (parse(q), parse(d), parse(r % d));
+            (&(&q * &d) + &r).checked_div_rem(&d) == Some((q, r))
+        }
+    }
+
+    quickcheck! {
+        fn rationals_are_in_lowest_terms(n: i64, d: i64) -> bool {
+            match Rational::new(n.into(), d.into()) {
+                Some(r) => {
+                    r.denominator() > &BigInt::zero()
+                        && r.numerator().gcd(r.denominator()) == BigInt::one()
+                        && &r * &Rational::from_integer(d.into()) == Rational::from_integer(n.into())
+                }
+                None => d == 0,
+            }
+        }
+    }
+
+    #[test]
+    fn decimal_rationals_are_exact() {
+        let tenth = |s| Rational::parse(s).unwrap();
+        assert_eq!(&tenth("0.1") + &tenth("0.2"), tenth("0.3"));
+        assert_eq!(tenth("-1.50").to_string(), "-3/2");
+    }
+}
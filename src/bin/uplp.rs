@@ -5,33 +5,89 @@ use std::{
     path::PathBuf,
 };
 
-use clap::{Parser, Subcommand};
-use untitled_programming_language_project::{check_types, error, evaluate, parse};
+use clap::{Parser, Subcommand, ValueEnum};
+use untitled_programming_language_project::{
+    check_types_all, error, evaluate, evaluate_pure, explain_types, parse, parse_typed,
+    with_large_stack,
+};
 
 fn main() {
     let cli = Cli::parse();
+    // The results are printed & dropped on the same large stack they're made
+    // on, since syntax trees & values may be as deep as the program.
+    with_large_stack(|| run(cli));
+}
 
+fn run(cli: Cli) {
     match cli.command {
-        Commands::AstDump { file } => {
+        Commands::AstDump { file, typed: true } => {
+            let expr = with_source_file(file, parse_typed);
+            handle_result(expr)
+        }
+        Commands::AstDump { file, .. } => {
             let expr = with_source_file(file, parse);
             handle_result(expr)
         }
-        Commands::Evaluate { file } => {
-            let val = with_source_file(file, evaluate);
+        Commands::Evaluate { file, pure } => {
+            let val = with_source_file(file, if pure { evaluate_pure } else { evaluate });
             handle_result(val)
         }
-        Commands::TypeCheck { file } => {
-            let ty = with_source_file(file, check_types);
-            handle_result(ty)
-        }
+        Commands::TypeCheck {
+            file,
+            explain: true,
+            format,
+        } => match with_source_file(file, explain_types) {
+            Ok(explanation) => match format {
+                Format::Text => {
+                    for step in &explanation.steps {
+                        print!("{step}");
+                    }
+                    match explanation.result {
+                        Ok(ty) => println!("{ty}"),
+                        Err(e) => println!("[Error] {e:?}"),
+                    }
+                }
+                Format::Json => match serde_json::to_string_pretty(&explanation) {
+                    Ok(json) => println!("{json}"),
+                    Err(e) => println!("[JSON Error] {e:?}"),
+                },
+            },
+            Err(e) => handle_result::<()>(Err(e)),
+        },
+        Commands::TypeCheck { file, .. } => match read_source_file(file) {
+            Ok(src) => match check_types_all(&src) {
+                Err(error::Error::TypeErrors(errors)) => {
+                    for (location, e) in errors {
+                        let (line, col) = line_and_column(&src, location);
+                        println!("[Error] {line}:{col} {e:?}");
+                    }
+                }
+                Ok(ty) => println!("{ty}"),
+                Err(e) => handle_result::<()>(Err(e.into())),
+            },
+            Err(e) => handle_result::<()>(Err(e.into())),
+        },
     };
 }
 
 fn with_source_file<T>(p: PathBuf, op: fn(&str) -> Result<T, error::Error>) -> Result<T, Error> {
+    let contents = read_source_file(p)?;
+    Ok(op(contents.as_str())?)
+}
+
+fn read_source_file(p: PathBuf) -> io::Result<String> {
     let mut file = File::open(p)?;
     let mut contents = String::new();
     file.read_to_string(&mut contents)?;
-    Ok(op(contents.as_str())?)
+    Ok(contents)
+}
+
+/// The 1-based line & column of the byte offset `location` in `src`.
+fn line_and_column(src: &str, location: usize) -> (usize, usize) {
+    let before = &src[..location.min(src.len())];
+    let line = before.matches('\n').count() + 1;
+    let col = before.chars().rev().take_while(|c| *c != '\n').count() + 1;
+    (line, col)
 }
 
 enum Error {
@@ -81,11 +137,18 @@ enum Commands {
         /// The uplp source file to dump
         #[arg(short, long, value_name = "FILE")]
         file: PathBuf,
+        /// Typecheck the expression, & annotate every node with its type
+        #[arg(long)]
+        typed: bool,
     },
     /// Evaluate a uplp source file
     Evaluate {
         #[arg(short, long, value_name = "FILE")]
         file: PathBuf,
+        /// Reject the program without evaluating it if it could perform any
+        /// effects
+        #[arg(long)]
+        pure: bool,
     },
     /// Typecheck a uplp source file
     #[command(name = "typecheck")]
@@ -93,5 +156,18 @@ enum Commands {
         /// The uplp source file to typecheck
         #[arg(short, long, value_name = "FILE")]
         file: PathBuf,
+        /// Show each step taken to infer the type, along with how it changed
+        /// the typing context
+        #[arg(long)]
+        explain: bool,
+        /// The format to show the steps in
+        #[arg(long, value_enum, default_value_t = Format::Text, requires = "explain")]
+        format: Format,
     },
 }
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Format {
+    Text,
+    Json,
+}
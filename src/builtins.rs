@@ -0,0 +1,666 @@
+//! The types & functions which are available to every uplp program.
+
+use std::rc::Rc;
+
+use once_cell::sync::Lazy;
+
+use crate::{
+    ast::{Class, DataDecl, Expr, Variant},
+    bignum::Rational,
+    error::EvaluationError,
+    interner, parse_and_scope_check,
+    typ::{EffectVar, Effects, Row, Type},
+    values::{Key, List, Map, Val},
+};
+
+pub(crate) static OPTION: Lazy<interner::Id> = Lazy::new(|| interner::Id::new("Option"));
+pub(crate) static NONE: Lazy<interner::Id> = Lazy::new(|| interner::Id::new("None"));
+pub(crate) static SOME: Lazy<interner::Id> = Lazy::new(|| interner::Id::new("Some"));
+
+pub(crate) static RESULT: Lazy<interner::Id> = Lazy::new(|| interner::Id::new("Result"));
+pub(crate) static ERR: Lazy<interner::Id> = Lazy::new(|| interner::Id::new("Err"));
+pub(crate) static OK: Lazy<interner::Id> = Lazy::new(|| interner::Id::new("Ok"));
+
+pub(crate) static LIST: Lazy<interner::Id> = Lazy::new(|| interner::Id::new("List"));
+pub(crate) static NIL: Lazy<interner::Id> = Lazy::new(|| interner::Id::new("[]"));
+pub(crate) static CONS: Lazy<interner::Id> = Lazy::new(|| interner::Id::new("::"));
+
+pub(crate) static ARRAY: Lazy<interner::Id> = Lazy::new(|| interner::Id::new("Array"));
+pub(crate) static MAP: Lazy<interner::Id> = Lazy::new(|| interner::Id::new("Map"));
+pub(crate) static SET: Lazy<interner::Id> = Lazy::new(|| interner::Id::new("Set"));
+
+/// The built-in data types, which are declared before any program runs.
+///
+/// `List` is declared like any other data type so that it can be matched on,
+/// but its constructors can only be written as `[]` and `::`, and its values
+/// are represented by `Val::List` rather than `Val::Data`. Types without any
+/// variants, such as `Array` and `Map`, are opaque: their values can only be
+/// built and taken apart by built-in functions.
+pub(crate) fn data_types() -> Vec<DataDecl> {
+    let e = interner::Id::new("e");
+    let a = interner::Id::new("a");
+    let k = interner::Id::new("k");
+    let v = interner::Id::new("v");
+    vec![
+        // type Option a = None | Some a
+        DataDecl {
+            name: *OPTION,
+            params: vec![a],
+            variants: vec![
+                Variant {
+                    name: *NONE,
+                    fields: vec![],
+                },
+                Variant {
+                    name: *SOME,
+                    fields: vec![Type::Var(a)],
+                },
+            ],
+        },
+        // type Result e a = Err e | Ok a
+        DataDecl {
+            name: *RESULT,
+            params: vec![e, a],
+            variants: vec![
+                Variant {
+                    name: *ERR,
+                    fields: vec![Type::Var(e)],
+                },
+                Variant {
+                    name: *OK,
+                    fields: vec![Type::Var(a)],
+                },
+            ],
+        },
+        // type List a = [] | :: a (List a)
+        DataDecl {
+            name: *LIST,
+            params: vec![a],
+            variants: vec![
+                Variant {
+                    name: *NIL,
+                    fields: vec![],
+                },
+                Variant {
+                    name: *CONS,
+                    fields: vec![Type::Var(a), Type::Named(*LIST, vec![Type::Var(a)])],
+                },
+            ],
+        },
+        DataDecl {
+            name: *ARRAY,
+            params: vec![a],
+            variants: vec![],
+        },
+        DataDecl {
+            name: *MAP,
+            params: vec![k, v],
+            variants: vec![],
+        },
+        DataDecl {
+            name: *SET,
+            params: vec![a],
+            variants: vec![],
+        },
+    ]
+}
+
+/// A built-in function. These are referred to by qualified names, such as
+/// `Option.map`, and are either defined in uplp itself or implemented
+/// natively as primitives.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Builtin {
+    ArrayFromList,
+    ArrayLength,
+    ArrayMake,
+    ArraySet,
+    BigIntToInt,
+    BigIntToRational,
+    IntToBigInt,
+    IntToNum,
+    ListFold,
+    ListHead,
+    ListLength,
+    ListMap,
+    ListTail,
+    MapDelete,
+    MapEmpty,
+    MapFold,
+    MapInsert,
+    MapKeys,
+    MapLookup,
+    MapSize,
+    MapToList,
+    NumToInt,
+    OptionAndThen,
+    OptionMap,
+    OptionOkOr,
+    OptionUnwrapOr,
+    RationalDenominator,
+    RationalNumerator,
+    RationalToNum,
+    ResultAndThen,
+    ResultMap,
+    ResultMapErr,
+    ResultOk,
+    ResultUnwrapOr,
+    SetDelete,
+    SetEmpty,
+    SetFold,
+    SetInsert,
+    SetMember,
+    SetSize,
+    SetToList,
+    ShowShow,
+}
+
+impl Builtin {
+    const ALL: &'static [Builtin] = &[
+        Builtin::ArrayFromList,
+        Builtin::ArrayLength,
+        Builtin::ArrayMake,
+        Builtin::ArraySet,
+        Builtin::BigIntToInt,
+        Builtin::BigIntToRational,
+        Builtin::IntToBigInt,
+        Builtin::IntToNum,
+        Builtin::ListFold,
+        Builtin::ListHead,
+        Builtin::ListLength,
+        Builtin::ListMap,
+        Builtin::ListTail,
+        Builtin::MapDelete,
+        Builtin::MapEmpty,
+        Builtin::MapFold,
+        Builtin::MapInsert,
+        Builtin::MapKeys,
+        Builtin::MapLookup,
+        Builtin::MapSize,
+        Builtin::MapToList,
+        Builtin::NumToInt,
+        Builtin::OptionAndThen,
+        Builtin::OptionMap,
+        Builtin::OptionOkOr,
+        Builtin::OptionUnwrapOr,
+        Builtin::RationalDenominator,
+        Builtin::RationalNumerator,
+        Builtin::RationalToNum,
+        Builtin::ResultAndThen,
+        Builtin::ResultMap,
+        Builtin::ResultMapErr,
+        Builtin::ResultOk,
+        Builtin::ResultUnwrapOr,
+        Builtin::SetDelete,
+        Builtin::SetEmpty,
+        Builtin::SetFold,
+        Builtin::SetInsert,
+        Builtin::SetMember,
+        Builtin::SetSize,
+        Builtin::SetToList,
+        Builtin::ShowShow,
+    ];
+
+    /// Look up a built-in function by its qualified name.
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.iter().copied().find(|b| b.name() == name)
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Builtin::ArrayFromList => "Array.from_list",
+            Builtin::ArrayLength => "Array.length",
+            Builtin::ArrayMake => "Array.make",
+            Builtin::ArraySet => "Array.set",
+            Builtin::BigIntToInt => "BigInt.to_int",
+            Builtin::BigIntToRational => "BigInt.to_rational",
+            Builtin::IntToBigInt => "Int.to_big_int",
+            Builtin::IntToNum => "Int.to_num",
+            Builtin::ListFold => "List.fold",
+            Builtin::ListHead => "List.head",
+            Builtin::ListLength => "List.length",
+            Builtin::ListMap => "List.map",
+            Builtin::ListTail => "List.tail",
+            Builtin::MapDelete => "Map.delete",
+            Builtin::MapEmpty => "Map.empty",
+            Builtin::MapFold => "Map.fold",
+            Builtin::MapInsert => "Map.insert",
+            Builtin::MapKeys => "Map.keys",
+            Builtin::MapLookup => "Map.lookup",
+            Builtin::MapSize => "Map.size",
+            Builtin::MapToList => "Map.to_list",
+            Builtin::NumToInt => "Num.to_int",
+            Builtin::OptionAndThen => "Option.and_then",
+            Builtin::OptionMap => "Option.map",
+            Builtin::OptionOkOr => "Option.ok_or",
+            Builtin::OptionUnwrapOr => "Option.unwrap_or",
+            Builtin::RationalDenominator => "Rational.denominator",
+            Builtin::RationalNumerator => "Rational.numerator",
+            Builtin::RationalToNum => "Rational.to_num",
+            Builtin::ResultAndThen => "Result.and_then",
+            Builtin::ResultMap => "Result.map",
+            Builtin::ResultMapErr => "Result.map_err",
+            Builtin::ResultOk => "Result.ok",
+            Builtin::ResultUnwrapOr => "Result.unwrap_or",
+            Builtin::SetDelete => "Set.delete",
+            Builtin::SetEmpty => "Set.empty",
+            Builtin::SetFold => "Set.fold",
+            Builtin::SetInsert => "Set.insert",
+            Builtin::SetMember => "Set.member",
+            Builtin::SetSize => "Set.size",
+            Builtin::SetToList => "Set.to_list",
+            Builtin::ShowShow => "Show.show",
+        }
+    }
+
+    /// The type of the function. Any `Type::Var`s it contains are implicitly
+    /// quantified, so each use of the function may instantiate them
+    /// differently.
+    pub(crate) fn typ(&self) -> Type {
+        let var = |name: &str| Type::Var(interner::Id::new(name));
+        let (a, b, e, f, k, v) = (var("a"), var("b"), var("e"), var("f"), var("k"), var("v"));
+        let option = |t: &Type| Type::Named(*OPTION, vec![t.clone()]);
+        let result = |e: &Type, a: &Type| Type::Named(*RESULT, vec![e.clone(), a.clone()]);
+        let list = |t: &Type| Type::Named(*LIST, vec![t.clone()]);
+        let array = |t: &Type| Type::Named(*ARRAY, vec![t.clone()]);
+        let map = |k: &Type, v: &Type| Type::Named(*MAP, vec![k.clone(), v.clone()]);
+        let set = |t: &Type| Type::Named(*SET, vec![t.clone()]);
+        // Functions which call their function arguments perform whatever
+        // effects those do.
+        let eff = Effects::new(Vec::new(), Some(EffectVar::Var(interner::Id::new("eff"))));
+
+        match self {
+            Builtin::ArrayFromList => fun([list(&a)], array(&a)),
+            Builtin::ArrayLength => fun([array(&a)], Type::int()),
+            Builtin::ArrayMake => fun([Type::int(), a.clone()], array(&a)),
+            Builtin::ArraySet => fun([Type::int(), a.clone(), array(&a)], array(&a)),
+            Builtin::BigIntToInt => fun([Type::big_int()], Type::int()),
+            Builtin::BigIntToRational => fun([Type::big_int()], Type::rational()),
+            Builtin::IntToBigInt => fun([Type::int()], Type::big_int()),
+            Builtin::IntToNum => fun([Type::int()], Type::num()),
+            Builtin::ListFold => fun_performing(
+                [
+                    fun_performing([b.clone(), a.clone()], b.clone(), &eff),
+                    b.clone(),
+                    list(&a),
+                ],
+                b,
+                &eff,
+            ),
+            Builtin::ListHead => fun([list(&a)], option(&a)),
+            Builtin::ListLength => fun([list(&a)], Type::int()),
+            Builtin::ListMap => fun_performing(
+                [fun_performing([a.clone()], b.clone(), &eff), list(&a)],
+                list(&b),
+                &eff,
+            ),
+            Builtin::ListTail => fun([list(&a)], option(&list(&a))),
+            Builtin::MapDelete => fun([k.clone(), map(&k, &v)], map(&k, &v)),
+            Builtin::MapEmpty => map(&k, &v),
+            Builtin::MapFold => fun_performing(
+                [
+                    fun_performing([b.clone(), k.clone(), v.clone()], b.clone(), &eff),
+                    b.clone(),
+                    map(&k, &v),
+                ],
+                b,
+                &eff,
+            ),
+            Builtin::MapInsert => fun([k.clone(), v.clone(), map(&k, &v)], map(&k, &v)),
+            Builtin::MapKeys => fun([map(&k, &v)], list(&k)),
+            Builtin::MapLookup => fun([k.clone(), map(&k, &v)], option(&v)),
+            Builtin::MapSize => fun([map(&k, &v)], Type::int()),
+            Builtin::MapToList => {
+                let entry = Type::Record(Row::new(
+                    vec![
+                        (interner::Id::new("key"), k.clone()),
+                        (interner::Id::new("value"), v.clone()),
+                    ],
+                    None,
+                ));
+                fun([map(&k, &v)], list(&entry))
+            }
+            Builtin::NumToInt => fun([Type::num()], Type::int()),
+            Builtin::OptionAndThen => fun_performing(
+                [fun_performing([a.clone()], option(&b), &eff), option(&a)],
+                option(&b),
+                &eff,
+            ),
+            Builtin::OptionMap => fun_performing(
+                [fun_performing([a.clone()], b.clone(), &eff), option(&a)],
+                option(&b),
+                &eff,
+            ),
+            Builtin::OptionOkOr => fun([e.clone(), option(&a)], result(&e, &a)),
+            Builtin::OptionUnwrapOr => fun([a.clone(), option(&a)], a),
+            Builtin::RationalDenominator => fun([Type::rational()], Type::big_int()),
+            Builtin::RationalNumerator => fun([Type::rational()], Type::big_int()),
+            Builtin::RationalToNum => fun([Type::rational()], Type::num()),
+            Builtin::ResultAndThen => fun_performing(
+                [
+                    fun_performing([a.clone()], result(&e, &b), &eff),
+                    result(&e, &a),
+                ],
+                result(&e, &b),
+                &eff,
+            ),
+            Builtin::ResultMap => fun_performing(
+                [fun_performing([a.clone()], b.clone(), &eff), result(&e, &a)],
+                result(&e, &b),
+                &eff,
+            ),
+            Builtin::ResultMapErr => fun_performing(
+                [fun_performing([e.clone()], f.clone(), &eff), result(&e, &a)],
+                result(&f, &a),
+                &eff,
+            ),
+            Builtin::ResultOk => fun([result(&e, &a)], option(&a)),
+            Builtin::ResultUnwrapOr => fun([a.clone(), result(&e, &a)], a),
+            Builtin::SetDelete => fun([a.clone(), set(&a)], set(&a)),
+            Builtin::SetEmpty => set(&a),
+            Builtin::SetFold => fun_performing(
+                [
+                    fun_performing([b.clone(), a.clone()], b.clone(), &eff),
+                    b.clone(),
+                    set(&a),
+                ],
+                b,
+                &eff,
+            ),
+            Builtin::SetInsert => fun([a.clone(), set(&a)], set(&a)),
+            Builtin::SetMember => fun([a.clone(), set(&a)], Type::bool()),
+            Builtin::SetSize => fun([set(&a)], Type::int()),
+            Builtin::SetToList => fun([set(&a)], list(&a)),
+            Builtin::ShowShow => fun([a], Type::string()),
+        }
+    }
+
+    /// The type class which the function's first argument must have an
+    /// instance of, if any.
+    pub(crate) fn class(&self) -> Option<Class> {
+        match self {
+            Builtin::ShowShow => Some(Class::Show),
+            _ => None,
+        }
+    }
+
+    /// Whether the function looks its first argument up as a key in a map or
+    /// set, which it's ordered in by its type's instance of `Ord`.
+    pub(crate) fn is_keyed(&self) -> bool {
+        matches!(
+            self,
+            Builtin::MapDelete
+                | Builtin::MapInsert
+                | Builtin::MapLookup
+                | Builtin::SetDelete
+                | Builtin::SetInsert
+                | Builtin::SetMember
+        )
+    }
+
+    /// The uplp source code which defines the function, or `None` if it is a
+    /// primitive. Since definitions are compiled wherever the function is used,
+    /// they can't refer to themselves, even via other built-ins, without
+    /// recursing forever.
+    pub(crate) fn source(&self) -> Option<&'static str> {
+        let source = match self {
+            Builtin::ArrayFromList
+            | Builtin::ArrayLength
+            | Builtin::ArrayMake
+            | Builtin::ArraySet
+            | Builtin::BigIntToInt
+            | Builtin::BigIntToRational
+            | Builtin::IntToBigInt
+            | Builtin::IntToNum
+            | Builtin::MapDelete
+            | Builtin::MapEmpty
+            | Builtin::MapInsert
+            | Builtin::MapKeys
+            | Builtin::MapLookup
+            | Builtin::MapSize
+            | Builtin::MapToList
+            | Builtin::NumToInt
+            | Builtin::RationalDenominator
+            | Builtin::RationalNumerator
+            | Builtin::RationalToNum
+            | Builtin::SetDelete
+            | Builtin::SetEmpty
+            | Builtin::SetInsert
+            | Builtin::SetMember
+            | Builtin::SetSize
+            | Builtin::SetToList
+            | Builtin::ShowShow => return None,
+            Builtin::ListFold => {
+                "|f, init, xs|
+                    let rec go = |acc, xs| match xs with
+                        | [] -> acc
+                        | x :: rest -> go (f acc x) rest
+                    end in
+                    go init xs"
+            }
+            Builtin::ListHead => "|xs| match xs with | x :: _ -> Some x | [] -> None end",
+            Builtin::ListLength => {
+                "|xs|
+                    let rec go = |n, xs| match xs with
+                        | [] -> n
+                        | _ :: rest -> go (n + 1) rest
+                    end in
+                    go 0 xs"
+            }
+            Builtin::ListMap => {
+                "|f, xs|
+                    let rec go = |xs| match xs with
+                        | [] -> []
+                        | x :: rest -> f x :: go rest
+                    end in
+                    go xs"
+            }
+            Builtin::ListTail => "|xs| match xs with | _ :: rest -> Some rest | [] -> None end",
+            Builtin::MapFold => {
+                "|f, init, m| List.fold (|acc, entry| f acc entry.key entry.value) init (Map.to_list m)"
+            }
+            Builtin::OptionAndThen => "|f, opt| match opt with | Some x -> f x | None -> None end",
+            Builtin::OptionMap => {
+                "|f, opt| match opt with | Some x -> Some (f x) | None -> None end"
+            }
+            Builtin::OptionOkOr => "|e, opt| match opt with | Some x -> Ok x | None -> Err e end",
+            Builtin::OptionUnwrapOr => {
+                "|default, opt| match opt with | Some x -> x | None -> default end"
+            }
+            Builtin::ResultAndThen => "|f, res| match res with | Ok x -> f x | Err e -> Err e end",
+            Builtin::ResultMap => "|f, res| match res with | Ok x -> Ok (f x) | Err e -> Err e end",
+            Builtin::ResultMapErr => {
+                "|f, res| match res with | Ok x -> Ok x | Err e -> Err (f e) end"
+            }
+            Builtin::ResultOk => "|res| match res with | Ok x -> Some x | Err _ -> None end",
+            Builtin::ResultUnwrapOr => {
+                "|default, res| match res with | Ok x -> x | Err _ -> default end"
+            }
+            Builtin::SetFold => "|f, init, s| List.fold f init (Set.to_list s)",
+        };
+        Some(source)
+    }
+
+    /// How the function is defined.
+    pub(crate) fn definition(&self) -> Definition {
+        match self.source() {
+            Some(source) => {
+                let expr = parse_and_scope_check(source)
+                    .unwrap_or_else(|e| panic!("invalid definition for {}: {e:?}", self.name()));
+                Definition::Source(*expr)
+            }
+            None => Definition::Primitive(self.arity()),
+        }
+    }
+
+    /// The number of arguments the function takes.
+    pub(crate) fn arity(&self) -> usize {
+        let mut t = self.typ();
+        let mut arity = 0;
+        while let Type::Arrow(_, to, _) = t {
+            arity += 1;
+            t = *to;
+        }
+        arity
+    }
+
+    /// Run a primitive on its arguments.
+    pub(crate) fn apply(&self, args: Vec<Val>) -> Result<Val, EvaluationError> {
+        let mut args = args.into_iter();
+        let mut arg = || {
+            args.next().ok_or_else(|| {
+                EvaluationError::Internal(format!("too few arguments for {}", self.name()))
+            })
+        };
+        match self {
+            Builtin::ArrayFromList => match arg()? {
+                Val::List(l) => Ok(Val::Array(Rc::new(l.iter().cloned().collect()))),
+                v => Err(EvaluationError::Internal(format!(
+                    "expected List, got {v:?}"
+                ))),
+            },
+            Builtin::ArrayLength => Ok(Val::Int(arg()?.as_array()?.len() as i64)),
+            Builtin::ArrayMake => {
+                let len = arg()?.as_int()?;
+                let v = arg()?;
+                let len = usize::try_from(len).map_err(|_| EvaluationError::InvalidSize(len))?;
+                Ok(Val::Array(Rc::new(vec![v; len])))
+            }
+            Builtin::ArraySet => {
+                let index = arg()?.as_int()?;
+                let v = arg()?;
+                let mut array = arg()?.as_array()?;
+                let idx = Val::array_index(index, array.len())?;
+                // This only copies the array if it is shared.
+                Rc::make_mut(&mut array)[idx] = v;
+                Ok(Val::Array(array))
+            }
+            Builtin::BigIntToInt => arg()?
+                .as_big_int()?
+                .to_i64()
+                .map(Val::Int)
+                .ok_or(EvaluationError::IntegerOverflow),
+            Builtin::BigIntToRational => Ok(Val::Rational(Rc::new(Rational::from_integer(
+                arg()?.as_big_int()?.as_ref().clone(),
+            )))),
+            Builtin::IntToBigInt => Ok(Val::BigInt(Rc::new(arg()?.as_int()?.into()))),
+            Builtin::MapDelete => {
+                let key = Key::try_from(arg()?)?;
+                Ok(Val::Map(arg()?.as_map()?.search(&key)?.remove()))
+            }
+            Builtin::MapEmpty => Ok(Val::Map(Map::empty())),
+            Builtin::MapInsert => {
+                let key = Key::try_from(arg()?)?;
+                let v = arg()?;
+                Ok(Val::Map(arg()?.as_map()?.search(&key)?.insert(key, v)))
+            }
+            Builtin::MapKeys => Ok(list(arg()?.as_map()?.iter().map(|(k, _)| k.clone().into()))),
+            Builtin::MapLookup => {
+                let key = Key::try_from(arg()?)?;
+                Ok(match arg()?.as_map()?.search(&key)?.value() {
+                    Some(v) => Val::construct(*SOME, vec![v.clone()]),
+                    None => Val::construct(*NONE, Vec::new()),
+                })
+            }
+            Builtin::IntToNum => Ok(Val::Num(arg()?.as_int()? as f64)),
+            Builtin::MapSize => Ok(Val::Int(arg()?.as_map()?.len() as i64)),
+            Builtin::MapToList => {
+                let (key, value) = (interner::Id::new("key"), interner::Id::new("value"));
+                Ok(list(arg()?.as_map()?.iter().map(|(k, v)| {
+                    Val::record(vec![(key, k.clone().into()), (value, v.clone())])
+                })))
+            }
+            // Fractions are truncated towards zero, and numbers which are
+            // out of range, including infinities & NaN, overflow.
+            Builtin::NumToInt => {
+                let n = arg()?.as_num()?;
+                if n.is_finite() && n >= i64::MIN as f64 && n < i64::MAX as f64 {
+                    Ok(Val::Int(n as i64))
+                } else {
+                    Err(EvaluationError::IntegerOverflow)
+                }
+            }
+            Builtin::RationalDenominator => Ok(Val::BigInt(Rc::new(
+                arg()?.as_rational()?.denominator().clone(),
+            ))),
+            Builtin::RationalNumerator => Ok(Val::BigInt(Rc::new(
+                arg()?.as_rational()?.numerator().clone(),
+            ))),
+            Builtin::RationalToNum => Ok(Val::Num(arg()?.as_rational()?.to_f64())),
+            Builtin::SetDelete => {
+                let key = Key::try_from(arg()?)?;
+                Ok(Val::Set(arg()?.as_set()?.search(&key)?.remove()))
+            }
+            Builtin::SetEmpty => Ok(Val::Set(Map::empty())),
+            Builtin::SetInsert => {
+                let key = Key::try_from(arg()?)?;
+                Ok(Val::Set(arg()?.as_set()?.search(&key)?.insert(key, ())))
+            }
+            Builtin::SetMember => {
+                let key = Key::try_from(arg()?)?;
+                Ok(Val::Bool(arg()?.as_set()?.search(&key)?.value().is_some()))
+            }
+            Builtin::SetSize => Ok(Val::Int(arg()?.as_set()?.len() as i64)),
+            Builtin::SetToList => Ok(list(arg()?.as_set()?.iter().map(|(k, _)| k.clone().into()))),
+            // The VM shows values using any user-declared instances, so this
+            // only applies the built-in ones.
+            Builtin::ShowShow => Ok(Val::Str(arg()?.to_string().into())),
+            b => Err(EvaluationError::Internal(format!(
+                "{} is not a primitive",
+                b.name()
+            ))),
+        }
+    }
+}
+
+/// How a built-in function is defined.
+pub(crate) enum Definition {
+    /// A scope-checked uplp expression, which is compiled wherever the
+    /// function is used.
+    Source(Expr),
+    /// A primitive taking the given number of arguments, which the VM runs
+    /// using `Builtin::apply`.
+    Primitive(usize),
+}
+
+/// Build a list of `vals`, in order.
+fn list(vals: impl Iterator<Item = Val>) -> Val {
+    let vals: Vec<_> = vals.collect();
+    Val::List(vals.into_iter().rev().fold(List::empty(), |l, v| l.cons(v)))
+}
+
+/// Build the curried function type taking `args` and returning `ret`.
+fn fun<const N: usize>(args: [Type; N], ret: Type) -> Type {
+    fun_performing(args, ret, &Effects::pure())
+}
+
+/// Build the curried function type taking `args` and returning `ret`, which
+/// performs `effects` once it has all of its arguments.
+fn fun_performing<const N: usize>(args: [Type; N], ret: Type, effects: &Effects) -> Type {
+    let mut effects = Some(effects.clone());
+    args.into_iter().rev().fold(ret, |to, from| {
+        Type::Arrow(
+            Box::new(from),
+            Box::new(to),
+            effects.take().unwrap_or_default(),
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_builtin_definition_is_well_formed() {
+        for b in Builtin::ALL {
+            b.definition();
+        }
+    }
+
+    #[test]
+    fn every_builtin_can_be_found_by_name() {
+        for b in Builtin::ALL {
+            assert_eq!(Some(*b), Builtin::from_name(b.name()));
+        }
+    }
+}
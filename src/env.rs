@@ -31,6 +31,24 @@ impl<T: Clone> Env<T> {
     pub fn lookup(&self, n: usize) -> Option<T> {
         self.do_at_position(n, |t| t.clone())
     }
+
+    /// Pop the latest binding from this environment.
+    pub fn unbind(&mut self) {
+        if self.current.borrow().is_empty() {
+            // The latest binding was moved into a previous layer when the
+            // environment was cloned. That layer may be shared, so we take a
+            // copy of it without its last binding as our current layer.
+            let prev = self.previous.take();
+            if let Some(prev) = prev {
+                let mut layer = prev.current.borrow().clone();
+                layer.pop();
+                self.current = Rc::new(RefCell::new(layer));
+                self.previous = RefCell::new(prev.previous.borrow().clone());
+            }
+        } else {
+            self.current.borrow_mut().pop();
+        }
+    }
 }
 
 impl<T> Default for Env<T> {
@@ -55,11 +73,6 @@ impl<T> Env<T> {
         self.current.borrow_mut().push(t)
     }
 
-    /// Pop the latest binding from this environment.
-    pub fn unbind(&mut self) {
-        self.current.borrow_mut().pop();
-    }
-
     fn was_cloned(&self) -> bool {
         Rc::strong_count(&self.current) > 1
     }
@@ -5,6 +5,9 @@ use lalrpop_util;
 pub enum Error {
     ParseError(ParseError),
     TypeError(typ::Error),
+    /// Every type error in a program, along with the byte offset in the source
+    /// which each was found at.
+    TypeErrors(Vec<(usize, typ::Error)>),
     EvaluationError(EvaluationError),
 }
 
@@ -24,6 +27,31 @@ pub enum ParseError {
     UnboundIdentifier {
         ident: interner::Id,
     },
+    UnboundType {
+        ident: interner::Id,
+    },
+    DuplicateBinding {
+        ident: interner::Id,
+    },
+    DuplicateDeclaration {
+        ident: interner::Id,
+    },
+    UnboundClass {
+        ident: interner::Id,
+    },
+    /// A unit of measure which hasn't been declared with `unit`.
+    UnboundUnit {
+        ident: interner::Id,
+    },
+    DuplicateInstance {
+        class: interner::Id,
+        ident: interner::Id,
+    },
+    /// An integer literal which is too big to be an `Int`.
+    IntegerOutOfRange {
+        literal: String,
+        location: usize,
+    },
 }
 
 #[derive(Debug, PartialEq, Eq)]
@@ -32,7 +60,7 @@ pub enum Tok {
     Raw(String),
 }
 
-type LalrpopError<'src> = lalrpop_util::ParseError<usize, Token<'src>, &'static str>;
+type LalrpopError<'src> = lalrpop_util::ParseError<usize, Token<'src>, ParseError>;
 
 impl Error {
     pub fn from_lalrpop<'src>(e: LalrpopError<'src>, src: &'src str) -> Self {
@@ -63,7 +91,7 @@ impl Error {
                 token: Tok::Raw(tok.to_string()),
                 expected: vec![],
             },
-            User { .. } => unreachable!("We don't currently use lalrpop's user error feature"),
+            User { error } => error,
         };
 
         Self::ParseError(parse_error)
@@ -80,9 +108,31 @@ impl From<ParseError> for Error {
 
 #[derive(Debug, PartialEq, Eq)]
 pub enum EvaluationError {
+    /// A value didn't have the type it was cast to where it left `Dyn`
+    /// code. `location` is that of the expression the cast was applied to.
+    CastFailed {
+        expected: String,
+        got: String,
+        location: usize,
+    },
+    /// A variable didn't satisfy the predicate refining its type when it was
+    /// bound. `location` is that of the predicate.
+    ContractViolation {
+        predicate: String,
+        value: String,
+        location: usize,
+    },
     DivisionByZero,
     IllegalEquality,
+    /// The result of an operation on `Int`s is too big to be represented.
+    IntegerOverflow,
+    IndexOutOfBounds {
+        index: i64,
+        len: usize,
+    },
     Internal(String),
+    /// An array was made with a negative number of elements.
+    InvalidSize(i64),
 }
 
 impl From<EvaluationError> for Error {
@@ -17,6 +17,12 @@ impl Id {
         INTERNER.intern(s.as_ref())
     }
 
+    /// Construct an `Id` with the same name as `self`, which is nonetheless
+    /// distinct from it, and from every other `Id`.
+    pub fn fresh(&self) -> Self {
+        INTERNER.fresh(*self)
+    }
+
     /// Retrieves the string the `Id` was created with.
     pub fn name(&self) -> &str {
         INTERNER.lookup(*self)
@@ -43,6 +49,13 @@ impl<'a> Interner<'a> {
             .intern(s)
     }
 
+    pub fn fresh(&self, id: Id) -> Id {
+        self.0
+            .write()
+            .expect("Poisoned RWLock in Interner")
+            .fresh(id)
+    }
+
     pub fn lookup(&self, id: Id) -> &str {
         // In order to read from the `InnerInterner` we have to acquire the
         // read lock. This gives it a lifetime of just this function body,
@@ -97,7 +110,7 @@ impl<'a> InnerInterner<'a> {
                     .storage
                     .lock()
                     .expect("Poisoned Mutex in InnerInterner");
-                std::mem::transmute(storage.alloc_str(s.as_ref()))
+                std::mem::transmute::<&mut str, &'a str>(storage.alloc_str(s.as_ref()))
             };
 
             let id = Id(self.id_lookup.len());
@@ -108,6 +121,14 @@ impl<'a> InnerInterner<'a> {
         }
     }
 
+    // Return a new [Id] for the string `id` is for, which interning the string
+    // won't return.
+    fn fresh(&mut self, id: Id) -> Id {
+        let fresh = Id(self.id_lookup.len());
+        self.id_lookup.push(self.id_lookup[id.0]);
+        fresh
+    }
+
     // Lookup the [Id] and return a reference to the interned string.
     //
     // Panics if `id` was not created via a call to `intern`.
@@ -139,6 +160,15 @@ mod axioms {
         }
     }
 
+    quickcheck! {
+        fn fresh_id_has_same_string_but_differs(s: String) -> bool {
+            let interner = Interner::new();
+            let id = interner.intern(&s);
+            let fresh = interner.fresh(id);
+            fresh != id && interner.lookup(fresh) == s && interner.intern(&s) == id
+        }
+    }
+
     #[derive(Clone, Debug)]
     struct NonEqualStrings(String, String);
 
@@ -1,4 +1,4 @@
-use ast::{Expr, RawExpr};
+use ast::{Expr, RawExpr, TypedExpr};
 use error::Error;
 use lalrpop_util::{self, lalrpop_mod};
 use parser::UplpParser;
@@ -6,6 +6,8 @@ use scopes::ScopeChecker;
 use typ::Type;
 
 pub mod ast;
+pub mod bignum;
+pub mod builtins;
 mod env;
 pub mod error;
 mod interner;
@@ -19,27 +21,98 @@ lalrpop_mod!(
     pub parser
 );
 
+/// Programs are checked, compiled & shown by recursing over their syntax
+/// trees, which are as deep as their longest chain of e.g. `let`s. So each
+/// entry point, and each level of the recursion, makes sure there's at least
+/// this much stack left, whichever thread it's on.
+const RED_ZONE: usize = 32 << 20;
+
+/// The size of each new stack switched to when there's less than `RED_ZONE`
+/// left.
+const STACK_SIZE: usize = 256 << 20;
+
+/// Run `f`, on a new stack if there's less than `RED_ZONE` left. The syntax
+/// trees returned by `parse` & `parse_typed` are as deep as the programs
+/// they're for, so hosts should drop them in here too.
+pub fn with_large_stack<T>(f: impl FnOnce() -> T) -> T {
+    stacker::maybe_grow(RED_ZONE, STACK_SIZE, f)
+}
+
 pub fn parse(input: &str) -> Result<Box<RawExpr>, Error> {
+    with_large_stack(|| parse_raw(input))
+}
+
+fn parse_raw(input: &str) -> Result<Box<RawExpr>, Error> {
     let parser = UplpParser::new();
     let expr = parser
-        .parse(input)
+        .parse(input, input)
         .map_err(|e| Error::from_lalrpop(e, input))?;
     Ok(expr)
 }
 
 pub fn check_types(input: &str) -> Result<Type, Error> {
-    let expr = parse_and_scope_check(input)?;
-    let typ = typ::infer(&expr)?;
-    Ok(typ)
+    with_large_stack(|| {
+        let expr = parse_and_scope_check(input)?;
+        let typ = typ::infer(&expr)?;
+        Ok(typ)
+    })
+}
+
+/// Like `check_types`, but reports every type error in `input` rather than
+/// just the first.
+pub fn check_types_all(input: &str) -> Result<Type, Error> {
+    with_large_stack(|| {
+        let expr = parse_and_scope_check(input)?;
+        let typ = typ::infer_all(&expr).map_err(Error::TypeErrors)?;
+        Ok(typ)
+    })
+}
+
+/// Parse & typecheck `input`, annotating every node with its type.
+pub fn parse_typed(input: &str) -> Result<TypedExpr, Error> {
+    with_large_stack(|| {
+        let expr = parse_and_scope_check(input)?;
+        let typed = typ::infer_typed(&expr)?;
+        Ok(typed)
+    })
+}
+
+/// Typecheck `input`, explaining how its type was inferred or where
+/// inference failed.
+pub fn explain_types(input: &str) -> Result<typ::Explanation, Error> {
+    with_large_stack(|| {
+        let expr = parse_and_scope_check(input)?;
+        Ok(typ::explain(&expr))
+    })
 }
 
 pub fn evaluate(input: &str) -> Result<values::Val, error::Error> {
-    let expr = parse_and_scope_check(input)?;
+    with_large_stack(|| {
+        let expr = parse_and_scope_check(input)?;
+        let (casts, overloads) = typ::infer_casts(&expr)?;
+        evaluate_checked(&expr, casts, overloads)
+    })
+}
 
-    let _ = typ::infer(&expr)?;
+/// Like `evaluate`, but rejects `input` before evaluating it if it could
+/// perform any effects, or evaluates to a function which could. This is for
+/// hosts which need the programs they run to be pure.
+pub fn evaluate_pure(input: &str) -> Result<values::Val, error::Error> {
+    with_large_stack(|| {
+        let expr = parse_and_scope_check(input)?;
+        let (casts, overloads) = typ::infer_pure_casts(&expr)?;
+        evaluate_checked(&expr, casts, overloads)
+    })
+}
 
-    let compiler = vm::Compiler::new();
-    let code = compiler.compile(&expr);
+/// Evaluate the typechecked `expr`, which needs `casts` & `overloads`.
+fn evaluate_checked(
+    expr: &Expr,
+    casts: typ::Casts,
+    overloads: typ::Overloads,
+) -> Result<values::Val, error::Error> {
+    let compiler = vm::Compiler::new(casts, overloads);
+    let code = compiler.compile(expr);
 
     let mut vm = vm::VirtualMachine::new(code);
 
@@ -48,7 +121,7 @@ pub fn evaluate(input: &str) -> Result<values::Val, error::Error> {
 }
 
 fn parse_and_scope_check(input: &str) -> Result<Box<Expr>, Error> {
-    let expr = parse(input)?;
+    let expr = parse_raw(input)?;
 
     let mut scope_checker = ScopeChecker::new();
     let expr = scope_checker.check(*expr)?;
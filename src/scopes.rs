@@ -1,30 +1,126 @@
+use std::collections::{HashMap, HashSet};
+
 use crate::{
-    ast::{Expr, RawExpr},
+    ast::{Class, DataDecl, Expr, Instance, Newtype, Pattern, RawExpr, Variant},
+    builtins::{self, Builtin},
     error::{Error, ParseError},
     interner,
+    typ::{self, Factor, Measure, Primitive, Row, Type},
 };
 
 pub struct ScopeChecker {
     idents: Vec<interner::Id>,
+    /// The type names currently in scope, as they're written along with the
+    /// names they were declared as.
+    types: Vec<(interner::Id, interner::Id)>,
+    /// The constructors currently in scope, as they're written along with the
+    /// names they were declared as & their arities.
+    constructors: Vec<(interner::Id, interner::Id, usize)>,
+    /// The newtypes currently in scope, like `types`, which they're also in.
+    newtypes: Vec<(interner::Id, interner::Id)>,
+    /// The units of measure currently in scope.
+    units: Vec<interner::Id>,
+    /// Every type name declared so far. Types are nominal, so a type declared
+    /// with the same name as one in a different scope is declared as a fresh
+    /// name, which is shown the same but doesn't mix with the other.
+    declared_types: HashSet<interner::Id>,
+    /// Likewise for every constructor & unit name. These are kept apart from
+    /// type names, so that e.g. `type P = P Int` declares both. Units are
+    /// erased along with their names, so they can't be redeclared, even in a
+    /// different scope.
+    declared: HashSet<interner::Id>,
+    /// The constructors of every type declared so far.
+    data_constructors: HashMap<interner::Id, Vec<interner::Id>>,
+    /// Every instance declared so far. There may only be one instance of each
+    /// class for each type.
+    instances: HashSet<(Class, interner::Id)>,
 }
 
 impl ScopeChecker {
     pub fn new() -> ScopeChecker {
         let idents = Vec::new();
-        ScopeChecker { idents }
+        let mut checker = ScopeChecker {
+            idents,
+            types: Vec::new(),
+            constructors: Vec::new(),
+            newtypes: Vec::new(),
+            units: Vec::new(),
+            declared_types: HashSet::new(),
+            declared: HashSet::new(),
+            data_constructors: HashMap::new(),
+            instances: HashSet::new(),
+        };
+        // Like any other types, built-in types such as `List` may refer to
+        // themselves.
+        for decl in builtins::data_types() {
+            checker
+                .declare_data(decl)
+                .expect("built-in types are well-formed");
+        }
+        checker
     }
 }
 
 impl ScopeChecker {
     pub fn check(&mut self, raw_expr: RawExpr) -> Result<Expr, Error> {
-        match raw_expr {
-            RawExpr::Ascribed(e, t) => Ok(Expr::Ascribed(Box::new(self.check(*e)?), t)),
+        crate::with_large_stack(|| match raw_expr {
+            RawExpr::Ascribed(e, t) => {
+                let t = self.check_type(&t)?;
+                Ok(Expr::Ascribed(Box::new(self.check(*e)?), t))
+            }
             RawExpr::App(fnc, arg) => {
                 let fnc = self.check(*fnc)?;
                 let arg = self.check(*arg)?;
                 Ok(Expr::App(Box::new(fnc), Box::new(arg)))
             }
+            RawExpr::Constructor(id) => match self.newtype(id) {
+                Some(n) => Ok(Expr::Wrap(n)),
+                None => {
+                    let (ctor, arity) = self.constructor(id)?;
+                    Ok(Expr::Constructor(ctor, arity))
+                }
+            },
+            RawExpr::Field(record, field) => {
+                let record = self.check(*record)?;
+                Ok(Expr::Field(Box::new(record), field))
+            }
+            RawExpr::Index(array, index) => {
+                let array = self.check(*array)?;
+                let index = self.check(*index)?;
+                Ok(Expr::Index(Box::new(array), Box::new(index)))
+            }
+            RawExpr::Instance(class_name, typ, methods, body) => {
+                let class = Class::from_name(class_name.name())
+                    .ok_or(ParseError::UnboundClass { ident: class_name })?;
+                let typ = self.type_name(typ)?;
+                if !self.instances.insert((class, typ)) {
+                    return Err(ParseError::DuplicateInstance {
+                        class: class_name,
+                        ident: typ,
+                    }
+                    .into());
+                }
+                let instance = Instance {
+                    class,
+                    typ,
+                    constructors: self
+                        .data_constructors
+                        .get(&typ)
+                        .cloned()
+                        .unwrap_or_default(),
+                };
+                // The instance's methods are bound in the environment, in
+                // their own definitions too, so we reserve a slot for them
+                // which can't be named in the source.
+                self.idents.push(interner::Id::new("<instance>"));
+                let methods = self.check(*methods);
+                let body = methods.and_then(|methods| Ok((methods, self.check(*body)?)));
+                self.idents.pop();
+                let (methods, body) = body?;
+                Ok(Expr::Instance(instance, Box::new(methods), Box::new(body)))
+            }
             RawExpr::Lambda(id, ty, body) => {
+                let ty = ty.map(|t| self.check_type(&t)).transpose()?;
                 self.idents.push(id);
                 let body = Box::new(self.check(*body)?);
                 self.idents.pop();
@@ -34,26 +130,111 @@ impl ScopeChecker {
                 let binding = Box::new(self.check(*binding)?);
                 self.idents.push(ident);
                 let body = Box::new(self.check(*body)?);
+                self.idents.pop();
                 Ok(Expr::Let(false, ident, binding, body))
             }
             RawExpr::Let(true, ident, binding, body) => {
                 self.idents.push(ident);
                 let binding = Box::new(self.check(*binding)?);
                 let body = Box::new(self.check(*body)?);
+                self.idents.pop();
                 Ok(Expr::Let(true, ident, binding, body))
             }
             RawExpr::Literal(v) => Ok(Expr::Literal(v)),
+            RawExpr::Located(location, e) => Ok(Expr::Located(location, Box::new(self.check(*e)?))),
+            RawExpr::Measured(n, m) => {
+                self.check_measure(&m)?;
+                Ok(Expr::Measured(n, m))
+            }
             RawExpr::IfThenElse(cond, thn, els) => {
                 let cond = Box::new(self.check(*cond)?);
                 let thn = Box::new(self.check(*thn)?);
                 let els = Box::new(self.check(*els)?);
                 Ok(Expr::IfThenElse(cond, thn, els))
             }
+            RawExpr::Match(scrutinee, arms) => {
+                let scrutinee = Box::new(self.check(*scrutinee)?);
+                // The scrutinee is bound in the environment while matching, so
+                // we reserve a slot for it which can't be named in the source.
+                self.idents.push(interner::Id::new("<scrutinee>"));
+                let arms = arms
+                    .into_iter()
+                    .map(|(p, e)| {
+                        let (p, bindings) = self.check_pattern(&p)?;
+                        let n = bindings.len();
+                        self.idents.extend(bindings);
+                        let e = self.check(e);
+                        self.idents.truncate(self.idents.len() - n);
+                        Ok((p, e?))
+                    })
+                    .collect::<Result<_, Error>>();
+                self.idents.pop();
+                Ok(Expr::Match(scrutinee, arms?))
+            }
             RawExpr::Op(l, op, r) => {
                 let l = self.check(*l)?;
                 let r = self.check(*r)?;
                 Ok(Expr::Op(Box::new(l), op, Box::new(r)))
             }
+            RawExpr::Record(fields) => {
+                let fields = fields
+                    .into_iter()
+                    .map(|(field, e)| Ok((field, self.check(e)?)))
+                    .collect::<Result<_, Error>>()?;
+                Ok(Expr::Record(fields))
+            }
+            RawExpr::TypeDecl(decl, body) => {
+                let (types, constructors) = (self.types.len(), self.constructors.len());
+                let res = self
+                    .declare_data(decl)
+                    .and_then(|decl| Ok((decl, self.check(*body)?)));
+                self.types.truncate(types);
+                self.constructors.truncate(constructors);
+                let (decl, body) = res?;
+                Ok(Expr::TypeDecl(decl, Box::new(body)))
+            }
+            RawExpr::NewtypeDecl(newtype, body) => {
+                // Unlike data types, a newtype can't be recursive, since it
+                // would have no values.
+                let typ = self.check_type(&newtype.typ)?;
+                // A newtype's name is also the name of its constructor.
+                let name = self.declare_type(newtype.name)?;
+                self.declare_constructor(newtype.name)?;
+                self.types.push((newtype.name, name));
+                self.newtypes.push((newtype.name, name));
+                let body = self.check(*body);
+                self.newtypes.pop();
+                self.types.pop();
+                Ok(Expr::NewtypeDecl(Newtype { name, typ }, Box::new(body?)))
+            }
+            // Units only exist in types, so their declarations are erased.
+            RawExpr::UnitDecl(unit, body) => {
+                self.declare_unit(unit)?;
+                self.units.push(unit);
+                let body = self.check(*body);
+                self.units.pop();
+                body
+            }
+            RawExpr::Refined(contract, predicate, body) => {
+                let predicate = self.check(*predicate)?;
+                let body = self.check(*body)?;
+                Ok(Expr::Refined(contract, Box::new(predicate), Box::new(body)))
+            }
+            RawExpr::Propagate(e) => Ok(Expr::Propagate(Box::new(self.check(*e)?))),
+            RawExpr::QualifiedVar(id) => {
+                if let Some(b) = Builtin::from_name(id.name()) {
+                    return Ok(Expr::Builtin(b));
+                }
+                // Each newtype `N` comes with an `N.unwrap` function.
+                let newtype = match id.name().split_once('.') {
+                    Some((newtype, "unwrap")) => Some(interner::Id::new(newtype)),
+                    _ => None,
+                };
+                match newtype.and_then(|n| self.newtype(n)) {
+                    Some(n) => Ok(Expr::Unwrap(n)),
+                    None => Err(ParseError::UnboundIdentifier { ident: id }.into()),
+                }
+            }
             RawExpr::Var(id) => {
                 let de_bruijn_idx = self
                     .idents
@@ -63,6 +244,206 @@ impl ScopeChecker {
                     .ok_or(ParseError::UnboundIdentifier { ident: id })?;
                 Ok(Expr::Var(id, de_bruijn_idx))
             }
+        })
+    }
+
+    /// Bring the type `decl` declares into scope, along with its constructors,
+    /// and check its fields. The type is in scope in its own declaration, so
+    /// that it can be recursive. Returns `decl` with every name in it replaced
+    /// by the name it's declared as.
+    fn declare_data(&mut self, decl: DataDecl) -> Result<DataDecl, Error> {
+        let mut seen = HashSet::new();
+        if let Some(ident) = decl.params.iter().find(|p| !seen.insert(**p)) {
+            return Err(ParseError::DuplicateBinding { ident: *ident }.into());
+        }
+        let name = self.declare_type(decl.name)?;
+        self.types.push((decl.name, name));
+        let mut ctors = Vec::with_capacity(decl.variants.len());
+        for v in decl.variants.iter() {
+            let ctor = self.declare_constructor(v.name)?;
+            self.constructors.push((v.name, ctor, v.fields.len()));
+            ctors.push(ctor);
+        }
+        self.data_constructors.insert(name, ctors.clone());
+        let variants = decl
+            .variants
+            .iter()
+            .zip(ctors)
+            .map(|(v, name)| {
+                let fields = v
+                    .fields
+                    .iter()
+                    .map(|t| self.check_type_with_params(t, &decl.params))
+                    .collect::<Result<_, Error>>()?;
+                Ok(Variant { name, fields })
+            })
+            .collect::<Result<_, Error>>()?;
+        Ok(DataDecl {
+            name,
+            params: decl.params,
+            variants,
+        })
+    }
+
+    /// Declare the type `ident`, returning the name it's declared as. It may
+    /// not be declared again while it's in scope.
+    fn declare_type(&mut self, ident: interner::Id) -> Result<interner::Id, Error> {
+        if self.types.iter().any(|(t, _)| *t == ident) {
+            return Err(ParseError::DuplicateDeclaration { ident }.into());
         }
+        Ok(Self::fresh(&mut self.declared_types, ident))
+    }
+
+    /// Declare the constructor `ident`, like `declare_type`.
+    fn declare_constructor(&mut self, ident: interner::Id) -> Result<interner::Id, Error> {
+        if self.constructors.iter().any(|(c, _, _)| *c == ident)
+            || self.newtypes.iter().any(|(n, _)| *n == ident)
+        {
+            return Err(ParseError::DuplicateDeclaration { ident }.into());
+        }
+        Ok(Self::fresh(&mut self.declared, ident))
+    }
+
+    /// The name to declare `ident` as: itself, unless it's already been
+    /// `declared` in another scope.
+    fn fresh(declared: &mut HashSet<interner::Id>, ident: interner::Id) -> interner::Id {
+        if declared.insert(ident) {
+            ident
+        } else {
+            ident.fresh()
+        }
+    }
+
+    fn declare_unit(&mut self, ident: interner::Id) -> Result<(), Error> {
+        if self.declared.insert(ident) {
+            Ok(())
+        } else {
+            Err(ParseError::DuplicateDeclaration { ident }.into())
+        }
+    }
+
+    /// The name the type `ident` in scope was declared as.
+    fn type_name(&self, ident: interner::Id) -> Result<interner::Id, Error> {
+        self.types
+            .iter()
+            .rev()
+            .find_map(|(t, name)| (*t == ident).then_some(*name))
+            .ok_or_else(|| ParseError::UnboundType { ident }.into())
+    }
+
+    /// The name the newtype `ident` was declared as, if it's in scope.
+    fn newtype(&self, ident: interner::Id) -> Option<interner::Id> {
+        self.newtypes
+            .iter()
+            .rev()
+            .find_map(|(n, name)| (*n == ident).then_some(*name))
+    }
+
+    /// The name the constructor `ident` in scope was declared as, along with
+    /// its arity.
+    fn constructor(&self, ident: interner::Id) -> Result<(interner::Id, usize), Error> {
+        self.constructors
+            .iter()
+            .rev()
+            .find_map(|(c, name, arity)| (*c == ident).then_some((*name, *arity)))
+            .ok_or_else(|| ParseError::UnboundIdentifier { ident }.into())
+    }
+
+    /// Checks that every constructor in `p` is in scope, and returns `p` with
+    /// the names they were declared as, along with the variables it binds.
+    fn check_pattern(&self, p: &Pattern) -> Result<(Pattern, Vec<interner::Id>), Error> {
+        fn check_constructors(s: &ScopeChecker, p: &Pattern) -> Result<Pattern, Error> {
+            match p {
+                Pattern::Constructor(c, args) => match (s.newtype(*c), &args[..]) {
+                    (Some(n), [arg]) => {
+                        Ok(Pattern::Newtype(n, Box::new(check_constructors(s, arg)?)))
+                    }
+                    // A newtype's constructor always takes exactly one
+                    // argument, so this is the error the typechecker gives
+                    // for any other constructor applied to the wrong number.
+                    (Some(_), _) => Err(typ::Error::PatternArity {
+                        constructor: *c,
+                        expected: 1,
+                        got: args.len(),
+                    }
+                    .into()),
+                    (None, _) => {
+                        let (c, _) = s.constructor(*c)?;
+                        let args = args
+                            .iter()
+                            .map(|a| check_constructors(s, a))
+                            .collect::<Result<_, Error>>()?;
+                        Ok(Pattern::Constructor(c, args))
+                    }
+                },
+                // Only the scope checker builds newtype patterns.
+                Pattern::Newtype(..)
+                | Pattern::Literal(_)
+                | Pattern::Var(_)
+                | Pattern::Wildcard => Ok(p.clone()),
+            }
+        }
+        let p = check_constructors(self, p)?;
+
+        let bindings = p.bindings();
+        let mut seen = HashSet::new();
+        match bindings.iter().find(|b| !seen.insert(**b)) {
+            Some(ident) => Err(ParseError::DuplicateBinding { ident: *ident }.into()),
+            None => Ok((p, bindings)),
+        }
+    }
+
+    /// Checks that every named type in `t` is in scope, and returns `t` with
+    /// the names they were declared as. Whether they're applied to the right
+    /// types is up to the kind checker.
+    fn check_type(&self, t: &Type) -> Result<Type, Error> {
+        self.check_type_with_params(t, &[])
+    }
+
+    /// `check_type` for a type which may also refer to the given parameters.
+    fn check_type_with_params(&self, t: &Type, params: &[interner::Id]) -> Result<Type, Error> {
+        let check_all = |ts: &[Type]| {
+            ts.iter()
+                .map(|t| self.check_type_with_params(t, params))
+                .collect::<Result<Vec<_>, Error>>()
+        };
+        match t {
+            Type::Arrow(from, to, effects) => Ok(Type::Arrow(
+                Box::new(self.check_type_with_params(from, params)?),
+                Box::new(self.check_type_with_params(to, params)?),
+                effects.clone(),
+            )),
+            Type::Named(ident, args) => Ok(Type::Named(self.type_name(*ident)?, check_all(args)?)),
+            Type::App(head, args) => Ok(Type::App(
+                Box::new(self.check_type_with_params(head, params)?),
+                check_all(args)?,
+            )),
+            Type::Record(row) => {
+                let fields = row
+                    .fields()
+                    .iter()
+                    .map(|(f, t)| Ok((*f, self.check_type_with_params(t, params)?)))
+                    .collect::<Result<_, Error>>()?;
+                Ok(Type::Record(Row::new(fields, row.rest())))
+            }
+            Type::Var(ident) if params.contains(ident) => Ok(t.clone()),
+            Type::Var(ident) => Err(ParseError::UnboundType { ident: *ident }.into()),
+            Type::Primitive(Primitive::Num(m)) => {
+                self.check_measure(m)?;
+                Ok(t.clone())
+            }
+            Type::Dyn | Type::Error | Type::Existential(_) | Type::Primitive(_) => Ok(t.clone()),
+        }
+    }
+
+    /// Checks that every unit in `m` is in scope. Unit variables don't need
+    /// to be declared.
+    fn check_measure(&self, m: &Measure) -> Result<(), Error> {
+        m.factors().iter().try_for_each(|(f, _)| match f {
+            Factor::Unit(ident) if !self.units.contains(ident) => {
+                Err(ParseError::UnboundUnit { ident: *ident }.into())
+            }
+            _ => Ok(()),
+        })
     }
 }
@@ -0,0 +1,151 @@
+use std::{collections::HashMap, fmt, rc::Rc};
+
+use crate::{
+    ast::{DataDecl, Expr},
+    interner,
+};
+
+use super::{Row, Type};
+
+/// The casts a program needs at runtime, keyed by the address of the
+/// expression whose value each is applied to.
+pub type Casts = HashMap<*const Expr, Rc<Cast>>;
+
+/// A runtime check that a value of type `from`, which involves `Dyn`, really
+/// has the more precise type `to`. Functions can't be checked until they're
+/// called, so casting one wraps it in a function which casts its argument &
+/// result instead.
+#[derive(Clone, PartialEq)]
+pub struct Cast {
+    from: Type,
+    to: Type,
+    /// The location of the expression being cast, which is blamed if its
+    /// value doesn't have type `to`.
+    location: usize,
+    /// The data types whose values the cast may need to look inside, by name.
+    data_types: Rc<HashMap<interner::Id, DataDecl>>,
+    /// The underlying type of each newtype, whose values are those of the
+    /// underlying type at runtime.
+    newtypes: Rc<HashMap<interner::Id, Type>>,
+}
+
+impl Cast {
+    pub(crate) fn new(
+        from: Type,
+        to: Type,
+        location: usize,
+        data_types: Rc<HashMap<interner::Id, DataDecl>>,
+        newtypes: Rc<HashMap<interner::Id, Type>>,
+    ) -> Self {
+        Cast {
+            from,
+            to,
+            location,
+            data_types,
+            newtypes,
+        }
+    }
+
+    /// A cast between parts of the types of this one, which blames the same
+    /// expression.
+    pub(crate) fn between(&self, from: &Type, to: &Type) -> Self {
+        Cast {
+            from: from.clone(),
+            to: to.clone(),
+            location: self.location,
+            data_types: self.data_types.clone(),
+            newtypes: self.newtypes.clone(),
+        }
+    }
+
+    pub fn from(&self) -> &Type {
+        &self.from
+    }
+
+    pub fn to(&self) -> &Type {
+        &self.to
+    }
+
+    pub fn location(&self) -> usize {
+        self.location
+    }
+
+    pub(crate) fn data_type(&self, name: interner::Id) -> Option<&DataDecl> {
+        self.data_types.get(&name)
+    }
+
+    pub(crate) fn newtype(&self, name: interner::Id) -> Option<&Type> {
+        self.newtypes.get(&name)
+    }
+
+    /// Whether the cast can never fail or change the value it's applied to.
+    pub(crate) fn is_trivial(&self) -> bool {
+        self.is_trivial_between(&self.from, &self.to)
+    }
+
+    /// Whether casting a value of type `from` to type `to`, which are parts
+    /// of the types of this cast, can never fail or change the value. The
+    /// checker has already made sure that the types are consistent, so only
+    /// the places where one has `Dyn` & the other doesn't need checking.
+    pub(crate) fn is_trivial_between(&self, from: &Type, to: &Type) -> bool {
+        match (from, to) {
+            _ if from == to => true,
+            // A newtype's values are tagged with its name on their way into
+            // `Dyn`, so that they can't be mistaken for its underlying type's.
+            (Type::Named(n, _), Type::Dyn) if self.newtypes.contains_key(n) => false,
+            // Other values carry enough at runtime to be checked when they're
+            // cast back out of `Dyn`, but functions don't, so they must be
+            // wrapped on their way in.
+            (_, Type::Dyn) => self.is_trivial_between(from, &self.erase(from)),
+            (_, Type::Error | Type::Existential(_) | Type::Var(_)) => true,
+            // The constructor of an applied type is only unknown if it's still
+            // to be inferred, so there's nothing to check it against.
+            (_, Type::App(..)) => true,
+            (Type::Dyn, _) => false,
+            (Type::Arrow(from1, to1, _), Type::Arrow(from2, to2, _)) => {
+                self.is_trivial_between(from2, from1) && self.is_trivial_between(to1, to2)
+            }
+            (Type::Named(_, args1), Type::Named(_, args2)) => args1
+                .iter()
+                .zip(args2)
+                .all(|(a1, a2)| self.is_trivial_between(a1, a2)),
+            (Type::Record(r1), Type::Record(r2)) => {
+                r2.fields().iter().all(|(f, t)| match r1.get(*f) {
+                    Some(from) => self.is_trivial_between(from, t),
+                    None => false,
+                })
+            }
+            _ => true,
+        }
+    }
+
+    /// The type values of type `t` are treated as once they're cast to
+    /// `Dyn`, which is `t` with every function type replaced by `Dyn -> Dyn`,
+    /// and every newtype, whose values are tagged, by `Dyn`.
+    pub(crate) fn erase(&self, t: &Type) -> Type {
+        match t {
+            Type::Arrow(..) => Type::function(Type::Dyn, Type::Dyn),
+            Type::App(head, args) => {
+                Type::App(head.clone(), args.iter().map(|t| self.erase(t)).collect())
+            }
+            Type::Named(n, _) if self.newtypes.contains_key(n) => Type::Dyn,
+            Type::Named(n, args) => Type::Named(*n, args.iter().map(|t| self.erase(t)).collect()),
+            Type::Record(row) => Type::Record(Row::new(
+                row.fields()
+                    .iter()
+                    .map(|(f, t)| (*f, self.erase(t)))
+                    .collect(),
+                row.rest(),
+            )),
+            Type::Dyn | Type::Error | Type::Existential(_) | Type::Primitive(_) | Type::Var(_) => {
+                t.clone()
+            }
+        }
+    }
+}
+
+impl fmt::Debug for Cast {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Cast({} => {})", self.from, self.to)
+    }
+}
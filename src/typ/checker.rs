@@ -1,18 +1,421 @@
-use crate::ast::{BinaryOp, Expr};
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
+
+use crate::ast::{BinaryOp, Class, DataDecl, Expr, Newtype, Pattern, Variant};
+use crate::builtins;
+use crate::interner;
 
 use crate::values::Val;
 
 use super::ctx::Ctx;
-use super::{ctx, Error, Existential, Primitive, Type};
+use super::kinds::KindChecker;
+use super::overload::{self, InstanceKey, Overloads};
+use super::trace::{Derivation, Rule, Tracer};
+use super::{
+    ctx, exhaustiveness, Cast, Casts, EffectVar, Effects, Error, Existential, Factor, Measure,
+    Normalizer, Primitive, Row, Type,
+};
+
+/// A requirement for a type to have an instance of a class.
+struct Constraint {
+    class: Class,
+    typ: Type,
+    /// The user-declared instances in scope where the requirement arose.
+    instances: Vec<InstanceKey>,
+    /// The location of the expression which the requirement arose from.
+    location: usize,
+    /// Whether the type's values are within others which are compared,
+    /// ordered or shown structurally, where newtypes are erased, so their
+    /// instances can't be found.
+    nested: bool,
+}
 
 pub(crate) struct State {
     next_existential: usize,
+    /// The parameters & constructors of each declared data type, in
+    /// declaration order.
+    data_types: HashMap<interner::Id, (Vec<interner::Id>, Vec<interner::Id>)>,
+    /// The data type each constructor belongs to, along with its field types.
+    constructors: HashMap<interner::Id, (interner::Id, Vec<Type>)>,
+    /// The underlying type of each declared newtype.
+    newtypes: HashMap<interner::Id, Type>,
+    /// The kinds of the declared types, which annotations are checked
+    /// against.
+    kinds: KindChecker,
+    /// The return types of the functions whose bodies are being checked,
+    /// innermost last. `?` returns from the innermost one.
+    returns: Vec<Type>,
+    /// The effects which the functions whose bodies are being checked may
+    /// perform, innermost last. Calls may only perform the innermost one's.
+    /// Outside of any function, calls may perform any effects, unless the
+    /// program must be pure.
+    effects: Vec<Effects>,
+    /// Existentials standing for effects, which have none if nothing else
+    /// turns out to be performed.
+    effect_existentials: Vec<Existential>,
+    /// Whether the program must not perform any effects, nor evaluate to a
+    /// function which could.
+    pure: bool,
+    /// Existentials standing for the rest of rows which a `let` has
+    /// generalized over, so that each use of the variable it binds may have
+    /// different other fields.
+    generic_rows: HashSet<Existential>,
+    /// Types which must have instances of type classes, but which weren't yet
+    /// known when they were checked.
+    constraints: Vec<Constraint>,
+    /// Types which `?` was applied to before it was known whether they were
+    /// `Option`s or `Result`s, as their constructors applied to the success
+    /// type. They must turn out to be one of the two, and are `Option`s if
+    /// nothing else decides.
+    propagated: Vec<(usize, Type)>,
+    /// Products & quotients of operands whose types weren't known when they
+    /// were checked, as the operator along with the types of its operands &
+    /// result. They're all the same type, unless they're `Num`s, in which case
+    /// the result's unit is the product or quotient of the operands'.
+    products: Vec<(usize, BinaryOp, [Type; 3])>,
+    /// The user-declared instances in scope, innermost last.
+    instances: Vec<InstanceKey>,
+    /// Every user-declared instance in the program. A type with one of these
+    /// can only use the class where the instance is in scope, since otherwise
+    /// its values would be treated differently in different places.
+    declared_instances: HashSet<InstanceKey>,
+    /// The types declared where their scope's result is the program's, which
+    /// are the only local types that result's type may mention.
+    program_types: HashSet<interner::Id>,
+    /// The location of the innermost located expression being checked.
+    location: usize,
+    /// Whether to carry on after errors, giving the expressions they're in
+    /// `Type::Error`, rather than stopping at the first.
+    recover: bool,
+    /// The errors recovered from so far, along with their locations.
+    errors: Vec<(usize, Error)>,
+    /// The steps taken so far, if they're being recorded.
+    tracer: Option<Tracer>,
+    /// The type of each expression checked so far, keyed by its address, if
+    /// they're being recorded.
+    node_types: Option<HashMap<*const Expr, Type>>,
+    /// The types each expression whose value involves `Dyn` must be cast
+    /// between, along with its location, keyed by its address, if they're
+    /// being recorded.
+    casts: Option<HashMap<*const Expr, (Type, Type, usize)>>,
+    /// The instance each overloaded operator or built-in needs, keyed by its
+    /// address, if they're being recorded.
+    overloads: Option<HashMap<*const Expr, Constraint>>,
+    /// Existentials solved while checking a lambda, which were dropped from
+    /// the `Ctx` along with its argument.
+    dropped: Vec<ctx::Element>,
 }
 
 impl State {
-    pub(crate) fn new() -> Self {
-        Self {
+    /// Create the state for checking the program `e`.
+    pub(crate) fn new(e: &Expr, recover: bool) -> Self {
+        let mut declared_instances = HashSet::new();
+        find_instances(e, &mut declared_instances);
+        let mut state = Self {
             next_existential: 0,
+            data_types: HashMap::new(),
+            constructors: HashMap::new(),
+            newtypes: HashMap::new(),
+            kinds: KindChecker::default(),
+            returns: Vec::new(),
+            effects: Vec::new(),
+            effect_existentials: Vec::new(),
+            pure: false,
+            generic_rows: HashSet::new(),
+            constraints: Vec::new(),
+            propagated: Vec::new(),
+            products: Vec::new(),
+            instances: Vec::new(),
+            declared_instances,
+            program_types: program_types(e),
+            location: 0,
+            recover,
+            errors: Vec::new(),
+            tracer: None,
+            node_types: None,
+            casts: None,
+            overloads: None,
+            dropped: Vec::new(),
+        };
+        for decl in builtins::data_types() {
+            state
+                .declare(&decl)
+                .expect("built-in types are well-kinded");
+        }
+        state
+    }
+
+    /// Record the data type declared by `decl`, after checking that its
+    /// fields' types are well-kinded. Names are checked for uniqueness during
+    /// scope checking, so we don't need to do that here.
+    fn declare(&mut self, decl: &DataDecl) -> Result<(), Error> {
+        self.kinds.declare(decl)?;
+        let ctors = decl.variants.iter().map(|v| v.name).collect();
+        self.data_types
+            .insert(decl.name, (decl.params.clone(), ctors));
+        for v in decl.variants.iter() {
+            self.constructors
+                .insert(v.name, (decl.name, v.fields.clone()));
+        }
+        Ok(())
+    }
+
+    /// Get the underlying type of the newtype `name`.
+    pub(crate) fn newtype(&self, name: interner::Id) -> Result<&Type, Error> {
+        self.newtypes
+            .get(&name)
+            .ok_or_else(|| Error::Internal(format!("undeclared newtype {}", name.name())))
+    }
+
+    /// Get the type parameters of the data type `name`.
+    fn params_of(&self, name: interner::Id) -> Result<&[interner::Id], Error> {
+        self.data_types
+            .get(&name)
+            .map(|(params, _)| params.as_slice())
+            .ok_or_else(|| Error::Internal(format!("undeclared type {}", name.name())))
+    }
+
+    /// Get the constructors of the data type `name`, if it has been declared.
+    pub(crate) fn constructors_of(&self, name: interner::Id) -> Option<&[interner::Id]> {
+        self.data_types
+            .get(&name)
+            .map(|(_, ctors)| ctors.as_slice())
+    }
+
+    /// Get the data type `ctor` constructs, along with its field types.
+    pub(crate) fn constructor(
+        &self,
+        ctor: interner::Id,
+    ) -> Result<&(interner::Id, Vec<Type>), Error> {
+        self.constructors
+            .get(&ctor)
+            .ok_or_else(|| Error::Internal(format!("undeclared constructor {}", ctor.name())))
+    }
+
+    /// Get the field types of `ctor` when it constructs a value of type `t`,
+    /// substituting `t`'s type arguments for the data type's parameters.
+    pub(crate) fn constructor_fields(
+        &self,
+        ctor: interner::Id,
+        t: &Type,
+    ) -> Result<Vec<Type>, Error> {
+        let (data_type, fields) = self.constructor(ctor)?;
+        let args = match t {
+            Type::Named(n, args) if n == data_type => args,
+            _ => {
+                return Err(Error::Internal(format!(
+                    "constructor {} used at type {t}",
+                    ctor.name()
+                )))
+            }
+        };
+        let substitution: Vec<_> = self
+            .params_of(*data_type)?
+            .iter()
+            .copied()
+            .zip(args.iter().cloned())
+            .collect();
+        Ok(fields.iter().map(|f| f.substitute(&substitution)).collect())
+    }
+
+    /// Require the key types of any maps or sets in `t` to be comparable.
+    fn require_comparable_keys(&mut self, t: &Type) {
+        match t {
+            Type::Arrow(from, to, _) => {
+                self.require_comparable_keys(from);
+                self.require_comparable_keys(to);
+            }
+            Type::Named(n, args) => {
+                if *n == *builtins::MAP || *n == *builtins::SET {
+                    self.require(Class::Ord, args[0].clone());
+                }
+                args.iter().for_each(|a| self.require_comparable_keys(a));
+            }
+            Type::App(head, args) => {
+                self.require_comparable_keys(head);
+                args.iter().for_each(|a| self.require_comparable_keys(a));
+            }
+            Type::Record(row) => row
+                .fields()
+                .iter()
+                .for_each(|(_, t)| self.require_comparable_keys(t)),
+            Type::Dyn | Type::Error | Type::Existential(_) | Type::Primitive(_) | Type::Var(_) => {}
+        }
+    }
+
+    /// Require `t` to have an instance of `class`.
+    fn require(&mut self, class: Class, t: Type) {
+        self.constraints.push(Constraint {
+            class,
+            typ: t,
+            instances: self.instances.clone(),
+            location: self.location,
+            nested: false,
+        });
+    }
+
+    /// Record `e`, which happened at `location`, and carry on if we're
+    /// recovering from errors. Otherwise, return it.
+    fn report(&mut self, location: usize, e: Error) -> Result<(), Error> {
+        if self.recover {
+            self.errors.push((location, e));
+            Ok(())
+        } else {
+            Err(e)
+        }
+    }
+
+    /// Take the errors recovered from so far.
+    pub(crate) fn take_errors(&mut self) -> Vec<(usize, Error)> {
+        std::mem::take(&mut self.errors)
+    }
+
+    /// The location of the innermost located expression being checked.
+    pub(crate) fn location(&self) -> usize {
+        self.location
+    }
+
+    /// Start recording the steps taken while checking.
+    pub(crate) fn record_trace(&mut self) {
+        self.tracer = Some(Tracer::default());
+    }
+
+    /// Take the derivations of the outermost steps taken so far.
+    pub(crate) fn take_trace(&mut self) -> Vec<Derivation> {
+        self.tracer.take().map(Tracer::finish).unwrap_or_default()
+    }
+
+    /// Enter a step, if they're being recorded. `judgement` is only rendered
+    /// when they are.
+    fn enter(
+        &mut self,
+        rule: Rule,
+        ctx: &Ctx,
+        judgement: impl FnOnce(&mut dyn std::fmt::Write) -> std::fmt::Result,
+    ) {
+        if let Some(tracer) = &mut self.tracer {
+            tracer.enter(rule, judgement, ctx);
+        }
+    }
+
+    /// Exit the innermost step, if they're being recorded.
+    fn exit(&mut self, output: Result<(&Ctx, Option<&Type>), &Error>) {
+        if let Some(tracer) = &mut self.tracer {
+            tracer.exit(output);
+        }
+    }
+
+    /// Require the program to be pure, so that it can't perform any effects
+    /// when it's evaluated.
+    pub(crate) fn require_pure(&mut self) {
+        self.pure = true;
+        self.effects = vec![Effects::pure()];
+    }
+
+    /// Start recording the type of every expression checked.
+    pub(crate) fn record_types(&mut self) {
+        self.node_types = Some(HashMap::new());
+    }
+
+    /// Extend `ctx` with any solutions which were dropped from it, so that
+    /// the types recorded while checking can be applied to it.
+    pub(crate) fn restore_dropped(&mut self, ctx: Ctx) -> Ctx {
+        std::mem::take(&mut self.dropped)
+            .into_iter()
+            .fold(ctx, Ctx::add)
+    }
+
+    /// Take the type of every expression checked.
+    pub(crate) fn take_types(&mut self) -> HashMap<*const Expr, Type> {
+        self.node_types.take().unwrap_or_default()
+    }
+
+    fn record_type(&mut self, e: &Expr, t: &Type) {
+        if let Some(types) = &mut self.node_types {
+            types.insert(e, t.clone());
+        }
+    }
+
+    /// Start recording the casts needed where `Dyn` values meet other types.
+    pub(crate) fn record_casts(&mut self) {
+        self.casts = Some(HashMap::new());
+    }
+
+    /// Take the casts recorded so far, with their types applied to `ctx`,
+    /// leaving out any which turned out not to need checking.
+    pub(crate) fn take_casts(&mut self, ctx: &Ctx) -> Casts {
+        let data_types: HashMap<_, _> = self
+            .data_types
+            .iter()
+            .map(|(name, (params, ctors))| {
+                let variants = ctors
+                    .iter()
+                    .map(|c| Variant {
+                        name: *c,
+                        fields: self.constructors[c].1.clone(),
+                    })
+                    .collect();
+                let decl = DataDecl {
+                    name: *name,
+                    params: params.clone(),
+                    variants,
+                };
+                (*name, decl)
+            })
+            .collect();
+        let data_types = Rc::new(data_types);
+        let newtypes = Rc::new(self.newtypes.clone());
+        self.casts
+            .take()
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|(e, (from, to, location))| {
+                let mut n = Normalizer::default();
+                let from = n.typ(&from.apply(ctx));
+                let to = n.typ(&to.apply(ctx));
+                let cast = Cast::new(from, to, location, data_types.clone(), newtypes.clone());
+                (!cast.is_trivial()).then(|| (e, Rc::new(cast)))
+            })
+            .collect()
+    }
+
+    /// Record that the value of `e`, of type `from`, must be cast to `to`.
+    fn record_cast(&mut self, e: &Expr, from: &Type, to: &Type) {
+        if let Some(casts) = &mut self.casts {
+            casts.insert(e.unlocated(), (from.clone(), to.clone(), self.location));
+        }
+    }
+
+    /// Start recording how overloaded operators & built-ins are resolved.
+    pub(crate) fn record_overloads(&mut self) {
+        self.overloads = Some(HashMap::new());
+    }
+
+    /// Take how the overloaded operators & built-ins recorded so far are
+    /// resolved, now that `ctx` knows their types, leaving out any which use
+    /// the built-in behaviour.
+    pub(crate) fn take_overloads(&mut self, ctx: &Ctx) -> Result<Overloads, Error> {
+        let mut resolved = HashMap::new();
+        for (e, c) in self.overloads.take().unwrap_or_default() {
+            if let Some(o) = overload::resolve(self, c.class, &c.typ.apply(ctx), &c.instances)? {
+                resolved.insert(e, o);
+            }
+        }
+        Ok(resolved)
+    }
+
+    /// Record that `e` is an overloaded operator or built-in, which needs an
+    /// instance of `class` for `t`.
+    fn record_overload(&mut self, e: &Expr, class: Class, t: &Type) {
+        if let Some(overloads) = &mut self.overloads {
+            let c = Constraint {
+                class,
+                typ: t.clone(),
+                instances: self.instances.clone(),
+                location: self.location,
+                nested: false,
+            };
+            overloads.insert(e.unlocated(), c);
         }
     }
 
@@ -28,20 +431,133 @@ impl State {
 /// Synthesize a type for `e`. Returns both the synthesized `Type` and the
 /// updated `Ctx` if successful, and an `Error` otherwise.
 pub(crate) fn synthesize_type(state: &mut State, ctx: Ctx, e: &Expr) -> Result<(Type, Ctx), Error> {
+    // Locations aren't interesting steps in their own right.
+    if let Expr::Located(..) = e {
+        return crate::with_large_stack(|| synthesize_type_untraced(state, ctx, e));
+    }
+    state.enter(Rule::Synthesize, &ctx, |w| write!(w, "{e:?} =>"));
+    let res = crate::with_large_stack(|| synthesize_type_untraced(state, ctx, e));
+    state.exit(res.as_ref().map(|(t, ctx)| (ctx, Some(t))));
+    if let Ok((t, _)) = &res {
+        state.record_type(e, t);
+    }
+    res
+}
+
+fn synthesize_type_untraced(state: &mut State, ctx: Ctx, e: &Expr) -> Result<(Type, Ctx), Error> {
     match e {
         Expr::Literal(l) => Ok((l.typ(), ctx)),
+        Expr::Measured(_, m) => Ok((Type::measured(m.clone()), ctx)),
+        Expr::Located(location, e) => {
+            let outer = std::mem::replace(&mut state.location, *location);
+            let res = if state.recover {
+                let before = ctx.clone();
+                synthesize_type(state, ctx, e).or_else(|err| {
+                    state.report(state.location, err)?;
+                    Ok((Type::Error, before))
+                })
+            } else {
+                synthesize_type(state, ctx, e)
+            };
+            state.location = outer;
+            res
+        }
         Expr::Var(id, _) => {
-            let t = ctx.get_annotation(*id)?;
-            Ok((t.clone(), ctx))
+            let t = ctx.get_annotation(*id)?.clone();
+            let (t, ctx) = instantiate_units(state, ctx, &t);
+            Ok(instantiate_rows(state, ctx, &t))
         }
         Expr::Ascribed(e, t) => {
+            state.kinds.check(t)?;
             ctx.check_type_well_formed(t)?;
             let ctx = check_type(state, ctx, e, t)?;
             Ok((t.clone(), ctx))
         }
+        Expr::Builtin(b) => {
+            let (t, ctx) = instantiate_vars(state, ctx, &b.typ());
+            state.require_comparable_keys(&t);
+            if let (Some(class), Type::Arrow(arg, _, _)) = (b.class(), &t) {
+                state.require(class, *arg.clone());
+                state.record_overload(e, class, arg);
+            }
+            if let (true, Type::Arrow(key, _, _)) = (b.is_keyed(), &t) {
+                state.record_overload(e, Class::Ord, key);
+            }
+            Ok((t, ctx))
+        }
+        Expr::Constructor(c, _) => {
+            let (data_type, fields) = state.constructor(*c)?;
+            let params = state.params_of(*data_type)?;
+            let result = Type::Named(*data_type, params.iter().copied().map(Type::Var).collect());
+            let t = fields
+                .iter()
+                .rev()
+                .fold(result, |to, from| Type::function(from.clone(), to));
+            Ok(instantiate_vars(state, ctx, &t))
+        }
+        Expr::Field(record, field) => {
+            let (record_ty, ctx) = synthesize_type(state, ctx, record)?;
+            match record_ty.apply(&ctx) {
+                Type::Record(row) if row.get(*field).is_some() => {
+                    let t = row.get(*field).cloned().expect("field was just found");
+                    Ok((t, ctx))
+                }
+                // A `Dyn` value is checked for the field when it's accessed.
+                Type::Dyn => {
+                    let rest = state.fresh_existential();
+                    let ctx = ctx.add(ctx::Element::Existential(rest));
+                    let expected = Type::Record(Row::new(vec![(*field, Type::Dyn)], Some(rest)));
+                    state.record_cast(record, &Type::Dyn, &expected);
+                    Ok((Type::Dyn, ctx))
+                }
+                record_ty => {
+                    // We don't know the field's type yet, so we check that the
+                    // record is a subtype of `{ field: α | ρ }`, for fresh α & ρ.
+                    let field_ty = state.fresh_existential();
+                    let rest = state.fresh_existential();
+                    let ctx = ctx
+                        .add(ctx::Element::Existential(field_ty))
+                        .add(ctx::Element::Existential(rest));
+                    let expected = Type::Record(Row::new(
+                        vec![(*field, Type::Existential(field_ty))],
+                        Some(rest),
+                    ));
+                    let ctx = covariant_subtype(state, ctx, &record_ty, &expected)?;
+                    Ok((Type::Existential(field_ty).apply(&ctx), ctx))
+                }
+            }
+        }
+        Expr::Index(array, index) => {
+            let ctx = check_type(state, ctx, index, &Type::int())?;
+            let (array_ty, ctx) = synthesize_type(state, ctx, array)?;
+            if array_ty.apply(&ctx) == Type::Dyn {
+                let expected = Type::Named(*builtins::ARRAY, vec![Type::Dyn]);
+                state.record_cast(array, &Type::Dyn, &expected);
+                return Ok((Type::Dyn, ctx));
+            }
+            let (elem_ty, ctx) = fresh(state, ctx);
+            let expected = Type::Named(*builtins::ARRAY, vec![elem_ty.clone()]);
+            let array_ty = array_ty.apply(&ctx);
+            let ctx = covariant_subtype(state, ctx, &array_ty, &expected)?;
+            Ok((elem_ty.apply(&ctx), ctx))
+        }
+        Expr::Instance(instance, methods, body) => {
+            let methods_ty = methods_type(instance.class, &Type::Named(instance.typ, Vec::new()));
+            // The instance is in scope in its own methods, so that they can
+            // be recursive.
+            state.instances.push((instance.class, instance.typ));
+            let res = check_methods(instance.class, methods)
+                .and_then(|()| check_type(state, ctx, methods, &methods_ty))
+                .and_then(|ctx| synthesize_type(state, ctx, body));
+            state.instances.pop();
+            res
+        }
         Expr::Lambda(id, t, e) => {
             let (from_ty, ctx) = match t {
-                Some(t) => (t.clone(), ctx),
+                Some(t) => {
+                    state.kinds.check(t)?;
+                    (t.clone(), ctx)
+                }
                 None => {
                     let from = state.fresh_existential();
                     let ctx = ctx.add(ctx::Element::Existential(from));
@@ -49,16 +565,26 @@ pub(crate) fn synthesize_type(state: &mut State, ctx: Ctx, e: &Expr) -> Result<(
                 }
             };
             let to = state.fresh_existential();
+            // The lambda performs whatever effects its body turns out to.
+            let (effects, ctx) = fresh_effects(state, ctx);
 
             // Insert the existential types into the context, as well as `v: inferred_from`.
+            let typed_var = ctx::Element::TypedVariable(*id, from_ty.clone());
             let ctx = ctx
                 .add(ctx::Element::Existential(to))
-                .add(ctx::Element::TypedVariable(*id, from_ty.clone()));
+                .add(typed_var.clone());
             // Then check that the lambda's body typechecks as `inferred_to` in that context
-            let ctx = check_type(state, ctx, e, &Type::Existential(to))?;
+            state.returns.push(Type::Existential(to));
+            state.effects.push(effects.clone());
+            let ctx = check_type(state, ctx, e, &Type::Existential(to));
+            state.effects.pop();
+            state.returns.pop();
+            // The argument goes out of scope with the body, but any
+            // existentials declared since are kept, as the result may need them.
+            let ctx = ctx?.insert_in_place(typed_var, &[])?;
             // if so then it must have type `inferred_from -> inferred_to`
             Ok((
-                Type::Arrow(Box::new(from_ty), Box::new(Type::Existential(to))),
+                Type::Arrow(Box::new(from_ty), Box::new(Type::Existential(to)), effects),
                 ctx,
             ))
         }
@@ -69,10 +595,12 @@ pub(crate) fn synthesize_type(state: &mut State, ctx: Ctx, e: &Expr) -> Result<(
                 Type::Existential(a) => {
                     let from = state.fresh_existential();
                     let to = state.fresh_existential();
+                    let effects = fresh_effect_existential(state);
 
                     let ctx = ctx.insert_in_place(
                         ctx::Element::Existential(a),
                         &[
+                            ctx::Element::Existential(effects),
                             ctx::Element::Existential(to),
                             ctx::Element::Existential(from),
                             ctx::Element::Solved(
@@ -80,22 +608,39 @@ pub(crate) fn synthesize_type(state: &mut State, ctx: Ctx, e: &Expr) -> Result<(
                                 Type::Arrow(
                                     Box::new(Type::Existential(from)),
                                     Box::new(Type::Existential(to)),
+                                    Effects::unknown(effects),
                                 ),
                             ),
                         ],
                     )?;
                     let ctx = check_type(state, ctx, e2, &Type::Existential(from))?;
+                    let ctx = perform(state, ctx, &Effects::unknown(effects))?;
                     Ok((Type::Existential(to), ctx))
                 }
-                Type::Arrow(from, to) => {
+                Type::Arrow(from, to, effects) => {
                     let ctx = check_type(state, ctx, e2, &from)?;
+                    let ctx = perform(state, ctx, &effects)?;
                     Ok((*to, ctx))
                 }
+                Type::Error => {
+                    let ctx = check_type(state, ctx, e2, &Type::Error)?;
+                    Ok((Type::Error, ctx))
+                }
+                // A `Dyn` value is checked to be a function when it's applied.
+                Type::Dyn => {
+                    let expected = Type::function(Type::Dyn, Type::Dyn);
+                    state.record_cast(e1, &Type::Dyn, &expected);
+                    let ctx = check_type(state, ctx, e2, &Type::Dyn)?;
+                    Ok((Type::Dyn, ctx))
+                }
                 t => Err(Error::InvalidApplication(t)),
             }
         }
         Expr::Let(false, id, binding, body) => {
             let (binding_type, ctx) = synthesize_type(state, ctx, binding)?;
+            let ctx = resolve_products(state, ctx, Ctx::has_existential_after_variables)?;
+            let binding_type = generalize_units(&ctx, &binding_type);
+            generalize_rows(state, &ctx, &binding_type)?;
             let ctx = ctx.add(ctx::Element::TypedVariable(*id, binding_type.clone()));
 
             let (body_type, ctx) = synthesize_type(state, ctx, body)?;
@@ -105,12 +650,17 @@ pub(crate) fn synthesize_type(state: &mut State, ctx: Ctx, e: &Expr) -> Result<(
             ))
         }
         Expr::Let(true, id, binding, body) => {
+            check_recursive_binding(*id, binding)?;
             let binding_existential = state.fresh_existential();
             let ctx = ctx.add(ctx::Element::Existential(binding_existential)).add(
                 ctx::Element::TypedVariable(*id, Type::Existential(binding_existential)),
             );
 
-            let (_, ctx) = synthesize_type(state, ctx, binding)?;
+            // The binding's uses of itself must agree with its definition.
+            let (binding_ty, ctx) = synthesize_type(state, ctx, binding)?;
+            let binding_ty = binding_ty.apply(&ctx);
+            let existential_ty = Type::Existential(binding_existential).apply(&ctx);
+            let ctx = covariant_subtype(state, ctx, &binding_ty, &existential_ty)?;
             let (body_type, ctx) = synthesize_type(state, ctx, body)?;
 
             let ctx = ctx.insert_in_place(
@@ -121,34 +671,254 @@ pub(crate) fn synthesize_type(state: &mut State, ctx: Ctx, e: &Expr) -> Result<(
             Ok((body_type, ctx))
         }
         Expr::IfThenElse(cond, thn, els) => {
-            let (cond_type, ctx) = synthesize_type(state, ctx, cond)?;
-            let ctx = covariant_subtype(state, ctx, &cond_type, &Type::bool())?;
+            let ctx = check_type(state, ctx, cond, &Type::bool())?;
             let (thn_ty, ctx) = synthesize_type(state, ctx, thn)?;
             let ctx = check_type(state, ctx, els, &thn_ty)?;
             Ok((thn_ty, ctx))
         }
+        Expr::Match(scrutinee, arms) => {
+            let (scrutinee_ty, ctx) = synthesize_type(state, ctx, scrutinee)?;
+            // A scrutinee which involves `Dyn` is cast to whatever type its
+            // patterns match.
+            let dyn_ty = Some(scrutinee_ty.apply(&ctx)).filter(Type::contains_dyn);
+            let (scrutinee_ty, mut ctx) = match dyn_ty {
+                Some(_) => fresh(state, ctx),
+                None => (scrutinee_ty, ctx),
+            };
+            let mut result_ty: Option<Type> = None;
+            for (pattern, body) in arms {
+                let scrutinee_ty = scrutinee_ty.apply(&ctx);
+                let (arm_ctx, bindings) = check_pattern(state, ctx, pattern, &scrutinee_ty)?;
+                let typed_vars: Vec<_> = bindings
+                    .into_iter()
+                    .map(|(id, t)| ctx::Element::TypedVariable(id, t))
+                    .collect();
+                let arm_ctx = typed_vars.iter().cloned().fold(arm_ctx, Ctx::add);
+                let arm_ctx = match &result_ty {
+                    Some(t) => {
+                        let t = t.apply(&arm_ctx);
+                        check_type(state, arm_ctx, body, &t)?
+                    }
+                    None => {
+                        let (t, arm_ctx) = synthesize_type(state, arm_ctx, body)?;
+                        result_ty = Some(t);
+                        arm_ctx
+                    }
+                };
+                ctx = typed_vars
+                    .into_iter()
+                    .rev()
+                    .try_fold(arm_ctx, |ctx, v| ctx.insert_in_place(v, &[]))?;
+            }
+
+            if let Some(from) = dyn_ty {
+                let to = scrutinee_ty.apply(&ctx);
+                ctx = covariant_subtype(state, ctx, &from, &to)?;
+                state.record_cast(scrutinee, &from, &to);
+            }
+
+            // We can't tell which patterns are needed if the scrutinee is
+            // ill-typed.
+            let scrutinee_ty = scrutinee_ty.apply(&ctx);
+            if scrutinee_ty != Type::Error {
+                let patterns: Vec<_> = arms.iter().map(|(p, _)| p).collect();
+                exhaustiveness::check(state, &scrutinee_ty, &patterns)?;
+            }
+
+            let result_ty =
+                result_ty.ok_or_else(|| Error::Internal("match with no arms".to_owned()))?;
+            Ok((result_ty.apply(&ctx), ctx))
+        }
+        Expr::Propagate(e) => {
+            let ret = state
+                .returns
+                .last()
+                .cloned()
+                .ok_or(Error::PropagationOutsideFunction)?;
+            let (t, ctx) = synthesize_type(state, ctx, e)?;
+            let t = t.apply(&ctx);
+            let ret = ret.apply(&ctx);
+
+            // If we don't know what `e` is yet then we assume it fails in the
+            // same way as the enclosing function.
+            let (t, ctx) = match (&t, &ret) {
+                (Type::Error, _) | (_, Type::Error) => return Ok((Type::Error, ctx)),
+                (Type::Dyn, Type::Named(n, args))
+                    if *n == *builtins::OPTION || *n == *builtins::RESULT =>
+                {
+                    let mut args = args.clone();
+                    *args.last_mut().expect("Option & Result have parameters") = Type::Dyn;
+                    let expected = Type::Named(*n, args);
+                    state.record_cast(e, &t, &expected);
+                    (expected, ctx)
+                }
+                (Type::Existential(_), Type::Named(n, args))
+                    if *n == *builtins::OPTION || *n == *builtins::RESULT =>
+                {
+                    let mut args = args.clone();
+                    let (success, ctx) = fresh(state, ctx);
+                    *args.last_mut().expect("Option & Result have parameters") = success;
+                    let expected = Type::Named(*n, args);
+                    let ctx = covariant_subtype(state, ctx, &t, &expected)?;
+                    (expected.apply(&ctx), ctx)
+                }
+                // If we don't know what the enclosing function returns
+                // either, then all we know is that they're both `Option`s or
+                // `Result`s, with the same error.
+                (Type::Existential(a), _) => {
+                    let (ctx, head, success) = instantiate_to_app(state, ctx, *a, 1)?;
+                    let success = Type::Existential(success[0]);
+                    return propagate_unknown(state, ctx, Type::Existential(head), success, &ret);
+                }
+                (Type::App(head, args), _)
+                    if matches!(**head, Type::Existential(_)) && args.len() == 1 =>
+                {
+                    let success = args[0].clone();
+                    return propagate_unknown(state, ctx, (**head).clone(), success, &ret);
+                }
+                _ => (t, ctx),
+            };
+
+            // The enclosing function must be able to return the failure case,
+            // i.e. it must return an `Option` or a `Result` with the same error.
+            let (name, mut failure, success) = match &t {
+                Type::Named(n, args) if *n == *builtins::OPTION => {
+                    (*n, Vec::new(), args[0].clone())
+                }
+                Type::Named(n, args) if *n == *builtins::RESULT => {
+                    (*n, vec![args[0].clone()], args[1].clone())
+                }
+                _ => return Err(Error::InvalidPropagation(t)),
+            };
+            let (any, ctx) = fresh(state, ctx);
+            failure.push(any);
+            let ctx = covariant_subtype(state, ctx, &Type::Named(name, failure), &ret)?;
+            Ok((success.apply(&ctx), ctx))
+        }
+        Expr::Record(fields) => {
+            let mut ctx = ctx;
+            let mut field_types = Vec::with_capacity(fields.len());
+            for (field, e) in fields {
+                let (t, new_ctx) = synthesize_type(state, ctx, e)?;
+                ctx = new_ctx;
+                field_types.push((*field, t));
+            }
+            let row = Row::new(field_types, None);
+            if let Some(field) = row.duplicate_field() {
+                return Err(Error::DuplicateField(field));
+            }
+            Ok((Type::Record(row).apply(&ctx), ctx))
+        }
+        Expr::TypeDecl(decl, body) => {
+            state.declare(decl)?;
+            let before = ctx.clone();
+            let (t, ctx) = synthesize_type(state, ctx, body)?;
+            check_not_escaping(state, decl.name, &before, t, ctx)
+        }
+        Expr::NewtypeDecl(newtype, body) => {
+            state.kinds.check(&newtype.typ)?;
+            state.kinds.declare_type(newtype.name);
+            state.newtypes.insert(newtype.name, newtype.typ.clone());
+            let before = ctx.clone();
+            let (t, ctx) = synthesize_type(state, ctx, body)?;
+            check_not_escaping(state, newtype.name, &before, t, ctx)
+        }
+        Expr::Refined(_, predicate, body) => {
+            let ctx = check_type(state, ctx, predicate, &Type::bool())?;
+            synthesize_type(state, ctx, body)
+        }
+        Expr::Wrap(n) => {
+            let underlying = state.newtype(*n)?.clone();
+            Ok((Type::function(underlying, Type::Named(*n, Vec::new())), ctx))
+        }
+        Expr::Unwrap(n) => {
+            let underlying = state.newtype(*n)?.clone();
+            Ok((Type::function(Type::Named(*n, Vec::new()), underlying), ctx))
+        }
+        Expr::Op(l, BinaryOp::And, r) => {
+            let bl = Type::bool();
+            let ctx = check_type(state, ctx, l, &bl)?;
+            let ctx = check_type(state, ctx, r, &bl)?;
+            Ok((bl, ctx))
+        }
+        Expr::Op(l, op, r) if op.is_bitwise() => {
+            let int = Type::int();
+            let ctx = check_type(state, ctx, l, &int)?;
+            let ctx = check_type(state, ctx, r, &int)?;
+            Ok((int, ctx))
+        }
         Expr::Op(l, op, r) => {
+            // Both sides of an overloaded operator have the same type, which
+            // must be an instance of the operator's class.
             let (l_ty, ctx) = synthesize_type(state, ctx, l)?;
-            let (r_ty, ctx) = synthesize_type(state, ctx, r)?;
-
-            match op {
-                BinaryOp::Eq => Ok((Type::bool(), ctx)),
-                BinaryOp::And => {
-                    let bl = Type::bool();
-                    let l_ty = l_ty.apply(&ctx);
-                    let ctx = covariant_subtype(state, ctx, &l_ty, &bl)?;
-                    let r_ty = r_ty.apply(&ctx);
-                    let ctx = covariant_subtype(state, ctx, &r_ty, &bl)?;
-                    Ok((bl, ctx))
-                }
-                BinaryOp::Mul | BinaryOp::Div | BinaryOp::Add | BinaryOp::Sub => {
-                    let num = Type::num();
-                    let l_ty = l_ty.apply(&ctx);
-                    let ctx = covariant_subtype(state, ctx, &l_ty, &num)?;
-                    let r_ty = r_ty.apply(&ctx);
-                    let ctx = covariant_subtype(state, ctx, &r_ty, &num)?;
-                    Ok((num, ctx))
+            // A `Dyn` operand is cast to the type of the other one.
+            let (l_ty, ctx) = match l_ty.apply(&ctx) {
+                Type::Dyn => {
+                    let (alpha, ctx) = fresh(state, ctx);
+                    state.record_cast(l, &Type::Dyn, &alpha);
+                    (alpha, ctx)
                 }
+                l_ty => (l_ty, ctx),
+            };
+            if let (BinaryOp::Mul | BinaryOp::Div, Type::Primitive(Primitive::Num(u))) = (op, &l_ty)
+            {
+                // Except that the units of `Num`s are multiplied or divided
+                // along with them.
+                let (beta, ctx) = fresh_measure(state, ctx);
+                let ctx = check_type(state, ctx, r, &Type::measured(beta.clone()))?;
+                let m = match op {
+                    BinaryOp::Mul => u.mul(&beta),
+                    _ => u.div(&beta),
+                };
+                return Ok((Type::measured(m).apply(&ctx), ctx));
+            }
+            if let (BinaryOp::Mul | BinaryOp::Div, Type::Existential(a)) = (op, &l_ty) {
+                // An unknown operand is an `Int` if the other one is, and a
+                // `Num` in a unit of its own if the other one is a `Num`, so
+                // that e.g. `|x| x * 2.0` can be used at any unit. If neither
+                // is known, that's decided once something about them is.
+                let (r_ty, ctx) = synthesize_type(state, ctx, r)?;
+                let (result, ctx) = match r_ty.apply(&ctx) {
+                    Type::Primitive(Primitive::Num(v)) => {
+                        let (ctx, u) = instantiate_to_num(state, ctx, *a)?;
+                        let m = match op {
+                            BinaryOp::Mul => u.mul(&v),
+                            _ => u.div(&v),
+                        };
+                        return Ok((Type::measured(m).apply(&ctx), ctx));
+                    }
+                    r_ty @ Type::Existential(_) => {
+                        let (result, ctx) = fresh(state, ctx);
+                        let types = [l_ty.clone(), r_ty, result.clone()];
+                        state.products.push((state.location, *op, types));
+                        (result, ctx)
+                    }
+                    r_ty => {
+                        let ctx = covariant_subtype(state, ctx, &r_ty, &l_ty)?;
+                        (l_ty.apply(&ctx), ctx)
+                    }
+                };
+                state.require(Class::Num, l_ty.clone());
+                state.record_overload(e, Class::Num, &l_ty);
+                return Ok((result, ctx));
+            }
+            let ctx = check_type(state, ctx, r, &l_ty)?;
+            let class = match op {
+                BinaryOp::Eq => Class::Eq,
+                BinaryOp::Lt | BinaryOp::Le | BinaryOp::Gt | BinaryOp::Ge => Class::Ord,
+                BinaryOp::Mul | BinaryOp::Div | BinaryOp::Add | BinaryOp::Sub => Class::Num,
+                BinaryOp::And
+                | BinaryOp::BitAnd
+                | BinaryOp::BitOr
+                | BinaryOp::BitXor
+                | BinaryOp::Shl
+                | BinaryOp::Shr => unreachable!("`{op:?}` isn't overloaded"),
+            };
+            state.require(class, l_ty.clone());
+            state.record_overload(e, class, &l_ty);
+            match class {
+                Class::Num => Ok((l_ty.apply(&ctx), ctx)),
+                _ => Ok((Type::bool(), ctx)),
             }
         }
     }
@@ -157,12 +927,50 @@ pub(crate) fn synthesize_type(state: &mut State, ctx: Ctx, e: &Expr) -> Result<(
 /// Check that `e` has type `t`. Returns an updated `Ctx` if it is, and an
 /// `Error` otherwise.
 fn check_type(state: &mut State, ctx: Ctx, e: &Expr, t: &Type) -> Result<Ctx, Error> {
+    if let Expr::Located(..) = e {
+        return crate::with_large_stack(|| check_type_untraced(state, ctx, e, t));
+    }
+    state.enter(Rule::Check, &ctx, |w| write!(w, "{e:?} <= {t}"));
+    let res = crate::with_large_stack(|| check_type_untraced(state, ctx, e, t));
+    state.exit(res.as_ref().map(|ctx| (ctx, None)));
+    if res.is_ok() {
+        state.record_type(e, t);
+    }
+    res
+}
+
+fn check_type_untraced(state: &mut State, ctx: Ctx, e: &Expr, t: &Type) -> Result<Ctx, Error> {
     ctx.check_type_well_formed(t)?;
 
     match (e, t) {
+        (Expr::Located(location, e), _) => {
+            let outer = std::mem::replace(&mut state.location, *location);
+            let res = if state.recover {
+                let before = ctx.clone();
+                check_type(state, ctx, e, t).or_else(|err| {
+                    state.report(state.location, err)?;
+                    // Anything the failed expression's type was still to be
+                    // inferred from is ill-typed too.
+                    match t.apply(&before) {
+                        Type::Existential(a) => instantiate_l(state, before, a, &Type::Error),
+                        _ => Ok(before),
+                    }
+                })
+            } else {
+                check_type(state, ctx, e, t)
+            };
+            state.location = outer;
+            res
+        }
+        // Unitless literals are dimensionless, which may tell us what an
+        // unknown unit is.
+        (Expr::Literal(l @ Val::Num(_)), Type::Primitive(Primitive::Num(_))) => {
+            covariant_subtype(state, ctx, &l.typ(), t)
+        }
         (Expr::Literal(l), Type::Primitive(p)) => check_literal_type(ctx, l, p),
-        (Expr::Lambda(id, arg_annot, e), Type::Arrow(from_ty, to_ty)) => {
+        (Expr::Lambda(id, arg_annot, e), Type::Arrow(from_ty, to_ty, effects)) => {
             let ctx = if let Some(t) = arg_annot {
+                state.kinds.check(t)?;
                 // subtype(state, ctx, from_ty, t)?
                 contravariant_subtype(state, ctx, from_ty, t)?
             } else {
@@ -175,13 +983,501 @@ fn check_type(state: &mut State, ctx: Ctx, e: &Expr, t: &Type) -> Result<Ctx, Er
             // drop everything from `typed_var` on from the context.
             // Note that in particular any earlier existentials which got solved during this
             // call will stick around.
-            check_type(state, ctx, e, to_ty)?.drop(&typed_var)
+            state.returns.push(*to_ty.clone());
+            state.effects.push(effects.clone());
+            let ctx = check_type(state, ctx, e, to_ty);
+            state.effects.pop();
+            state.returns.pop();
+            // Existentials are dropped along with `typed_var`, so this is our
+            // last chance to see what they were solved to.
+            let ctx = resolve_products(state, ctx?, |_, _| false)?;
+            check_constraints(state, &ctx)?;
+            if state.node_types.is_some() || state.casts.is_some() {
+                state.dropped.extend(ctx.solutions_since(&typed_var)?);
+            }
+            ctx.drop(&typed_var)
+        }
+        // Both branches are checked against the expected type, so that e.g.
+        // lambdas in them see their argument types, and a mismatch is
+        // reported in the branch which is actually wrong.
+        (Expr::Refined(_, predicate, body), _) => {
+            let ctx = check_type(state, ctx, predicate, &Type::bool())?;
+            check_type(state, ctx, body, t)
+        }
+        (Expr::IfThenElse(cond, thn, els), _) => {
+            let ctx = check_type(state, ctx, cond, &Type::bool())?;
+            let thn_ty = t.apply(&ctx);
+            let ctx = check_type(state, ctx, thn, &thn_ty)?;
+            let els_ty = t.apply(&ctx);
+            check_type(state, ctx, els, &els_ty)
+        }
+        (Expr::Let(false, id, binding, body), _) => {
+            let (binding_type, ctx) = synthesize_type(state, ctx, binding)?;
+            let ctx = resolve_products(state, ctx, Ctx::has_existential_after_variables)?;
+            let binding_type = generalize_units(&ctx, &binding_type);
+            generalize_rows(state, &ctx, &binding_type)?;
+            let typed_var = ctx::Element::TypedVariable(*id, binding_type);
+            let ctx = ctx.add(typed_var.clone());
+            let body_ty = t.apply(&ctx);
+            let ctx = check_type(state, ctx, body, &body_ty)?;
+            ctx.insert_in_place(typed_var, &[])
+        }
+        (Expr::Let(true, id, binding, body), _) => {
+            check_recursive_binding(*id, binding)?;
+            let binding_existential = state.fresh_existential();
+            let typed_var =
+                ctx::Element::TypedVariable(*id, Type::Existential(binding_existential));
+            let ctx = ctx
+                .add(ctx::Element::Existential(binding_existential))
+                .add(typed_var.clone());
+            let (binding_ty, ctx) = synthesize_type(state, ctx, binding)?;
+            let binding_ty = binding_ty.apply(&ctx);
+            let existential_ty = Type::Existential(binding_existential).apply(&ctx);
+            let ctx = covariant_subtype(state, ctx, &binding_ty, &existential_ty)?;
+            let body_ty = t.apply(&ctx);
+            let ctx = check_type(state, ctx, body, &body_ty)?;
+            ctx.insert_in_place(typed_var, &[])
         }
         (_, _) => {
             let (inferred_t, ctx) = synthesize_type(state, ctx, e)?;
             let a = inferred_t.apply(&ctx);
             let t = t.clone().apply(&ctx);
-            covariant_subtype(state, ctx, &a, &t)
+            // A `Dyn` value can be used at any type, as long as it turns out
+            // to have that type at runtime.
+            if a == Type::Dyn && !matches!(t, Type::Dyn | Type::Error | Type::Existential(_)) {
+                state.record_cast(e, &a, &t);
+                return Ok(ctx);
+            }
+            let ctx = covariant_subtype(state, ctx, &a, &t)?;
+            if a.apply(&ctx).contains_dyn() || t.apply(&ctx).contains_dyn() {
+                state.record_cast(e, &a, &t);
+            }
+            Ok(ctx)
+        }
+    }
+}
+
+/// Make the operands & result of each product or quotient whose types weren't
+/// known when it was checked agree, as far as `ctx` knows them. Those which are
+/// still all unknown are solved as `Num`s if `defaults` holds for all of their
+/// existentials, and are otherwise kept to be resolved later.
+fn resolve_products(
+    state: &mut State,
+    ctx: Ctx,
+    defaults: impl Fn(&Ctx, &Existential) -> bool,
+) -> Result<Ctx, Error> {
+    let mut ctx = ctx;
+    for (location, op, types) in std::mem::take(&mut state.products) {
+        let types = types.map(|t| t.apply(&ctx));
+        // Existentials which were dropped along with a lambda's argument, and
+        // so were never solved, don't tell us anything.
+        let unknown = |t: &Type| match t {
+            Type::Existential(a) => Some(*a),
+            _ => None,
+        };
+        let known = types
+            .iter()
+            .find(|t| !matches!(t, Type::Existential(_) | Type::Primitive(Primitive::Num(_))));
+        let res = match known {
+            Some(Type::Error) => continue,
+            // They're all e.g. `Int`s.
+            Some(known) => types
+                .iter()
+                .try_fold(ctx.clone(), |ctx, t| match unknown(t) {
+                    Some(a) if !ctx.has_existential(&a) => Ok(ctx),
+                    _ => covariant_subtype(state, ctx, t, known),
+                }),
+            None if types.iter().any(|t| unknown(t).is_none())
+                || types
+                    .iter()
+                    .filter_map(unknown)
+                    .all(|a| !ctx.has_existential(&a) || defaults(&ctx, &a)) =>
+            {
+                multiply_units(state, ctx.clone(), op, &types)
+            }
+            None => {
+                state.products.push((location, op, types));
+                continue;
+            }
+        };
+        match res {
+            Ok(new_ctx) => ctx = new_ctx,
+            Err(e) => state.report(location, e)?,
+        }
+    }
+    Ok(ctx)
+}
+
+/// Make the `types` of the operands & result of `op`, which is `*` or `/`,
+/// `Num`s whose units are multiplied or divided along with them.
+fn multiply_units(
+    state: &mut State,
+    ctx: Ctx,
+    op: BinaryOp,
+    types: &[Type; 3],
+) -> Result<Ctx, Error> {
+    let [l, r, result] = types;
+    let (ctx, u) = unit_of(state, ctx, l)?;
+    let (ctx, v) = unit_of(state, ctx, r)?;
+    let m = match op {
+        BinaryOp::Mul => u.mul(&v),
+        _ => u.div(&v),
+    };
+    let (m, result) = (Type::measured(m).apply(&ctx), result.apply(&ctx));
+    match result {
+        Type::Existential(a) if !ctx.has_existential(&a) => Ok(ctx),
+        result => covariant_subtype(state, ctx, &m, &result),
+    }
+}
+
+/// The unit of `t`, which is a `Num` or unknown. Unknown types are solved as a
+/// `Num` in a fresh unit.
+fn unit_of(state: &mut State, ctx: Ctx, t: &Type) -> Result<(Ctx, Measure), Error> {
+    match t.apply(&ctx) {
+        Type::Existential(a) if ctx.has_existential(&a) => instantiate_to_num(state, ctx, a),
+        Type::Primitive(Primitive::Num(m)) => Ok((ctx, m)),
+        _ => {
+            let (m, ctx) = fresh_measure(state, ctx);
+            Ok((ctx, m))
+        }
+    }
+}
+
+/// Check that each of the types which must be an instance of some class is,
+/// as far as `ctx` knows. Types which are still unknown are kept to be checked
+/// later, and are fine if they never become known.
+pub(crate) fn check_constraints(state: &mut State, ctx: &Ctx) -> Result<(), Error> {
+    for (location, t) in std::mem::take(&mut state.propagated) {
+        match t.apply(ctx) {
+            Type::Error => {}
+            t @ Type::App(..) => state.propagated.push((location, t)),
+            Type::Named(n, args)
+                if (n == *builtins::OPTION && args.len() == 1)
+                    || (n == *builtins::RESULT && args.len() == 2) => {}
+            t => state.report(location, Error::InvalidPropagation(t))?,
+        }
+    }
+    // The same requirement often arises more than once, such as when a key
+    // is inserted into an empty map, and is only checked, and so reported,
+    // once.
+    let mut checked: Vec<(Class, Type, Vec<InstanceKey>)> = Vec::new();
+    for c in std::mem::take(&mut state.constraints) {
+        let mut seen = Vec::new();
+        let t = c.typ.apply(ctx);
+        if checked
+            .iter()
+            .any(|(class, typ, instances)| (*class, typ, instances) == (c.class, &t, &c.instances))
+        {
+            continue;
+        }
+        checked.push((c.class, t.clone(), c.instances.clone()));
+        let outer = std::mem::replace(&mut state.location, c.location);
+        let res = resolve_instance(state, c.class, &t, &c.instances, &mut seen, c.nested);
+        state.location = outer;
+        if let Err(e) = res {
+            state.report(c.location, e)?;
+        }
+    }
+    Ok(())
+}
+
+/// Solve any types which are still unknown, but which must be instances of
+/// `Num`, as `Num`. This saves e.g. `|x| x + x` from having an unknown type.
+/// Likewise, the operands of products & quotients which are still unknown are
+/// solved as `Num`s, and any effects which are still unknown as none.
+pub(crate) fn default_constraints(state: &mut State, ctx: Ctx) -> Result<Ctx, Error> {
+    let mut ctx = resolve_products(state, ctx, |_, _| true)?;
+    for c in std::mem::take(&mut state.constraints) {
+        if let (Class::Num, Type::Existential(a)) = (c.class, c.typ.apply(&ctx)) {
+            if ctx.has_existential(&a) {
+                ctx = covariant_subtype(state, ctx, &Type::Existential(a), &Type::num())?;
+            }
+        }
+    }
+    // Values which `?` was applied to, which nothing else turned out to be a
+    // `Result`, are `Option`s.
+    for (_, t) in std::mem::take(&mut state.propagated) {
+        if let Type::App(head, _) = t.apply(&ctx) {
+            if let Type::Existential(a) = *head {
+                if ctx.has_existential(&a) {
+                    ctx = ctx.insert_in_place(
+                        ctx::Element::Existential(a),
+                        &[ctx::Element::Solved(
+                            a,
+                            Type::Named(*builtins::OPTION, Vec::new()),
+                        )],
+                    )?;
+                }
+            }
+        }
+    }
+    // Functions which nothing else turned out to be performed in are pure.
+    for a in std::mem::take(&mut state.effect_existentials) {
+        if ctx.has_existential(&a) {
+            ctx = ctx.insert_in_place(
+                ctx::Element::Existential(a),
+                &[ctx::Element::SolvedEffects(a, Effects::pure())],
+            )?;
+        }
+    }
+    Ok(ctx)
+}
+
+/// Check that `t` has an instance of `class`, given the user-declared
+/// `instances` in scope. Any parts of `t` which aren't yet known are added
+/// to `state.constraints` to be checked later. `seen` holds the data types
+/// which have already been checked, so that recursive types terminate, and
+/// `nested` is whether `t` is part of another type which is checked
+/// structurally.
+fn resolve_instance(
+    state: &mut State,
+    class: Class,
+    t: &Type,
+    instances: &[InstanceKey],
+    seen: &mut Vec<Type>,
+    nested: bool,
+) -> Result<(), Error> {
+    let no_instance = || Error::NoInstance {
+        class,
+        typ: t.clone(),
+    };
+    match t {
+        // An applied type whose constructor is unknown could still turn out
+        // to be anything.
+        Type::Existential(_) | Type::App(..) => {
+            state.constraints.push(Constraint {
+                class,
+                typ: t.clone(),
+                instances: instances.to_vec(),
+                location: state.location,
+                nested,
+            });
+            Ok(())
+        }
+        Type::Error => Ok(()),
+        // `Dyn` values are shown by their runtime types, but they can't be
+        // compared, ordered or used as numbers without a cast. They might be
+        // functions, which can't be compared for equality.
+        Type::Dyn => match class {
+            Class::Show => Ok(()),
+            Class::Eq => Err(Error::NotComparable(t.clone())),
+            Class::Num | Class::Ord => Err(no_instance()),
+        },
+        // A newtype's values are erased, so where they're within others
+        // there's nothing to find its instance by.
+        Type::Named(n, _)
+            if instances.contains(&(class, *n)) && nested && state.newtypes.contains_key(n) =>
+        {
+            Err(Error::NewtypeInstanceWithin {
+                class,
+                typ: t.clone(),
+            })
+        }
+        Type::Named(n, _) if instances.contains(&(class, *n)) => Ok(()),
+        Type::Named(n, _) if state.declared_instances.contains(&(class, *n)) => Err(no_instance()),
+        // Other newtypes are compared, ordered & shown as the values they
+        // wrap.
+        Type::Named(n, _) if class != Class::Num && state.newtypes.contains_key(n) => {
+            let underlying = state.newtype(*n)?.clone();
+            resolve_instance(state, class, &underlying, instances, seen, nested)
+        }
+        Type::Primitive(p) => match (class, p) {
+            (Class::Eq | Class::Ord | Class::Show, _)
+            | (
+                Class::Num,
+                Primitive::BigInt | Primitive::Int | Primitive::Num(_) | Primitive::Rational,
+            ) => Ok(()),
+            (Class::Num, _) => Err(no_instance()),
+        },
+        // Values of other data types & records are compared, ordered & shown
+        // structurally, so their components must be too.
+        Type::Named(n, args) if class != Class::Num => {
+            if seen.contains(t) {
+                return Ok(());
+            }
+            seen.push(t.clone());
+            let ctors = state.constructors_of(*n).unwrap_or_default().to_vec();
+            let components = if ctors.is_empty() {
+                // Opaque types are made of their type arguments.
+                args.clone()
+            } else {
+                let mut fields = Vec::new();
+                for c in ctors {
+                    fields.extend(state.constructor_fields(c, t)?);
+                }
+                fields
+            };
+            components
+                .iter()
+                .try_for_each(|c| resolve_instance(state, class, c, instances, seen, true))
+        }
+        Type::Record(row) if class != Class::Num => {
+            row.fields()
+                .iter()
+                .try_for_each(|(_, f)| resolve_instance(state, class, f, instances, seen, true))?;
+            // An open row could turn out to have any other fields.
+            if row.rest().is_some() {
+                state.constraints.push(Constraint {
+                    class,
+                    typ: t.clone(),
+                    instances: instances.to_vec(),
+                    location: state.location,
+                    nested,
+                });
+            }
+            Ok(())
+        }
+        // Functions can't be compared for equality.
+        Type::Arrow(..) if class == Class::Eq => Err(Error::NotComparable(t.clone())),
+        Type::Arrow(..) | Type::Named(..) | Type::Record(_) => Err(no_instance()),
+        Type::Var(_) => Err(Error::Internal(format!("unexpected type variable in {t}"))),
+    }
+}
+
+/// Check that `methods`, if it's written as a record, only defines methods
+/// which `class` declares, rather than leaving `check_type` to find that the
+/// type of the methods is missing a field.
+fn check_methods(class: Class, methods: &Expr) -> Result<(), Error> {
+    let Expr::Record(fields) = methods.unlocated() else {
+        return Ok(());
+    };
+    match fields
+        .iter()
+        .find(|(m, _)| !class.methods().contains(&m.name()))
+    {
+        Some((method, _)) => Err(Error::UnknownMethod {
+            class,
+            method: *method,
+        }),
+        None => Ok(()),
+    }
+}
+
+/// The type of the record of methods which an instance of `class` for `t`
+/// provides.
+fn methods_type(class: Class, t: &Type) -> Type {
+    let binary = |ret: Type| Type::function(t.clone(), Type::function(t.clone(), ret));
+    let fields = class
+        .methods()
+        .iter()
+        .map(|m| {
+            let ty = match class {
+                Class::Eq => binary(Type::bool()),
+                Class::Num => binary(t.clone()),
+                Class::Ord => binary(Type::int()),
+                Class::Show => Type::function(t.clone(), Type::string()),
+            };
+            (interner::Id::new(m), ty)
+        })
+        .collect();
+    Type::Record(Row::new(fields, None))
+}
+
+/// Add every instance declared in `e` to `acc`.
+/// The types declared along the spine of `e` whose scope's result is the
+/// program's, i.e. not inside any other expression but a declaration or the
+/// body of a `let`.
+fn program_types(e: &Expr) -> HashSet<interner::Id> {
+    let mut types = HashSet::new();
+    let mut e = e;
+    loop {
+        e = match e {
+            Expr::Located(_, body) | Expr::Let(_, _, _, body) | Expr::Instance(_, _, body) => body,
+            Expr::TypeDecl(DataDecl { name, .. }, body)
+            | Expr::NewtypeDecl(Newtype { name, .. }, body) => {
+                types.insert(*name);
+                body
+            }
+            _ => return types,
+        }
+    }
+}
+
+fn find_instances(e: &Expr, acc: &mut HashSet<InstanceKey>) {
+    crate::with_large_stack(|| match e {
+        Expr::Instance(instance, methods, body) => {
+            acc.insert((instance.class, instance.typ));
+            find_instances(methods, acc);
+            find_instances(body, acc);
+        }
+        Expr::Ascribed(e, _)
+        | Expr::Field(e, _)
+        | Expr::Lambda(_, _, e)
+        | Expr::Located(_, e)
+        | Expr::Propagate(e)
+        | Expr::TypeDecl(_, e)
+        | Expr::NewtypeDecl(_, e) => find_instances(e, acc),
+        Expr::App(l, r)
+        | Expr::Index(l, r)
+        | Expr::Let(_, _, l, r)
+        | Expr::Op(l, _, r)
+        | Expr::Refined(_, l, r) => {
+            find_instances(l, acc);
+            find_instances(r, acc);
+        }
+        Expr::IfThenElse(c, t, e) => {
+            find_instances(c, acc);
+            find_instances(t, acc);
+            find_instances(e, acc);
+        }
+        Expr::Match(e, arms) => {
+            find_instances(e, acc);
+            arms.iter().for_each(|(_, e)| find_instances(e, acc));
+        }
+        Expr::Record(fields) => fields.iter().for_each(|(_, e)| find_instances(e, acc)),
+        Expr::Builtin(_)
+        | Expr::Constructor(..)
+        | Expr::Literal(_)
+        | Expr::Measured(..)
+        | Expr::Unwrap(_)
+        | Expr::Var(..)
+        | Expr::Wrap(_) => {}
+    })
+}
+
+/// Check that `p` matches values of type `t`. Returns an updated `Ctx`, along
+/// with the types of the variables `p` binds, if it does, and an `Error`
+/// otherwise.
+fn check_pattern(
+    state: &mut State,
+    ctx: Ctx,
+    p: &Pattern,
+    t: &Type,
+) -> Result<(Ctx, Vec<(interner::Id, Type)>), Error> {
+    match p {
+        Pattern::Wildcard => Ok((ctx, Vec::new())),
+        Pattern::Var(id) => Ok((ctx, vec![(*id, t.clone())])),
+        Pattern::Literal(l) => {
+            let ctx = covariant_subtype(state, ctx, &l.typ(), t)?;
+            Ok((ctx, Vec::new()))
+        }
+        Pattern::Constructor(c, args) => {
+            let (data_type, fields) = state.constructor(*c)?.clone();
+            if args.len() != fields.len() {
+                return Err(Error::PatternArity {
+                    constructor: *c,
+                    expected: fields.len(),
+                    got: args.len(),
+                });
+            }
+            let params = state.params_of(data_type)?;
+            let data_ty = Type::Named(data_type, params.iter().copied().map(Type::Var).collect());
+            let (data_ty, ctx) = instantiate_vars(state, ctx, &data_ty);
+            let mut ctx = covariant_subtype(state, ctx, &data_ty, t)?;
+            let fields = state.constructor_fields(*c, &data_ty.apply(&ctx))?;
+            let mut bindings = Vec::new();
+            for (arg, field) in args.iter().zip(fields.iter()) {
+                let field = field.apply(&ctx);
+                let (new_ctx, arg_bindings) = check_pattern(state, ctx, arg, &field)?;
+                ctx = new_ctx;
+                bindings.extend(arg_bindings);
+            }
+            Ok((ctx, bindings))
+        }
+        Pattern::Newtype(n, p) => {
+            let ctx = covariant_subtype(state, ctx, &Type::Named(*n, Vec::new()), t)?;
+            let underlying = state.newtype(*n)?.clone();
+            check_pattern(state, ctx, p, &underlying)
         }
     }
 }
@@ -200,6 +1496,7 @@ fn contravariant_subtype(state: &mut State, ctx: Ctx, a: &Type, b: &Type) -> Res
     subtype(state, ctx, a, b, Variance::Contravariant)
 }
 
+#[derive(Clone, Copy)]
 enum Variance {
     Covariant,
     Contravariant,
@@ -208,34 +1505,493 @@ enum Variance {
 /// Ensures that `a` is a subtype of `b`. Returns an updated `Ctx` if it is, and
 /// an `Error` otherwise.
 fn subtype(state: &mut State, ctx: Ctx, a: &Type, b: &Type, v: Variance) -> Result<Ctx, Error> {
+    state.enter(Rule::Subtype, &ctx, |w| write!(w, "{a} <: {b}"));
+    let res = subtype_untraced(state, ctx, a, b, v);
+    state.exit(res.as_ref().map(|ctx| (ctx, None)));
+    res
+}
+
+fn subtype_untraced(
+    state: &mut State,
+    ctx: Ctx,
+    a: &Type,
+    b: &Type,
+    v: Variance,
+) -> Result<Ctx, Error> {
     ctx.check_type_well_formed(a)?;
     ctx.check_type_well_formed(b)?;
 
     match (a, b) {
+        // An error has already been reported for an ill-typed expression.
+        (Type::Error, _) | (_, Type::Error) => Ok(ctx),
+        (Type::Primitive(Primitive::Num(m1)), Type::Primitive(Primitive::Num(m2))) => {
+            match unify_measures(state, ctx, m1, m2)? {
+                Some(ctx) => Ok(ctx),
+                None => Err(mismatch(a, b, v)),
+            }
+        }
         (Type::Primitive(p1), Type::Primitive(p2)) if p1 == p2 => Ok(ctx),
+        (Type::Named(n1, args1), Type::Named(n2, args2))
+            if n1 == n2 && args1.len() == args2.len() =>
+        {
+            args1.iter().zip(args2).try_fold(ctx, |ctx, (a1, a2)| {
+                let a1 = a1.apply(&ctx);
+                let a2 = a2.apply(&ctx);
+                subtype(state, ctx, &a1, &a2, v)
+            })
+        }
+        (Type::App(head1, args1), Type::App(head2, args2)) => {
+            subtype_applied(state, ctx, (a, head1, args1), (b, head2, args2), v)
+        }
+        (Type::App(head1, args1), Type::Named(n, args2)) if args1.len() <= args2.len() => {
+            let head2 = Type::Named(*n, Vec::new());
+            subtype_applied(state, ctx, (a, head1, args1), (b, &head2, args2), v)
+        }
+        (Type::Named(n, args1), Type::App(head2, args2)) if args2.len() <= args1.len() => {
+            let head1 = Type::Named(*n, Vec::new());
+            subtype_applied(state, ctx, (a, &head1, args1), (b, head2, args2), v)
+        }
         (Type::Existential(e1), Type::Existential(e2)) if e1 == e2 => Ok(ctx),
-        (Type::Arrow(from1, to1), Type::Arrow(from2, to2)) => {
+        (Type::Arrow(from1, to1, effects1), Type::Arrow(from2, to2, effects2)) => {
             let ctx = contravariant_subtype(state, ctx, from2, from1)?;
             let to1 = to1.apply(&ctx);
             let to2 = to2.apply(&ctx);
-            let r = covariant_subtype(state, ctx, &to1, &to2)?;
-            Ok(r)
+            let ctx = covariant_subtype(state, ctx, &to1, &to2)?;
+            // A function can be used where one which performs more effects
+            // is expected.
+            match subeffect(state, ctx, effects1, effects2)? {
+                Some(ctx) => Ok(ctx),
+                None => Err(mismatch(a, b, v)),
+            }
         }
+        (Type::Record(r1), Type::Record(r2)) => subtype_rows(state, ctx, r1, r2, v),
         (Type::Existential(to_instantiate), _) => instantiate_l(state, ctx, *to_instantiate, b),
         (_, Type::Existential(to_instantiate)) => instantiate_r(state, ctx, a, *to_instantiate),
-        (_, _) => match v {
-            Variance::Covariant => Err(Error::Mismatch {
-                got: a.clone(),
-                expected: b.clone(),
-            }),
-            Variance::Contravariant => Err(Error::Mismatch {
-                got: b.clone(),
-                expected: a.clone(),
+        // `Dyn` is consistent with every type, and the places where it meets
+        // another are checked at runtime instead. Effects can't be checked
+        // at runtime though, so only pure functions can be used as `Dyn`.
+        (Type::Dyn, _) => Ok(ctx),
+        (_, Type::Dyn) => subtype(state, ctx, a, &a.pure(), v),
+        (_, _) => Err(mismatch(a, b, v)),
+    }
+}
+
+/// Check that `a`, which is `head1` applied to `args1`, is a subtype of `b`,
+/// which is `head2` applied to `args2`, where at least one of the heads is
+/// unknown. The last arguments of each are matched up, and whichever head has
+/// more arguments takes the rest, so e.g. `f a <: Result E A` solves `f` as
+/// `Result E`.
+fn subtype_applied(
+    state: &mut State,
+    ctx: Ctx,
+    (a, head1, args1): (&Type, &Type, &[Type]),
+    (b, head2, args2): (&Type, &Type, &[Type]),
+    v: Variance,
+) -> Result<Ctx, Error> {
+    let shared = args1.len().min(args2.len());
+    let (partial1, args1) = args1.split_at(args1.len() - shared);
+    let (partial2, args2) = args2.split_at(args2.len() - shared);
+    let head1 = head1.clone().applied_to(partial1.to_vec()).apply(&ctx);
+    let head2 = head2.clone().applied_to(partial2.to_vec()).apply(&ctx);
+    // A mismatch between the heads makes more sense in terms of the whole
+    // types.
+    let ctx = subtype(state, ctx, &head1, &head2, v).map_err(|e| match e {
+        Error::Mismatch { .. } => mismatch(a, b, v),
+        e => e,
+    })?;
+    args1.iter().zip(args2).try_fold(ctx, |ctx, (a1, a2)| {
+        let a1 = a1.apply(&ctx);
+        let a2 = a2.apply(&ctx);
+        subtype(state, ctx, &a1, &a2, v)
+    })
+}
+
+/// The error for `a` not being a subtype of `b`, showing the types in the
+/// positions given by `v`.
+fn mismatch(a: &Type, b: &Type, v: Variance) -> Error {
+    match v {
+        Variance::Covariant => Error::Mismatch {
+            got: a.clone(),
+            expected: b.clone(),
+        },
+        Variance::Contravariant => Error::Mismatch {
+            got: b.clone(),
+            expected: a.clone(),
+        },
+    }
+}
+
+/// Make the measures `m1` & `m2` equal, by solving the unit existentials in
+/// them, using Kennedy's algorithm for unification in an abelian group.
+/// Returns `None` if they can't be made equal.
+fn unify_measures(
+    state: &mut State,
+    ctx: Ctx,
+    m1: &Measure,
+    m2: &Measure,
+) -> Result<Option<Ctx>, Error> {
+    let mut ctx = ctx;
+    loop {
+        // `m1 = m2` exactly when `m1/m2` is dimensionless.
+        let d = m1.div(m2).apply(&ctx);
+        if d.is_one() {
+            return Ok(Some(ctx));
+        }
+
+        // Solve for the existential with the smallest power, preferring the
+        // one declared latest, since its solution can mention the others.
+        let mut chosen: Option<(Existential, i64)> = None;
+        for (a, power) in d.existentials() {
+            chosen = match chosen {
+                Some((b, p)) if p.abs() < power.abs() => Some((b, p)),
+                Some((b, p)) if p.abs() == power.abs() => {
+                    if ctx.has_existential_before(&b, &a) {
+                        Some((a, power))
+                    } else {
+                        Some((b, p))
+                    }
+                }
+                _ => Some((a, power)),
+            };
+        }
+        let Some((alpha, k)) = chosen else {
+            return Ok(None);
+        };
+        let rest = d.div(&Measure::factor(Factor::Existential(alpha)).pow(k));
+
+        if rest.factors().iter().all(|(_, power)| power % k == 0) {
+            // `alpha^k rest = 1`, so `alpha = rest^(-1/k)`.
+            let solution =
+                Measure::from_powers(rest.factors().iter().map(|(f, power)| (*f, -power / k)));
+            return solve_measure(state, ctx, alpha, &solution).map(Some);
+        }
+        if rest.existentials().all(|(_, power)| power % k == 0) {
+            // Only the powers of known units are left over, e.g. `a^2 = m`.
+            return Ok(None);
+        }
+
+        // Otherwise solve `alpha` as a fresh `beta` times as much of the rest
+        // as divides evenly, which leaves smaller powers to solve next time.
+        let beta = state.fresh_existential();
+        ctx = ctx.insert_in_place(
+            ctx::Element::Existential(alpha),
+            &[
+                ctx::Element::Existential(beta),
+                ctx::Element::Existential(alpha),
+            ],
+        )?;
+        let solution = Measure::factor(Factor::Existential(beta)).mul(&Measure::from_powers(
+            rest.factors()
+                .iter()
+                .map(|(f, power)| (*f, -power.div_euclid(k))),
+        ));
+        ctx = solve_measure(state, ctx, alpha, &solution)?;
+    }
+}
+
+/// Solve the unit existential `alpha` as `m`. Any existentials in `m` which
+/// were declared after `alpha` are first solved as fresh existentials
+/// declared before it, so that the solution is well-formed.
+fn solve_measure(
+    state: &mut State,
+    ctx: Ctx,
+    alpha: Existential,
+    m: &Measure,
+) -> Result<Ctx, Error> {
+    let mut ctx = ctx;
+    for (a, _) in m.existentials() {
+        if ctx.has_existential_before(&a, &alpha) {
+            continue;
+        }
+        let fresh = state.fresh_existential();
+        ctx = ctx
+            .insert_in_place(
+                ctx::Element::Existential(alpha),
+                &[
+                    ctx::Element::Existential(fresh),
+                    ctx::Element::Existential(alpha),
+                ],
+            )?
+            .insert_in_place(
+                ctx::Element::Existential(a),
+                &[ctx::Element::Solved(
+                    a,
+                    Type::measured(Measure::factor(Factor::Existential(fresh))),
+                )],
+            )?;
+    }
+    let solution = Type::measured(m.clone()).apply(&ctx);
+    ctx.insert_in_place(
+        ctx::Element::Existential(alpha),
+        &[ctx::Element::Solved(alpha, solution)],
+    )
+}
+
+/// Ensures that the record type with row `r1` is a subtype of the record type
+/// with row `r2`. Records are related by their fields rather than by width, so
+/// any fields which only appear on one side must be absorbed by the other
+/// side's row variable.
+fn subtype_rows(
+    state: &mut State,
+    ctx: Ctx,
+    r1: &Row,
+    r2: &Row,
+    v: Variance,
+) -> Result<Ctx, Error> {
+    let mut ctx = ctx;
+    let mut only1 = Vec::new();
+    for (field, t1) in r1.fields() {
+        match r2.get(*field) {
+            Some(t2) => {
+                let t1 = t1.apply(&ctx);
+                let t2 = t2.apply(&ctx);
+                ctx = subtype(state, ctx, &t1, &t2, v)?;
+            }
+            None => only1.push((*field, t1.clone())),
+        }
+    }
+    let only2: Vec<_> = r2
+        .fields()
+        .iter()
+        .filter(|(field, _)| r1.get(*field).is_none())
+        .cloned()
+        .collect();
+
+    let is_solved = |r: Option<Existential>| r.is_some_and(|r| ctx.get_solved(&r).is_some());
+    if is_solved(r1.rest()) || is_solved(r2.rest()) {
+        // Relating the shared fields told us more about the rest of the rows,
+        // so we start again with what we now know.
+        let a = Type::Record(Row::new(only1, r1.rest())).apply(&ctx);
+        let b = Type::Record(Row::new(only2, r2.rest())).apply(&ctx);
+        return subtype(state, ctx, &a, &b, v);
+    }
+
+    let missing = |field: &(interner::Id, Type), row: &Row| Error::MissingField {
+        field: field.0,
+        typ: Type::Record(row.clone()),
+    };
+
+    match (r1.rest(), r2.rest()) {
+        (None, _) if !only2.is_empty() => Err(missing(&only2[0], r1)),
+        (_, None) if !only1.is_empty() => Err(missing(&only1[0], r2)),
+        (Some(rho1), Some(rho2)) if rho1 == rho2 => match (only1.first(), only2.first()) {
+            (_, Some(field)) => Err(missing(field, r1)),
+            (Some(field), _) => Err(missing(field, r2)),
+            (None, None) => Ok(ctx),
+        },
+        (None, None) => Ok(ctx),
+        (Some(rho1), None) => {
+            let t = Type::Record(Row::new(only2, None));
+            instantiate_l(state, ctx, rho1, &t)
+        }
+        (None, Some(rho2)) => {
+            let t = Type::Record(Row::new(only1, None));
+            instantiate_r(state, ctx, &t, rho2)
+        }
+        (Some(rho1), Some(rho2)) if only1.is_empty() => {
+            let t = Type::Record(Row::new(only2, Some(rho2)));
+            instantiate_l(state, ctx, rho1, &t)
+        }
+        (Some(rho1), Some(rho2)) if only2.is_empty() => {
+            let t = Type::Record(Row::new(only1, Some(rho1)));
+            instantiate_r(state, ctx, &t, rho2)
+        }
+        (Some(rho1), Some(rho2)) => {
+            // Both rows have fields the other lacks, so they must share some
+            // new rest `ρ3`, which has to be declared before both `ρ1` & `ρ2`.
+            let rho3 = state.fresh_existential();
+            let earliest = if ctx.has_existential_before(&rho2, &rho1) {
+                rho2
+            } else {
+                rho1
+            };
+            let ctx = ctx.insert_in_place(
+                ctx::Element::Existential(earliest),
+                &[
+                    ctx::Element::Existential(rho3),
+                    ctx::Element::Existential(earliest),
+                ],
+            )?;
+            let t = Type::Record(Row::new(only2, Some(rho3)));
+            let ctx = instantiate_l(state, ctx, rho1, &t)?;
+            let t = Type::Record(Row::new(only1, Some(rho3))).apply(&ctx);
+            instantiate_r(state, ctx, &t, rho2)
+        }
+    }
+}
+
+/// Make sure that the effects `e1` are among the effects `e2`, by solving the
+/// existentials standing for the rest of either. Unknown effects are solved
+/// as all of the effects they're allowed, so that they can be used wherever
+/// those are. Returns `None` if the effects can't be made to fit.
+fn subeffect(
+    state: &mut State,
+    ctx: Ctx,
+    e1: &Effects,
+    e2: &Effects,
+) -> Result<Option<Ctx>, Error> {
+    let e1 = e1.apply(&ctx);
+    let e2 = e2.apply(&ctx);
+    let only1: Vec<_> = e1
+        .labels()
+        .iter()
+        .copied()
+        .filter(|l| !e2.contains(*l))
+        .collect();
+    let only2: Vec<_> = e2
+        .labels()
+        .iter()
+        .copied()
+        .filter(|l| !e1.contains(*l))
+        .collect();
+
+    match (e1.existential(), e2.existential()) {
+        (rest1, rest2) if only1.is_empty() && (rest1.is_none() || rest1 == rest2) => Ok(Some(ctx)),
+        (Some(rho1), Some(rho2)) if rho1 == rho2 => Ok(None),
+        (Some(rho1), None) if only1.is_empty() => {
+            solve_effects(state, ctx, rho1, &Effects::new(only2, None)).map(Some)
+        }
+        (_, None) => Ok(None),
+        (None, Some(rho2)) => extend_effects(state, ctx, rho2, only1).map(|(ctx, _)| Some(ctx)),
+        (Some(rho1), Some(rho2)) => {
+            // Any effects only `e1` performs are added to `e2`, which both
+            // then share the rest of.
+            let (ctx, rest) = match only1.is_empty() {
+                true => (ctx, rho2),
+                false => extend_effects(state, ctx, rho2, only1)?,
+            };
+            let effects = Effects::new(only2, Some(EffectVar::Existential(rest)));
+            solve_effects(state, ctx, rho1, &effects).map(Some)
+        }
+    }
+}
+
+/// Solve the effect existential `alpha` as `labels` along with a fresh rest,
+/// declared just before it. Returns the updated `Ctx` along with the rest.
+fn extend_effects(
+    state: &mut State,
+    ctx: Ctx,
+    alpha: Existential,
+    labels: Vec<interner::Id>,
+) -> Result<(Ctx, Existential), Error> {
+    let rest = fresh_effect_existential(state);
+    let ctx = ctx.insert_in_place(
+        ctx::Element::Existential(alpha),
+        &[
+            ctx::Element::Existential(rest),
+            ctx::Element::Existential(alpha),
+        ],
+    )?;
+    let effects = Effects::new(labels, Some(EffectVar::Existential(rest)));
+    let ctx = solve_effects(state, ctx, alpha, &effects)?;
+    Ok((ctx, rest))
+}
+
+/// Solve the effect existential `alpha` as `effects`. If the rest of
+/// `effects` was declared after `alpha`, it's first solved as a fresh
+/// existential declared before it, so that the solution is well-formed.
+fn solve_effects(
+    state: &mut State,
+    ctx: Ctx,
+    alpha: Existential,
+    effects: &Effects,
+) -> Result<Ctx, Error> {
+    let mut ctx = ctx;
+    if let Some(rest) = effects
+        .existential()
+        .filter(|r| !ctx.has_existential_before(r, &alpha))
+    {
+        let fresh = fresh_effect_existential(state);
+        ctx = ctx
+            .insert_in_place(
+                ctx::Element::Existential(alpha),
+                &[
+                    ctx::Element::Existential(fresh),
+                    ctx::Element::Existential(alpha),
+                ],
+            )?
+            .insert_in_place(
+                ctx::Element::Existential(rest),
+                &[ctx::Element::SolvedEffects(rest, Effects::unknown(fresh))],
+            )?;
+    }
+    let solution = effects.apply(&ctx);
+    ctx.insert_in_place(
+        ctx::Element::Existential(alpha),
+        &[ctx::Element::SolvedEffects(alpha, solution)],
+    )
+}
+
+/// Perform `effects` by calling a function, which is only allowed if they're
+/// among the effects which the innermost function being checked may perform.
+fn perform(state: &mut State, ctx: Ctx, effects: &Effects) -> Result<Ctx, Error> {
+    let Some(allowed) = state.effects.last().cloned() else {
+        return Ok(ctx);
+    };
+    let effects = effects.apply(&ctx);
+    let allowed = allowed.apply(&ctx);
+    match subeffect(state, ctx, &effects, &allowed)? {
+        Some(ctx) => Ok(ctx),
+        None => match effects.labels().iter().find(|l| !allowed.contains(**l)) {
+            Some(effect) => Err(Error::DisallowedEffect {
+                effect: *effect,
+                allowed,
             }),
+            None => Err(Error::Internal(format!(
+                "couldn't perform {effects} where {allowed} are allowed"
+            ))),
         },
     }
 }
 
+/// Make sure that the program's type `t` doesn't have any functions in it
+/// which perform effects, if the program must be pure.
+pub(crate) fn check_pure(state: &mut State, ctx: Ctx, t: &Type) -> Result<Ctx, Error> {
+    if !state.pure {
+        return Ok(ctx);
+    }
+    covariant_subtype(state, ctx, t, &t.pure())
+}
+
+/// Ensure that the type `name`, declared by the scope which had context
+/// `before` and type `t`, doesn't escape it: neither `t`, unless it's the
+/// program's type, nor anything solved from outside the scope may mention it.
+fn check_not_escaping(
+    state: &State,
+    name: interner::Id,
+    before: &Ctx,
+    t: Type,
+    ctx: Ctx,
+) -> Result<(Type, Ctx), Error> {
+    let t = t.apply(&ctx);
+    let result = (!state.program_types.contains(&name)).then(|| t.clone());
+    let escaping = result
+        .into_iter()
+        .chain(
+            ctx.outer_solutions_since(before)
+                .into_iter()
+                .map(|s| s.apply(&ctx)),
+        )
+        .find(|s| s.mentions(name));
+    match escaping {
+        Some(typ) => Err(Error::TypeEscapes { ident: name, typ }),
+        None => Ok((t, ctx)),
+    }
+}
+
+/// Ensure that `to_instantiate` can be solved as `t` without creating an
+/// infinite type.
+fn occurs_check(ctx: &Ctx, to_instantiate: Existential, t: &Type) -> Result<(), Error> {
+    let t = t.apply(ctx);
+    if t != Type::Existential(to_instantiate) && t.occurs(to_instantiate) {
+        return Err(Error::InfiniteType {
+            var: to_instantiate,
+            ty: t,
+        });
+    }
+    Ok(())
+}
+
 /// Instantiate `to_instantiate` with `t` as an upper-bound.
 fn instantiate_l(
     state: &mut State,
@@ -243,25 +1999,43 @@ fn instantiate_l(
     to_instantiate: Existential,
     t: &Type,
 ) -> Result<Ctx, Error> {
-    let (left, right) = ctx.split_at(&ctx::Element::Existential(to_instantiate.to_owned()))?;
+    state.enter(Rule::InstantiateL, &ctx, |w| {
+        write!(w, "{to_instantiate} :=< {t}")
+    });
+    let res = instantiate_l_untraced(state, ctx, to_instantiate, t);
+    state.exit(res.as_ref().map(|ctx| (ctx, None)));
+    res
+}
 
-    if left.check_type_well_formed(t).is_ok() {
+fn instantiate_l_untraced(
+    state: &mut State,
+    ctx: Ctx,
+    to_instantiate: Existential,
+    t: &Type,
+) -> Result<Ctx, Error> {
+    occurs_check(&ctx, to_instantiate, t)?;
+    if ctx
+        .check_type_well_formed_before(t, &to_instantiate)
+        .is_ok()
+    {
         ctx.insert_in_place(
             ctx::Element::Existential(to_instantiate.to_owned()),
             &[ctx::Element::Solved(to_instantiate.to_owned(), t.clone())],
         )
     } else {
         match t {
-            Type::Arrow(from, to) => {
-                // Instantiate `to_instantiate` to an arrow `e1 -> e2` and then
-                // check that `e1 -> e2 <: from -> to` (i.e. that `from <: e1`
-                // and `e2 <: to`)
+            Type::Arrow(from, to, effects) => {
+                // Instantiate `to_instantiate` to an arrow `e1 -{r}-> e2` and
+                // then check that `e1 -{r}-> e2 <: from -{effects}-> to` (i.e.
+                // that `from <: e1`, `e2 <: to` and `r` is among `effects`)
                 let inferred_from = state.fresh_existential();
                 let inferred_to = state.fresh_existential();
+                let inferred_effects = fresh_effect_existential(state);
 
                 let ctx = ctx.insert_in_place(
                     ctx::Element::Existential(to_instantiate.to_owned()),
                     &[
+                        ctx::Element::Existential(inferred_effects),
                         ctx::Element::Existential(inferred_to),
                         ctx::Element::Existential(inferred_from),
                         ctx::Element::Solved(
@@ -269,6 +2043,7 @@ fn instantiate_l(
                             Type::Arrow(
                                 Box::new(Type::Existential(inferred_from)),
                                 Box::new(Type::Existential(inferred_to)),
+                                Effects::unknown(inferred_effects),
                             ),
                         ),
                     ],
@@ -276,16 +2051,72 @@ fn instantiate_l(
 
                 let ctx = instantiate_r(state, ctx, from, inferred_from)?;
                 let to = to.apply(&ctx);
-                instantiate_l(state, ctx, inferred_to, &to)
+                let ctx = instantiate_l(state, ctx, inferred_to, &to)?;
+                subeffect(state, ctx, &Effects::unknown(inferred_effects), effects)?.ok_or_else(
+                    || mismatch(&Type::Existential(to_instantiate), t, Variance::Covariant),
+                )
             }
             Type::Existential(e) => {
-                right.check_type_well_formed(t)?;
+                ctx.check_type_well_formed_from(t, &to_instantiate)?;
                 ctx.insert_in_place(
                     ctx::Element::Existential(*e),
                     &[ctx::Element::Solved(*e, Type::Existential(to_instantiate))],
                 )
             }
-            Type::Primitive(_) => unreachable!("handled in first branch of if"),
+            Type::Record(row) => {
+                // Instantiate `to_instantiate` to a record `{ f: e.. | r }` and
+                // then check that each `e <: t` for the corresponding field
+                // type `t`, and that `r <: rest`.
+                let (ctx, fields, rest) = instantiate_to_record(state, ctx, to_instantiate, row)?;
+                let mut ctx = ctx;
+                for ((_, inferred), (_, t)) in fields.iter().zip(row.fields()) {
+                    let t = t.apply(&ctx);
+                    ctx = instantiate_l(state, ctx, *inferred, &t)?;
+                }
+                match (rest, row.rest()) {
+                    (Some(inferred), Some(r)) => {
+                        instantiate_l(state, ctx, inferred, &Type::Existential(r))
+                    }
+                    _ => Ok(ctx),
+                }
+            }
+            Type::Named(n, args) => {
+                let (ctx, inferred) = instantiate_to_named(state, ctx, to_instantiate, *n, args)?;
+                inferred
+                    .iter()
+                    .zip(args)
+                    .try_fold(ctx, |ctx, (inferred, t)| {
+                        let t = t.apply(&ctx);
+                        instantiate_l(state, ctx, *inferred, &t)
+                    })
+            }
+            Type::App(head, args) => {
+                // Instantiate `to_instantiate` to `h e..` for a fresh
+                // constructor `h`, and then solve `h` & each `e` from `head`
+                // & the corresponding argument.
+                let (ctx, inferred_head, inferred) =
+                    instantiate_to_app(state, ctx, to_instantiate, args.len())?;
+                let head = head.apply(&ctx);
+                let ctx = instantiate_l(state, ctx, inferred_head, &head)?;
+                inferred
+                    .iter()
+                    .zip(args)
+                    .try_fold(ctx, |ctx, (inferred, t)| {
+                        let t = t.apply(&ctx);
+                        instantiate_l(state, ctx, *inferred, &t)
+                    })
+            }
+            Type::Primitive(Primitive::Num(_)) => {
+                // The measure mentions later existentials, so solve
+                // `to_instantiate` as a `Num` in some fresh unit, and then
+                // unify the units.
+                let (ctx, inferred) = instantiate_to_num(state, ctx, to_instantiate)?;
+                covariant_subtype(state, ctx, &Type::measured(inferred), t)
+            }
+            Type::Dyn | Type::Error | Type::Primitive(_) => {
+                unreachable!("handled in first branch of if")
+            }
+            Type::Var(_) => Err(Error::IllFormedType(t.clone())),
         }
     }
 }
@@ -297,23 +2128,41 @@ fn instantiate_r(
     t: &Type,
     to_instantiate: Existential,
 ) -> Result<Ctx, Error> {
-    let (left, right) = ctx.split_at(&ctx::Element::Existential(to_instantiate))?;
+    state.enter(Rule::InstantiateR, &ctx, |w| {
+        write!(w, "{t} =<: {to_instantiate}")
+    });
+    let res = instantiate_r_untraced(state, ctx, t, to_instantiate);
+    state.exit(res.as_ref().map(|ctx| (ctx, None)));
+    res
+}
 
-    if left.check_type_well_formed(t).is_ok() {
+fn instantiate_r_untraced(
+    state: &mut State,
+    ctx: Ctx,
+    t: &Type,
+    to_instantiate: Existential,
+) -> Result<Ctx, Error> {
+    occurs_check(&ctx, to_instantiate, t)?;
+    if ctx
+        .check_type_well_formed_before(t, &to_instantiate)
+        .is_ok()
+    {
         ctx.insert_in_place(
             ctx::Element::Existential(to_instantiate),
             &[ctx::Element::Solved(to_instantiate, t.clone())],
         )
     } else {
         match t {
-            Type::Arrow(from, to) => {
-                // Instantiate `to_instantiate` to an arrow `e1 -> e2` and then
-                // check that `from -> to <: e1 -> e2` (i.e. that `e1 <: from`
-                // and `to <: e2`)
+            Type::Arrow(from, to, effects) => {
+                // Instantiate `to_instantiate` to an arrow `e1 -{r}-> e2` and
+                // then check that `from -{effects}-> to <: e1 -{r}-> e2` (i.e.
+                // that `e1 <: from`, `to <: e2` and `effects` are among `r`)
                 let inferred_from = state.fresh_existential();
                 let inferred_to = state.fresh_existential();
+                let inferred_effects = fresh_effect_existential(state);
 
                 let ctx = ctx
+                    .add(ctx::Element::Existential(inferred_effects))
                     .add(ctx::Element::Existential(inferred_to))
                     .add(ctx::Element::Existential(inferred_from))
                     .add(ctx::Element::Solved(
@@ -321,32 +2170,460 @@ fn instantiate_r(
                         Type::Arrow(
                             Box::new(Type::Existential(inferred_from)),
                             Box::new(Type::Existential(inferred_to)),
+                            Effects::unknown(inferred_effects),
                         ),
                     ));
 
                 let ctx = instantiate_l(state, ctx, inferred_from, from)?;
                 let to = to.apply(&ctx);
-                instantiate_r(state, ctx, &to, inferred_to)
+                let ctx = instantiate_r(state, ctx, &to, inferred_to)?;
+                subeffect(state, ctx, effects, &Effects::unknown(inferred_effects))?.ok_or_else(
+                    || mismatch(t, &Type::Existential(to_instantiate), Variance::Covariant),
+                )
             }
             Type::Existential(e) => {
-                right.check_type_well_formed(t)?;
+                ctx.check_type_well_formed_from(t, &to_instantiate)?;
                 Ok(ctx.add(ctx::Element::Solved(*e, Type::Existential(to_instantiate))))
             }
-            Type::Primitive(_) => unreachable!("handled in first branch of if"),
+            Type::Record(row) => {
+                // Instantiate `to_instantiate` to a record `{ f: e.. | r }` and
+                // then check that each `t <: e` for the corresponding field
+                // type `t`, and that `rest <: r`.
+                let (ctx, fields, rest) = instantiate_to_record(state, ctx, to_instantiate, row)?;
+                let mut ctx = ctx;
+                for ((_, inferred), (_, t)) in fields.iter().zip(row.fields()) {
+                    let t = t.apply(&ctx);
+                    ctx = instantiate_r(state, ctx, &t, *inferred)?;
+                }
+                match (rest, row.rest()) {
+                    (Some(inferred), Some(r)) => {
+                        instantiate_r(state, ctx, &Type::Existential(r), inferred)
+                    }
+                    _ => Ok(ctx),
+                }
+            }
+            Type::Named(n, args) => {
+                let (ctx, inferred) = instantiate_to_named(state, ctx, to_instantiate, *n, args)?;
+                inferred
+                    .iter()
+                    .zip(args)
+                    .try_fold(ctx, |ctx, (inferred, t)| {
+                        let t = t.apply(&ctx);
+                        instantiate_r(state, ctx, &t, *inferred)
+                    })
+            }
+            Type::App(head, args) => {
+                let (ctx, inferred_head, inferred) =
+                    instantiate_to_app(state, ctx, to_instantiate, args.len())?;
+                let head = head.apply(&ctx);
+                let ctx = instantiate_r(state, ctx, &head, inferred_head)?;
+                inferred
+                    .iter()
+                    .zip(args)
+                    .try_fold(ctx, |ctx, (inferred, t)| {
+                        let t = t.apply(&ctx);
+                        instantiate_r(state, ctx, &t, *inferred)
+                    })
+            }
+            Type::Primitive(Primitive::Num(_)) => {
+                let (ctx, inferred) = instantiate_to_num(state, ctx, to_instantiate)?;
+                covariant_subtype(state, ctx, t, &Type::measured(inferred))
+            }
+            Type::Dyn | Type::Error | Type::Primitive(_) => {
+                unreachable!("handled in first branch of if")
+            }
+            Type::Var(_) => Err(Error::IllFormedType(t.clone())),
+        }
+    }
+}
+
+/// Solve `to_instantiate` as the named type `name` applied to fresh
+/// existentials, one for each of `args`. Returns the updated `Ctx` along with
+/// the new existentials.
+fn instantiate_to_named(
+    state: &mut State,
+    ctx: Ctx,
+    to_instantiate: Existential,
+    name: interner::Id,
+    args: &[Type],
+) -> Result<(Ctx, Vec<Existential>), Error> {
+    let inferred: Vec<_> = args.iter().map(|_| state.fresh_existential()).collect();
+    let mut replacements: Vec<_> = inferred
+        .iter()
+        .rev()
+        .map(|e| ctx::Element::Existential(*e))
+        .collect();
+    replacements.push(ctx::Element::Solved(
+        to_instantiate,
+        Type::Named(
+            name,
+            inferred.iter().map(|e| Type::Existential(*e)).collect(),
+        ),
+    ));
+    let ctx = ctx.insert_in_place(ctx::Element::Existential(to_instantiate), &replacements)?;
+    Ok((ctx, inferred))
+}
+
+/// Solve `to_instantiate` as a fresh existential type constructor applied to
+/// `arity` fresh existentials. Returns the updated `Ctx` along with the
+/// constructor's & arguments' existentials.
+fn instantiate_to_app(
+    state: &mut State,
+    ctx: Ctx,
+    to_instantiate: Existential,
+    arity: usize,
+) -> Result<(Ctx, Existential, Vec<Existential>), Error> {
+    let head = state.fresh_existential();
+    let inferred: Vec<_> = (0..arity).map(|_| state.fresh_existential()).collect();
+    let mut replacements: Vec<_> = inferred
+        .iter()
+        .rev()
+        .map(|e| ctx::Element::Existential(*e))
+        .collect();
+    replacements.push(ctx::Element::Existential(head));
+    replacements.push(ctx::Element::Solved(
+        to_instantiate,
+        Type::App(
+            Box::new(Type::Existential(head)),
+            inferred.iter().map(|e| Type::Existential(*e)).collect(),
+        ),
+    ));
+    let ctx = ctx.insert_in_place(ctx::Element::Existential(to_instantiate), &replacements)?;
+    Ok((ctx, head, inferred))
+}
+
+/// Solve `to_instantiate` as a `Num` in a fresh unit existential. Returns the
+/// updated `Ctx` along with the unit.
+fn instantiate_to_num(
+    state: &mut State,
+    ctx: Ctx,
+    to_instantiate: Existential,
+) -> Result<(Ctx, Measure), Error> {
+    let inferred = state.fresh_existential();
+    let m = Measure::factor(Factor::Existential(inferred));
+    let ctx = ctx.insert_in_place(
+        ctx::Element::Existential(to_instantiate),
+        &[
+            ctx::Element::Existential(inferred),
+            ctx::Element::Solved(to_instantiate, Type::measured(m.clone())),
+        ],
+    )?;
+    Ok((ctx, m))
+}
+
+/// Replace each distinct type parameter in `t` with a fresh existential, so
+/// that every use of a polymorphic constructor or built-in can be at a
+/// different type.
+fn instantiate_vars(state: &mut State, ctx: Ctx, t: &Type) -> (Type, Ctx) {
+    fn vars(t: &Type, acc: &mut Vec<interner::Id>) {
+        match t {
+            Type::Arrow(from, to, _) => {
+                vars(from, acc);
+                vars(to, acc);
+            }
+            Type::App(head, args) => {
+                vars(head, acc);
+                args.iter().for_each(|a| vars(a, acc));
+            }
+            Type::Named(_, args) => args.iter().for_each(|a| vars(a, acc)),
+            Type::Record(row) => row.fields().iter().for_each(|(_, t)| vars(t, acc)),
+            Type::Var(v) if !acc.contains(v) => acc.push(*v),
+            Type::Dyn | Type::Error | Type::Existential(_) | Type::Primitive(_) | Type::Var(_) => {}
+        }
+    }
+
+    let mut params = Vec::new();
+    vars(t, &mut params);
+    let mut effect_params = Vec::new();
+    t.visit_effects(&mut |effects| match effects.rest() {
+        Some(v @ EffectVar::Var(_)) if !effect_params.contains(&v) => effect_params.push(v),
+        _ => {}
+    });
+    let mut ctx = ctx;
+    let mut substitution = Vec::with_capacity(params.len());
+    for p in params {
+        let (e, new_ctx) = fresh(state, ctx);
+        ctx = new_ctx;
+        substitution.push((p, e));
+    }
+    let mut effect_substitution = Vec::with_capacity(effect_params.len());
+    for p in effect_params {
+        let (effects, new_ctx) = fresh_effects(state, ctx);
+        ctx = new_ctx;
+        effect_substitution.push((p, effects));
+    }
+    let t = t.substitute(&substitution).substitute_effects(&mut |v| {
+        effect_substitution
+            .iter()
+            .find_map(|(p, effects)| (*p == v).then(|| effects.clone()))
+    });
+    (t, ctx)
+}
+
+/// Add a fresh existential to the end of `ctx`.
+fn fresh(state: &mut State, ctx: Ctx) -> (Type, Ctx) {
+    let e = state.fresh_existential();
+    (Type::Existential(e), ctx.add(ctx::Element::Existential(e)))
+}
+
+/// Generate a fresh existential standing for some effects.
+fn fresh_effect_existential(state: &mut State) -> Existential {
+    let e = state.fresh_existential();
+    state.effect_existentials.push(e);
+    e
+}
+
+/// Add fresh unknown effects to the end of `ctx`.
+fn fresh_effects(state: &mut State, ctx: Ctx) -> (Effects, Ctx) {
+    let e = fresh_effect_existential(state);
+    (Effects::unknown(e), ctx.add(ctx::Element::Existential(e)))
+}
+
+/// Add a fresh unit existential to the end of `ctx`.
+fn fresh_measure(state: &mut State, ctx: Ctx) -> (Measure, Ctx) {
+    let e = state.fresh_existential();
+    (
+        Measure::factor(Factor::Existential(e)),
+        ctx.add(ctx::Element::Existential(e)),
+    )
+}
+
+/// Replace each distinct generalized unit variable in `t` with a fresh unit
+/// existential, so that every use of a unit-polymorphic variable can be at a
+/// different unit.
+fn instantiate_units(state: &mut State, ctx: Ctx, t: &Type) -> (Type, Ctx) {
+    let mut generics = Vec::new();
+    t.visit_measures(&mut |m| {
+        for (f, _) in m.factors() {
+            if matches!(f, Factor::Generic(_)) && !generics.contains(f) {
+                generics.push(*f);
+            }
         }
+    });
+    let mut ctx = ctx;
+    let mut substitution = Vec::with_capacity(generics.len());
+    for g in generics {
+        let (m, new_ctx) = fresh_measure(state, ctx);
+        ctx = new_ctx;
+        substitution.push((g, m));
     }
+    let t = t.substitute_measures(&mut |f| {
+        substitution
+            .iter()
+            .find_map(|(g, m)| if *g == f { Some(m.clone()) } else { None })
+    });
+    (t, ctx)
+}
+
+/// Replace the rest of each row in `t` which a `let` has generalized over with
+/// a fresh existential, so that every use of a row-polymorphic variable can be
+/// at records with different other fields.
+fn instantiate_rows(state: &mut State, ctx: Ctx, t: &Type) -> (Type, Ctx) {
+    if state.generic_rows.is_empty() {
+        return (t.clone(), ctx);
+    }
+    let mut ctx = ctx;
+    let mut substitution: Vec<(Existential, Existential)> = Vec::new();
+    let t = t.apply(&ctx).substitute_rows(&mut |r| {
+        if !state.generic_rows.contains(&r) {
+            return None;
+        }
+        if let Some((_, fresh)) = substitution.iter().find(|(g, _)| *g == r) {
+            return Some(*fresh);
+        }
+        let fresh = state.fresh_existential();
+        ctx = ctx.clone().add(ctx::Element::Existential(fresh));
+        substitution.push((r, fresh));
+        Some(fresh)
+    });
+    (t, ctx)
+}
+
+/// Type `e?`, where `e` has type `head success` & the constructor `head` isn't
+/// known yet, in a function which returns `ret`. The function must return
+/// `head` applied to something, and `head` must turn out to be `Option` or
+/// `Result` with some error.
+fn propagate_unknown(
+    state: &mut State,
+    ctx: Ctx,
+    head: Type,
+    success: Type,
+    ret: &Type,
+) -> Result<(Type, Ctx), Error> {
+    state.propagated.push((
+        state.location,
+        Type::App(Box::new(head.clone()), vec![success.clone()]),
+    ));
+    let (any, ctx) = fresh(state, ctx);
+    let ctx = covariant_subtype(state, ctx, &Type::App(Box::new(head), vec![any]), ret)?;
+    Ok((success.apply(&ctx), ctx))
+}
+
+/// Check that `binding`, which is bound to `id` by a `let rec`, is a
+/// function. Its uses of itself are then only evaluated once it's called, by
+/// which point it's been defined.
+fn check_recursive_binding(id: interner::Id, binding: &Expr) -> Result<(), Error> {
+    match binding.unlocated() {
+        Expr::Lambda(..) => Ok(()),
+        Expr::Ascribed(e, _) => check_recursive_binding(id, e),
+        _ => Err(Error::RecursiveNonFunction(id)),
+    }
+}
+
+/// Generalize the unit variables in `t` which don't appear in the types of any
+/// of the variables in `ctx`, so that `t` can be used at any units. So are the
+/// units which are still unknown, and were only inferred for `t`, such as the
+/// unit of `x` in `|x| x * 2.0`.
+fn generalize_units(ctx: &Ctx, t: &Type) -> Type {
+    let t = t.apply(ctx);
+    let mut has_vars = false;
+    let mut names = Vec::new();
+    let mut inferred = Vec::new();
+    t.visit_measures(&mut |m| {
+        for (f, _) in m.factors() {
+            match f {
+                Factor::Var(v) => {
+                    has_vars = true;
+                    names.push(*v);
+                }
+                Factor::Generic(v) => names.push(*v),
+                Factor::Existential(a)
+                    if !inferred.contains(a) && ctx.has_existential_after_variables(a) =>
+                {
+                    inferred.push(*a)
+                }
+                Factor::Existential(_) | Factor::Unit(_) => {}
+            }
+        }
+    });
+    // The inferred units are named after their existentials, as long as the
+    // name isn't taken.
+    let mut generics = Vec::with_capacity(inferred.len());
+    for a in inferred {
+        let name = (0..)
+            .map(|n| match n {
+                0 => interner::Id::new(a.to_string()),
+                n => interner::Id::new(format!("{a}{n}")),
+            })
+            .find(|id| !names.contains(id))
+            .expect("there are infinitely many names");
+        names.push(name);
+        generics.push((a, Measure::factor(Factor::Generic(name))));
+    }
+    let t = t.substitute_measures(&mut |f| match f {
+        Factor::Existential(a) => generics
+            .iter()
+            .find_map(|(b, m)| (a == *b).then(|| m.clone())),
+        _ => None,
+    });
+    // Finding which are bound means applying every variable's type, so skip
+    // it when there's nothing else to generalize.
+    if !has_vars {
+        return t;
+    }
+    let mut bound = Vec::new();
+    for v in ctx.variable_types() {
+        v.apply(ctx).visit_measures(&mut |m| {
+            bound.extend(m.factors().iter().map(|(f, _)| *f));
+        });
+    }
+    t.substitute_measures(&mut |f| match f {
+        Factor::Var(v) if !bound.contains(&f) => Some(Measure::factor(Factor::Generic(v))),
+        _ => None,
+    })
+}
+
+/// Generalize the rests of the rows in `t`, the type bound by a `let`, which
+/// are still unknown & don't appear in the types of any of the variables in
+/// `ctx`, so that `t` can be used at records with any other fields. Rows which
+/// must have instances of some class are left alone, since which instances
+/// their values need depends on all of their fields.
+fn generalize_rows(state: &mut State, ctx: &Ctx, t: &Type) -> Result<(), Error> {
+    let mut rests = Vec::new();
+    t.apply(ctx).visit_rows(&mut |row| rests.extend(row.rest()));
+    rests.retain(|r| ctx.has_existential_after_variables(r));
+    if rests.is_empty() {
+        return Ok(());
+    }
+    // Only the constraints which are still unknown could be on these rows.
+    check_constraints(state, ctx)?;
+    let mut constrained = Vec::new();
+    for c in state.constraints.iter() {
+        c.typ
+            .apply(ctx)
+            .visit_rows(&mut |row| constrained.extend(row.rest()));
+    }
+    state
+        .generic_rows
+        .extend(rests.into_iter().filter(|r| !constrained.contains(r)));
+    Ok(())
+}
+
+/// Solve `to_instantiate` as a record with the same fields as `row`, each of
+/// which has a fresh existential type, and a fresh existential rest if `row` is
+/// open. Returns the updated `Ctx` along with the new existentials.
+#[allow(clippy::type_complexity)]
+fn instantiate_to_record(
+    state: &mut State,
+    ctx: Ctx,
+    to_instantiate: Existential,
+    row: &Row,
+) -> Result<(Ctx, Vec<(interner::Id, Existential)>, Option<Existential>), Error> {
+    let fields: Vec<_> = row
+        .fields()
+        .iter()
+        .map(|(f, _)| (*f, state.fresh_existential()))
+        .collect();
+    let rest = row.rest().map(|_| state.fresh_existential());
+
+    let mut replacements: Vec<_> = rest.into_iter().map(ctx::Element::Existential).collect();
+    replacements.extend(
+        fields
+            .iter()
+            .rev()
+            .map(|(_, e)| ctx::Element::Existential(*e)),
+    );
+    replacements.push(ctx::Element::Solved(
+        to_instantiate,
+        Type::Record(Row::new(
+            fields
+                .iter()
+                .map(|(f, e)| (*f, Type::Existential(*e)))
+                .collect(),
+            rest,
+        )),
+    ));
+
+    let ctx = ctx.insert_in_place(ctx::Element::Existential(to_instantiate), &replacements)?;
+    Ok((ctx, fields, rest))
 }
 
 /// Check whether value `l` has primitive type `p`.
 fn check_literal_type(ctx: Ctx, l: &Val, p: &Primitive) -> Result<Ctx, Error> {
     match (l, p) {
-        (Val::Bool(_), Primitive::Bool)
-        | (Val::Num(_), Primitive::Num)
+        (Val::BigInt(_), Primitive::BigInt)
+        | (Val::Bool(_), Primitive::Bool)
+        | (Val::Int(_), Primitive::Int)
+        | (Val::Num(_), Primitive::Num(_))
+        | (Val::Rational(_), Primitive::Rational)
+        | (Val::Str(_), Primitive::String)
         | (Val::Unit, Primitive::Unit) => Ok(ctx),
-        (Val::Closure { .. } | Val::Dummy, _) => unreachable!("Runtime-only"),
+        (
+            Val::Array(_)
+            | Val::Closure { .. }
+            | Val::Data { .. }
+            | Val::Dummy
+            | Val::List(_)
+            | Val::Map(_)
+            | Val::Record(_)
+            | Val::Set(_),
+            _,
+        ) => {
+            unreachable!("Runtime-only")
+        }
         (l, p) => Err(Error::Mismatch {
             got: l.typ(),
-            expected: Type::Primitive(*p),
+            expected: Type::Primitive(p.clone()),
         }),
     }
 }
@@ -356,27 +2633,102 @@ impl Type {
     /// their solutions.
     pub(crate) fn apply(&self, ctx: &Ctx) -> Self {
         match self {
-            Type::Arrow(from, to) => {
-                Type::Arrow(Box::new(from.apply(ctx)), Box::new(to.apply(ctx)))
-            }
+            Type::Arrow(from, to, effects) => Type::Arrow(
+                Box::new(from.apply(ctx)),
+                Box::new(to.apply(ctx)),
+                effects.apply(ctx),
+            ),
             Type::Existential(a) => match ctx.get_solved(a) {
                 Some(t) => t.clone().apply(ctx),
                 None => self.clone(),
             },
-            Type::Primitive(p) => Type::Primitive(*p),
+            // Once its constructor is known, an applied type is `Named`.
+            Type::App(head, args) => head
+                .apply(ctx)
+                .applied_to(args.iter().map(|t| t.apply(ctx)).collect()),
+            Type::Named(n, args) => Type::Named(*n, args.iter().map(|t| t.apply(ctx)).collect()),
+            Type::Dyn => Type::Dyn,
+            Type::Error => Type::Error,
+            Type::Primitive(Primitive::Num(m)) => Type::measured(m.apply(ctx)),
+            Type::Primitive(p) => Type::Primitive(p.clone()),
+            Type::Record(row) => Type::Record(row.apply(ctx)),
+            Type::Var(v) => Type::Var(*v),
         }
     }
 }
 
+impl Row {
+    /// Apply a `Ctx` to the `Row`, replacing any solved existentials with
+    /// their solutions. If the rest of the row has been solved then its
+    /// fields are merged into this one.
+    pub(crate) fn apply(&self, ctx: &Ctx) -> Self {
+        let mut fields: Vec<_> = self
+            .fields()
+            .iter()
+            .map(|(f, t)| (*f, t.apply(ctx)))
+            .collect();
+        let rest = match self.rest() {
+            Some(r) => match ctx.get_solved(&r).map(|t| t.apply(ctx)) {
+                Some(Type::Record(solved)) => {
+                    fields.extend(solved.fields().iter().cloned());
+                    solved.rest()
+                }
+                Some(Type::Existential(e)) => Some(e),
+                _ => Some(r),
+            },
+            None => None,
+        };
+        Row::new(fields, rest)
+    }
+}
+
+impl Effects {
+    /// Apply a `Ctx` to the `Effects`, merging in the rest of them if it has
+    /// been solved.
+    pub(crate) fn apply(&self, ctx: &Ctx) -> Self {
+        self.substitute(&mut |v| match v {
+            EffectVar::Existential(a) => ctx.get_solved_effects(&a).map(|e| e.apply(ctx)),
+            EffectVar::Var(_) => None,
+        })
+    }
+}
+
+impl Measure {
+    /// Apply a `Ctx` to the `Measure`, replacing any solved unit existentials
+    /// with their solutions.
+    pub(crate) fn apply(&self, ctx: &Ctx) -> Self {
+        self.substitute(&mut |f| match f {
+            Factor::Existential(a) => match ctx.get_solved(&a).map(|t| t.apply(ctx)) {
+                Some(Type::Primitive(Primitive::Num(m))) => Some(m),
+                _ => None,
+            },
+            _ => None,
+        })
+    }
+}
+
 impl Val {
-    /// Get the primitive type of `self`. Panics if called on a `Closure` or
-    /// `Dummy` value.
-    fn typ(&self) -> Type {
+    /// Get the primitive type of `self`. Panics if called on a runtime-only
+    /// value, such as a `Closure`.
+    pub(crate) fn typ(&self) -> Type {
         Type::Primitive(match self {
+            Val::BigInt(_) => Primitive::BigInt,
             Val::Bool(_) => Primitive::Bool,
-            Val::Num(_) => Primitive::Num,
+            Val::Int(_) => Primitive::Int,
+            Val::Num(_) => Primitive::Num(Measure::one()),
+            Val::Rational(_) => Primitive::Rational,
+            Val::Str(_) => Primitive::String,
             Val::Unit => Primitive::Unit,
-            Val::Closure { .. } | Val::Dummy => unreachable!("Runtime-only"),
+            Val::Array(_)
+            | Val::Closure { .. }
+            | Val::Data { .. }
+            | Val::Dummy
+            | Val::List(_)
+            | Val::Map(_)
+            | Val::Record(_)
+            | Val::Set(_) => {
+                unreachable!("Runtime-only")
+            }
         })
     }
 }
@@ -1,8 +1,10 @@
-use std::fmt;
+use std::{cmp::Reverse, fmt};
+
+use im_rc::{ordmap::DiffItem, HashMap, OrdMap, Vector};
 
 use crate::interner;
 
-use super::{Error, Existential, Type};
+use super::{EffectVar, Effects, Error, Existential, Primitive, Type};
 
 /// An element of the typing context.
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -13,6 +15,20 @@ pub(crate) enum Element {
     Existential(Existential),
     /// A former existential, now solved.
     Solved(Existential, Type),
+    /// A former existential standing for some effects, now solved.
+    SolvedEffects(Existential, Effects),
+}
+
+impl Element {
+    /// The existential this element declares or solves, if any.
+    fn existential(&self) -> Option<Existential> {
+        match self {
+            Element::Existential(a) | Element::Solved(a, _) | Element::SolvedEffects(a, _) => {
+                Some(*a)
+            }
+            Element::TypedVariable(..) => None,
+        }
+    }
 }
 
 impl fmt::Display for Element {
@@ -21,26 +37,63 @@ impl fmt::Display for Element {
             Element::TypedVariable(i, t) => write!(f, "{}: {t}", i.name()),
             Element::Existential(e) => write!(f, "{e}"),
             Element::Solved(e, t) => write!(f, "{e} = {t}"),
+            Element::SolvedEffects(e, effects) => write!(f, "{e} = {effects}"),
+        }
+    }
+}
+
+/// Where an element is in the context. Elements added to the end each get a
+/// new `label`. Elements inserted in place of another share its label, and
+/// come just before it, so they're still ordered the same way relative to
+/// everything else, without anything having to move.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+struct Position {
+    label: usize,
+    /// Counts down, so that elements inserted in place of another come before
+    /// it, and before any inserted in its place earlier. The elements sharing
+    /// a label were all inserted in place of the same one, so they're in the
+    /// same scope, and any order between them will do.
+    seq: Reverse<usize>,
+}
+
+/// Which part of the context to look for existentials in.
+#[derive(Clone, Copy)]
+enum Scope {
+    All,
+    Before(Position),
+    From(Position),
+}
+
+impl Scope {
+    fn contains(self, p: Position) -> bool {
+        match self {
+            Scope::All => true,
+            Scope::Before(q) => p < q,
+            Scope::From(q) => p >= q,
         }
     }
 }
 
+/// The typing context: an ordered list of elements, as in Dunfield &
+/// Krishnaswami's algorithm, along with indexes for finding existentials &
+/// variables without searching it. The structures are persistent, so cloning
+/// a `Ctx` is cheap, and so is updating one which shares them with another.
 #[derive(Debug, Clone, PartialEq, Eq)]
-/// The typing context
 pub(crate) struct Ctx {
-    /// The elements of the typing context.
-    ///
-    /// NOTE: In order for variable shadowing to work correctly the typing
-    /// context must always be searched from the back. That means that most
-    /// methods which iterate through `elements` will do so using a reversed
-    /// iterator.
-    elements: Vec<Element>,
+    elements: OrdMap<Position, Element>,
+    /// The position of each existential, whether it's been solved or not.
+    existentials: HashMap<Existential, Position>,
+    /// The positions of the variables with each name, innermost last, so that
+    /// shadowing works.
+    variables: HashMap<interner::Id, Vector<Position>>,
+    next_label: usize,
+    next_seq: usize,
 }
 
 impl fmt::Display for Ctx {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "[")?;
-        for (idx, e) in self.elements.iter().enumerate() {
+        for (idx, e) in self.elements.values().enumerate() {
             if idx != 0 {
                 write!(f, ", ")?;
             }
@@ -53,114 +106,351 @@ impl fmt::Display for Ctx {
 impl Ctx {
     pub(crate) fn new() -> Self {
         Self {
-            elements: Vec::new(),
+            elements: OrdMap::new(),
+            existentials: HashMap::new(),
+            variables: HashMap::new(),
+            next_label: 0,
+            // Sequence number 0 is for elements added to the end.
+            next_seq: 1,
         }
     }
 
     pub(crate) fn add(self, element: Element) -> Self {
-        let mut elements = self.elements;
-        elements.push(element);
-        Self { elements }
-    }
-
-    pub(crate) fn split_at(&self, element: &Element) -> Result<(Ctx, Ctx), Error> {
-        self.first_appearance_from_back(element)
-            .map(|i| {
-                let (left, right) = self.elements.split_at(i);
-                (
-                    Ctx {
-                        elements: left.to_vec(),
-                    },
-                    Ctx {
-                        elements: right.to_vec(),
-                    },
-                )
-            })
-            .ok_or_else(|| {
-                Error::Internal(format!(
-                    "split_at called with non-existent element {element:?}"
-                ))
-            })
+        let mut ctx = self;
+        let p = Position {
+            label: ctx.next_label,
+            seq: Reverse(0),
+        };
+        ctx.next_label += 1;
+        ctx.insert(p, element);
+        ctx
     }
 
+    /// Replace `element` with `replacements`, in order. If `element` is an
+    /// existential which the last replacement declares or solves, it stays
+    /// where it was and the rest come before it.
     pub(crate) fn insert_in_place(
         self,
         element: Element,
         replacements: &[Element],
     ) -> Result<Self, Error> {
-        self.first_appearance_from_back(&element)
-            .map(|i| {
-                let mut elements = self.elements;
-                let _ = elements.splice(i..=i, replacements.iter().cloned()).count();
-                Ctx { elements }
-            })
-            .ok_or_else(|| {
-                Error::Internal(format!(
-                    "insert_in_place called with non-existent element: {element:?}"
-                ))
-            })
+        let p = self.position(&element).ok_or_else(|| {
+            Error::Internal(format!(
+                "insert_in_place called with non-existent element: {element:?}"
+            ))
+        })?;
+        let mut ctx = self;
+        ctx.remove(p);
+        let mut rest = replacements;
+        if let Some((last, init)) = replacements.split_last() {
+            if last.existential().is_some() && last.existential() == element.existential() {
+                ctx.insert(p, last.clone());
+                rest = init;
+            }
+        }
+        // Working backwards, each element comes before the one after it.
+        for e in rest.iter().rev() {
+            let q = Position {
+                label: p.label,
+                seq: Reverse(ctx.next_seq),
+            };
+            ctx.next_seq += 1;
+            ctx.insert(q, e.clone());
+        }
+        Ok(ctx)
     }
 
+    /// Remove `element` & everything after it.
     pub(crate) fn drop(self, element: &Element) -> Result<Self, Error> {
-        self.first_appearance_from_back(element)
-            .map(|i| {
-                let mut elements = self.elements;
-                let _ = elements.split_off(i);
-                Ctx { elements }
-            })
-            .ok_or_else(|| {
-                Error::Internal(format!(
-                    "drop called with non-existent element: {element:?}"
-                ))
-            })
+        let p = self.position(element).ok_or_else(|| {
+            Error::Internal(format!(
+                "drop called with non-existent element: {element:?}"
+            ))
+        })?;
+        let mut ctx = self;
+        let (kept, dropped) = ctx.elements.split(&p);
+        ctx.elements = kept;
+        ctx.unindex(p, element);
+        for (q, e) in dropped.iter() {
+            ctx.unindex(*q, e);
+        }
+        Ok(ctx)
+    }
+
+    /// The solved existentials from `element` on.
+    pub(crate) fn solutions_since(
+        &self,
+        element: &Element,
+    ) -> Result<impl Iterator<Item = Element> + '_, Error> {
+        let p = self.position(element).ok_or_else(|| {
+            Error::Internal(format!(
+                "solutions_since called with non-existent element {element:?}"
+            ))
+        })?;
+        Ok(self
+            .elements
+            .range(p..)
+            .map(|(_, e)| e)
+            .filter(|e| matches!(e, Element::Solved(..) | Element::SolvedEffects(..)))
+            .cloned())
+    }
+
+    /// The solutions given since the context was `before` to existentials
+    /// which come before everything added to it since, i.e. those declared
+    /// outside whatever was checked in between.
+    pub(crate) fn outer_solutions_since<'a>(&'a self, before: &'a Ctx) -> Vec<&'a Type> {
+        let mut solutions = Vec::new();
+        for change in before.elements.diff(&self.elements) {
+            let (p, e) = match change {
+                DiffItem::Add(p, e) | DiffItem::Update { new: (p, e), .. } => (p, e),
+                DiffItem::Remove(..) => continue,
+            };
+            if let (true, Element::Solved(_, t)) = (p.label < before.next_label, e) {
+                solutions.push(t);
+            }
+        }
+        solutions
+    }
+
+    /// The elements added to (`+`) or removed from (`-`) the context since it
+    /// was `before`, in order. This only looks at the parts of the context
+    /// which aren't shared with `before`.
+    pub(crate) fn changes_since(&self, before: &Ctx) -> Vec<String> {
+        let mut changes = Vec::new();
+        for change in before.elements.diff(&self.elements) {
+            match change {
+                DiffItem::Add(_, e) => changes.push(format!("+{e}")),
+                DiffItem::Remove(_, e) => changes.push(format!("-{e}")),
+                DiffItem::Update {
+                    old: (_, old),
+                    new: (_, new),
+                } => {
+                    changes.push(format!("-{old}"));
+                    changes.push(format!("+{new}"));
+                }
+            }
+        }
+        changes
     }
 
     pub(crate) fn get_solved(&self, alpha: &Existential) -> Option<&Type> {
-        self.elements.iter().rev().find_map(|e| match e {
-            Element::Solved(a, ty) if alpha == a => Some(ty),
+        match self.get_existential(alpha) {
+            Some(Element::Solved(_, ty)) => Some(ty),
             _ => None,
-        })
+        }
     }
 
+    pub(crate) fn get_solved_effects(&self, alpha: &Existential) -> Option<&Effects> {
+        match self.get_existential(alpha) {
+            Some(Element::SolvedEffects(_, effects)) => Some(effects),
+            _ => None,
+        }
+    }
+
+    /// Whether `alpha` is in the context and hasn't been solved.
     pub(crate) fn has_existential(&self, alpha: &Existential) -> bool {
-        self.elements
-            .iter()
-            .any(|e| matches!(e, Element::Existential(a) if a == alpha))
+        matches!(self.get_existential(alpha), Some(Element::Existential(_)))
+    }
+
+    /// Whether `alpha` is in the context before `beta`, and hasn't been
+    /// solved.
+    pub(crate) fn has_existential_before(&self, alpha: &Existential, beta: &Existential) -> bool {
+        self.has_existential(alpha)
+            && matches!(
+                (self.existentials.get(alpha), self.existentials.get(beta)),
+                (Some(a), Some(b)) if a < b
+            )
+    }
+
+    /// Whether `alpha` is in the context & hasn't been solved, and comes after
+    /// every variable. Everything in a variable's type comes before it, so
+    /// none of their types can mention `alpha`.
+    pub(crate) fn has_existential_after_variables(&self, alpha: &Existential) -> bool {
+        self.has_existential(alpha)
+            && self.existentials.get(alpha).is_some_and(|p| {
+                self.elements
+                    .range(p..)
+                    .all(|(_, e)| !matches!(e, Element::TypedVariable(..)))
+            })
     }
 
     pub(crate) fn get_annotation(&self, x: interner::Id) -> Result<&Type, Error> {
-        self.elements
-            .iter()
-            .rev()
-            .find_map(|e| match e {
-                Element::TypedVariable(v, t) if v == &x => Some(t),
+        self.variables
+            .get(&x)
+            .and_then(|ps| ps.last())
+            .and_then(|p| match self.elements.get(p) {
+                Some(Element::TypedVariable(_, t)) => Some(t),
                 _ => None,
             })
             .ok_or(Error::UnboundVariable(x))
     }
 
+    /// The types of the variables in the context.
+    pub(crate) fn variable_types(&self) -> impl Iterator<Item = &Type> + '_ {
+        self.variables
+            .values()
+            .flat_map(|ps| ps.iter())
+            .filter_map(|p| match self.elements.get(p) {
+                Some(Element::TypedVariable(_, t)) => Some(t),
+                _ => None,
+            })
+    }
+
     pub(crate) fn check_type_well_formed(&self, t: &Type) -> Result<(), Error> {
+        self.check_type_well_formed_in(t, Scope::All)
+    }
+
+    /// Check that `t` is well-formed in the part of the context before
+    /// `alpha`, i.e. that `alpha` could be solved as `t`.
+    pub(crate) fn check_type_well_formed_before(
+        &self,
+        t: &Type,
+        alpha: &Existential,
+    ) -> Result<(), Error> {
+        let p = self.existential_position(alpha)?;
+        self.check_type_well_formed_in(t, Scope::Before(p))
+    }
+
+    /// Check that `t` is well-formed in the part of the context from `alpha`
+    /// on.
+    pub(crate) fn check_type_well_formed_from(
+        &self,
+        t: &Type,
+        alpha: &Existential,
+    ) -> Result<(), Error> {
+        let p = self.existential_position(alpha)?;
+        self.check_type_well_formed_in(t, Scope::From(p))
+    }
+
+    fn check_type_well_formed_in(&self, t: &Type, scope: Scope) -> Result<(), Error> {
+        let in_scope =
+            |a: &Existential| self.existentials.get(a).is_some_and(|p| scope.contains(*p));
+        let declared = |a: &Existential| {
+            in_scope(a) && (self.has_existential(a) || self.get_solved(a).is_some())
+        };
         match t {
-            Type::Primitive(_) => Ok(()),
+            Type::Primitive(Primitive::Num(m)) => {
+                if m.existentials().all(|(a, _)| declared(&a)) {
+                    Ok(())
+                } else {
+                    Err(Error::IllFormedType(t.clone()))
+                }
+            }
+            Type::Dyn | Type::Error | Type::Primitive(_) => Ok(()),
+            Type::Named(_, args) => args
+                .iter()
+                .try_for_each(|t| self.check_type_well_formed_in(t, scope)),
+            Type::App(head, args) => {
+                self.check_type_well_formed_in(head, scope)?;
+                args.iter()
+                    .try_for_each(|t| self.check_type_well_formed_in(t, scope))
+            }
+            Type::Var(_) => Err(Error::IllFormedType(t.clone())),
             Type::Existential(a) => {
-                if self.has_existential(a) || self.get_solved(a).is_some() {
+                if declared(a) {
                     Ok(())
                 } else {
                     Err(Error::IllFormedType(t.clone()))
                 }
             }
-            Type::Arrow(from, to) => {
-                self.check_type_well_formed(from)?;
-                self.check_type_well_formed(to)
+            Type::Arrow(from, to, effects) => {
+                self.check_type_well_formed_in(from, scope)?;
+                self.check_type_well_formed_in(to, scope)?;
+                match effects.rest() {
+                    Some(EffectVar::Existential(a))
+                        if in_scope(&a)
+                            && (self.has_existential(&a)
+                                || self.get_solved_effects(&a).is_some()) =>
+                    {
+                        Ok(())
+                    }
+                    Some(_) => Err(Error::IllFormedType(t.clone())),
+                    None => Ok(()),
+                }
+            }
+            Type::Record(row) => {
+                if let Some(field) = row.duplicate_field() {
+                    return Err(Error::DuplicateField(field));
+                }
+                for (_, t) in row.fields() {
+                    self.check_type_well_formed_in(t, scope)?;
+                }
+                match row.rest() {
+                    Some(r) => self.check_type_well_formed_in(&Type::Existential(r), scope),
+                    None => Ok(()),
+                }
+            }
+        }
+    }
+
+    fn get_existential(&self, alpha: &Existential) -> Option<&Element> {
+        self.existentials
+            .get(alpha)
+            .and_then(|p| self.elements.get(p))
+    }
+
+    fn existential_position(&self, alpha: &Existential) -> Result<Position, Error> {
+        self.existentials
+            .get(alpha)
+            .copied()
+            .ok_or_else(|| Error::Internal(format!("existential {alpha} isn't in the context")))
+    }
+
+    /// The position of `element`, searching from the back so that shadowing
+    /// works.
+    fn position(&self, element: &Element) -> Option<Position> {
+        let found = |p: &Position| self.elements.get(p) == Some(element);
+        match element {
+            Element::TypedVariable(x, _) => self
+                .variables
+                .get(x)
+                .and_then(|ps| ps.iter().rev().find(|p| found(p)).copied()),
+            _ => element
+                .existential()
+                .and_then(|a| self.existentials.get(&a))
+                .filter(|p| found(p))
+                .copied(),
+        }
+    }
+
+    fn insert(&mut self, p: Position, element: Element) {
+        match &element {
+            // Variables are only ever added to the end, so these stay in
+            // order.
+            Element::TypedVariable(x, _) => self.variables.entry(*x).or_default().push_back(p),
+            e => {
+                if let Some(a) = e.existential() {
+                    self.existentials.insert(a, p);
+                }
             }
         }
+        self.elements.insert(p, element);
+    }
+
+    fn remove(&mut self, p: Position) {
+        if let Some(element) = self.elements.remove(&p) {
+            self.unindex(p, &element);
+        }
     }
 
-    fn first_appearance_from_back(&self, element: &Element) -> Option<usize> {
-        self.elements
-            .iter()
-            .rev()
-            .position(|e1| e1 == element)
-            .map(|p| self.elements.len() - p - 1)
+    /// Remove the element at `p` from the indexes.
+    fn unindex(&mut self, p: Position, element: &Element) {
+        match element {
+            Element::TypedVariable(x, _) => {
+                if let Some(ps) = self.variables.get_mut(x) {
+                    ps.retain(|q| *q != p);
+                    if ps.is_empty() {
+                        self.variables.remove(x);
+                    }
+                }
+            }
+            e => {
+                if let Some(a) = e.existential() {
+                    if self.existentials.get(&a) == Some(&p) {
+                        self.existentials.remove(&a);
+                    }
+                }
+            }
+        }
     }
 }
@@ -0,0 +1,118 @@
+use std::fmt;
+
+use crate::interner;
+
+use super::Existential;
+
+/// The effects which calling a function may perform, such as `io`, written
+/// `-{io}->` in its type. Effects are labels which only the checker knows
+/// about, and a function performs every effect of the calls in its body.
+#[derive(Clone, Default, PartialEq, Eq)]
+pub struct Effects {
+    /// Sorted by name, without any repeats, so that equal effects have equal
+    /// representations.
+    labels: Vec<interner::Id>,
+    /// Any other effects which are still to be inferred, if there may be any.
+    rest: Option<EffectVar>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EffectVar {
+    /// The effects of a function argument of a built-in function, which
+    /// each use of the built-in may instantiate differently.
+    Var(interner::Id),
+    /// Effects which are still to be inferred.
+    Existential(Existential),
+}
+
+impl Effects {
+    /// The effects of a function which doesn't perform any.
+    pub fn pure() -> Self {
+        Self::default()
+    }
+
+    pub fn new(labels: Vec<interner::Id>, rest: Option<EffectVar>) -> Self {
+        let mut labels = labels;
+        labels.sort_by(|l, r| l.name().cmp(r.name()));
+        labels.dedup();
+        Effects { labels, rest }
+    }
+
+    /// Effects which are still to be inferred as `e`.
+    pub(crate) fn unknown(e: Existential) -> Self {
+        Self::new(Vec::new(), Some(EffectVar::Existential(e)))
+    }
+
+    pub fn labels(&self) -> &[interner::Id] {
+        &self.labels
+    }
+
+    pub fn rest(&self) -> Option<EffectVar> {
+        self.rest
+    }
+
+    pub fn is_pure(&self) -> bool {
+        self.labels.is_empty() && self.rest.is_none()
+    }
+
+    pub(crate) fn contains(&self, label: interner::Id) -> bool {
+        self.labels.contains(&label)
+    }
+
+    /// The existential standing for the rest of the effects, if there is one.
+    pub(crate) fn existential(&self) -> Option<Existential> {
+        match self.rest {
+            Some(EffectVar::Existential(e)) => Some(e),
+            _ => None,
+        }
+    }
+
+    /// Replace the rest of the effects with the effects `replace` returns
+    /// for it, if any.
+    pub(crate) fn substitute(
+        &self,
+        replace: &mut impl FnMut(EffectVar) -> Option<Effects>,
+    ) -> Self {
+        match self.rest.and_then(&mut *replace) {
+            Some(rest) => {
+                let mut labels = self.labels.clone();
+                labels.extend(rest.labels);
+                Effects::new(labels, rest.rest)
+            }
+            None => self.clone(),
+        }
+    }
+}
+
+impl fmt::Display for Effects {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{{")?;
+        for (idx, label) in self.labels.iter().enumerate() {
+            if idx != 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{}", label.name())?;
+        }
+        match self.rest {
+            Some(rest) if self.labels.is_empty() => write!(f, "{rest}")?,
+            Some(rest) => write!(f, " | {rest}")?,
+            None => {}
+        }
+        write!(f, "}}")
+    }
+}
+
+impl fmt::Debug for Effects {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{self}")
+    }
+}
+
+impl fmt::Display for EffectVar {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EffectVar::Var(v) => write!(f, "{}", v.name()),
+            EffectVar::Existential(e) => write!(f, "{e}"),
+        }
+    }
+}
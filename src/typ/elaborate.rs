@@ -0,0 +1,63 @@
+use std::collections::HashMap;
+
+use crate::ast::{Expr, TypedExpr, TypedExprKind};
+
+use super::{ctx::Ctx, Normalizer, Type};
+
+/// Annotate every node of `e` with its type from `types`, applied to `ctx`.
+pub(crate) fn elaborate(
+    e: &Expr,
+    types: &HashMap<*const Expr, Type>,
+    ctx: &Ctx,
+    n: &mut Normalizer,
+) -> TypedExpr {
+    // Naming the node's type before its children's means type variables are
+    // named in the order they're read.
+    let typ = types
+        .get(&(e.unlocated() as *const Expr))
+        .map(|t| n.typ(&t.apply(ctx)))
+        .unwrap_or(Type::Error);
+    let mut go = |e: &Expr| Box::new(crate::with_large_stack(|| elaborate(e, types, ctx, n)));
+    let kind = match e {
+        Expr::Ascribed(e, t) => TypedExprKind::Ascribed(go(e), t.clone()),
+        Expr::App(f, a) => TypedExprKind::App(go(f), go(a)),
+        Expr::Builtin(b) => TypedExprKind::Builtin(*b),
+        Expr::Constructor(c, arity) => TypedExprKind::Constructor(*c, *arity),
+        Expr::Field(r, field) => TypedExprKind::Field(go(r), *field),
+        Expr::Index(a, i) => TypedExprKind::Index(go(a), go(i)),
+        Expr::Instance(instance, methods, body) => {
+            TypedExprKind::Instance(instance.clone(), go(methods), go(body))
+        }
+        Expr::Lambda(id, ty, body) => TypedExprKind::Lambda(*id, ty.clone(), go(body)),
+        Expr::Let(rec, id, bnd, body) => TypedExprKind::Let(*rec, *id, go(bnd), go(body)),
+        Expr::Literal(v) => TypedExprKind::Literal(v.clone()),
+        Expr::Located(location, e) => TypedExprKind::Located(*location, go(e)),
+        Expr::Measured(n, m) => TypedExprKind::Measured(*n, m.clone()),
+        Expr::IfThenElse(c, t, e) => TypedExprKind::IfThenElse(go(c), go(t), go(e)),
+        Expr::Match(e, arms) => {
+            let e = go(e);
+            let arms = arms
+                .iter()
+                .map(|(p, e)| (p.clone(), elaborate(e, types, ctx, n)))
+                .collect();
+            TypedExprKind::Match(e, arms)
+        }
+        Expr::Propagate(e) => TypedExprKind::Propagate(go(e)),
+        Expr::Var(id, idx) => TypedExprKind::Var(*id, *idx),
+        Expr::Op(l, op, r) => TypedExprKind::Op(go(l), *op, go(r)),
+        Expr::Record(fields) => TypedExprKind::Record(
+            fields
+                .iter()
+                .map(|(f, e)| (*f, elaborate(e, types, ctx, n)))
+                .collect(),
+        ),
+        Expr::TypeDecl(decl, body) => TypedExprKind::TypeDecl(decl.clone(), go(body)),
+        Expr::NewtypeDecl(newtype, body) => TypedExprKind::NewtypeDecl(newtype.clone(), go(body)),
+        Expr::Refined(contract, predicate, body) => {
+            TypedExprKind::Refined(contract.clone(), go(predicate), go(body))
+        }
+        Expr::Wrap(n) => TypedExprKind::Wrap(*n),
+        Expr::Unwrap(n) => TypedExprKind::Unwrap(*n),
+    };
+    TypedExpr { kind, typ }
+}
@@ -0,0 +1,235 @@
+//! Exhaustiveness & redundancy checking for `match` expressions.
+//!
+//! The algorithm is the "usefulness" check described in Luc Maranget's
+//! [Warnings for pattern matching](http://moscova.inria.fr/~maranget/papers/warn/index.html).
+//! A pattern is useful with respect to some earlier patterns if there is a
+//! value which it matches and they don't. An arm is then redundant if its
+//! pattern isn't useful with respect to the arms above it, and a match is
+//! exhaustive if a wildcard wouldn't be useful after its last arm. Values
+//! which show a wildcard to be useful are exactly the missing cases.
+
+use crate::{ast::Pattern, interner, values::Val};
+
+use super::{checker::State, Error, Primitive, Type};
+
+/// Check that `patterns`, which match values of type `t`, cover every value of
+/// that type and that none of them is made redundant by those before it.
+pub(crate) fn check(state: &State, t: &Type, patterns: &[&Pattern]) -> Result<(), Error> {
+    let mut rows: Vec<Vec<Pattern>> = Vec::with_capacity(patterns.len());
+    for p in patterns {
+        let row = vec![(*p).clone()];
+        if useful(state, &rows, &row, std::slice::from_ref(t))?.is_empty() {
+            return Err(Error::RedundantPattern((*p).clone()));
+        }
+        rows.push(row);
+    }
+
+    let missing = useful(state, &rows, &[Pattern::Wildcard], std::slice::from_ref(t))?;
+    if missing.is_empty() {
+        Ok(())
+    } else {
+        let missing = missing.into_iter().flatten().collect();
+        Err(Error::NonExhaustive { missing })
+    }
+}
+
+/// The "head" of a non-wildcard pattern, i.e. the constructor or literal
+/// which a value must have at the top level in order to match it.
+#[derive(Clone, PartialEq)]
+enum Head {
+    Constructor(interner::Id, usize),
+    /// A newtype's constructor, which every value of the newtype has.
+    Newtype(interner::Id),
+    Literal(Val),
+}
+
+impl Head {
+    fn of(p: &Pattern) -> Option<Head> {
+        match p {
+            Pattern::Constructor(c, args) => Some(Head::Constructor(*c, args.len())),
+            Pattern::Newtype(n, _) => Some(Head::Newtype(*n)),
+            Pattern::Literal(l) => Some(Head::Literal(l.clone())),
+            Pattern::Var(_) | Pattern::Wildcard => None,
+        }
+    }
+
+    fn arity(&self) -> usize {
+        match self {
+            Head::Constructor(_, arity) => *arity,
+            Head::Newtype(_) => 1,
+            Head::Literal(_) => 0,
+        }
+    }
+
+    /// The types of the values this head's sub-patterns match, when the
+    /// head's value has type `t`.
+    fn field_types(&self, state: &State, t: &Type) -> Result<Vec<Type>, Error> {
+        match self {
+            Head::Constructor(c, _) => state.constructor_fields(*c, t),
+            Head::Newtype(n) => Ok(vec![state.newtype(*n)?.clone()]),
+            Head::Literal(_) => Ok(Vec::new()),
+        }
+    }
+
+    /// Build a pattern with this head out of the given sub-patterns.
+    fn to_pattern(&self, mut args: Vec<Pattern>) -> Pattern {
+        match self {
+            Head::Constructor(c, _) => Pattern::Constructor(*c, args),
+            Head::Newtype(n) => Pattern::Newtype(*n, Box::new(args.remove(0))),
+            Head::Literal(l) => Pattern::Literal(l.clone()),
+        }
+    }
+}
+
+/// Every head which a value of type `t` could have, or `None` if there are
+/// too many to list.
+fn signature(state: &State, t: &Type) -> Result<Option<Vec<Head>>, Error> {
+    match t {
+        Type::Named(n, _) if state.newtype(*n).is_ok() => Ok(Some(vec![Head::Newtype(*n)])),
+        Type::Named(n, _) => match state.constructors_of(*n) {
+            // Opaque types can only be matched by variables & wildcards.
+            Some([]) => Ok(None),
+            Some(ctors) => ctors
+                .iter()
+                .map(|c| Ok(Head::Constructor(*c, state.constructor(*c)?.1.len())))
+                .collect::<Result<_, _>>()
+                .map(Some),
+            None => Err(Error::Internal(format!("undeclared type {}", n.name()))),
+        },
+        Type::Primitive(Primitive::Bool) => Ok(Some(vec![
+            Head::Literal(Val::Bool(true)),
+            Head::Literal(Val::Bool(false)),
+        ])),
+        Type::Primitive(Primitive::Unit) => Ok(Some(vec![Head::Literal(Val::Unit)])),
+        _ => Ok(None),
+    }
+}
+
+/// Keep only the rows whose first pattern could match a value with the given
+/// head, replacing that pattern with its sub-patterns.
+fn specialize(rows: &[Vec<Pattern>], head: &Head) -> Vec<Vec<Pattern>> {
+    rows.iter()
+        .filter_map(|row| specialize_row(row, head))
+        .collect()
+}
+
+fn specialize_row(row: &[Pattern], head: &Head) -> Option<Vec<Pattern>> {
+    let (first, rest) = row.split_first()?;
+    let mut specialized = match first {
+        Pattern::Var(_) | Pattern::Wildcard => vec![Pattern::Wildcard; head.arity()],
+        p if Head::of(p).as_ref() == Some(head) => match p {
+            Pattern::Constructor(_, args) => args.clone(),
+            Pattern::Newtype(_, p) => vec![(**p).clone()],
+            _ => Vec::new(),
+        },
+        _ => return None,
+    };
+    specialized.extend_from_slice(rest);
+    Some(specialized)
+}
+
+/// Keep only the rows whose first pattern matches anything, dropping it.
+fn default(rows: &[Vec<Pattern>]) -> Vec<Vec<Pattern>> {
+    rows.iter()
+        .filter(|row| matches!(row.first(), Some(Pattern::Var(_) | Pattern::Wildcard)))
+        .map(|row| row[1..].to_vec())
+        .collect()
+}
+
+/// Find the values matched by `row` but by none of `rows`, where each column
+/// matches values of the corresponding type in `types`. The values are
+/// returned as patterns, with one pattern per column. If the result is empty
+/// then `row` is not useful.
+fn useful(
+    state: &State,
+    rows: &[Vec<Pattern>],
+    row: &[Pattern],
+    types: &[Type],
+) -> Result<Vec<Vec<Pattern>>, Error> {
+    let (first, rest) = match row.split_first() {
+        Some(split) => split,
+        None if rows.is_empty() => return Ok(vec![Vec::new()]),
+        None => return Ok(Vec::new()),
+    };
+
+    match Head::of(first) {
+        Some(head) => {
+            let specialized = specialize_row(row, &head).expect("row starts with head");
+            useful_with_head(state, rows, &specialized, types, &head)
+        }
+        None => {
+            let mut used = Vec::new();
+            for h in rows.iter().filter_map(|r| r.first().and_then(Head::of)) {
+                if !used.contains(&h) {
+                    used.push(h);
+                }
+            }
+
+            let sig = signature(state, &types[0])?;
+            let complete = sig
+                .as_ref()
+                .is_some_and(|sig| sig.iter().all(|h| used.contains(h)));
+            // If the heads used by `rows` don't cover the type then any value
+            // with some other head is missing, as long as the rest of the row
+            // is useful with respect to the rows which match anything here.
+            let rest_witnesses = if complete {
+                Vec::new()
+            } else {
+                useful(state, &default(rows), rest, &types[1..])?
+            };
+            let missing = |head: Pattern| {
+                rest_witnesses.iter().map(move |w| {
+                    let mut full = vec![head.clone()];
+                    full.extend_from_slice(w);
+                    full
+                })
+            };
+
+            if used.is_empty() {
+                return Ok(missing(Pattern::Wildcard).collect());
+            }
+
+            let mut witnesses = Vec::new();
+            for head in sig.as_ref().unwrap_or(&used) {
+                if used.contains(head) {
+                    // Values with a used head may still be missing if their
+                    // sub-patterns aren't covered.
+                    let specialized = specialize_row(row, head).expect("row starts with _");
+                    witnesses.extend(useful_with_head(state, rows, &specialized, types, head)?);
+                } else {
+                    witnesses.extend(missing(
+                        head.to_pattern(vec![Pattern::Wildcard; head.arity()]),
+                    ));
+                }
+            }
+            if sig.is_none() {
+                witnesses.extend(missing(Pattern::Wildcard));
+            }
+            Ok(witnesses)
+        }
+    }
+}
+
+/// `useful` for values with the given `head`, where `row` has already been
+/// specialized to it.
+fn useful_with_head(
+    state: &State,
+    rows: &[Vec<Pattern>],
+    row: &[Pattern],
+    types: &[Type],
+    head: &Head,
+) -> Result<Vec<Vec<Pattern>>, Error> {
+    let mut sub_types = head.field_types(state, &types[0])?;
+    sub_types.extend_from_slice(&types[1..]);
+
+    let witnesses = useful(state, &specialize(rows, head), row, &sub_types)?;
+    Ok(witnesses
+        .into_iter()
+        .map(|mut w| {
+            let rest = w.split_off(head.arity());
+            let mut rebuilt = vec![head.to_pattern(w)];
+            rebuilt.extend(rest);
+            rebuilt
+        })
+        .collect())
+}
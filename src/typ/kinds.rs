@@ -0,0 +1,259 @@
+use std::{collections::HashMap, fmt};
+
+use crate::{ast::DataDecl, interner};
+
+use super::{Error, Type};
+
+/// The kind of a type: `*` for the types of values, or `k1 -> k2` for type
+/// constructors, which make a type of kind `k2` when applied to one of kind
+/// `k1`. E.g. `List` has kind `* -> *`.
+#[derive(Clone, PartialEq, Eq)]
+pub enum Kind {
+    Type,
+    Arrow(Box<Kind>, Box<Kind>),
+    /// A kind which is still being inferred. Shown as `k`, `k1`, `k2`, ...
+    Unknown(usize),
+}
+
+impl Kind {
+    fn arrow(from: Kind, to: Kind) -> Self {
+        Kind::Arrow(Box::new(from), Box::new(to))
+    }
+
+    /// The kind of a type constructor which makes a type of kind `result`
+    /// from types of kinds `params`.
+    fn constructor(params: Vec<Kind>, result: Kind) -> Self {
+        params
+            .into_iter()
+            .rev()
+            .fold(result, |to, from| Kind::arrow(from, to))
+    }
+
+    /// Renumber the unknowns in `self` in the order they're first seen,
+    /// sharing `seen` with any other kinds they should be consistent with.
+    pub(crate) fn normalize(&self, seen: &mut Vec<usize>) -> Self {
+        match self {
+            Kind::Type => Kind::Type,
+            Kind::Arrow(from, to) => {
+                let from = from.normalize(seen);
+                Kind::arrow(from, to.normalize(seen))
+            }
+            Kind::Unknown(u) => match seen.iter().position(|s| s == u) {
+                Some(idx) => Kind::Unknown(idx),
+                None => {
+                    seen.push(*u);
+                    Kind::Unknown(seen.len() - 1)
+                }
+            },
+        }
+    }
+}
+
+impl fmt::Display for Kind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Kind::Type => write!(f, "*"),
+            Kind::Arrow(from, to) => match **from {
+                Kind::Arrow(..) => write!(f, "({from}) -> {to}"),
+                _ => write!(f, "{from} -> {to}"),
+            },
+            Kind::Unknown(0) => write!(f, "k"),
+            Kind::Unknown(n) => write!(f, "k{n}"),
+        }
+    }
+}
+
+impl fmt::Debug for Kind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{self}")
+    }
+}
+
+/// Checks that type constructors are only applied to the right number &
+/// kinds of types, inferring the kinds of declared types' parameters from how
+/// their variants use them.
+#[derive(Default)]
+pub(crate) struct KindChecker {
+    /// The kind of each type declared so far, by name.
+    types: HashMap<interner::Id, Kind>,
+    /// The kinds of the parameters of the declaration being checked.
+    params: Vec<(interner::Id, Kind)>,
+    /// The solution of each `Kind::Unknown` so far, if it has one.
+    solutions: Vec<Option<Kind>>,
+}
+
+impl KindChecker {
+    /// Record the kind of the type `decl` declares. Parameters which aren't
+    /// used in a way which requires anything else have kind `*`.
+    pub(crate) fn declare(&mut self, decl: &DataDecl) -> Result<(), Error> {
+        self.solutions.clear();
+        self.params = decl.params.iter().map(|p| (*p, self.fresh())).collect();
+        let params = self.params.iter().map(|(_, k)| k.clone()).collect();
+        let kind = Kind::constructor(params, Kind::Type);
+        // The type may refer to itself.
+        self.types.insert(decl.name, kind.clone());
+        let res = decl
+            .variants
+            .iter()
+            .flat_map(|v| v.fields.iter())
+            .try_for_each(|t| self.check_kind(t, &Kind::Type));
+        let kind = self.default(&kind);
+        self.types.insert(decl.name, kind);
+        self.params.clear();
+        res
+    }
+
+    /// Record that `name` is a type without any parameters.
+    pub(crate) fn declare_type(&mut self, name: interner::Id) {
+        self.types.insert(name, Kind::Type);
+    }
+
+    /// Check that `t` is the type of some values, i.e. that it has kind `*`.
+    pub(crate) fn check(&mut self, t: &Type) -> Result<(), Error> {
+        self.solutions.clear();
+        self.check_kind(t, &Kind::Type)
+    }
+
+    fn check_kind(&mut self, t: &Type, expected: &Kind) -> Result<(), Error> {
+        let (head, args) = match t {
+            Type::Named(n, args) => (Type::Named(*n, Vec::new()), args.as_slice()),
+            Type::App(head, args) => ((**head).clone(), args.as_slice()),
+            Type::Arrow(from, to, _) => {
+                self.check_kind(from, &Kind::Type)?;
+                self.check_kind(to, &Kind::Type)?;
+                return self.unify(t, expected, &Kind::Type);
+            }
+            Type::Record(row) => {
+                for (_, t) in row.fields() {
+                    self.check_kind(t, &Kind::Type)?;
+                }
+                return self.unify(t, expected, &Kind::Type);
+            }
+            Type::Dyn | Type::Error | Type::Existential(_) | Type::Primitive(_) => {
+                return self.unify(t, expected, &Kind::Type)
+            }
+            Type::Var(v) => {
+                let got = self
+                    .params
+                    .iter()
+                    .find_map(|(p, k)| (p == v).then(|| k.clone()))
+                    .ok_or_else(|| Error::IllFormedType(t.clone()))?;
+                return self.unify(t, expected, &got);
+            }
+        };
+        let head_kind = self.kind_of_head(&head)?;
+        let mut kind = head_kind.clone();
+        for arg in args {
+            kind = match self.resolve(&kind) {
+                Kind::Arrow(param, result) => {
+                    self.check_kind(arg, &param)?;
+                    *result
+                }
+                Kind::Unknown(u) => {
+                    let (param, result) = (self.fresh(), self.fresh());
+                    self.solutions[u] = Some(Kind::arrow(param.clone(), result.clone()));
+                    self.check_kind(arg, &param)?;
+                    result
+                }
+                // The head is applied to more types than it takes.
+                Kind::Type => {
+                    let mut arg_kinds = Vec::new();
+                    for arg in args {
+                        let k = self.fresh();
+                        self.check_kind(arg, &k)?;
+                        arg_kinds.push(self.zonk(&k));
+                    }
+                    return Err(Error::KindMismatch {
+                        typ: head,
+                        expected: Kind::constructor(arg_kinds, self.zonk(expected)),
+                        got: self.zonk(&head_kind),
+                    });
+                }
+            };
+        }
+        self.unify(t, expected, &kind)
+    }
+
+    /// The kind of `head`, which a type is being applied to.
+    fn kind_of_head(&mut self, head: &Type) -> Result<Kind, Error> {
+        match head {
+            Type::Named(n, args) if args.is_empty() => self
+                .types
+                .get(n)
+                .cloned()
+                .ok_or_else(|| Error::Internal(format!("undeclared type {}", n.name()))),
+            _ => {
+                let k = self.fresh();
+                self.check_kind(head, &k)?;
+                Ok(k)
+            }
+        }
+    }
+
+    /// Make `expected` & `got`, the kind of `t`, equal.
+    fn unify(&mut self, t: &Type, expected: &Kind, got: &Kind) -> Result<(), Error> {
+        match (self.resolve(expected), self.resolve(got)) {
+            (Kind::Type, Kind::Type) => Ok(()),
+            (Kind::Unknown(u1), Kind::Unknown(u2)) if u1 == u2 => Ok(()),
+            (Kind::Unknown(u), k) | (k, Kind::Unknown(u)) if !self.occurs(u, &k) => {
+                self.solutions[u] = Some(k);
+                Ok(())
+            }
+            (Kind::Arrow(from1, to1), Kind::Arrow(from2, to2)) => self
+                .unify(t, &from1, &from2)
+                .and_then(|()| self.unify(t, &to1, &to2))
+                .map_err(|_| self.mismatch(t, expected, got)),
+            _ => Err(self.mismatch(t, expected, got)),
+        }
+    }
+
+    fn mismatch(&self, t: &Type, expected: &Kind, got: &Kind) -> Error {
+        Error::KindMismatch {
+            typ: t.clone(),
+            expected: self.zonk(expected),
+            got: self.zonk(got),
+        }
+    }
+
+    fn fresh(&mut self) -> Kind {
+        self.solutions.push(None);
+        Kind::Unknown(self.solutions.len() - 1)
+    }
+
+    /// `k`, or what it's been solved as if it's unknown.
+    fn resolve(&self, k: &Kind) -> Kind {
+        match k {
+            Kind::Unknown(u) => match &self.solutions[*u] {
+                Some(solution) => self.resolve(solution),
+                None => k.clone(),
+            },
+            _ => k.clone(),
+        }
+    }
+
+    /// `k` with every solved unknown in it replaced by its solution.
+    fn zonk(&self, k: &Kind) -> Kind {
+        match self.resolve(k) {
+            Kind::Arrow(from, to) => Kind::arrow(self.zonk(&from), self.zonk(&to)),
+            k => k,
+        }
+    }
+
+    /// `zonk`, but with any unknowns which are left as `*`.
+    fn default(&self, k: &Kind) -> Kind {
+        match self.resolve(k) {
+            Kind::Arrow(from, to) => Kind::arrow(self.default(&from), self.default(&to)),
+            _ => Kind::Type,
+        }
+    }
+
+    /// Whether the unknown `u` appears in `k`, in which case solving it as `k`
+    /// would make an infinite kind.
+    fn occurs(&self, u: usize, k: &Kind) -> bool {
+        match self.resolve(k) {
+            Kind::Unknown(v) => u == v,
+            Kind::Arrow(from, to) => self.occurs(u, &from) || self.occurs(u, &to),
+            Kind::Type => false,
+        }
+    }
+}
@@ -0,0 +1,190 @@
+use std::{cmp::Ordering, fmt};
+
+use crate::interner;
+
+use super::Existential;
+
+/// A unit of measure, such as `kg m/s^2`, which a `Num` can be annotated with.
+/// Measures are products of factors raised to integer powers, so they form an
+/// abelian group whose identity is the dimensionless measure.
+#[derive(Clone, Default, PartialEq, Eq)]
+pub struct Measure {
+    /// Sorted, without any repeated factors or zero powers, so that equal
+    /// measures have equal representations.
+    factors: Vec<(Factor, i64)>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Factor {
+    /// A unit introduced by a `unit` declaration, such as `m`.
+    Unit(interner::Id),
+    /// A unit variable, such as `'u`, which stands for some particular unit
+    /// while the expression it's written in is checked.
+    Var(interner::Id),
+    /// A unit variable which a `let` has generalized over, so that each use
+    /// of the variable it binds may be at a different unit.
+    Generic(interner::Id),
+    /// A unit which is still to be inferred.
+    Existential(Existential),
+}
+
+impl Factor {
+    fn order(&self, other: &Factor) -> Ordering {
+        let rank = |f: &Factor| match f {
+            Factor::Unit(_) => 0,
+            Factor::Var(_) => 1,
+            Factor::Generic(_) => 2,
+            Factor::Existential(_) => 3,
+        };
+        match (self, other) {
+            (Factor::Unit(l), Factor::Unit(r))
+            | (Factor::Var(l), Factor::Var(r))
+            | (Factor::Generic(l), Factor::Generic(r)) => l.name().cmp(r.name()),
+            (Factor::Existential(l), Factor::Existential(r)) => l.0.cmp(&r.0),
+            (l, r) => rank(l).cmp(&rank(r)),
+        }
+    }
+}
+
+impl fmt::Display for Factor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Factor::Unit(u) => write!(f, "{}", u.name()),
+            Factor::Var(v) | Factor::Generic(v) => write!(f, "'{}", v.name()),
+            Factor::Existential(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl Measure {
+    /// The dimensionless measure.
+    pub fn one() -> Self {
+        Self::default()
+    }
+
+    pub fn factor(f: Factor) -> Self {
+        Self {
+            factors: vec![(f, 1)],
+        }
+    }
+
+    /// The product of `factors`, each raised to its power.
+    pub(crate) fn from_powers(factors: impl IntoIterator<Item = (Factor, i64)>) -> Self {
+        let mut sorted: Vec<(Factor, i64)> = Vec::new();
+        for (f, power) in factors {
+            match sorted.iter_mut().find(|(g, _)| *g == f) {
+                Some((_, p)) => *p += power,
+                None => sorted.push((f, power)),
+            }
+        }
+        sorted.retain(|(_, power)| *power != 0);
+        sorted.sort_by(|(l, _), (r, _)| l.order(r));
+        Self { factors: sorted }
+    }
+
+    pub fn is_one(&self) -> bool {
+        self.factors.is_empty()
+    }
+
+    pub fn factors(&self) -> &[(Factor, i64)] {
+        &self.factors
+    }
+
+    /// The existentials in the measure, along with their powers.
+    pub(crate) fn existentials(&self) -> impl Iterator<Item = (Existential, i64)> + '_ {
+        self.factors.iter().filter_map(|(f, power)| match f {
+            Factor::Existential(e) => Some((*e, *power)),
+            _ => None,
+        })
+    }
+
+    pub fn mul(&self, other: &Measure) -> Measure {
+        Self::from_powers(self.factors.iter().chain(&other.factors).copied())
+    }
+
+    pub fn div(&self, other: &Measure) -> Measure {
+        self.mul(&other.pow(-1))
+    }
+
+    pub fn pow(&self, n: i64) -> Measure {
+        Self::from_powers(self.factors.iter().map(|(f, power)| (*f, power * n)))
+    }
+
+    /// Replace each factor for which `replace` returns `Some` measure with
+    /// that measure, raised to the factor's power.
+    pub(crate) fn substitute(&self, replace: &mut impl FnMut(Factor) -> Option<Measure>) -> Self {
+        let mut res = Measure::one();
+        for (f, power) in &self.factors {
+            let m = replace(*f).unwrap_or_else(|| Measure::factor(*f));
+            res = res.mul(&m.pow(*power));
+        }
+        res
+    }
+}
+
+/// Shows the factors with positive powers, followed by those with negative
+/// powers after a `/`, e.g. `kg m/s^2`.
+impl fmt::Display for Measure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let write_factors = |f: &mut fmt::Formatter<'_>, factors: &[(Factor, i64)]| {
+            for (idx, (factor, power)) in factors.iter().enumerate() {
+                let sep = if idx == 0 { "" } else { " " };
+                match power {
+                    1 => write!(f, "{sep}{factor}")?,
+                    _ => write!(f, "{sep}{factor}^{power}")?,
+                }
+            }
+            Ok(())
+        };
+        let (positive, negative): (Vec<_>, Vec<_>) =
+            self.factors.iter().partition(|(_, power)| *power > 0);
+        if positive.is_empty() {
+            return match negative.is_empty() {
+                true => write!(f, "1"),
+                false => write_factors(f, &negative),
+            };
+        }
+        write_factors(f, &positive)?;
+        if !negative.is_empty() {
+            let inverted: Vec<_> = negative.iter().map(|(g, power)| (*g, -power)).collect();
+            write!(f, "/")?;
+            write_factors(f, &inverted)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for Measure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Measure({self})")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use quickcheck::quickcheck;
+
+    /// A measure made of the units `u0` to `u3`, raised to the given powers.
+    fn measure(powers: &[(u8, i8)]) -> Measure {
+        Measure::from_powers(powers.iter().map(|(u, power)| {
+            let unit = interner::Id::new(format!("u{}", u % 4));
+            (Factor::Unit(unit), i64::from(*power))
+        }))
+    }
+
+    quickcheck! {
+        fn measures_form_an_abelian_group(a: Vec<(u8, i8)>, b: Vec<(u8, i8)>) -> bool {
+            let (a, b) = (measure(&a), measure(&b));
+            a.mul(&b) == b.mul(&a) && a.mul(&b).div(&b) == a && a.div(&a).is_one()
+        }
+    }
+
+    quickcheck! {
+        fn order_of_factors_does_not_matter(a: Vec<(u8, i8)>) -> bool {
+            let mut reversed = a.clone();
+            reversed.reverse();
+            measure(&a) == measure(&reversed)
+        }
+    }
+}
@@ -1,87 +1,869 @@
 use std::fmt;
 
-use crate::{ast::Expr, error, interner};
+use crate::{
+    ast::{Class, Expr, Pattern, TypedExpr},
+    error, interner,
+};
 
 use self::ctx::Ctx;
 
+mod cast;
 mod checker;
 mod ctx;
+mod effects;
+mod elaborate;
+mod exhaustiveness;
+mod kinds;
+mod measure;
+mod overload;
+mod trace;
+
+pub use cast::{Cast, Casts};
+pub use effects::{EffectVar, Effects};
+pub use kinds::Kind;
+pub use measure::{Factor, Measure};
+pub use overload::{InstanceKey, Overload, Overloads};
+pub use trace::{Derivation, Explanation, Rule};
 
 /// Infer the `Type` of `e`, or return an appropriate `Error`.
 pub fn infer(e: &Expr) -> Result<Type, Error> {
-    let (t, ctx) = checker::synthesize_type(&mut checker::State::new(), Ctx::new(), e)?;
+    infer_with(&mut checker::State::new(e, false), e)
+        .map(|(t, _)| t.normalize())
+        .map_err(Error::normalize)
+}
+
+/// Infer the `Type` of `e` like `infer`, recording each step the typechecker
+/// takes along the way. The existentials in the
+/// derivations are named by when they were created, rather than normalized.
+pub fn explain(e: &Expr) -> Explanation {
+    let mut state = checker::State::new(e, false);
+    state.record_trace();
+    let result = infer_with(&mut state, e)
+        .map(|(t, _)| t.normalize())
+        .map_err(Error::normalize);
+    Explanation {
+        steps: state.take_trace(),
+        result,
+    }
+}
+
+/// Infer the type of every node in `e`, which are all named consistently.
+pub fn infer_typed(e: &Expr) -> Result<TypedExpr, Error> {
+    let mut state = checker::State::new(e, false);
+    state.record_types();
+    let (_, ctx) = infer_with(&mut state, e).map_err(Error::normalize)?;
+    let ctx = state.restore_dropped(ctx);
+    let types = state.take_types();
+    Ok(elaborate::elaborate(
+        e,
+        &types,
+        &ctx,
+        &mut Normalizer::default(),
+    ))
+}
+
+/// Typecheck `e` like `infer`, returning the casts its `Dyn` values need when
+/// it's evaluated, and how its overloaded operations are resolved.
+pub fn infer_casts(e: &Expr) -> Result<(Casts, Overloads), Error> {
+    let mut state = checker::State::new(e, false);
+    state.record_casts();
+    state.record_overloads();
+    let (_, ctx) = infer_with(&mut state, e).map_err(Error::normalize)?;
+    take_runtime_info(&mut state, ctx)
+}
+
+/// Typecheck `e` like `infer_casts`, but also make sure that evaluating it
+/// can't perform any effects, and nor can any function it evaluates to.
+pub fn infer_pure_casts(e: &Expr) -> Result<(Casts, Overloads), Error> {
+    let mut state = checker::State::new(e, false);
+    state.record_casts();
+    state.record_overloads();
+    state.require_pure();
+    let (_, ctx) = infer_with(&mut state, e).map_err(Error::normalize)?;
+    take_runtime_info(&mut state, ctx)
+}
+
+/// Take the casts & overloads recorded while checking, given the final `ctx`.
+fn take_runtime_info(state: &mut checker::State, ctx: Ctx) -> Result<(Casts, Overloads), Error> {
+    let ctx = state.restore_dropped(ctx);
+    let casts = state.take_casts(&ctx);
+    let overloads = state.take_overloads(&ctx).map_err(Error::normalize)?;
+    Ok((casts, overloads))
+}
+
+/// Infer the `Type` of `e`, returning it along with the final `Ctx`.
+fn infer_with(state: &mut checker::State, e: &Expr) -> Result<(Type, Ctx), Error> {
+    let (t, ctx) = checker::synthesize_type(state, Ctx::new(), e)?;
+    checker::check_constraints(state, &ctx)?;
+    let ctx = checker::default_constraints(state, ctx)?;
     let t = t.apply(&ctx);
-    Ok(t)
+    let ctx = checker::check_pure(state, ctx, &t)?;
+    Ok((t, ctx))
+}
+
+/// Infer the `Type` of `e`, carrying on past errors rather than stopping at
+/// the first. Returns every error found, along with the location it was found
+/// at, ordered by location.
+pub fn infer_all(e: &Expr) -> Result<Type, Vec<(usize, Error)>> {
+    let mut state = checker::State::new(e, true);
+    let res = checker::synthesize_type(&mut state, Ctx::new(), e).and_then(|(t, ctx)| {
+        checker::check_constraints(&mut state, &ctx)?;
+        let ctx = checker::default_constraints(&mut state, ctx)?;
+        Ok(t.apply(&ctx))
+    });
+    let mut errors = state.take_errors();
+    if let Err(err) = &res {
+        errors.push((state.location(), err.clone()));
+    }
+    let mut errors: Vec<_> = errors
+        .into_iter()
+        .map(|(location, e)| (location, e.normalize()))
+        .collect();
+    errors.sort_by_key(|(location, _)| *location);
+    errors.dedup();
+    match res {
+        Ok(t) if errors.is_empty() => Ok(t.normalize()),
+        _ => Err(errors),
+    }
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub enum Error {
+    DuplicateField(interner::Id),
     IllFormedType(Type),
-    Mismatch { got: Type, expected: Type },
+    /// Solving `var` as `ty` would need an infinite type, since `ty` contains
+    /// `var`, e.g. when a function is applied to itself.
+    InfiniteType {
+        var: Existential,
+        ty: Type,
+    },
+    Mismatch {
+        got: Type,
+        expected: Type,
+    },
+    /// A function which performs `effect` was called where only the effects
+    /// in `allowed` may be performed.
+    DisallowedEffect {
+        effect: interner::Id,
+        allowed: Effects,
+    },
+    /// `typ` has kind `got` but is used where a type of kind `expected` is
+    /// needed, e.g. `Tree Num Num` for a `Tree` with one parameter.
+    KindMismatch {
+        typ: Type,
+        expected: Kind,
+        got: Kind,
+    },
+    MissingField {
+        field: interner::Id,
+        typ: Type,
+    },
+    /// Values of the type can't be compared, but need to be, e.g. because
+    /// they're used as the keys of a `Map` or on either side of `==`.
+    NotComparable(Type),
+    /// There's no instance of `class` for `typ` in scope.
+    NoInstance {
+        class: Class,
+        typ: Type,
+    },
+    /// The user-declared instance of `class` for the newtype `typ` is needed
+    /// for its values within others which are compared, ordered or shown
+    /// structurally, where newtypes are erased so it can't be found.
+    NewtypeInstanceWithin {
+        class: Class,
+        typ: Type,
+    },
+    NonExhaustive {
+        missing: Vec<Pattern>,
+    },
+    /// An instance of `class` defines `method`, which the class doesn't
+    /// declare.
+    UnknownMethod {
+        class: Class,
+        method: interner::Id,
+    },
+    PatternArity {
+        constructor: interner::Id,
+        expected: usize,
+        got: usize,
+    },
+    RedundantPattern(Pattern),
     InvalidApplication(Type),
+    InvalidPropagation(Type),
+    PropagationOutsideFunction,
+    /// The variable is bound by `let rec` to something other than a function,
+    /// whose value would depend on itself.
+    RecursiveNonFunction(interner::Id),
+    /// The type `ident` is declared locally but appears in `typ`, which is
+    /// used outside of its scope, e.g. as the type of the scope's result.
+    TypeEscapes {
+        ident: interner::Id,
+        typ: Type,
+    },
     UnboundVariable(interner::Id),
     Internal(String),
 }
 
+impl Error {
+    /// Rename the existentials in the types of `self` to `a`, `b`, `c`, ...,
+    /// and any unknown kinds to `k`, `k1`, `k2`, ..., consistently across all
+    /// of them.
+    pub(crate) fn normalize(self) -> Self {
+        let mut n = Normalizer::default();
+        match self {
+            Error::IllFormedType(t) => Error::IllFormedType(n.typ(&t)),
+            Error::InfiniteType { var, ty } => Error::InfiniteType {
+                var: n.existential(var),
+                ty: n.typ(&ty),
+            },
+            Error::Mismatch { got, expected } => Error::Mismatch {
+                got: n.typ(&got),
+                expected: n.typ(&expected),
+            },
+            Error::DisallowedEffect { effect, allowed } => Error::DisallowedEffect {
+                effect,
+                allowed: n.effects(&allowed),
+            },
+            Error::KindMismatch { typ, expected, got } => {
+                let mut seen = Vec::new();
+                Error::KindMismatch {
+                    typ: n.typ(&typ),
+                    expected: expected.normalize(&mut seen),
+                    got: got.normalize(&mut seen),
+                }
+            }
+            Error::MissingField { field, typ } => Error::MissingField {
+                field,
+                typ: n.typ(&typ),
+            },
+            Error::NotComparable(t) => Error::NotComparable(n.typ(&t)),
+            Error::NoInstance { class, typ } => Error::NoInstance {
+                class,
+                typ: n.typ(&typ),
+            },
+            Error::NewtypeInstanceWithin { class, typ } => Error::NewtypeInstanceWithin {
+                class,
+                typ: n.typ(&typ),
+            },
+            Error::InvalidApplication(t) => Error::InvalidApplication(n.typ(&t)),
+            Error::InvalidPropagation(t) => Error::InvalidPropagation(n.typ(&t)),
+            Error::TypeEscapes { ident, typ } => Error::TypeEscapes {
+                ident,
+                typ: n.typ(&typ),
+            },
+            e @ (Error::DuplicateField(_)
+            | Error::NonExhaustive { .. }
+            | Error::PatternArity { .. }
+            | Error::RedundantPattern(_)
+            | Error::PropagationOutsideFunction
+            | Error::RecursiveNonFunction(_)
+            | Error::UnknownMethod { .. }
+            | Error::UnboundVariable(_)
+            | Error::Internal(_)) => e,
+        }
+    }
+}
+
 impl From<Error> for error::Error {
     fn from(e: Error) -> Self {
         error::Error::TypeError(e)
     }
 }
 
-#[derive(Clone, Debug, PartialEq, Eq)]
+#[derive(Clone, PartialEq, Eq)]
 pub enum Type {
-    Arrow(Box<Type>, Box<Type>),
+    /// A type constructor which isn't known by name, such as a parameter of
+    /// kind `* -> *`, applied to its type arguments.
+    App(Box<Type>, Vec<Type>),
+    /// The type of functions, along with the effects they perform when
+    /// they're called.
+    Arrow(Box<Type>, Box<Type>, Effects),
+    /// The type of values whose types are only checked at runtime, which is
+    /// consistent with every other type.
+    Dyn,
+    /// The type of an expression which failed to typecheck, which is
+    /// compatible with every type so that checking can carry on past it.
+    Error,
     Existential(Existential),
+    /// A nominal type, introduced by a `type` declaration, applied to its
+    /// type arguments.
+    Named(interner::Id, Vec<Type>),
     Primitive(Primitive),
+    Record(Row),
+    /// A type parameter of a type declaration or a built-in function. These
+    /// are always replaced by existentials before checking begins.
+    Var(interner::Id),
 }
 
 impl Type {
+    pub fn big_int() -> Self {
+        Self::Primitive(Primitive::BigInt)
+    }
+
     pub fn bool() -> Self {
         Self::Primitive(Primitive::Bool)
     }
 
+    pub fn int() -> Self {
+        Self::Primitive(Primitive::Int)
+    }
+
+    /// The type of dimensionless `Num`s.
     pub fn num() -> Self {
-        Self::Primitive(Primitive::Num)
+        Self::Primitive(Primitive::Num(Measure::one()))
+    }
+
+    pub fn measured(m: Measure) -> Self {
+        Self::Primitive(Primitive::Num(m))
+    }
+
+    pub fn rational() -> Self {
+        Self::Primitive(Primitive::Rational)
+    }
+
+    pub fn string() -> Self {
+        Self::Primitive(Primitive::String)
     }
 
     pub fn unit() -> Self {
         Self::Primitive(Primitive::Unit)
     }
+
+    /// The type of pure functions from `from` to `to`.
+    pub fn function(from: Type, to: Type) -> Self {
+        Self::Arrow(Box::new(from), Box::new(to), Effects::pure())
+    }
+
+    /// `self` applied to the type arguments `args`. Types which are known by
+    /// name stay `Named`, so that a type is only ever written one way.
+    pub(crate) fn applied_to(self, args: Vec<Type>) -> Self {
+        if args.is_empty() {
+            return self;
+        }
+        match self {
+            Type::Named(n, mut ts) => {
+                ts.extend(args);
+                Type::Named(n, ts)
+            }
+            Type::App(head, mut ts) => {
+                ts.extend(args);
+                Type::App(head, ts)
+            }
+            head => Type::App(Box::new(head), args),
+        }
+    }
+
+    /// Replace each type parameter in `self` with its type from `args`.
+    pub(crate) fn substitute(&self, args: &[(interner::Id, Type)]) -> Self {
+        match self {
+            Type::App(head, ts) => head
+                .substitute(args)
+                .applied_to(ts.iter().map(|t| t.substitute(args)).collect()),
+            Type::Arrow(from, to, effects) => Type::Arrow(
+                Box::new(from.substitute(args)),
+                Box::new(to.substitute(args)),
+                effects.clone(),
+            ),
+            Type::Named(n, ts) => Type::Named(*n, ts.iter().map(|t| t.substitute(args)).collect()),
+            Type::Record(row) => Type::Record(Row::new(
+                row.fields()
+                    .iter()
+                    .map(|(f, t)| (*f, t.substitute(args)))
+                    .collect(),
+                row.rest(),
+            )),
+            Type::Var(v) => args
+                .iter()
+                .find_map(|(p, t)| if p == v { Some(t.clone()) } else { None })
+                .unwrap_or_else(|| self.clone()),
+            Type::Dyn | Type::Error | Type::Existential(_) | Type::Primitive(_) => self.clone(),
+        }
+    }
+
+    /// Replace the factors of each measure in `self` for which `replace`
+    /// returns `Some` measure.
+    pub(crate) fn substitute_measures(
+        &self,
+        replace: &mut impl FnMut(Factor) -> Option<Measure>,
+    ) -> Self {
+        match self {
+            Type::Arrow(from, to, effects) => Type::Arrow(
+                Box::new(from.substitute_measures(replace)),
+                Box::new(to.substitute_measures(replace)),
+                effects.clone(),
+            ),
+            Type::App(head, ts) => Type::App(
+                Box::new(head.substitute_measures(replace)),
+                ts.iter().map(|t| t.substitute_measures(replace)).collect(),
+            ),
+            Type::Named(n, ts) => Type::Named(
+                *n,
+                ts.iter().map(|t| t.substitute_measures(replace)).collect(),
+            ),
+            Type::Primitive(Primitive::Num(m)) => Type::measured(m.substitute(replace)),
+            Type::Record(row) => Type::Record(Row::new(
+                row.fields()
+                    .iter()
+                    .map(|(f, t)| (*f, t.substitute_measures(replace)))
+                    .collect(),
+                row.rest(),
+            )),
+            Type::Dyn | Type::Error | Type::Existential(_) | Type::Primitive(_) | Type::Var(_) => {
+                self.clone()
+            }
+        }
+    }
+
+    /// Replace the rest of the effects of each function in `self` for which
+    /// `replace` returns `Some` effects.
+    pub(crate) fn substitute_effects(
+        &self,
+        replace: &mut impl FnMut(EffectVar) -> Option<Effects>,
+    ) -> Self {
+        match self {
+            Type::Arrow(from, to, effects) => Type::Arrow(
+                Box::new(from.substitute_effects(replace)),
+                Box::new(to.substitute_effects(replace)),
+                effects.substitute(replace),
+            ),
+            Type::App(head, ts) => Type::App(
+                Box::new(head.substitute_effects(replace)),
+                ts.iter().map(|t| t.substitute_effects(replace)).collect(),
+            ),
+            Type::Named(n, ts) => Type::Named(
+                *n,
+                ts.iter().map(|t| t.substitute_effects(replace)).collect(),
+            ),
+            Type::Record(row) => Type::Record(Row::new(
+                row.fields()
+                    .iter()
+                    .map(|(f, t)| (*f, t.substitute_effects(replace)))
+                    .collect(),
+                row.rest(),
+            )),
+            Type::Dyn | Type::Error | Type::Existential(_) | Type::Primitive(_) | Type::Var(_) => {
+                self.clone()
+            }
+        }
+    }
+
+    /// Call `visit` on the effects of each function in `self`.
+    pub(crate) fn visit_effects(&self, visit: &mut impl FnMut(&Effects)) {
+        match self {
+            Type::Arrow(from, to, effects) => {
+                from.visit_effects(visit);
+                to.visit_effects(visit);
+                visit(effects);
+            }
+            Type::App(head, ts) => {
+                head.visit_effects(visit);
+                ts.iter().for_each(|t| t.visit_effects(visit));
+            }
+            Type::Named(_, ts) => ts.iter().for_each(|t| t.visit_effects(visit)),
+            Type::Record(row) => row
+                .fields()
+                .iter()
+                .for_each(|(_, t)| t.visit_effects(visit)),
+            Type::Dyn | Type::Error | Type::Existential(_) | Type::Primitive(_) | Type::Var(_) => {}
+        }
+    }
+
+    /// Replace the rest of each row in `self` for which `replace` returns
+    /// `Some` existential.
+    pub(crate) fn substitute_rows(
+        &self,
+        replace: &mut impl FnMut(Existential) -> Option<Existential>,
+    ) -> Self {
+        match self {
+            Type::Arrow(from, to, effects) => Type::Arrow(
+                Box::new(from.substitute_rows(replace)),
+                Box::new(to.substitute_rows(replace)),
+                effects.clone(),
+            ),
+            Type::App(head, ts) => Type::App(
+                Box::new(head.substitute_rows(replace)),
+                ts.iter().map(|t| t.substitute_rows(replace)).collect(),
+            ),
+            Type::Named(n, ts) => {
+                Type::Named(*n, ts.iter().map(|t| t.substitute_rows(replace)).collect())
+            }
+            Type::Record(row) => Type::Record(Row::new(
+                row.fields()
+                    .iter()
+                    .map(|(f, t)| (*f, t.substitute_rows(replace)))
+                    .collect(),
+                row.rest().map(|r| replace(r).unwrap_or(r)),
+            )),
+            Type::Dyn | Type::Error | Type::Existential(_) | Type::Primitive(_) | Type::Var(_) => {
+                self.clone()
+            }
+        }
+    }
+
+    /// Call `visit` on each row in `self`.
+    pub(crate) fn visit_rows(&self, visit: &mut impl FnMut(&Row)) {
+        match self {
+            Type::Arrow(from, to, _) => {
+                from.visit_rows(visit);
+                to.visit_rows(visit);
+            }
+            Type::App(head, ts) => {
+                head.visit_rows(visit);
+                ts.iter().for_each(|t| t.visit_rows(visit));
+            }
+            Type::Named(_, ts) => ts.iter().for_each(|t| t.visit_rows(visit)),
+            Type::Record(row) => {
+                visit(row);
+                row.fields().iter().for_each(|(_, t)| t.visit_rows(visit));
+            }
+            Type::Dyn | Type::Error | Type::Existential(_) | Type::Primitive(_) | Type::Var(_) => {}
+        }
+    }
+
+    /// The type `self` would have if none of its functions performed any
+    /// effects.
+    pub(crate) fn pure(&self) -> Self {
+        match self {
+            Type::Arrow(from, to, _) => Type::function(from.pure(), to.pure()),
+            Type::App(head, ts) => {
+                Type::App(Box::new(head.pure()), ts.iter().map(Type::pure).collect())
+            }
+            Type::Named(n, ts) => Type::Named(*n, ts.iter().map(Type::pure).collect()),
+            Type::Record(row) => Type::Record(Row::new(
+                row.fields().iter().map(|(f, t)| (*f, t.pure())).collect(),
+                row.rest(),
+            )),
+            Type::Dyn | Type::Error | Type::Existential(_) | Type::Primitive(_) | Type::Var(_) => {
+                self.clone()
+            }
+        }
+    }
+
+    /// Call `visit` on each measure in `self`.
+    pub(crate) fn visit_measures(&self, visit: &mut impl FnMut(&Measure)) {
+        match self {
+            Type::Arrow(from, to, _) => {
+                from.visit_measures(visit);
+                to.visit_measures(visit);
+            }
+            Type::App(head, ts) => {
+                head.visit_measures(visit);
+                ts.iter().for_each(|t| t.visit_measures(visit));
+            }
+            Type::Named(_, ts) => ts.iter().for_each(|t| t.visit_measures(visit)),
+            Type::Primitive(Primitive::Num(m)) => visit(m),
+            Type::Record(row) => row
+                .fields()
+                .iter()
+                .for_each(|(_, t)| t.visit_measures(visit)),
+            Type::Dyn | Type::Error | Type::Existential(_) | Type::Primitive(_) | Type::Var(_) => {}
+        }
+    }
+
+    /// Rename the existentials in `self` to `a`, `b`, `c`, ... in the order
+    /// they first appear.
+    pub fn normalize(&self) -> Self {
+        Normalizer::default().typ(self)
+    }
+
+    /// Whether the existential `a` appears anywhere in `self`.
+    pub(crate) fn occurs(&self, a: Existential) -> bool {
+        match self {
+            Type::Arrow(from, to, effects) => {
+                from.occurs(a) || to.occurs(a) || effects.existential() == Some(a)
+            }
+            Type::Existential(e) => *e == a,
+            Type::App(head, args) => head.occurs(a) || args.iter().any(|t| t.occurs(a)),
+            Type::Named(_, args) => args.iter().any(|t| t.occurs(a)),
+            Type::Primitive(Primitive::Num(m)) => m.existentials().any(|(e, _)| e == a),
+            Type::Record(row) => {
+                row.rest() == Some(a) || row.fields().iter().any(|(_, t)| t.occurs(a))
+            }
+            Type::Dyn | Type::Error | Type::Primitive(_) | Type::Var(_) => false,
+        }
+    }
+
+    /// Whether the named type `name` appears anywhere in `self`.
+    pub(crate) fn mentions(&self, name: interner::Id) -> bool {
+        match self {
+            Type::Arrow(from, to, _) => from.mentions(name) || to.mentions(name),
+            Type::App(head, args) => head.mentions(name) || args.iter().any(|t| t.mentions(name)),
+            Type::Named(n, args) => *n == name || args.iter().any(|t| t.mentions(name)),
+            Type::Record(row) => row.fields().iter().any(|(_, t)| t.mentions(name)),
+            Type::Dyn | Type::Error | Type::Existential(_) | Type::Primitive(_) | Type::Var(_) => {
+                false
+            }
+        }
+    }
+
+    /// Whether `Dyn` appears anywhere in `self`.
+    pub(crate) fn contains_dyn(&self) -> bool {
+        match self {
+            Type::Arrow(from, to, _) => from.contains_dyn() || to.contains_dyn(),
+            Type::Dyn => true,
+            Type::App(head, args) => head.contains_dyn() || args.iter().any(Type::contains_dyn),
+            Type::Named(_, args) => args.iter().any(Type::contains_dyn),
+            Type::Record(row) => row.fields().iter().any(|(_, t)| t.contains_dyn()),
+            Type::Error | Type::Existential(_) | Type::Primitive(_) | Type::Var(_) => false,
+        }
+    }
+}
+
+// Like a derived `Debug`, except that names are shown as they're written, so
+// that e.g. errors mentioning a newtype show which one it is.
+impl fmt::Debug for Type {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Type::App(head, args) => f.debug_tuple("App").field(head).field(args).finish(),
+            Type::Arrow(t1, t2, effects) => f
+                .debug_tuple("Arrow")
+                .field(t1)
+                .field(t2)
+                .field(effects)
+                .finish(),
+            Type::Dyn => write!(f, "Dyn"),
+            Type::Error => write!(f, "Error"),
+            Type::Existential(a) => f.debug_tuple("Existential").field(a).finish(),
+            Type::Named(n, args) => f
+                .debug_tuple("Named")
+                .field(&format_args!("{}", n.name()))
+                .field(args)
+                .finish(),
+            Type::Primitive(p) => f.debug_tuple("Primitive").field(p).finish(),
+            Type::Record(row) => f.debug_tuple("Record").field(row).finish(),
+            Type::Var(v) => f
+                .debug_tuple("Var")
+                .field(&format_args!("{}", v.name()))
+                .finish(),
+        }
+    }
 }
 
 impl fmt::Display for Type {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            Type::Arrow(t1, t2) => write!(f, "{t1} -> {t2}"),
+            Type::Arrow(t1, t2, effects) => {
+                match **t1 {
+                    Type::Arrow(..) => write!(f, "({t1})")?,
+                    _ => write!(f, "{t1}")?,
+                }
+                if effects.is_pure() {
+                    write!(f, " -> {t2}")
+                } else {
+                    write!(f, " -{effects}-> {t2}")
+                }
+            }
+            Type::Dyn => write!(f, "Dyn"),
+            Type::Error => write!(f, "<error>"),
             Type::Existential(n) => write!(f, "{n}"),
+            Type::App(head, args) => {
+                match **head {
+                    Type::Arrow(..) => write!(f, "({head})")?,
+                    _ => write!(f, "{head}")?,
+                }
+                write_args(f, args)
+            }
+            Type::Named(n, args) => {
+                write!(f, "{}", n.name())?;
+                write_args(f, args)
+            }
             Type::Primitive(p) => write!(f, "{p}"),
+            Type::Record(r) => write!(f, "{r}"),
+            Type::Var(v) => write!(f, "{}", v.name()),
+        }
+    }
+}
+
+/// Write the type arguments `args` of an applied type, each after a space.
+fn write_args(f: &mut fmt::Formatter<'_>, args: &[Type]) -> fmt::Result {
+    for a in args {
+        match a {
+            Type::Arrow(..) | Type::App(..) => write!(f, " ({a})")?,
+            Type::Named(_, args) if !args.is_empty() => write!(f, " ({a})")?,
+            _ => write!(f, " {a}")?,
         }
     }
+    Ok(())
 }
 
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+/// The fields of a record type, along with an optional row variable standing
+/// for any other fields the record might have.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Row {
+    /// The known fields, kept sorted by name.
+    fields: Vec<(interner::Id, Type)>,
+    /// The rest of the row, if it is open.
+    rest: Option<Existential>,
+}
+
+impl Row {
+    /// Construct a new `Row`. Duplicated fields are kept, so that they can be
+    /// reported when the type is checked for well-formedness.
+    pub fn new(fields: Vec<(interner::Id, Type)>, rest: Option<Existential>) -> Self {
+        let mut fields = fields;
+        fields.sort_by(|(l, _), (r, _)| l.name().cmp(r.name()));
+        Row { fields, rest }
+    }
+
+    /// The closed row with no fields.
+    pub fn empty() -> Self {
+        Row {
+            fields: Vec::new(),
+            rest: None,
+        }
+    }
+
+    pub fn fields(&self) -> &[(interner::Id, Type)] {
+        &self.fields
+    }
+
+    pub fn rest(&self) -> Option<Existential> {
+        self.rest
+    }
+
+    /// Get the first field name which appears more than once, if any.
+    pub fn duplicate_field(&self) -> Option<interner::Id> {
+        self.fields
+            .windows(2)
+            .find(|w| w[0].0 == w[1].0)
+            .map(|w| w[0].0)
+    }
+
+    /// Get the type of `field`, if the row is known to contain it.
+    pub fn get(&self, field: interner::Id) -> Option<&Type> {
+        self.fields
+            .iter()
+            .find_map(|(f, t)| if *f == field { Some(t) } else { None })
+    }
+}
+
+impl fmt::Display for Row {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.fields.is_empty() && self.rest.is_none() {
+            return write!(f, "{{}}");
+        }
+        write!(f, "{{")?;
+        for (idx, (field, t)) in self.fields.iter().enumerate() {
+            let sep = if idx == 0 { " " } else { ", " };
+            write!(f, "{sep}{}: {t}", field.name())?;
+        }
+        if let Some(r) = self.rest {
+            write!(f, " | {r}")?;
+        }
+        write!(f, " }}")
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
 pub struct Existential(usize);
 
 impl fmt::Display for Existential {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "?{}", self.0)
+        let letter = char::from(b'a' + (self.0 % 26) as u8);
+        match self.0 / 26 {
+            0 => write!(f, "{letter}"),
+            n => write!(f, "{letter}{n}"),
+        }
     }
 }
 
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+impl fmt::Debug for Existential {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Existential({self})")
+    }
+}
+
+/// Renumbers existentials from zero in the order they're first seen.
+#[derive(Default)]
+struct Normalizer {
+    seen: Vec<Existential>,
+}
+
+impl Normalizer {
+    fn existential(&mut self, e: Existential) -> Existential {
+        match self.seen.iter().position(|s| *s == e) {
+            Some(idx) => Existential(idx),
+            None => {
+                self.seen.push(e);
+                Existential(self.seen.len() - 1)
+            }
+        }
+    }
+
+    /// Unknown effects are left out, since they're none unless something
+    /// turns out to perform them.
+    fn effects(&mut self, effects: &Effects) -> Effects {
+        Effects::new(effects.labels().to_vec(), None)
+    }
+
+    fn typ(&mut self, t: &Type) -> Type {
+        match t {
+            Type::Arrow(from, to, effects) => {
+                let from = self.typ(from);
+                Type::Arrow(
+                    Box::new(from),
+                    Box::new(self.typ(to)),
+                    self.effects(effects),
+                )
+            }
+            Type::Existential(e) => Type::Existential(self.existential(*e)),
+            Type::App(head, args) => {
+                let head = self.typ(head);
+                head.applied_to(args.iter().map(|t| self.typ(t)).collect())
+            }
+            Type::Named(n, args) => Type::Named(*n, args.iter().map(|t| self.typ(t)).collect()),
+            Type::Primitive(Primitive::Num(m)) => Type::measured(m.substitute(&mut |f| match f {
+                Factor::Existential(e) => {
+                    Some(Measure::factor(Factor::Existential(self.existential(e))))
+                }
+                _ => None,
+            })),
+            Type::Record(row) => {
+                let fields = row
+                    .fields()
+                    .iter()
+                    .map(|(f, t)| (*f, self.typ(t)))
+                    .collect();
+                let rest = row.rest().map(|r| self.existential(r));
+                Type::Record(Row::new(fields, rest))
+            }
+            Type::Dyn | Type::Error | Type::Primitive(_) | Type::Var(_) => t.clone(),
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Eq)]
 pub enum Primitive {
+    /// Arbitrary-precision integers.
+    BigInt,
     Bool,
-    Num,
+    /// 64-bit signed integers, whose arithmetic is checked for overflow.
+    Int,
+    /// 64-bit floating point numbers, in some unit of measure.
+    Num(Measure),
+    /// Exact fractions of `BigInt`s.
+    Rational,
+    String,
     Unit,
 }
 
 impl fmt::Display for Primitive {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
+            Primitive::BigInt => write!(f, "BigInt"),
             Primitive::Bool => write!(f, "Bool"),
-            Primitive::Num => write!(f, "Num"),
+            Primitive::Int => write!(f, "Int"),
+            Primitive::Num(m) if m.is_one() => write!(f, "Num"),
+            Primitive::Num(m) => write!(f, "Num<{m}>"),
+            Primitive::Rational => write!(f, "Rational"),
+            Primitive::String => write!(f, "String"),
             Primitive::Unit => write!(f, "Unit"),
         }
     }
 }
+
+impl fmt::Debug for Primitive {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{self}")
+    }
+}
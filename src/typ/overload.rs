@@ -0,0 +1,122 @@
+use std::collections::HashMap;
+
+use crate::{
+    ast::{Class, Expr},
+    interner,
+};
+
+use super::{checker::State, Error, Type};
+
+/// A user-declared instance, by its class & the type it's for.
+pub type InstanceKey = (Class, interner::Id);
+
+/// How the overloaded operators & built-ins whose types involve user-declared
+/// instances are resolved, keyed by the address of the operator or built-in.
+/// The rest use the built-in behaviour.
+pub type Overloads = HashMap<*const Expr, Overload>;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Overload {
+    /// The type has a user-declared instance, whose method is called.
+    Method(InstanceKey),
+    /// The type is compared, ordered or shown structurally, calling the
+    /// methods of these instances for any values of their types within it.
+    Structural(Vec<InstanceKey>),
+}
+
+/// How an operation of `class` on values of type `t` is resolved, given the
+/// user-declared `instances` in scope where it happens, or `None` if it uses
+/// the built-in behaviour. Values which aren't primitives are always ordered
+/// structurally, since the built-in behaviour only orders primitives.
+pub(crate) fn resolve(
+    state: &State,
+    class: Class,
+    t: &Type,
+    instances: &[InstanceKey],
+) -> Result<Option<Overload>, Error> {
+    match t {
+        Type::Named(n, _) if instances.contains(&(class, *n)) => {
+            Ok(Some(Overload::Method((class, *n))))
+        }
+        _ if matches!(class, Class::Eq | Class::Show) => {
+            let mut found = Vec::new();
+            find_instances(state, class, t, instances, &mut Vec::new(), &mut found)?;
+            Ok((!found.is_empty()).then_some(Overload::Structural(found)))
+        }
+        Type::Primitive(_) => Ok(None),
+        _ if class == Class::Ord => {
+            let mut found = Vec::new();
+            find_instances(state, class, t, instances, &mut Vec::new(), &mut found)?;
+            Ok(Some(Overload::Structural(found)))
+        }
+        _ => Ok(None),
+    }
+}
+
+/// Add each of the `instances` of `class` which values within a value of type
+/// `t` might need to `found`. `seen` holds the data types which have already
+/// been looked inside, so that recursive types terminate.
+fn find_instances(
+    state: &State,
+    class: Class,
+    t: &Type,
+    instances: &[InstanceKey],
+    seen: &mut Vec<Type>,
+    found: &mut Vec<InstanceKey>,
+) -> Result<(), Error> {
+    match t {
+        Type::Named(n, _) if instances.contains(&(class, *n)) => {
+            if !found.contains(&(class, *n)) {
+                found.push((class, *n));
+            }
+            Ok(())
+        }
+        Type::Named(n, args) => {
+            if seen.contains(t) {
+                return Ok(());
+            }
+            seen.push(t.clone());
+            // Newtypes are erased, so their values are those of the
+            // underlying type.
+            if let Ok(underlying) = state.newtype(*n) {
+                return find_instances(state, class, underlying, instances, seen, found);
+            }
+            let ctors = state.constructors_of(*n).unwrap_or_default().to_vec();
+            let components = if ctors.is_empty() {
+                args.clone()
+            } else {
+                let mut fields = Vec::new();
+                for c in ctors {
+                    fields.extend(state.constructor_fields(c, t)?);
+                }
+                fields
+            };
+            components
+                .iter()
+                .try_for_each(|c| find_instances(state, class, c, instances, seen, found))
+        }
+        Type::Record(row) => row
+            .fields()
+            .iter()
+            .try_for_each(|(_, f)| find_instances(state, class, f, instances, seen, found)),
+        // A `Dyn` value might be of any type, except that newtypes in `Dyn`
+        // are compared, ordered & shown as their tagged underlying values.
+        Type::Dyn => {
+            for key in instances
+                .iter()
+                .filter(|(c, n)| *c == class && state.newtype(*n).is_err())
+            {
+                if !found.contains(key) {
+                    found.push(*key);
+                }
+            }
+            Ok(())
+        }
+        Type::App(..)
+        | Type::Arrow(..)
+        | Type::Error
+        | Type::Existential(_)
+        | Type::Primitive(_)
+        | Type::Var(_) => Ok(()),
+    }
+}
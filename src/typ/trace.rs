@@ -0,0 +1,216 @@
+use std::fmt::{self, Write};
+
+use serde::{Serialize, Serializer};
+
+use super::{ctx::Ctx, Error, Type};
+
+/// The outcome of typechecking a program, along with the derivation of each
+/// outermost step the typechecker took.
+#[derive(Debug, Serialize)]
+pub struct Explanation {
+    pub steps: Vec<Derivation>,
+    #[serde(serialize_with = "serialize_result")]
+    pub result: Result<Type, Error>,
+}
+
+fn serialize_result<S: Serializer>(r: &Result<Type, Error>, s: S) -> Result<S::Ok, S::Error> {
+    #[derive(Serialize)]
+    #[serde(rename_all = "snake_case")]
+    enum Outcome {
+        Type(String),
+        Error(String),
+    }
+
+    match r {
+        Ok(t) => Outcome::Type(t.to_string()),
+        Err(e) => Outcome::Error(format!("{e:?}")),
+    }
+    .serialize(s)
+}
+
+/// The kinds of step the typechecker takes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Rule {
+    Synthesize,
+    Check,
+    Subtype,
+    InstantiateL,
+    InstantiateR,
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Rule::Synthesize => write!(f, "synthesize"),
+            Rule::Check => write!(f, "check"),
+            Rule::Subtype => write!(f, "subtype"),
+            Rule::InstantiateL => write!(f, "instantiate_l"),
+            Rule::InstantiateR => write!(f, "instantiate_r"),
+        }
+    }
+}
+
+/// Steps nested more deeply than this aren't recorded, so that explaining a
+/// long program, whose steps are as deeply nested as its syntax tree, takes
+/// space in proportion to its length.
+const MAX_DEPTH: usize = 64;
+
+/// Judgements are cut short after this many bytes, since they include the
+/// expressions being checked, which may be the rest of the program.
+const MAX_JUDGEMENT: usize = 200;
+
+/// A step taken by the typechecker, along with the steps it took to make it.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Derivation {
+    pub rule: Rule,
+    /// What the step set out to show, e.g. `x <= Num` or `a <: Num`.
+    pub judgement: String,
+    /// The type synthesized, for `Rule::Synthesize` steps which succeeded.
+    pub typ: Option<String>,
+    /// The elements the step added to (`+`) or removed from (`-`) the
+    /// context, in order, if it succeeded. Solving an existential replaces
+    /// it. The whole context isn't shown at each step, since it may be as
+    /// long as the program.
+    pub changes: Option<Vec<String>>,
+    /// The error the step failed with, if it failed.
+    pub error: Option<String>,
+    pub steps: Vec<Derivation>,
+    /// The number of steps this one took which were nested too deeply to be
+    /// recorded.
+    pub elided: usize,
+}
+
+impl Derivation {
+    fn write_indented(&self, f: &mut fmt::Formatter<'_>, depth: usize) -> fmt::Result {
+        write!(
+            f,
+            "{:indent$}{}: {}",
+            "",
+            self.rule,
+            self.judgement,
+            indent = depth * 2
+        )?;
+        if let Some(t) = &self.typ {
+            write!(f, " {t}")?;
+        }
+        match (&self.changes, &self.error) {
+            (Some(changes), _) => writeln!(f, " -| [{}]", changes.join(", "))?,
+            (None, Some(e)) => writeln!(f, " failed: {e}")?,
+            (None, None) => writeln!(f)?,
+        }
+        self.steps
+            .iter()
+            .try_for_each(|s| s.write_indented(f, depth + 1))?;
+        if self.elided != 0 {
+            writeln!(
+                f,
+                "{:indent$}... {} more steps",
+                "",
+                self.elided,
+                indent = (depth + 1) * 2
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Renders the derivation as a tree, with each step on its own line and
+/// indented under the step which took it.
+impl fmt::Display for Derivation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.write_indented(f, 0)
+    }
+}
+
+/// Records the steps taken by the typechecker as they're entered & exited.
+#[derive(Default)]
+pub(crate) struct Tracer {
+    /// The steps which have been entered but not yet exited, innermost last,
+    /// along with the contexts they started with.
+    open: Vec<(Derivation, Ctx)>,
+    /// The number of steps which have been entered but not yet exited within
+    /// the innermost open one, which are nested too deeply to be recorded.
+    hidden: usize,
+    /// The outermost steps which have been exited.
+    done: Vec<Derivation>,
+}
+
+impl Tracer {
+    /// Enter a step, which sets out to show the judgement `judgement` writes,
+    /// starting with the context `input`.
+    pub(crate) fn enter(
+        &mut self,
+        rule: Rule,
+        judgement: impl FnOnce(&mut dyn Write) -> fmt::Result,
+        input: &Ctx,
+    ) {
+        if self.open.len() >= MAX_DEPTH {
+            self.hidden += 1;
+            return;
+        }
+        let mut judgement_text = Truncated(String::new());
+        // Writing fails once the judgement is cut short.
+        if judgement(&mut judgement_text).is_err() {
+            judgement_text.0.push_str("...");
+        }
+        let step = Derivation {
+            rule,
+            judgement: judgement_text.0,
+            typ: None,
+            changes: None,
+            error: None,
+            steps: Vec::new(),
+            elided: 0,
+        };
+        self.open.push((step, input.clone()));
+    }
+
+    /// Exit the innermost open step, which finished with `output`.
+    pub(crate) fn exit(&mut self, output: Result<(&Ctx, Option<&Type>), &Error>) {
+        if self.hidden != 0 {
+            self.hidden -= 1;
+            if let (0, Some((parent, _))) = (self.hidden, self.open.last_mut()) {
+                parent.elided += 1;
+            }
+            return;
+        }
+        let Some((mut step, input)) = self.open.pop() else {
+            return;
+        };
+        match output {
+            Ok((ctx, t)) => {
+                step.changes = Some(ctx.changes_since(&input));
+                step.typ = t.map(ToString::to_string);
+            }
+            Err(e) => step.error = Some(format!("{e:?}")),
+        }
+        match self.open.last_mut().map(|(parent, _)| parent) {
+            Some(parent) => parent.steps.push(step),
+            None => self.done.push(step),
+        }
+    }
+
+    pub(crate) fn finish(self) -> Vec<Derivation> {
+        self.done
+    }
+}
+
+/// A string which can't be written past `MAX_JUDGEMENT` bytes.
+struct Truncated(String);
+
+impl Write for Truncated {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let room = MAX_JUDGEMENT.saturating_sub(self.0.len());
+        if s.len() <= room {
+            self.0.push_str(s);
+            return Ok(());
+        }
+        let mut end = room;
+        while !s.is_char_boundary(end) {
+            end -= 1;
+        }
+        self.0.push_str(&s[..end]);
+        Err(fmt::Error)
+    }
+}
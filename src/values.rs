@@ -1,31 +1,104 @@
-use std::{cell::RefCell, fmt::Display};
+use std::{
+    any::TypeId, cell::RefCell, cmp::Ordering, collections::HashMap, fmt::Display, mem, ops::Deref,
+    rc::Rc,
+};
 
 use crate::{
+    bignum::{BigInt, Rational},
+    builtins,
     env::Env,
     error::EvaluationError,
+    interner,
     vm::{stack::Stack, Op},
 };
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, PartialEq)]
 pub enum Val {
+    /// An array, which is copied when it is updated while shared.
+    Array(Rc<Vec<Val>>),
+    BigInt(Rc<BigInt>),
     Bool(bool),
     Closure {
         body: Stack<Op>,
         env: Env<RefCell<Val>>,
     },
+    /// A value built by an algebraic data type's constructor.
+    Data {
+        ctor: interner::Id,
+        fields: Fields,
+    },
     Dummy,
+    Int(i64),
+    List(List),
+    Map(Map<Val>),
     Num(f64),
+    Rational(Rc<Rational>),
+    /// A record's fields, sorted by name.
+    Record(Rc<[(interner::Id, Val)]>),
+    Set(Map<()>),
+    Str(Rc<str>),
     Unit,
 }
 
+// Like a derived `Debug`, except that nested data is shown one value at a time
+// rather than recursively, so that showing a deeply nested value, such as a
+// long chain of a recursive type's constructors, can't overflow the stack.
+impl std::fmt::Debug for Val {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        enum Part<'a> {
+            Text(&'static str),
+            Val(&'a Val),
+        }
+
+        let mut pending = vec![Part::Val(self)];
+        while let Some(part) = pending.pop() {
+            let v = match part {
+                Part::Text(text) => {
+                    f.write_str(text)?;
+                    continue;
+                }
+                Part::Val(v) => v,
+            };
+            match v {
+                Val::Array(vs) => f.debug_tuple("Array").field(vs).finish()?,
+                Val::BigInt(n) => f.debug_tuple("BigInt").field(n).finish()?,
+                Val::Bool(b) => f.debug_tuple("Bool").field(b).finish()?,
+                Val::Closure { body, env } => f
+                    .debug_struct("Closure")
+                    .field("body", body)
+                    .field("env", env)
+                    .finish()?,
+                Val::Data { ctor, fields } => {
+                    write!(f, "Data {{ ctor: {ctor:?}, fields: [")?;
+                    pending.push(Part::Text("] }"));
+                    for (idx, v) in fields.iter().enumerate().rev() {
+                        pending.push(Part::Val(v));
+                        if idx != 0 {
+                            pending.push(Part::Text(", "));
+                        }
+                    }
+                }
+                Val::Dummy => f.write_str("Dummy")?,
+                Val::Int(i) => f.debug_tuple("Int").field(i).finish()?,
+                Val::List(l) => f.debug_tuple("List").field(l).finish()?,
+                Val::Map(m) => f.debug_tuple("Map").field(m).finish()?,
+                Val::Num(n) => f.debug_tuple("Num").field(n).finish()?,
+                Val::Rational(r) => f.debug_tuple("Rational").field(r).finish()?,
+                Val::Record(fields) => f.debug_tuple("Record").field(fields).finish()?,
+                Val::Set(set) => f.debug_tuple("Set").field(set).finish()?,
+                Val::Str(s) => f.debug_tuple("Str").field(s).finish()?,
+                Val::Unit => f.write_str("Unit")?,
+            }
+        }
+        Ok(())
+    }
+}
+
 impl Display for Val {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            Val::Bool(b) => write!(f, "{}", b),
-            Val::Closure { .. } => write!(f, "<function>"),
-            Val::Dummy => write!(f, "<dummy>"),
-            Val::Num(n) => write!(f, "{}", n),
-            Val::Unit => write!(f, "()"),
+        match Showing::new(self.clone()).resume(|_| false) {
+            Progress::Done(s) => write!(f, "{s}"),
+            Progress::Call(_) => unreachable!("no values are shown by calling a method"),
         }
     }
 }
@@ -41,6 +114,36 @@ impl Val {
         }
     }
 
+    pub fn as_int(self) -> Result<i64, EvaluationError> {
+        match self {
+            Val::Int(i) => Ok(i),
+            v => Err(EvaluationError::Internal(format!(
+                "expected Int, got {:?}",
+                v
+            ))),
+        }
+    }
+
+    pub fn as_big_int(self) -> Result<Rc<BigInt>, EvaluationError> {
+        match self {
+            Val::BigInt(n) => Ok(n),
+            v => Err(EvaluationError::Internal(format!(
+                "expected BigInt, got {:?}",
+                v
+            ))),
+        }
+    }
+
+    pub fn as_rational(self) -> Result<Rc<Rational>, EvaluationError> {
+        match self {
+            Val::Rational(r) => Ok(r),
+            v => Err(EvaluationError::Internal(format!(
+                "expected Rational, got {:?}",
+                v
+            ))),
+        }
+    }
+
     pub fn as_bool(self) -> Result<bool, EvaluationError> {
         match self {
             Val::Bool(b) => Ok(b),
@@ -51,13 +154,1050 @@ impl Val {
         }
     }
 
+    pub fn as_array(self) -> Result<Rc<Vec<Val>>, EvaluationError> {
+        match self {
+            Val::Array(vs) => Ok(vs),
+            v => Err(EvaluationError::Internal(format!(
+                "expected Array, got {:?}",
+                v
+            ))),
+        }
+    }
+
+    pub fn as_map(self) -> Result<Map<Val>, EvaluationError> {
+        match self {
+            Val::Map(m) => Ok(m),
+            v => Err(EvaluationError::Internal(format!(
+                "expected Map, got {:?}",
+                v
+            ))),
+        }
+    }
+
+    pub fn as_set(self) -> Result<Map<()>, EvaluationError> {
+        match self {
+            Val::Set(s) => Ok(s),
+            v => Err(EvaluationError::Internal(format!(
+                "expected Set, got {:?}",
+                v
+            ))),
+        }
+    }
+
+    /// Convert `index` to a position in an array of length `len`.
+    pub fn array_index(index: i64, len: usize) -> Result<usize, EvaluationError> {
+        match usize::try_from(index) {
+            Ok(idx) if idx < len => Ok(idx),
+            _ => Err(EvaluationError::IndexOutOfBounds { index, len }),
+        }
+    }
+
+    pub fn index(self, index: Val) -> Result<Val, EvaluationError> {
+        let array = self.as_array()?;
+        let idx = Val::array_index(index.as_int()?, array.len())?;
+        Ok(array[idx].clone())
+    }
+
+    /// Build the value which `ctor` constructs from `fields`.
+    pub fn construct(ctor: interner::Id, fields: Vec<Val>) -> Self {
+        if ctor == *builtins::NIL {
+            return Val::List(List::empty());
+        }
+        if ctor == *builtins::CONS {
+            if let [head, Val::List(tail)] = fields.as_slice() {
+                return Val::List(tail.cons(head.clone()));
+            }
+        }
+        Val::Data {
+            ctor,
+            fields: fields.into(),
+        }
+    }
+
+    pub fn record(fields: Vec<(interner::Id, Val)>) -> Self {
+        let mut fields = fields;
+        fields.sort_by(|(l, _), (r, _)| l.name().cmp(r.name()));
+        Val::Record(fields.into())
+    }
+
+    pub fn field(self, field: interner::Id) -> Result<Val, EvaluationError> {
+        match self {
+            Val::Record(fields) => fields
+                .iter()
+                .find_map(|(f, v)| if *f == field { Some(v.clone()) } else { None })
+                .ok_or_else(|| {
+                    EvaluationError::Internal(format!("missing field {}", field.name()))
+                }),
+            v => Err(EvaluationError::Internal(format!(
+                "expected Record, got {:?}",
+                v
+            ))),
+        }
+    }
+
+    pub fn project(self, idx: usize) -> Result<Val, EvaluationError> {
+        match self {
+            Val::Data { ctor, fields } => fields.get(idx).cloned().ok_or_else(|| {
+                EvaluationError::Internal(format!("{} has no field {idx}", ctor.name()))
+            }),
+            Val::List(l) => match (l.uncons(), idx) {
+                (Some((head, _)), 0) => Ok(head.clone()),
+                (Some((_, tail)), 1) => Ok(Val::List(tail.clone())),
+                _ => Err(EvaluationError::Internal(format!(
+                    "list has no field {idx}"
+                ))),
+            },
+            v => Err(EvaluationError::Internal(format!(
+                "expected Data, got {:?}",
+                v
+            ))),
+        }
+    }
+
     pub fn try_eq(&self, other: &Self) -> Result<bool, EvaluationError> {
+        match Equality::new(self.clone(), other.clone()).resume(|_| false)? {
+            Progress::Done(eq) => Ok(eq),
+            Progress::Call(_) => unreachable!("no values are compared by calling a method"),
+        }
+    }
+}
+
+/// How far an `Equality` or a `Showing` got: either it's finished, or it
+/// needs the result of calling a method on some values before it can carry
+/// on.
+pub enum Progress<T, A> {
+    Done(T),
+    Call(A),
+}
+
+/// A comparison of two values for equality, which may be paused to compare
+/// some of the values within them by calling a method, so that the method
+/// can run in the same machine as the rest of the program.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Equality {
+    /// The pairs of values still to compare, in reverse order, so that
+    /// comparing deeply nested values can't overflow the stack. `None` stands
+    /// for a pair which is already known to differ, such as the end of the
+    /// shorter of two lists, so that they're still compared from left to
+    /// right.
+    pending: Vec<Option<(Val, Val)>>,
+}
+
+impl Equality {
+    pub fn new(l: Val, r: Val) -> Self {
+        Equality {
+            pending: vec![Some((l, r))],
+        }
+    }
+
+    /// Carry on comparing until the values are known to be equal or not, or
+    /// until reaching a pair whose left side is `overridden`, which is
+    /// returned to be compared by the caller.
+    pub fn resume(
+        &mut self,
+        overridden: impl Fn(&Val) -> bool,
+    ) -> Result<Progress<bool, (Val, Val)>, EvaluationError> {
+        let pending = &mut self.pending;
+        while let Some(next) = pending.pop() {
+            let Some((l, r)) = next else {
+                return Ok(Progress::Done(false));
+            };
+            if overridden(&l) {
+                return Ok(Progress::Call((l, r)));
+            }
+            let start = pending.len();
+            match (l, r) {
+                (Val::Array(l), Val::Array(r)) => {
+                    if l.len() != r.len() {
+                        return Ok(Progress::Done(false));
+                    }
+                    pending.extend(l.iter().cloned().zip(r.iter().cloned()).map(Some));
+                }
+                (Val::BigInt(l), Val::BigInt(r)) if l == r => (),
+                (Val::Bool(l), Val::Bool(r)) if l == r => (),
+                (Val::Int(l), Val::Int(r)) if l == r => (),
+                (Val::Num(l), Val::Num(r)) if l == r => (),
+                (Val::Rational(l), Val::Rational(r)) if l == r => (),
+                (
+                    Val::Data {
+                        ctor: lc,
+                        fields: lf,
+                    },
+                    Val::Data {
+                        ctor: rc,
+                        fields: rf,
+                    },
+                ) => {
+                    if lc != rc {
+                        return Ok(Progress::Done(false));
+                    }
+                    pending.extend(lf.iter().cloned().zip(rf.iter().cloned()).map(Some));
+                }
+                (Val::Record(l), Val::Record(r)) => {
+                    if l.len() != r.len() {
+                        return Ok(Progress::Done(false));
+                    }
+                    for ((lf, lv), (rf, rv)) in l.iter().zip(r.iter()) {
+                        pending.push((lf == rf).then(|| (lv.clone(), rv.clone())));
+                    }
+                }
+                (Val::List(l), Val::List(r)) => {
+                    let (mut l, mut r) = (l.iter(), r.iter());
+                    loop {
+                        match (l.next(), r.next()) {
+                            (Some(lv), Some(rv)) => pending.push(Some((lv.clone(), rv.clone()))),
+                            (None, None) => break,
+                            _ => {
+                                pending.push(None);
+                                break;
+                            }
+                        }
+                    }
+                }
+                (Val::Map(l), Val::Map(r)) => {
+                    if l.len() != r.len() {
+                        return Ok(Progress::Done(false));
+                    }
+                    for ((lk, lv), (rk, rv)) in l.iter().zip(r.iter()) {
+                        push_keys(pending, lk, rk);
+                        pending.push(Some((lv.clone(), rv.clone())));
+                    }
+                }
+                (Val::Set(l), Val::Set(r)) => {
+                    if l.len() != r.len() {
+                        return Ok(Progress::Done(false));
+                    }
+                    for ((lk, _), (rk, _)) in l.iter().zip(r.iter()) {
+                        push_keys(pending, lk, rk);
+                    }
+                }
+                (Val::Str(l), Val::Str(r)) if l == r => (),
+                (Val::Unit, Val::Unit) => (),
+                // The typechecker rejects `==` on functions, and on `Dyn`
+                // values which might be functions, so this only happens in
+                // ill-typed programs.
+                (Val::Closure { .. }, Val::Closure { .. }) => {
+                    return Err(EvaluationError::IllegalEquality)
+                }
+                (_, _) => return Ok(Progress::Done(false)),
+            }
+            pending[start..].reverse();
+        }
+        Ok(Progress::Done(true))
+    }
+}
+
+/// The position of each constructor among its data type's constructors.
+/// Values built by different constructors of a type are ordered by these.
+pub type Ranks = HashMap<interner::Id, usize>;
+
+/// A comparison of the order of two values of the same type, which may be
+/// paused to compare some of the values within them by calling a method, like
+/// an `Equality`. Values are ordered lexicographically by their components,
+/// and values of data types by their constructors' `Ranks` first.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Comparison {
+    /// The pairs of values still to compare, in reverse order, like an
+    /// `Equality`'s. An `Err` stands for the order of two sequences which is
+    /// already known if all of the pairs before it are equal, such as when
+    /// one is shorter.
+    pending: Vec<Result<(Val, Val), Ordering>>,
+    ranks: Rc<Ranks>,
+}
+
+impl Comparison {
+    pub fn new(l: Val, r: Val, ranks: Rc<Ranks>) -> Self {
+        Comparison {
+            pending: vec![Ok((l, r))],
+            ranks,
+        }
+    }
+
+    /// Carry on comparing until the order of the values is known, or until
+    /// reaching a pair whose left side is `overridden`, which is returned to
+    /// be compared by the caller, who passes the result to `compared`.
+    pub fn resume(
+        &mut self,
+        overridden: impl Fn(&Val) -> bool,
+    ) -> Result<Progress<Ordering, (Val, Val)>, EvaluationError> {
+        let (pending, ranks) = (&mut self.pending, &self.ranks);
+        // The order of two sequences if one is a prefix of the other.
+        let by_length = |pending: &mut Vec<_>, l: usize, r: usize| {
+            if l != r {
+                pending.push(Err(l.cmp(&r)));
+            }
+        };
+        while let Some(next) = pending.pop() {
+            let (l, r) = match next {
+                Ok(pair) => pair,
+                Err(ord) => return Ok(Progress::Done(ord)),
+            };
+            if overridden(&l) {
+                return Ok(Progress::Call((l, r)));
+            }
+            let start = pending.len();
+            let ord = match (l, r) {
+                (Val::Array(l), Val::Array(r)) => {
+                    pending.extend(l.iter().cloned().zip(r.iter().cloned()).map(Ok));
+                    by_length(pending, l.len(), r.len());
+                    Ordering::Equal
+                }
+                (Val::BigInt(l), Val::BigInt(r)) => l.cmp(&r),
+                (Val::Bool(l), Val::Bool(r)) => l.cmp(&r),
+                (Val::Int(l), Val::Int(r)) => l.cmp(&r),
+                // Use `partial_cmp` where we can, like `Key::compare`.
+                (Val::Num(l), Val::Num(r)) => l.partial_cmp(&r).unwrap_or_else(|| l.total_cmp(&r)),
+                (Val::Rational(l), Val::Rational(r)) => l.cmp(&r),
+                (
+                    Val::Data {
+                        ctor: lc,
+                        fields: lf,
+                    },
+                    Val::Data {
+                        ctor: rc,
+                        fields: rf,
+                    },
+                ) => {
+                    if lc == rc {
+                        pending.extend(lf.iter().cloned().zip(rf.iter().cloned()).map(Ok));
+                        Ordering::Equal
+                    } else {
+                        rank(ranks, lc)?.cmp(&rank(ranks, rc)?)
+                    }
+                }
+                // Records of the same type have the same fields, in the same
+                // order.
+                (Val::Record(l), Val::Record(r)) => {
+                    pending.extend(
+                        l.iter()
+                            .zip(r.iter())
+                            .map(|((_, lv), (_, rv))| Ok((lv.clone(), rv.clone()))),
+                    );
+                    Ordering::Equal
+                }
+                (Val::List(l), Val::List(r)) => {
+                    let (mut l, mut r) = (l.iter(), r.iter());
+                    loop {
+                        match (l.next(), r.next()) {
+                            (Some(lv), Some(rv)) => pending.push(Ok((lv.clone(), rv.clone()))),
+                            (lv, rv) => {
+                                by_length(pending, lv.is_some().into(), rv.is_some().into());
+                                break;
+                            }
+                        }
+                    }
+                    Ordering::Equal
+                }
+                (Val::Map(l), Val::Map(r)) => {
+                    for ((lk, lv), (rk, rv)) in l.iter().zip(r.iter()) {
+                        pending.push(Ok((lk.clone().into(), rk.clone().into())));
+                        pending.push(Ok((lv.clone(), rv.clone())));
+                    }
+                    by_length(pending, l.len(), r.len());
+                    Ordering::Equal
+                }
+                (Val::Set(l), Val::Set(r)) => {
+                    for ((lk, _), (rk, _)) in l.iter().zip(r.iter()) {
+                        pending.push(Ok((lk.clone().into(), rk.clone().into())));
+                    }
+                    by_length(pending, l.len(), r.len());
+                    Ordering::Equal
+                }
+                (Val::Str(l), Val::Str(r)) => l.cmp(&r),
+                (Val::Unit, Val::Unit) => Ordering::Equal,
+                // The typechecker only allows values of types with instances
+                // of `Ord` to be ordered, so this only happens in ill-typed
+                // programs.
+                (l, r) => {
+                    return Err(EvaluationError::Internal(format!(
+                        "{l:?} & {r:?} can't be ordered"
+                    )))
+                }
+            };
+            if ord != Ordering::Equal {
+                return Ok(Progress::Done(ord));
+            }
+            pending[start..].reverse();
+        }
+        Ok(Progress::Done(Ordering::Equal))
+    }
+
+    /// Carry on after a method found that the pair it was given are ordered
+    /// by `ord`.
+    pub fn compared(&mut self, ord: Ordering) {
+        if ord != Ordering::Equal {
+            self.pending = vec![Err(ord)];
+        }
+    }
+}
+
+/// The position of `ctor` among its data type's constructors.
+fn rank(ranks: &Ranks, ctor: interner::Id) -> Result<usize, EvaluationError> {
+    ranks
+        .get(&ctor)
+        .copied()
+        .ok_or_else(|| EvaluationError::Internal(format!("unknown constructor {}", ctor.name())))
+}
+
+/// Part of the text of a value which is being shown.
+#[derive(Clone, Debug, PartialEq)]
+enum Piece {
+    Name(interner::Id),
+    Text(&'static str),
+    Val(Val),
+}
+
+/// The showing of a value as text, which may be paused to show some of the
+/// values within it by calling a method, so that the method can run in the
+/// same machine as the rest of the program.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Showing {
+    shown: String,
+    /// The pieces still to show, in reverse order, so that showing deeply
+    /// nested values can't overflow the stack.
+    pending: Vec<Piece>,
+}
+
+impl Showing {
+    pub fn new(v: Val) -> Self {
+        Showing {
+            shown: String::new(),
+            pending: vec![Piece::Val(v)],
+        }
+    }
+
+    /// Add `s`, which a method showed a value as, to the text.
+    pub fn push_str(&mut self, s: &str) {
+        self.shown.push_str(s);
+    }
+
+    /// Carry on showing until finished, returning the text, or until reaching
+    /// a value which is `overridden`, which is returned to be shown by the
+    /// caller.
+    pub fn resume(&mut self, overridden: impl Fn(&Val) -> bool) -> Progress<String, Val> {
+        while let Some(piece) = self.pending.pop() {
+            let v = match piece {
+                Piece::Name(name) => {
+                    self.shown.push_str(name.name());
+                    continue;
+                }
+                Piece::Text(text) => {
+                    self.shown.push_str(text);
+                    continue;
+                }
+                Piece::Val(v) if overridden(&v) => return Progress::Call(v),
+                Piece::Val(v) => v,
+            };
+            let mut pieces = Vec::new();
+            let mut seq = |vals: &mut dyn Iterator<Item = Val>, open, close| {
+                pieces.push(Piece::Text(open));
+                for (idx, v) in vals.enumerate() {
+                    if idx != 0 {
+                        pieces.push(Piece::Text(", "));
+                    }
+                    pieces.push(Piece::Val(v));
+                }
+                pieces.push(Piece::Text(close));
+            };
+            match v {
+                Val::Array(vs) => seq(&mut vs.iter().cloned(), "[|", "|]"),
+                Val::BigInt(n) => self.shown.push_str(&n.to_string()),
+                Val::Bool(b) => self.shown.push_str(&b.to_string()),
+                Val::Closure { .. } => self.shown.push_str("<function>"),
+                Val::Data { ctor, fields } => {
+                    pieces.push(Piece::Name(ctor));
+                    for v in fields.iter() {
+                        match v {
+                            Val::Data { fields, .. } if !fields.is_empty() => {
+                                pieces.push(Piece::Text(" ("));
+                                pieces.push(Piece::Val(v.clone()));
+                                pieces.push(Piece::Text(")"));
+                            }
+                            _ => {
+                                pieces.push(Piece::Text(" "));
+                                pieces.push(Piece::Val(v.clone()));
+                            }
+                        }
+                    }
+                }
+                Val::Dummy => self.shown.push_str("<dummy>"),
+                Val::Int(i) => self.shown.push_str(&i.to_string()),
+                Val::List(l) => seq(&mut l.iter().cloned(), "[", "]"),
+                Val::Map(m) => {
+                    pieces.push(Piece::Text("#{"));
+                    for (idx, (k, v)) in m.iter().enumerate() {
+                        if idx != 0 {
+                            pieces.push(Piece::Text(", "));
+                        }
+                        pieces.push(Piece::Val(k.clone().into()));
+                        pieces.push(Piece::Text(" => "));
+                        pieces.push(Piece::Val(v.clone()));
+                    }
+                    pieces.push(Piece::Text("}"));
+                }
+                Val::Num(n) => self.shown.push_str(&n.to_string()),
+                Val::Rational(r) => self.shown.push_str(&r.to_string()),
+                Val::Record(fields) if fields.is_empty() => self.shown.push_str("{}"),
+                Val::Record(fields) => {
+                    for (idx, (field, v)) in fields.iter().enumerate() {
+                        pieces.push(Piece::Text(if idx == 0 { "{ " } else { ", " }));
+                        pieces.push(Piece::Name(*field));
+                        pieces.push(Piece::Text(" = "));
+                        pieces.push(Piece::Val(v.clone()));
+                    }
+                    pieces.push(Piece::Text(" }"));
+                }
+                Val::Set(set) => seq(&mut set.iter().map(|(k, _)| k.clone().into()), "#{", "}"),
+                Val::Str(s) => self.shown.push_str(&format!("{s:?}")),
+                Val::Unit => self.shown.push_str("()"),
+            }
+            self.pending.extend(pieces.into_iter().rev());
+        }
+        Progress::Done(mem::take(&mut self.shown))
+    }
+}
+
+/// A persistent singly-linked list. Cloning a list, or adding an element to
+/// the front of one, is O(1) and shares the existing elements.
+#[derive(Clone, Default)]
+pub struct List(Option<Rc<Node>>);
+
+struct Node {
+    head: Val,
+    tail: List,
+}
+
+impl List {
+    pub fn empty() -> Self {
+        List(None)
+    }
+
+    pub fn cons(&self, head: Val) -> Self {
+        List(Some(Rc::new(Node {
+            head,
+            tail: self.clone(),
+        })))
+    }
+
+    pub fn uncons(&self) -> Option<(&Val, &List)> {
+        self.0.as_ref().map(|n| (&n.head, &n.tail))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_none()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Val> {
+        let mut next = self;
+        std::iter::from_fn(move || {
+            let (head, tail) = next.uncons()?;
+            next = tail;
+            Some(head)
+        })
+    }
+}
+
+impl PartialEq for List {
+    fn eq(&self, other: &Self) -> bool {
+        self.iter().eq(other.iter())
+    }
+}
+
+impl std::fmt::Debug for List {
+    /// Show the elements, walking the list rather than recursing down its
+    /// nodes, so that showing a long list can't overflow the stack.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
+
+/// The fields of a `Val::Data`.
+#[derive(Clone, PartialEq)]
+pub struct Fields(Rc<[Val]>);
+
+impl Deref for Fields {
+    type Target = [Val];
+
+    fn deref(&self) -> &[Val] {
+        &self.0
+    }
+}
+
+impl From<Vec<Val>> for Fields {
+    fn from(fields: Vec<Val>) -> Self {
+        Fields(fields.into())
+    }
+}
+
+impl std::fmt::Debug for Fields {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl Fields {
+    /// Move any fields which are themselves `Data` into `pending`, if nothing
+    /// else shares them.
+    fn take_data(&mut self, pending: &mut Vec<Val>) {
+        if let Some(fields) = Rc::get_mut(&mut self.0) {
+            for field in fields.iter_mut() {
+                if let Val::Data { .. } = field {
+                    pending.push(mem::replace(field, Val::Unit));
+                }
+            }
+        }
+    }
+}
+
+impl Drop for Fields {
+    /// Drop nested data one value at a time, rather than recursively, so that
+    /// dropping a deeply nested value, such as a long chain of a recursive
+    /// type's constructors, can't overflow the stack.
+    fn drop(&mut self) {
+        let mut pending = Vec::new();
+        self.take_data(&mut pending);
+        while let Some(mut v) = pending.pop() {
+            if let Val::Data { fields, .. } = &mut v {
+                fields.take_data(&mut pending);
+            }
+        }
+    }
+}
+
+impl Drop for List {
+    /// Drop the nodes one at a time, rather than recursively, so that
+    /// dropping a long list can't overflow the stack.
+    fn drop(&mut self) {
+        let mut next = self.0.take();
+        while let Some(node) = next {
+            match Rc::try_unwrap(node) {
+                Ok(mut node) => next = node.tail.0.take(),
+                Err(_) => break,
+            }
+        }
+    }
+}
+
+/// Add the comparison of keys `l` & `r` to `pending`. Compound keys are
+/// compared as values, since the `Eq` instances of the types within them may
+/// be overridden.
+fn push_keys(pending: &mut Vec<Option<(Val, Val)>>, l: &Key, r: &Key) {
+    match (l, r) {
+        (Key::Compound(l), Key::Compound(r)) => pending.push(Some((l.clone(), r.clone()))),
+        (l, r) if l == r => (),
+        _ => pending.push(None),
+    }
+}
+
+/// A value which can be used as the key of a `Map`. Only values of comparable
+/// types can be keys, which the typechecker ensures.
+#[derive(Clone, Debug)]
+pub enum Key {
+    BigInt(Rc<BigInt>),
+    Bool(bool),
+    /// A value made of other values, such as a list or a value of a user
+    /// type, which is ordered by calling a method: either its type's `Ord`
+    /// instance's, or one which compares it structurally.
+    Compound(Val),
+    Int(i64),
+    Num(f64),
+    Rational(Rc<Rational>),
+    Str(Rc<str>),
+    Unit,
+}
+
+impl TryFrom<Val> for Key {
+    type Error = EvaluationError;
+
+    fn try_from(v: Val) -> Result<Self, Self::Error> {
+        match v {
+            Val::BigInt(n) => Ok(Key::BigInt(n)),
+            Val::Bool(b) => Ok(Key::Bool(b)),
+            v @ (Val::Array(_)
+            | Val::Data { .. }
+            | Val::List(_)
+            | Val::Map(_)
+            | Val::Record(_)
+            | Val::Set(_)) => Ok(Key::Compound(v)),
+            Val::Int(i) => Ok(Key::Int(i)),
+            Val::Num(n) => Ok(Key::Num(n)),
+            Val::Rational(r) => Ok(Key::Rational(r)),
+            Val::Str(s) => Ok(Key::Str(s)),
+            Val::Unit => Ok(Key::Unit),
+            v => Err(EvaluationError::Internal(format!(
+                "{:?} can't be used as a key",
+                v
+            ))),
+        }
+    }
+}
+
+impl From<Key> for Val {
+    fn from(k: Key) -> Self {
+        match k {
+            Key::BigInt(n) => Val::BigInt(n),
+            Key::Bool(b) => Val::Bool(b),
+            Key::Compound(v) => v,
+            Key::Int(i) => Val::Int(i),
+            Key::Num(n) => Val::Num(n),
+            Key::Rational(r) => Val::Rational(r),
+            Key::Str(s) => Val::Str(s),
+            Key::Unit => Val::Unit,
+        }
+    }
+}
+
+impl Key {
+    /// Compare with `other`. Compound keys can't be compared here, since that
+    /// means calling a method.
+    pub fn compare(&self, other: &Self) -> Result<Ordering, EvaluationError> {
+        Ok(match (self, other) {
+            (Key::BigInt(l), Key::BigInt(r)) => l.cmp(r),
+            (Key::Bool(l), Key::Bool(r)) => l.cmp(r),
+            (Key::Int(l), Key::Int(r)) => l.cmp(r),
+            // Use `partial_cmp` where we can so that `0` & `-0` are the same
+            // key, just as they are equal under `==`.
+            (Key::Num(l), Key::Num(r)) => l.partial_cmp(r).unwrap_or_else(|| l.total_cmp(r)),
+            (Key::Rational(l), Key::Rational(r)) => l.cmp(r),
+            (Key::Str(l), Key::Str(r)) => l.cmp(r),
+            (Key::Unit, Key::Unit) => Ordering::Equal,
+            (Key::Compound(l), Key::Compound(r)) => {
+                return Err(EvaluationError::Internal(format!(
+                    "{l:?} & {r:?} are ordered by a method"
+                )))
+            }
+            // Keys of different types are never in the same map, so any
+            // consistent order will do.
+            (l, r) => l.rank().cmp(&r.rank()),
+        })
+    }
+
+    fn rank(&self) -> usize {
+        match self {
+            Key::BigInt(_) => 0,
+            Key::Bool(_) => 1,
+            Key::Compound(_) => 2,
+            Key::Int(_) => 3,
+            Key::Num(_) => 4,
+            Key::Rational(_) => 5,
+            Key::Str(_) => 6,
+            Key::Unit => 7,
+        }
+    }
+}
+
+impl PartialEq for Key {
+    fn eq(&self, other: &Self) -> bool {
         match (self, other) {
-            (Val::Bool(l), Val::Bool(r)) => Ok(l == r),
-            (Val::Num(l), Val::Num(r)) => Ok(l == r),
-            (Val::Unit, Val::Unit) => Ok(true),
-            (Val::Closure { .. }, Val::Closure { .. }) => Err(EvaluationError::IllegalEquality),
-            (_, _) => Ok(false),
+            (Key::Compound(l), Key::Compound(r)) => l == r,
+            (l, r) => matches!(l.compare(r), Ok(Ordering::Equal)),
         }
     }
 }
+
+/// A persistent map, ordered by key, implemented as an AVL tree. Cloning a map
+/// is O(1), and inserting or removing a key is O(log n) and shares all of the
+/// untouched parts of the tree. Sets are maps whose values are all `()`.
+pub struct Map<V>(Option<Rc<MapNode<V>>>);
+
+struct MapNode<V> {
+    key: Key,
+    value: V,
+    left: Map<V>,
+    right: Map<V>,
+    height: usize,
+    len: usize,
+}
+
+impl<V: std::fmt::Debug + 'static> std::fmt::Debug for Map<V> {
+    /// Show the entries in order, leaving out how the tree is balanced. Sets
+    /// are shown as their keys alone.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if TypeId::of::<V>() == TypeId::of::<()>() {
+            f.debug_set().entries(self.iter().map(|(k, _)| k)).finish()
+        } else {
+            f.debug_map().entries(self.iter()).finish()
+        }
+    }
+}
+
+impl<V> Clone for Map<V> {
+    fn clone(&self) -> Self {
+        Map(self.0.clone())
+    }
+}
+
+impl<V> Map<V> {
+    pub fn empty() -> Self {
+        Map(None)
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.as_ref().map_or(0, |n| n.len)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_none()
+    }
+
+    /// Iterate through the entries in order of their keys.
+    pub fn iter(&self) -> impl Iterator<Item = (&Key, &V)> {
+        let mut stack = Vec::new();
+        let mut next = self;
+        std::iter::from_fn(move || {
+            while let Some(node) = &next.0 {
+                stack.push(node.as_ref());
+                next = &node.left;
+            }
+            let node = stack.pop()?;
+            next = &node.right;
+            Some((&node.key, &node.value))
+        })
+    }
+
+    fn height(&self) -> usize {
+        self.0.as_ref().map_or(0, |n| n.height)
+    }
+}
+
+impl<V: Clone> Map<V> {
+    /// Search for `key`, which mustn't be of a user type.
+    pub fn search(&self, key: &Key) -> Result<Search<V>, EvaluationError> {
+        let mut search = Search::new(self);
+        while let Some(next) = search.next() {
+            let ord = key.compare(next)?;
+            search.compared(ord);
+        }
+        Ok(search)
+    }
+
+    /// Build a map from `entries`, which are already in order of their keys.
+    pub fn from_sorted(entries: &[(Key, V)]) -> Self {
+        if entries.is_empty() {
+            return Map::empty();
+        }
+        let mid = entries.len() / 2;
+        let (key, value) = entries[mid].clone();
+        let left = Map::from_sorted(&entries[..mid]);
+        let right = Map::from_sorted(&entries[mid + 1..]);
+        Map::node(key, value, left, right)
+    }
+
+    /// Remove the entry with the smallest key, returning it along with the
+    /// rest of the map.
+    fn remove_min(&self) -> Option<(Key, V, Self)> {
+        let node = self.0.as_ref()?;
+        Some(match node.left.remove_min() {
+            None => (node.key.clone(), node.value.clone(), node.right.clone()),
+            Some((k, v, left)) => (
+                k,
+                v,
+                Map::balance(
+                    node.key.clone(),
+                    node.value.clone(),
+                    left,
+                    node.right.clone(),
+                ),
+            ),
+        })
+    }
+
+    fn node(key: Key, value: V, left: Self, right: Self) -> Self {
+        let height = left.height().max(right.height()) + 1;
+        let len = left.len() + right.len() + 1;
+        Map(Some(Rc::new(MapNode {
+            key,
+            value,
+            left,
+            right,
+            height,
+            len,
+        })))
+    }
+
+    /// Build a node from subtrees whose heights differ by at most two,
+    /// rotating it so that they differ by at most one.
+    fn balance(key: Key, value: V, left: Self, right: Self) -> Self {
+        let (lh, rh) = (left.height(), right.height());
+        if lh > rh + 1 {
+            let l = left.0.as_ref().expect("left is taller than right");
+            if l.left.height() >= l.right.height() {
+                Map::node(
+                    l.key.clone(),
+                    l.value.clone(),
+                    l.left.clone(),
+                    Map::node(key, value, l.right.clone(), right),
+                )
+            } else {
+                let lr = l
+                    .right
+                    .0
+                    .as_ref()
+                    .expect("left's right is taller than its left");
+                Map::node(
+                    lr.key.clone(),
+                    lr.value.clone(),
+                    Map::node(
+                        l.key.clone(),
+                        l.value.clone(),
+                        l.left.clone(),
+                        lr.left.clone(),
+                    ),
+                    Map::node(key, value, lr.right.clone(), right),
+                )
+            }
+        } else if rh > lh + 1 {
+            let r = right.0.as_ref().expect("right is taller than left");
+            if r.right.height() >= r.left.height() {
+                Map::node(
+                    r.key.clone(),
+                    r.value.clone(),
+                    Map::node(key, value, left, r.left.clone()),
+                    r.right.clone(),
+                )
+            } else {
+                let rl = r
+                    .left
+                    .0
+                    .as_ref()
+                    .expect("right's left is taller than its right");
+                Map::node(
+                    rl.key.clone(),
+                    rl.value.clone(),
+                    Map::node(key, value, left, rl.left.clone()),
+                    Map::node(
+                        r.key.clone(),
+                        r.value.clone(),
+                        rl.right.clone(),
+                        r.right.clone(),
+                    ),
+                )
+            }
+        } else {
+            Map::node(key, value, left, right)
+        }
+    }
+}
+
+/// A search for a key in a map, which is paused at each node it passes so that
+/// the caller can compare the key with the node's, e.g. by calling a method.
+#[derive(Clone)]
+pub struct Search<V> {
+    /// The nodes passed so far, along with the side of each which the search
+    /// carried on down.
+    path: Vec<(Rc<MapNode<V>>, Ordering)>,
+    /// The subtree the search has reached.
+    at: Map<V>,
+    /// Whether the key is at the root of `at`.
+    found: bool,
+}
+
+impl<V: Clone> Search<V> {
+    pub fn new(map: &Map<V>) -> Self {
+        Search {
+            path: Vec::new(),
+            at: map.clone(),
+            found: false,
+        }
+    }
+
+    /// The key to compare the key being searched for with next, or `None` if
+    /// the search is over.
+    pub fn next(&self) -> Option<&Key> {
+        match &self.at.0 {
+            Some(node) if !self.found => Some(&node.key),
+            _ => None,
+        }
+    }
+
+    /// Carry on, given how the key being searched for compares with the one
+    /// from `next`.
+    pub fn compared(&mut self, ord: Ordering) {
+        let node = self.at.0.clone().expect("the search isn't over");
+        self.at = match ord {
+            Ordering::Equal => {
+                self.found = true;
+                return;
+            }
+            Ordering::Less => node.left.clone(),
+            Ordering::Greater => node.right.clone(),
+        };
+        self.path.push((node, ord));
+    }
+
+    /// The value for the key, if it was found.
+    pub fn value(&self) -> Option<&V> {
+        match &self.at.0 {
+            Some(node) if self.found => Some(&node.value),
+            _ => None,
+        }
+    }
+
+    /// The map with an entry for `key`, which was searched for, replacing any
+    /// existing one.
+    pub fn insert(self, key: Key, value: V) -> Map<V> {
+        let subtree = match &self.at.0 {
+            Some(node) if self.found => {
+                Map::node(key, value, node.left.clone(), node.right.clone())
+            }
+            _ => Map::node(key, value, Map::empty(), Map::empty()),
+        };
+        self.rebuild(subtree)
+    }
+
+    /// The map without the entry for the key searched for, if there is one.
+    pub fn remove(self) -> Map<V> {
+        let subtree = match &self.at.0 {
+            Some(node) if self.found => match node.right.remove_min() {
+                None => node.left.clone(),
+                Some((k, v, right)) => Map::balance(k, v, node.left.clone(), right),
+            },
+            _ => self.at.clone(),
+        };
+        self.rebuild(subtree)
+    }
+
+    /// Rebuild the nodes passed on the way to `at`, with `subtree` in its
+    /// place.
+    fn rebuild(self, subtree: Map<V>) -> Map<V> {
+        self.path
+            .into_iter()
+            .rev()
+            .fold(subtree, |subtree, (node, side)| {
+                let (key, value) = (node.key.clone(), node.value.clone());
+                match side {
+                    Ordering::Less => Map::balance(key, value, subtree, node.right.clone()),
+                    _ => Map::balance(key, value, node.left.clone(), subtree),
+                }
+            })
+    }
+}
+
+impl<V: std::fmt::Debug + 'static> std::fmt::Debug for Search<V> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Search")
+            .field("depth", &self.path.len())
+            .field("at", &self.at)
+            .field("found", &self.found)
+            .finish()
+    }
+}
+
+impl<V: PartialEq> PartialEq for Search<V> {
+    fn eq(&self, other: &Self) -> bool {
+        self.found == other.found
+            && self.at == other.at
+            && self.path.len() == other.path.len()
+            && self
+                .path
+                .iter()
+                .zip(other.path.iter())
+                .all(|((l, ls), (r, rs))| Rc::ptr_eq(l, r) && ls == rs)
+    }
+}
+
+impl<V: PartialEq> PartialEq for Map<V> {
+    fn eq(&self, other: &Self) -> bool {
+        self.len() == other.len() && self.iter().eq(other.iter())
+    }
+}
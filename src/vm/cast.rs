@@ -0,0 +1,207 @@
+use std::{cell::RefCell, rc::Rc};
+
+use crate::{
+    builtins,
+    env::Env,
+    error::EvaluationError,
+    interner,
+    typ::{Cast, Primitive, Type},
+    values::{List, Map, Val},
+};
+
+use super::{stack::Stack, Op};
+
+/// Apply `cast` to `v`, failing if `v` doesn't have the type it's cast to.
+pub(super) fn apply(v: Val, cast: &Cast) -> Result<Val, EvaluationError> {
+    cast_to(v, cast.from(), cast.to(), cast)
+}
+
+/// Cast `v` from `from` to `to`, which are parts of the types of `cast`.
+fn cast_to(v: Val, from: &Type, to: &Type, cast: &Cast) -> Result<Val, EvaluationError> {
+    if cast.is_trivial_between(from, to) {
+        return Ok(v);
+    }
+    let failed = |v: &Val| EvaluationError::CastFailed {
+        expected: to.to_string(),
+        got: v.to_string(),
+        location: cast.location(),
+    };
+    // The type of each part of `v` before the cast, which is `Dyn` for any
+    // parts `from` doesn't know about.
+    let from_args = |n| match from {
+        Type::Named(m, args) if *m == n => args.clone(),
+        _ => Vec::new(),
+    };
+    let arg = |args: &[Type], idx: usize| args.get(idx).cloned().unwrap_or(Type::Dyn);
+    // Newtypes are represented by their underlying values, except in `Dyn`,
+    // where they're tagged with the newtype's name as if it were a
+    // constructor, so that they can only be cast back to the same newtype.
+    if let (Some((n, underlying)), Type::Dyn) = (newtype(from, cast), to) {
+        let v = cast_to(v, underlying, &Type::Dyn, cast)?;
+        return Ok(Val::construct(n, vec![v]));
+    }
+    if let Some((n, underlying)) = newtype(to, cast) {
+        return match v {
+            Val::Data { ctor, fields } if ctor == n => {
+                cast_to(fields[0].clone(), &Type::Dyn, underlying, cast)
+            }
+            v => Err(failed(&v)),
+        };
+    }
+    match (to, v) {
+        (Type::Dyn, v) => cast_to(v, from, &cast.erase(from), cast),
+        (Type::Primitive(p), v) => match (p, &v) {
+            (Primitive::BigInt, Val::BigInt(_))
+            | (Primitive::Bool, Val::Bool(_))
+            | (Primitive::Int, Val::Int(_))
+            | (Primitive::Num(_), Val::Num(_))
+            | (Primitive::Rational, Val::Rational(_))
+            | (Primitive::String, Val::Str(_))
+            | (Primitive::Unit, Val::Unit) => Ok(v),
+            _ => Err(failed(&v)),
+        },
+        (Type::Arrow(to_arg, to_res, _), f @ Val::Closure { .. }) => {
+            let dyn_fn = (Type::Dyn, Type::Dyn);
+            let (from_arg, from_res) = match from {
+                Type::Arrow(from_arg, from_res, _) => (&**from_arg, &**from_res),
+                _ => (&dyn_fn.0, &dyn_fn.1),
+            };
+            // Arguments flow the other way, so they're cast back to the type
+            // the function expects.
+            Ok(proxy(
+                f,
+                cast.between(to_arg, from_arg),
+                cast.between(from_res, to_res),
+            ))
+        }
+        (Type::Record(row), Val::Record(fields)) => {
+            let has_fields = match row.rest() {
+                Some(_) => row
+                    .fields()
+                    .iter()
+                    .all(|(f, _)| fields.iter().any(|(g, _)| f == g)),
+                None => {
+                    row.fields().len() == fields.len()
+                        && row
+                            .fields()
+                            .iter()
+                            .zip(fields.iter())
+                            .all(|((f, _), (g, _))| f == g)
+                }
+            };
+            if !has_fields {
+                return Err(failed(&Val::Record(fields)));
+            }
+            let mut cast_fields = Vec::with_capacity(fields.len());
+            for (f, v) in fields.iter() {
+                let v = match row.get(*f) {
+                    Some(t) => {
+                        let from = match from {
+                            Type::Record(from_row) => from_row.get(*f).cloned(),
+                            _ => None,
+                        };
+                        cast_to(v.clone(), &from.unwrap_or(Type::Dyn), t, cast)?
+                    }
+                    None => v.clone(),
+                };
+                cast_fields.push((*f, v));
+            }
+            Ok(Val::record(cast_fields))
+        }
+        (Type::Named(n, args), Val::List(l)) if *n == *builtins::LIST => {
+            let from_elem = arg(&from_args(*n), 0);
+            let mut elems = Vec::new();
+            for v in l.iter() {
+                elems.push(cast_to(v.clone(), &from_elem, &args[0], cast)?);
+            }
+            let l = elems
+                .into_iter()
+                .rev()
+                .fold(List::empty(), |l, v| l.cons(v));
+            Ok(Val::List(l))
+        }
+        (Type::Named(n, args), Val::Array(vs)) if *n == *builtins::ARRAY => {
+            let from_elem = arg(&from_args(*n), 0);
+            let vs = vs
+                .iter()
+                .map(|v| cast_to(v.clone(), &from_elem, &args[0], cast))
+                .collect::<Result<_, _>>()?;
+            Ok(Val::Array(Rc::new(vs)))
+        }
+        (Type::Named(n, args), Val::Map(m)) if *n == *builtins::MAP => {
+            let from_args = from_args(*n);
+            let (from_key, from_value) = (arg(&from_args, 0), arg(&from_args, 1));
+            // Casts don't change keys, so the entries stay in the same order.
+            let mut entries = Vec::with_capacity(m.len());
+            for (k, v) in m.iter() {
+                cast_to(Val::from(k.clone()), &from_key, &args[0], cast)?;
+                entries.push((k.clone(), cast_to(v.clone(), &from_value, &args[1], cast)?));
+            }
+            Ok(Val::Map(Map::from_sorted(&entries)))
+        }
+        (Type::Named(n, args), Val::Set(s)) if *n == *builtins::SET => {
+            let from_elem = arg(&from_args(*n), 0);
+            for (k, _) in s.iter() {
+                cast_to(Val::from(k.clone()), &from_elem, &args[0], cast)?;
+            }
+            Ok(Val::Set(s))
+        }
+        (Type::Named(n, args), Val::Data { ctor, fields }) => {
+            let variant = cast
+                .data_type(*n)
+                .and_then(|decl| Some(decl).zip(decl.variants.iter().find(|v| v.name == ctor)));
+            let (decl, variant) = match variant {
+                Some(found) => found,
+                None => return Err(failed(&Val::Data { ctor, fields })),
+            };
+            let from_args = from_args(*n);
+            let to_substitution: Vec<_> = decl.params.iter().copied().zip(args.clone()).collect();
+            let from_substitution: Vec<_> = decl
+                .params
+                .iter()
+                .enumerate()
+                .map(|(idx, p)| (*p, arg(&from_args, idx)))
+                .collect();
+            let mut cast_fields = Vec::with_capacity(fields.len());
+            for (v, t) in fields.iter().zip(variant.fields.iter()) {
+                let from = t.substitute(&from_substitution);
+                let to = t.substitute(&to_substitution);
+                cast_fields.push(cast_to(v.clone(), &from, &to, cast)?);
+            }
+            Ok(Val::construct(ctor, cast_fields))
+        }
+        (_, v) => Err(failed(&v)),
+    }
+}
+
+/// The name & underlying type of `t`, if it's a newtype.
+fn newtype<'a>(t: &Type, cast: &'a Cast) -> Option<(interner::Id, &'a Type)> {
+    match t {
+        Type::Named(n, _) => cast.newtype(*n).map(|u| (*n, u)),
+        _ => None,
+    }
+}
+
+/// Wrap the function `f` in one which casts its argument with `arg` before
+/// calling `f`, and then casts `f`'s result with `res`.
+fn proxy(f: Val, arg: Cast, res: Cast) -> Val {
+    // With `f` bound in its environment, the wrapper grabs its argument,
+    // casts it & tail-calls `f`, or returns to cast the result if it needs
+    // to.
+    let mut body = vec![Op::Apply(), Op::Access(1)];
+    if !arg.is_trivial() {
+        body.push(Op::Cast(Rc::new(arg)));
+    }
+    body.push(Op::Access(0));
+    if !res.is_trivial() {
+        let ret = vec![Op::Return(), Op::Cast(Rc::new(res))];
+        body.push(Op::PushRetAddr(Stack::from_stacked_vec(ret)));
+    }
+    body.push(Op::Grab());
+    let mut env = Env::new();
+    env.bind(RefCell::new(f));
+    Val::Closure {
+        body: Stack::from_stacked_vec(body),
+        env,
+    }
+}
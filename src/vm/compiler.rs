@@ -1,6 +1,18 @@
-use crate::ast::Expr;
+use std::rc::Rc;
 
-use super::{stack::Stack, Op};
+use crate::{
+    ast::{BinaryOp, Class, DataDecl, Expr, Instance},
+    builtins::{self, Builtin, Definition},
+    interner,
+    typ::{Casts, InstanceKey, Overload, Overloads},
+    values::{Ranks, Val},
+};
+
+use super::{
+    matching::{self, Tree},
+    stack::Stack,
+    Op,
+};
 
 enum CompilerMode {
     Normal,
@@ -12,27 +24,146 @@ enum CompilerMode {
 pub struct Compiler {
     mode: CompilerMode,
     code: Stack<Op>,
+    /// The number of return addresses which will have been pushed since the
+    /// start of the enclosing function body when the code being compiled
+    /// runs. `?` needs this to find the function's own return address.
+    frames: usize,
+    /// The casts to apply to the values of expressions, keyed by their
+    /// addresses.
+    casts: Rc<Casts>,
+    /// How the overloaded operators & built-ins which need user-declared
+    /// instances are resolved, keyed by their addresses.
+    overloads: Rc<Overloads>,
+    /// The number of values bound in the environment when the code being
+    /// compiled runs.
+    depth: usize,
+    /// The user-declared instances in scope, innermost last, along with the
+    /// depth their methods are bound at.
+    instances: Vec<(Instance, usize)>,
+    /// The ranks of the constructors of the data types in scope, which values
+    /// are ordered by when they're compared structurally.
+    ranks: Rc<Ranks>,
 }
 
 impl Compiler {
-    pub fn new() -> Self {
+    pub fn new(casts: Casts, overloads: Overloads) -> Self {
         let mode = CompilerMode::Normal;
         let code = Stack::new();
-        Compiler { mode, code }
+        let mut ranks = Ranks::new();
+        for decl in builtins::data_types() {
+            add_ranks(&mut ranks, &decl);
+        }
+        Compiler {
+            mode,
+            code,
+            frames: 0,
+            casts: Rc::new(casts),
+            overloads: Rc::new(overloads),
+            depth: 0,
+            instances: Vec::new(),
+            ranks: Rc::new(ranks),
+        }
     }
 
-    fn with_mode_and_ops<I: IntoIterator<Item = Op>>(mode: CompilerMode, i: I) -> Self {
+    fn with_mode_and_ops<I: IntoIterator<Item = Op>>(&self, mode: CompilerMode, i: I) -> Self {
         let code: Vec<Op> = i.into_iter().collect();
         let code = Stack::from_stacked_vec(code);
-        Compiler { mode, code }
+        Compiler {
+            mode,
+            code,
+            frames: 0,
+            casts: self.casts.clone(),
+            overloads: self.overloads.clone(),
+            depth: self.depth,
+            instances: self.instances.clone(),
+            ranks: self.ranks.clone(),
+        }
     }
 
-    fn for_tail() -> Self {
-        Self::with_mode_and_ops(CompilerMode::Tail, None)
+    fn for_tail(&self) -> Self {
+        self.with_mode_and_ops(CompilerMode::Tail, None)
     }
 
-    fn for_branch() -> Self {
-        Self::with_mode_and_ops(CompilerMode::Normal, Some(Op::Join()))
+    fn for_branch(&self) -> Self {
+        let mut compiler = self.with_mode_and_ops(CompilerMode::Normal, Some(Op::Join()));
+        compiler.frames = self.frames;
+        compiler
+    }
+
+    /// Whether the value of `e` is cast, in which case it can't be merged
+    /// into the code around it.
+    fn is_cast(&self, e: &Expr) -> bool {
+        self.casts.contains_key(&(e as *const Expr))
+    }
+
+    /// How `e` is resolved, if it's an overloaded operator or built-in which
+    /// needs user-declared instances.
+    fn overload(&self, e: &Expr) -> Option<&Overload> {
+        self.overloads.get(&(e.unlocated() as *const Expr))
+    }
+
+    /// Run `f` with the constructors of `decl` ranked.
+    fn declaring(&mut self, decl: &DataDecl, f: impl FnOnce(&mut Self)) {
+        let outer = self.ranks.clone();
+        add_ranks(Rc::make_mut(&mut self.ranks), decl);
+        f(self);
+        self.ranks = outer;
+    }
+
+    /// Run `f` with `n` more values bound in the environment.
+    fn binding<T>(&mut self, n: usize, f: impl FnOnce(&mut Self) -> T) -> T {
+        self.depth += n;
+        let res = f(self);
+        self.depth -= n;
+        res
+    }
+
+    /// Find the methods of the user-declared instance `key`, which is in
+    /// scope, returning their index in the environment & the constructors
+    /// of the instance's type.
+    fn find_methods(&self, key: &InstanceKey) -> (usize, Vec<interner::Id>) {
+        let (instance, depth) = self
+            .instances
+            .iter()
+            .rev()
+            .find(|(i, _)| (i.class, i.typ) == *key)
+            .expect("the typechecker only resolves instances which are in scope");
+        (self.depth - 1 - depth, instance.constructors.clone())
+    }
+
+    /// Push `op`, which is made from the constructors of the types of the
+    /// user-declared instances `keys`, after pushing each one's methods in
+    /// order.
+    fn push_with_methods(
+        &mut self,
+        keys: &[InstanceKey],
+        op: impl FnOnce(Rc<[Vec<interner::Id>]>) -> Op,
+    ) {
+        let found: Vec<_> = keys.iter().map(|k| self.find_methods(k)).collect();
+        self.code
+            .push(op(found.iter().map(|(_, ctors)| ctors.clone()).collect()));
+        for (idx, _) in found.iter().rev() {
+            self.code.push(Op::Access(*idx));
+        }
+    }
+
+    /// Push the methods of the user-declared instance `instance`, which are
+    /// in scope in their own definitions, and `body`, which is compiled by
+    /// `push_body` with them in scope.
+    fn push_instance(
+        &mut self,
+        instance: &Instance,
+        methods: &Expr,
+        push_body: impl FnOnce(&mut Self),
+    ) {
+        self.instances.push((instance.clone(), self.depth));
+        self.binding(1, |c| {
+            push_body(c);
+            c.code.push(Op::Update());
+            c.push(methods);
+        });
+        self.instances.pop();
+        self.code.push(Op::Dummy());
     }
 
     /// compiles the syntax tree to a "bytecode" representation.
@@ -49,110 +180,415 @@ impl Compiler {
         self.code
     }
 
+    fn compile_tree(mut self, t: &Tree) -> Stack<Op> {
+        self.push_tree(t);
+        self.code
+    }
+
     fn push(&mut self, e: &Expr) {
-        match e {
+        // The cast runs after the code which computes the value.
+        if let Some(cast) = self.casts.get(&(e as *const Expr)) {
+            self.code.push(Op::Cast(cast.clone()));
+        }
+        crate::with_large_stack(|| match e {
             Expr::Ascribed(e, _t) => self.push(e),
             Expr::App(fnc, arg) => {
                 let code = std::mem::take(&mut self.code);
                 self.code.push(Op::Apply());
-                let mut fnc = fnc.clone();
+                let mut fnc: &Expr = fnc;
                 // The args passed to the function, in reverse order.
-                let mut args_rev = vec![arg.clone()];
+                let mut args_rev: Vec<&Expr> = vec![arg];
                 // If the function is another app, then we treat the whole thing
                 // as a multi-arg function call.
-                while let Expr::App(nxt_fnc, nxt_arg) = *fnc {
+                while let Expr::App(nxt_fnc, nxt_arg) = fnc {
+                    if self.is_cast(fnc) {
+                        break;
+                    }
                     args_rev.push(nxt_arg);
                     fnc = nxt_fnc;
                 }
-                self.push(&fnc);
+                // Wrapping or unwrapping a newtype leaves its argument as it
+                // is, so that argument is the function being called.
+                if matches!(fnc.unlocated(), Expr::Wrap(_) | Expr::Unwrap(_)) && !self.is_cast(fnc)
+                {
+                    let inner = args_rev.pop().expect("applied to an argument");
+                    if args_rev.is_empty() {
+                        self.code = code;
+                        self.push(inner);
+                        return;
+                    }
+                    fnc = inner;
+                }
+                let saturated = saturated_op(fnc, args_rev.len());
+                if let Some(op) = saturated.filter(|_| self.overload(fnc).is_none()) {
+                    // A constructor or primitive applied to all of its
+                    // arguments doesn't need a closure or a call, so we run
+                    // it directly on its (left-to-right evaluated) arguments.
+                    self.code = code;
+                    self.code.push(op);
+                    for a in args_rev.iter() {
+                        self.push(a);
+                    }
+                    return;
+                }
+                self.frames += 1;
+                self.push(fnc);
                 for a in args_rev.iter().rev() {
                     self.push(a);
                 }
+                self.frames -= 1;
                 self.code.push(Op::PushRetAddr(code));
             }
-            Expr::Lambda(_, _, body) => {
-                let closure_code = match body.as_ref() {
-                    // If the lambda body is another lambda, then we treat the
-                    // whole thing as a single multi-arg lambda. This avoids the
-                    // creation of pointless nested `Op::Closure`s by just
-                    // grabbing all the arguments we need at once.
-                    Expr::Lambda(_, _, body) => {
-                        let mut grabs = 2;
-                        let mut body = body.clone();
-                        while let Expr::Lambda(_, _, nxt_body) = *body {
-                            grabs += 1;
-                            body = nxt_body
-                        }
-                        let mut code = Compiler::for_tail().compile(&body);
-                        for _ in 0..grabs {
-                            code.push(Op::Grab());
-                        }
-                        code
+            // `Show.show` at a type involving user-declared instances uses
+            // their methods.
+            Expr::Builtin(Builtin::ShowShow) if self.overload(e).is_some() => {
+                match self.overload(e).cloned() {
+                    // The method is the function.
+                    Some(Overload::Method(key)) => {
+                        self.code.push(Op::Field(interner::Id::new("show")));
+                        self.code.push(Op::Access(self.find_methods(&key).0));
+                    }
+                    // A function which shows its argument using the
+                    // instances' methods.
+                    Some(Overload::Structural(keys)) => {
+                        let code = std::mem::take(&mut self.code);
+                        self.code.push(Op::Return());
+                        self.binding(1, |c| c.push_with_methods(&keys, Op::Show));
+                        self.code.push(Op::Access(0));
+                        self.code.push(Op::Grab());
+                        let body = std::mem::replace(&mut self.code, code);
+                        self.code.push(Op::Closure(body));
+                    }
+                    None => unreachable!("the built-in is overloaded"),
+                }
+            }
+            // Built-ins which look up keys of a type with a user-declared
+            // instance of `Ord` use its method to compare them, and those
+            // which look up compound keys use a function which compares them
+            // structurally, standing in for the methods.
+            Expr::Builtin(b) if b.is_keyed() && self.overload(e).is_some() => {
+                let arity = b.arity();
+                let code = std::mem::take(&mut self.code);
+                self.code.push(Op::Return());
+                self.code.push(Op::Keyed(*b));
+                match self.overload(e).cloned() {
+                    Some(Overload::Method(key)) => {
+                        let methods = self.binding(arity, |c| c.find_methods(&key).0);
+                        self.code.push(Op::Access(methods));
                     }
-                    _ => {
-                        let mut code = Compiler::for_tail().compile(body);
-                        code.push(Op::Grab());
-                        code
+                    Some(Overload::Structural(keys)) => {
+                        self.code
+                            .push(Op::Record(vec![interner::Id::new("compare")]));
+                        let mut compare = Stack::new();
+                        std::mem::swap(&mut self.code, &mut compare);
+                        self.code.push(Op::Return());
+                        let ranks = self.ranks.clone();
+                        self.binding(arity + 2, |c| {
+                            c.push_with_methods(&keys, |ctors| Op::Compare(ctors, ranks))
+                        });
+                        self.code.push(Op::Access(0));
+                        self.code.push(Op::Access(1));
+                        self.code.push(Op::Grab());
+                        self.code.push(Op::Grab());
+                        std::mem::swap(&mut self.code, &mut compare);
+                        self.code.push(Op::Closure(compare));
+                    }
+                    None => unreachable!("the built-in is overloaded"),
+                }
+                for i in 0..arity {
+                    self.code.push(Op::Access(i));
+                }
+                for _ in 0..arity {
+                    self.code.push(Op::Grab());
+                }
+                let body = std::mem::replace(&mut self.code, code);
+                self.code.push(Op::Closure(body));
+            }
+            // Most built-ins are defined in uplp, so we compile their
+            // definitions in place.
+            Expr::Builtin(b) => match b.definition() {
+                Definition::Source(e) => self.push(&e),
+                // Primitives which don't take any arguments are just values.
+                Definition::Primitive(0) => self.code.push(Op::Primitive(*b)),
+                Definition::Primitive(arity) => self.push_primitive(Op::Primitive(*b), arity),
+            },
+            Expr::Constructor(ctor, 0) => {
+                self.code.push(Op::Const(Val::construct(*ctor, Vec::new())))
+            }
+            Expr::Constructor(ctor, arity) => {
+                self.push_primitive(Op::Construct(*ctor, *arity), *arity)
+            }
+            Expr::Field(record, field) => {
+                self.code.push(Op::Field(*field));
+                self.push(record);
+            }
+            Expr::Index(array, index) => {
+                self.code.push(Op::Index());
+                self.push(index);
+                self.push(array);
+            }
+            // An instance's methods are bound in the environment, like a
+            // recursive `let`, so that the overloaded operations which need
+            // them can find them.
+            Expr::Instance(instance, methods, body) => {
+                self.code.push(Op::EndLet());
+                self.push_instance(instance, methods, |c| c.push(body));
+            }
+            Expr::Lambda(_, _, body) => {
+                // If the lambda body is another lambda, then we treat the
+                // whole thing as a single multi-arg lambda. This avoids the
+                // creation of pointless nested `Op::Closure`s by just
+                // grabbing all the arguments we need at once.
+                let mut grabs = 1;
+                let mut body: &Expr = body;
+                while let Expr::Lambda(_, _, nxt_body) = body {
+                    if self.is_cast(body) {
+                        break;
                     }
-                };
+                    grabs += 1;
+                    body = nxt_body
+                }
+                let mut compiler = self.for_tail();
+                compiler.depth += grabs;
+                let mut closure_code = compiler.compile(body);
+                for _ in 0..grabs {
+                    closure_code.push(Op::Grab());
+                }
                 self.code.push(Op::Closure(closure_code))
             }
             Expr::Let(false, _, binding, body) => {
                 self.code.push(Op::EndLet());
-                self.push(body);
+                self.binding(1, |c| c.push(body));
                 self.code.push(Op::Grab());
                 self.push(binding);
             }
             Expr::Let(true, _, binding, body) => {
                 self.code.push(Op::EndLet());
-                self.push(body);
-                self.code.push(Op::Update());
-                self.push(binding);
+                self.binding(1, |c| {
+                    c.push(body);
+                    c.code.push(Op::Update());
+                    c.push(binding);
+                });
                 self.code.push(Op::Dummy());
             }
             Expr::Literal(v) => self.code.push(Op::Const(v.clone())),
+            Expr::Measured(n, _) => self.code.push(Op::Const(Val::Num(*n))),
+            Expr::Located(_, e) => self.push(e),
             Expr::IfThenElse(cond, thn, els) => {
-                let thn_ops = Compiler::for_branch().compile(thn);
-                let els_ops = Compiler::for_branch().compile(els);
+                let thn_ops = self.for_branch().compile(thn);
+                let els_ops = self.for_branch().compile(els);
                 self.code.push(Op::Sel(thn_ops, els_ops));
                 self.push(cond);
             }
+            Expr::Match(scrutinee, arms) => {
+                // The scrutinee is bound in the environment while matching, so
+                // that the decision tree can access it as many times as it
+                // needs to.
+                self.code.push(Op::EndLet());
+                self.binding(1, |c| c.push_tree(&matching::decision_tree(arms)));
+                self.code.push(Op::Grab());
+                self.push(scrutinee);
+            }
             Expr::Op(l, op, r) => {
-                self.code.push(Op::Binary(*op));
+                match self.overload(e).cloned() {
+                    None => self.code.push(Op::Binary(*op)),
+                    Some(Overload::Method(key)) => {
+                        // `compare` returns a negative, zero or positive `Int`,
+                        // which is compared with zero instead.
+                        if key.0 == Class::Ord {
+                            self.code.push(Op::Binary(*op));
+                            self.code.push(Op::Const(Val::Int(0)));
+                        }
+                        self.code
+                            .push(Op::Method(interner::Id::new(method_name(*op))));
+                        self.code.push(Op::Access(self.find_methods(&key).0));
+                    }
+                    Some(Overload::Structural(keys)) if *op == BinaryOp::Eq => {
+                        self.push_with_methods(&keys, Op::Equal)
+                    }
+                    // Like a `compare` method.
+                    Some(Overload::Structural(keys)) => {
+                        self.code.push(Op::Binary(*op));
+                        self.code.push(Op::Const(Val::Int(0)));
+                        let ranks = self.ranks.clone();
+                        self.push_with_methods(&keys, |ctors| Op::Compare(ctors, ranks));
+                    }
+                }
                 self.push(r);
                 self.push(l);
             }
+            Expr::Propagate(e) => {
+                self.code.push(Op::Propagate(self.frames));
+                self.push(e);
+            }
+            Expr::Refined(contract, predicate, body) => {
+                self.push(body);
+                self.code.push(Op::Check(Rc::new(contract.clone())));
+                self.push(predicate);
+            }
+            Expr::Record(fields) => {
+                self.code
+                    .push(Op::Record(fields.iter().map(|(f, _)| *f).collect()));
+                for (_, e) in fields.iter().rev() {
+                    self.push(e);
+                }
+            }
+            Expr::TypeDecl(decl, body) => self.declaring(decl, |c| c.push(body)),
+            Expr::NewtypeDecl(_, body) => self.push(body),
+            // Newtypes are erased, so wrapping & unwrapping does nothing.
+            Expr::Wrap(_) | Expr::Unwrap(_) => {
+                let mut code = Stack::new();
+                code.push(Op::Return());
+                code.push(Op::Access(0));
+                code.push(Op::Grab());
+                self.code.push(Op::Closure(code))
+            }
             Expr::Var(_, i) => {
                 self.code.push(Op::Access(*i));
             }
+        })
+    }
+
+    /// Pushes a function which grabs each of its `arity` arguments and then
+    /// runs `op` on them.
+    fn push_primitive(&mut self, op: Op, arity: usize) {
+        let mut code = Stack::new();
+        code.push(Op::Return());
+        code.push(op);
+        for i in 0..arity {
+            code.push(Op::Access(i));
+        }
+        for _ in 0..arity {
+            code.push(Op::Grab());
+        }
+        self.code.push(Op::Closure(code))
+    }
+
+    fn push_tree(&mut self, t: &Tree) {
+        match t {
+            Tree::Leaf { bindings, body } => {
+                for _ in bindings.iter() {
+                    self.code.push(Op::EndLet());
+                }
+                self.binding(bindings.len(), |c| c.push(body));
+                // Each binding pushes the scrutinee one place further back in
+                // the environment.
+                for (idx, occurrence) in bindings.iter().enumerate().rev() {
+                    self.code.push(Op::Grab());
+                    for field in occurrence.iter().rev() {
+                        self.code.push(Op::Project(*field));
+                    }
+                    self.code.push(Op::Access(idx));
+                }
+            }
+            Tree::Switch {
+                occurrence,
+                cases,
+                default,
+            } => {
+                let cases = cases
+                    .iter()
+                    .map(|(case, t)| {
+                        let code = self.for_branch().compile_tree(t);
+                        (case.clone(), code)
+                    })
+                    .collect();
+                let default = default.as_ref().map(|t| self.for_branch().compile_tree(t));
+                self.code.push(Op::Switch(cases, default));
+                for field in occurrence.iter().rev() {
+                    self.code.push(Op::Project(*field));
+                }
+                self.code.push(Op::Access(0));
+            }
         }
     }
 
     fn push_tail(&mut self, e: &Expr) {
-        match e {
-            Expr::App(f, arg) => {
+        crate::with_large_stack(|| match e {
+            // A cast value has to be returned, so that it can be cast first.
+            a if self.is_cast(a) => {
+                self.code.push(Op::Return());
+                self.push(a);
+            }
+            Expr::App(f, arg) if !is_saturated(e) => {
                 self.push_tail(f);
                 self.push(arg);
             }
             Expr::Lambda(_, _, a) => {
-                self.push_tail(a);
+                self.binding(1, |c| c.push_tail(a));
                 self.code.push(Op::Grab());
             }
             Expr::Let(false, _, a, b) => {
-                self.push_tail(b);
+                self.binding(1, |c| c.push_tail(b));
                 self.code.push(Op::Grab());
                 self.push(a);
             }
             Expr::Let(true, _, a, b) => {
-                self.push_tail(b);
-                self.code.push(Op::Update());
-                self.push(a);
+                self.binding(1, |c| {
+                    c.push_tail(b);
+                    c.code.push(Op::Update());
+                    c.push(a);
+                });
                 self.code.push(Op::Dummy());
             }
+            Expr::Instance(instance, methods, body) => {
+                self.push_instance(instance, methods, |c| c.push_tail(body));
+            }
+            Expr::Refined(contract, predicate, body) => {
+                self.push_tail(body);
+                self.code.push(Op::Check(Rc::new(contract.clone())));
+                self.push(predicate);
+            }
+            Expr::TypeDecl(decl, e) => self.declaring(decl, |c| c.push_tail(e)),
+            Expr::Located(_, e) | Expr::NewtypeDecl(_, e) => self.push_tail(e),
             a => {
                 self.code.push(Op::Return());
                 self.push(a);
             }
-        }
+        })
+    }
+}
+
+/// Add the ranks of the constructors of `decl` to `ranks`.
+fn add_ranks(ranks: &mut Ranks, decl: &DataDecl) {
+    ranks.extend(decl.variants.iter().enumerate().map(|(i, v)| (v.name, i)));
+}
+
+/// The name of the method which implements `op`.
+fn method_name(op: BinaryOp) -> &'static str {
+    match op {
+        BinaryOp::Add => "add",
+        BinaryOp::Div => "div",
+        BinaryOp::Mul => "mul",
+        BinaryOp::Sub => "sub",
+        BinaryOp::Eq => "eq",
+        BinaryOp::Lt | BinaryOp::Le | BinaryOp::Gt | BinaryOp::Ge => "compare",
+        BinaryOp::And
+        | BinaryOp::BitAnd
+        | BinaryOp::BitOr
+        | BinaryOp::BitXor
+        | BinaryOp::Shl
+        | BinaryOp::Shr => unreachable!("`{op:?}` isn't overloaded"),
+    }
+}
+
+/// The op which runs `fnc` directly, if it's a constructor or primitive
+/// built-in which takes exactly `args` arguments.
+fn saturated_op(fnc: &Expr, args: usize) -> Option<Op> {
+    match fnc.unlocated() {
+        Expr::Constructor(ctor, arity) if *arity == args => Some(Op::Construct(*ctor, *arity)),
+        Expr::Builtin(b) if b.source().is_none() && b.arity() == args => Some(Op::Primitive(*b)),
+        _ => None,
+    }
+}
+
+/// Whether `e` applies a constructor or primitive to all of its arguments.
+fn is_saturated(e: &Expr) -> bool {
+    let mut args = 0;
+    let mut fnc = e.unlocated();
+    while let Expr::App(f, _) = fnc {
+        args += 1;
+        fnc = f.unlocated();
     }
+    saturated_op(fnc, args).is_some()
 }
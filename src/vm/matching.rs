@@ -0,0 +1,158 @@
+//! Compilation of `match` expressions to decision trees, following the
+//! approach from Jules Jacobs' [How to compile pattern matching](https://julesjacobs.com/notes/patternmatching/patternmatching.pdf).
+
+use crate::ast::{Expr, Pattern};
+
+use super::Case;
+
+/// The position of a value within the scrutinee, as the path of constructor
+/// field indices which leads to it.
+pub(super) type Occurrence = Vec<usize>;
+
+pub(super) enum Tree<'a> {
+    /// Evaluate `body` with the values at each of the `bindings` bound, in
+    /// order.
+    Leaf {
+        bindings: Vec<Occurrence>,
+        body: &'a Expr,
+    },
+    /// Test the value at `occurrence` against each of the `cases` in turn,
+    /// falling back to `default` if none of them match.
+    Switch {
+        occurrence: Occurrence,
+        cases: Vec<(Case, Tree<'a>)>,
+        default: Option<Box<Tree<'a>>>,
+    },
+}
+
+/// Build a decision tree which picks the first of the `arms` whose pattern
+/// matches the scrutinee. Every value is assumed to be matched by some arm,
+/// since exhaustiveness has already been checked.
+pub(super) fn decision_tree(arms: &[(Pattern, Expr)]) -> Tree<'_> {
+    let clauses = arms
+        .iter()
+        .map(|(p, body)| Clause {
+            tests: test(Vec::new(), p).into_iter().collect(),
+            bindings: bindings(Vec::new(), p),
+            body,
+        })
+        .collect();
+    build(clauses)
+}
+
+#[derive(Clone)]
+struct Clause<'a> {
+    /// The tests which must all pass for the clause to be chosen.
+    tests: Vec<(Occurrence, &'a Pattern)>,
+    bindings: Vec<Occurrence>,
+    body: &'a Expr,
+}
+
+impl<'a> Clause<'a> {
+    /// Remove the test against `occurrence`, if the clause has one.
+    fn take_test(&mut self, occurrence: &Occurrence) -> Option<&'a Pattern> {
+        let idx = self.tests.iter().position(|(o, _)| o == occurrence)?;
+        Some(self.tests.remove(idx).1)
+    }
+}
+
+fn build(mut clauses: Vec<Clause<'_>>) -> Tree<'_> {
+    let occurrence = match clauses[0].tests.first() {
+        Some((o, _)) => o.clone(),
+        None => {
+            let first = clauses.swap_remove(0);
+            return Tree::Leaf {
+                bindings: first.bindings,
+                body: first.body,
+            };
+        }
+    };
+
+    let mut cases: Vec<(Case, Vec<Clause>)> = Vec::new();
+    for clause in clauses.iter() {
+        if let Some((_, p)) = clause.tests.iter().find(|(o, _)| o == &occurrence) {
+            let case = case_of(p);
+            if !cases.iter().any(|(c, _)| c == &case) {
+                cases.push((case, Vec::new()));
+            }
+        }
+    }
+
+    let mut default = Vec::new();
+    for mut clause in clauses {
+        match clause.take_test(&occurrence) {
+            Some(p) => {
+                let case = case_of(p);
+                if let Some((_, sub)) = cases.iter_mut().find(|(c, _)| c == &case) {
+                    if let Pattern::Constructor(_, args) = p {
+                        for (idx, arg) in args.iter().enumerate() {
+                            let mut o = occurrence.clone();
+                            o.push(idx);
+                            clause.tests.extend(test(o, arg));
+                        }
+                    }
+                    sub.push(clause);
+                }
+            }
+            None => {
+                for (_, sub) in cases.iter_mut() {
+                    sub.push(clause.clone());
+                }
+                default.push(clause);
+            }
+        }
+    }
+
+    Tree::Switch {
+        occurrence,
+        cases: cases
+            .into_iter()
+            .map(|(case, sub)| (case, build(sub)))
+            .collect(),
+        default: if default.is_empty() {
+            None
+        } else {
+            Some(Box::new(build(default)))
+        },
+    }
+}
+
+/// The test that the value at `occurrence` matches `p`, unless `p` matches
+/// anything.
+fn test(occurrence: Occurrence, p: &Pattern) -> Option<(Occurrence, &Pattern)> {
+    match p {
+        // Newtypes are erased, so their values are those of the underlying
+        // type.
+        Pattern::Newtype(_, p) => test(occurrence, p),
+        Pattern::Var(_) | Pattern::Wildcard => None,
+        Pattern::Constructor(..) | Pattern::Literal(_) => Some((occurrence, p)),
+    }
+}
+
+fn case_of(p: &Pattern) -> Case {
+    match p {
+        Pattern::Constructor(c, _) => Case::Constructor(*c),
+        Pattern::Literal(l) => Case::Literal(l.clone()),
+        Pattern::Newtype(..) | Pattern::Var(_) | Pattern::Wildcard => {
+            unreachable!("newtypes & wildcards are never tested")
+        }
+    }
+}
+
+/// The occurrences of each of the variables bound by `p`, from left to right.
+fn bindings(occurrence: Occurrence, p: &Pattern) -> Vec<Occurrence> {
+    match p {
+        Pattern::Constructor(_, args) => args
+            .iter()
+            .enumerate()
+            .flat_map(|(idx, arg)| {
+                let mut o = occurrence.clone();
+                o.push(idx);
+                bindings(o, arg)
+            })
+            .collect(),
+        Pattern::Newtype(_, p) => bindings(occurrence, p),
+        Pattern::Var(_) => vec![occurrence],
+        Pattern::Literal(_) | Pattern::Wildcard => Vec::new(),
+    }
+}
@@ -1,8 +1,19 @@
-use std::{cell::RefCell, mem};
+use std::{cell::RefCell, cmp::Ordering, collections::HashMap, mem, rc::Rc};
 
-use crate::{ast::BinaryOp, env::Env, error::EvaluationError, values::Val};
+use crate::{
+    ast::{BinaryOp, Contract},
+    bignum::{BigInt, Rational},
+    builtins::{self, Builtin},
+    env::Env,
+    error::EvaluationError,
+    interner,
+    typ::Cast,
+    values::{Comparison, Equality, Key, Progress, Ranks, Search, Showing, Val},
+};
 
+mod cast;
 mod compiler;
+mod matching;
 pub mod stack;
 
 use self::stack::Stack;
@@ -14,18 +25,192 @@ pub enum Op {
     Access(usize),
     Apply(),
     Binary(BinaryOp),
+    /// Check that the value on top of the stack has the type it's cast to,
+    /// wrapping it if it's a function.
+    Cast(Rc<Cast>),
+    /// Check that the predicate whose value is on top of the stack held,
+    /// failing with the value of the innermost variable if it didn't.
+    Check(Rc<Contract>),
     Closure(Stack<Op>),
+    /// Compare the order of the two values beneath the methods of some
+    /// instances of `Ord`, like `Op::Equal`, giving a negative, zero or
+    /// positive `Int` like a `compare` method. Values of data types are
+    /// ordered by the ranks of their constructors first.
+    Compare(Rc<[Vec<interner::Id>]>, Rc<Ranks>),
     Const(Val),
+    Construct(interner::Id, usize),
     Dummy(),
     EndLet(),
+    /// Compare the two values beneath the methods of some instances of `Eq`,
+    /// which are on top of the stack, calling them for the values of their
+    /// types. The constructors of each one's type are given in the same
+    /// order as the methods were pushed.
+    Equal(Rc<[Vec<interner::Id>]>),
+    Field(interner::Id),
     Grab(),
+    Index(),
     Join(),
+    /// Run a built-in which looks up a key in a map or set on the arguments
+    /// beneath the methods of an instance of `Ord` for the key's type, which
+    /// is on top of the stack, calling its `compare` method. Compound keys
+    /// are compared structurally by a record standing in for the methods.
+    Keyed(Builtin),
+    /// Call the method with the given name from the record of an instance's
+    /// methods on top of the stack on the two values beneath it.
+    Method(interner::Id),
+    Project(usize),
+    /// Unwrap the `Some` or `Ok` on top of the stack, or return the `None` or
+    /// `Err` from the current function. The argument is the number of return
+    /// addresses pushed by the function which are still on the stack.
+    Propagate(usize),
+    /// Run a primitive built-in on the arguments on top of the stack.
+    Primitive(Builtin),
     PushRetAddr(Stack<Op>),
+    Record(Vec<interner::Id>),
+    /// Carry on with a comparison which was paused to call a `compare`
+    /// method, whose result is on top of the stack.
+    ResumeCompare(Comparison, Rc<Methods>),
+    /// Carry on with a comparison which was paused to call an `eq` method,
+    /// whose result is on top of the stack.
+    ResumeEqual(Equality, Rc<Methods>),
+    /// Carry on with a built-in which was paused to call a `compare` method,
+    /// whose result is on top of the stack.
+    ResumeKeyed(Box<Keyed>),
+    /// Carry on showing a value, which was paused to call a `show` method,
+    /// whose result is on top of the stack.
+    ResumeShow(Showing, Rc<Methods>),
     Return(),
     Sel(Stack<Op>, Stack<Op>),
+    /// Show the value beneath the methods of some instances of `Show`, like
+    /// `Op::Equal`.
+    Show(Rc<[Vec<interner::Id>]>),
+    Switch(Vec<(Case, Stack<Op>)>, Option<Stack<Op>>),
     Update(),
 }
 
+/// A test performed by `Op::Switch` against the value on top of the stack.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Case {
+    Constructor(interner::Id),
+    Literal(Val),
+}
+
+impl Case {
+    fn matches(&self, v: &Val) -> Result<bool, EvaluationError> {
+        match (self, v) {
+            (Case::Constructor(c), Val::Data { ctor, .. }) => Ok(c == ctor),
+            (Case::Constructor(c), Val::List(l)) => Ok(if l.is_empty() {
+                *c == *builtins::NIL
+            } else {
+                *c == *builtins::CONS
+            }),
+            (Case::Literal(l), v) => l.try_eq(v),
+            (Case::Constructor(c), v) => Err(EvaluationError::Internal(format!(
+                "expected {} but got {:?}",
+                c.name(),
+                v
+            ))),
+        }
+    }
+}
+
+/// The methods of some user-declared instances of a class, keyed by the
+/// constructors of their types.
+type Methods = HashMap<interner::Id, Val>;
+
+/// A built-in which looks up a key of a user type in a map or set, which is
+/// run by searching with the `compare` method of the type's instance of `Ord`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Keyed {
+    builtin: Builtin,
+    key: Val,
+    /// The value to insert, for `Map.insert`.
+    value: Option<Val>,
+    compare: Val,
+    search: Searching,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Searching {
+    Map(Search<Val>),
+    Set(Search<()>),
+}
+
+impl Keyed {
+    /// Start running `builtin` on `args`, using the `methods` of an instance
+    /// of `Ord` for the key's type.
+    fn new(builtin: Builtin, args: Vec<Val>, methods: Val) -> Result<Self, EvaluationError> {
+        let mut args = args.into_iter();
+        let mut arg = || {
+            args.next().ok_or_else(|| {
+                EvaluationError::Internal(format!("too few arguments to {}", builtin.name()))
+            })
+        };
+        let key = arg()?;
+        let value = match builtin {
+            Builtin::MapInsert => Some(arg()?),
+            _ => None,
+        };
+        let search = match builtin {
+            Builtin::MapDelete | Builtin::MapInsert | Builtin::MapLookup => {
+                Searching::Map(Search::new(&arg()?.as_map()?))
+            }
+            Builtin::SetDelete | Builtin::SetInsert | Builtin::SetMember => {
+                Searching::Set(Search::new(&arg()?.as_set()?))
+            }
+            b => {
+                return Err(EvaluationError::Internal(format!(
+                    "{} doesn't look up keys",
+                    b.name()
+                )))
+            }
+        };
+        Ok(Keyed {
+            builtin,
+            key,
+            value,
+            compare: methods.field(interner::Id::new("compare"))?,
+            search,
+        })
+    }
+
+    /// The key in the map or set to compare the key being looked up with
+    /// next, or `None` if the search is over.
+    fn next(&self) -> Option<Val> {
+        match &self.search {
+            Searching::Map(s) => s.next().cloned().map(Val::from),
+            Searching::Set(s) => s.next().cloned().map(Val::from),
+        }
+    }
+
+    /// Carry on, given how the key being looked up compares with the one from
+    /// `next`.
+    fn compared(&mut self, ord: Ordering) {
+        match &mut self.search {
+            Searching::Map(s) => s.compared(ord),
+            Searching::Set(s) => s.compared(ord),
+        }
+    }
+
+    /// The built-in's result, once the search is over.
+    fn finish(self) -> Result<Val, EvaluationError> {
+        let key = Key::try_from(self.key)?;
+        Ok(match (self.search, self.value) {
+            (Searching::Map(s), Some(v)) => Val::Map(s.insert(key, v)),
+            (Searching::Map(s), None) if self.builtin == Builtin::MapDelete => Val::Map(s.remove()),
+            (Searching::Map(s), None) => match s.value() {
+                Some(v) => Val::construct(*builtins::SOME, vec![v.clone()]),
+                None => Val::construct(*builtins::NONE, Vec::new()),
+            },
+            (Searching::Set(s), _) => match self.builtin {
+                Builtin::SetDelete => Val::Set(s.remove()),
+                Builtin::SetInsert => Val::Set(s.insert(key, ())),
+                _ => Val::Bool(s.value().is_some()),
+            },
+        })
+    }
+}
+
 pub struct VirtualMachine {
     code: Stack<Op>,
     env: Env<RefCell<Val>>,
@@ -39,6 +224,161 @@ impl VirtualMachine {
         VirtualMachine { code, env, stack }
     }
 
+    /// Call the function `f` on `args`, carrying on with the rest of the code
+    /// once it returns.
+    fn call(&mut self, f: Val, args: Vec<Val>) {
+        let code = mem::replace(&mut self.code, Stack::from_stacked_vec(vec![Op::Apply()]));
+        self.stack.push(Marker::Env(self.env.clone()));
+        self.stack.push(Marker::Code(code));
+        self.stack.push(Marker::AppDelim);
+        for arg in args.into_iter().rev() {
+            self.stack.push(Marker::Val(arg));
+        }
+        self.stack.push(Marker::Val(f));
+    }
+
+    /// Pop the methods of the instances whose types have the constructors
+    /// `ctors`, which were pushed in the same order.
+    fn pop_methods(&mut self, ctors: &[Vec<interner::Id>]) -> Result<Rc<Methods>, EvaluationError> {
+        let mut methods = HashMap::new();
+        for ctors in ctors.iter().rev() {
+            let m = self.stack.force_pop_val()?;
+            methods.extend(ctors.iter().map(|c| (*c, m.clone())));
+        }
+        Ok(Rc::new(methods))
+    }
+
+    /// Carry on comparing with `cmp`, calling the methods in `methods` for the
+    /// values of their types.
+    fn resume_compare(
+        &mut self,
+        mut cmp: Comparison,
+        methods: Rc<Methods>,
+    ) -> Result<(), EvaluationError> {
+        match cmp.resume(|v| has_method(&methods, v))? {
+            Progress::Done(ord) => self.stack.push(Marker::Val(Val::Int(ord as i64))),
+            Progress::Call((l, r)) => {
+                let method = find_method(&methods, &l, "compare")?;
+                self.code.push(Op::ResumeCompare(cmp, methods));
+                self.call(method, vec![l, r]);
+            }
+        }
+        Ok(())
+    }
+
+    /// Carry on comparing with `eq`, calling the methods in `methods` for
+    /// the values of their types.
+    fn resume_equal(
+        &mut self,
+        mut eq: Equality,
+        methods: Rc<Methods>,
+    ) -> Result<(), EvaluationError> {
+        match eq.resume(|v| has_method(&methods, v))? {
+            Progress::Done(res) => self.stack.push(Marker::Val(Val::Bool(res))),
+            Progress::Call((l, r)) => {
+                let method = find_method(&methods, &l, "eq")?;
+                self.code.push(Op::ResumeEqual(eq, methods));
+                self.call(method, vec![l, r]);
+            }
+        }
+        Ok(())
+    }
+
+    /// Carry on running `keyed`, calling its `compare` method for each key
+    /// it passes.
+    fn resume_keyed(&mut self, keyed: Keyed) -> Result<(), EvaluationError> {
+        match keyed.next() {
+            Some(k) => {
+                let (compare, key) = (keyed.compare.clone(), keyed.key.clone());
+                self.code.push(Op::ResumeKeyed(Box::new(keyed)));
+                self.call(compare, vec![key, k]);
+            }
+            None => self.stack.push(Marker::Val(keyed.finish()?)),
+        }
+        Ok(())
+    }
+
+    /// Carry on showing with `showing`, calling the methods in `methods` for
+    /// the values of their types.
+    fn resume_show(
+        &mut self,
+        mut showing: Showing,
+        methods: Rc<Methods>,
+    ) -> Result<(), EvaluationError> {
+        match showing.resume(|v| has_method(&methods, v)) {
+            Progress::Done(s) => self.stack.push(Marker::Val(Val::Str(s.into()))),
+            Progress::Call(v) => {
+                let method = find_method(&methods, &v, "show")?;
+                self.code.push(Op::ResumeShow(showing, methods));
+                self.call(method, vec![v]);
+            }
+        }
+        Ok(())
+    }
+
+    fn binary(&self, op: BinaryOp, l: Val, r: Val) -> Result<Val, EvaluationError> {
+        match op {
+            BinaryOp::Add | BinaryOp::Mul | BinaryOp::Sub | BinaryOp::Div
+                if matches!(l, Val::Int(_)) =>
+            {
+                int_binary(op, l.as_int()?, r.as_int()?)
+            }
+            BinaryOp::Add | BinaryOp::Mul | BinaryOp::Sub | BinaryOp::Div
+                if matches!(l, Val::BigInt(_)) =>
+            {
+                let (l, r) = (l.as_big_int()?, r.as_big_int()?);
+                big_int_binary(op, &l, &r)
+            }
+            BinaryOp::Add | BinaryOp::Mul | BinaryOp::Sub | BinaryOp::Div
+                if matches!(l, Val::Rational(_)) =>
+            {
+                let (l, r) = (l.as_rational()?, r.as_rational()?);
+                rational_binary(op, &l, &r)
+            }
+            op if op.is_bitwise() => int_binary(op, l.as_int()?, r.as_int()?),
+            BinaryOp::Add => {
+                let (l, r) = (l.as_num()?, r.as_num()?);
+                Ok(Val::Num(l + r))
+            }
+            BinaryOp::Mul => {
+                let (l, r) = (l.as_num()?, r.as_num()?);
+                Ok(Val::Num(l * r))
+            }
+            BinaryOp::Sub => {
+                let (l, r) = (l.as_num()?, r.as_num()?);
+                Ok(Val::Num(l - r))
+            }
+            BinaryOp::Div => {
+                let (l, r) = (l.as_num()?, r.as_num()?);
+                if r == 0.0 {
+                    Err(EvaluationError::DivisionByZero)
+                } else {
+                    Ok(Val::Num(l / r))
+                }
+            }
+            BinaryOp::Eq => Ok(Val::Bool(l.try_eq(&r)?)),
+            BinaryOp::Lt => Ok(Val::Bool(compare(l, r)? == Some(Ordering::Less))),
+            BinaryOp::Le => Ok(Val::Bool(matches!(
+                compare(l, r)?,
+                Some(Ordering::Less | Ordering::Equal)
+            ))),
+            BinaryOp::Gt => Ok(Val::Bool(compare(l, r)? == Some(Ordering::Greater))),
+            BinaryOp::Ge => Ok(Val::Bool(matches!(
+                compare(l, r)?,
+                Some(Ordering::Greater | Ordering::Equal)
+            ))),
+            BinaryOp::And => {
+                let (l, r) = (l.as_bool()?, r.as_bool()?);
+                Ok(Val::Bool(l && r))
+            }
+            BinaryOp::BitAnd
+            | BinaryOp::BitOr
+            | BinaryOp::BitXor
+            | BinaryOp::Shl
+            | BinaryOp::Shr => unreachable!("handled with the other bitwise operators"),
+        }
+    }
+
     pub fn evaluate(&mut self) -> Result<Val, EvaluationError> {
         while let Some(o) = self.code.pop() {
             match o {
@@ -60,43 +400,56 @@ impl VirtualMachine {
                     let r = self.stack.force_pop_val()?;
                     let l = self.stack.force_pop_val()?;
 
-                    let res = match op {
-                        BinaryOp::Add => {
-                            let (l, r) = (l.as_num()?, r.as_num()?);
-                            Ok(Val::Num(l + r))
-                        }
-                        BinaryOp::Mul => {
-                            let (l, r) = (l.as_num()?, r.as_num()?);
-                            Ok(Val::Num(l * r))
-                        }
-                        BinaryOp::Sub => {
-                            let (l, r) = (l.as_num()?, r.as_num()?);
-                            Ok(Val::Num(l - r))
-                        }
-                        BinaryOp::Div => {
-                            let (l, r) = (l.as_num()?, r.as_num()?);
-                            if r == 0.0 {
-                                Err(EvaluationError::DivisionByZero)
-                            } else {
-                                Ok(Val::Num(l / r))
-                            }
-                        }
-                        BinaryOp::Eq => Ok(Val::Bool(l.try_eq(&r)?)),
-                        BinaryOp::And => {
-                            let (l, r) = (l.as_bool()?, r.as_bool()?);
-                            Ok(Val::Bool(l && r))
-                        }
-                    }?;
+                    let res = self.binary(op, l, r)?;
 
                     self.stack.push(Marker::Val(res));
                 }
+                Op::Cast(c) => {
+                    let v = self.stack.force_pop_val()?;
+                    self.stack.push(Marker::Val(cast::apply(v, &c)?));
+                }
+                Op::Check(contract) => {
+                    if !self.stack.force_pop_bool()? {
+                        let value = self.env.lookup(0).ok_or_else(|| {
+                            EvaluationError::Internal(format!(
+                                "{} is unbound where it's checked",
+                                contract.var.name()
+                            ))
+                        })?;
+                        return Err(EvaluationError::ContractViolation {
+                            predicate: contract.source.to_string(),
+                            value: value.into_inner().to_string(),
+                            location: contract.location,
+                        });
+                    }
+                }
                 Op::Closure(body) => self.stack.push(Marker::Val(Val::Closure {
                     body,
                     env: self.env.clone(),
                 })),
                 Op::Const(v) => self.stack.push(Marker::Val(v)),
+                Op::Construct(ctor, arity) => {
+                    let mut fields = Vec::with_capacity(arity);
+                    for _ in 0..arity {
+                        fields.push(self.stack.force_pop_val()?);
+                    }
+                    fields.reverse();
+                    self.stack.push(Marker::Val(Val::construct(ctor, fields)));
+                }
                 Op::Dummy() => self.env.bind(RefCell::new(Val::Dummy)),
                 Op::EndLet() => self.env.unbind(),
+                Op::Field(field) => {
+                    let record = self.stack.force_pop_val()?;
+                    self.stack.push(Marker::Val(record.field(field)?));
+                }
+                Op::Record(fields) => {
+                    let mut vals = Vec::with_capacity(fields.len());
+                    for _ in 0..fields.len() {
+                        vals.push(self.stack.force_pop_val()?);
+                    }
+                    let record = fields.into_iter().zip(vals.into_iter().rev()).collect();
+                    self.stack.push(Marker::Val(Val::record(record)));
+                }
                 Op::Return() => {
                     if self.stack.peek_closure().is_some() {
                         let (fn_body, fn_env) = self.stack.force_pop_closure()?;
@@ -122,6 +475,23 @@ impl VirtualMachine {
 
                     self.stack.push(Marker::Code(prev_code));
                 }
+                Op::Switch(cases, default) => {
+                    let v = self.stack.force_pop_val()?;
+                    let mut branch = None;
+                    for (case, code) in cases {
+                        if case.matches(&v)? {
+                            branch = Some(code);
+                            break;
+                        }
+                    }
+                    let branch = branch.or(default).ok_or_else(|| {
+                        EvaluationError::Internal(format!("no case matched {:?}", v))
+                    })?;
+
+                    let prev_code = mem::replace(&mut self.code, branch);
+
+                    self.stack.push(Marker::Code(prev_code));
+                }
                 Op::Join() => {
                     let ret_val = self.stack.force_pop_val()?;
                     let code = self.stack.force_pop_code()?;
@@ -150,6 +520,107 @@ impl VirtualMachine {
                         }))
                     }
                 }
+                Op::Index() => {
+                    let index = self.stack.force_pop_val()?;
+                    let array = self.stack.force_pop_val()?;
+                    self.stack.push(Marker::Val(array.index(index)?));
+                }
+                Op::Compare(ctors, ranks) => {
+                    let methods = self.pop_methods(&ctors)?;
+                    let r = self.stack.force_pop_val()?;
+                    let l = self.stack.force_pop_val()?;
+                    self.resume_compare(Comparison::new(l, r, ranks), methods)?;
+                }
+                Op::Equal(ctors) => {
+                    let methods = self.pop_methods(&ctors)?;
+                    let r = self.stack.force_pop_val()?;
+                    let l = self.stack.force_pop_val()?;
+                    self.resume_equal(Equality::new(l, r), methods)?;
+                }
+                Op::Keyed(b) => {
+                    let methods = self.stack.force_pop_val()?;
+                    let mut args = Vec::with_capacity(b.arity());
+                    for _ in 0..b.arity() {
+                        args.push(self.stack.force_pop_val()?);
+                    }
+                    args.reverse();
+                    self.resume_keyed(Keyed::new(b, args, methods)?)?;
+                }
+                Op::Method(name) => {
+                    let methods = self.stack.force_pop_val()?;
+                    let r = self.stack.force_pop_val()?;
+                    let l = self.stack.force_pop_val()?;
+                    self.call(methods.field(name)?, vec![l, r]);
+                }
+                Op::ResumeCompare(mut cmp, methods) => {
+                    cmp.compared(self.stack.force_pop_val()?.as_int()?.cmp(&0));
+                    self.resume_compare(cmp, methods)?;
+                }
+                Op::ResumeEqual(eq, methods) => {
+                    if self.stack.force_pop_bool()? {
+                        self.resume_equal(eq, methods)?;
+                    } else {
+                        self.stack.push(Marker::Val(Val::Bool(false)));
+                    }
+                }
+                Op::ResumeKeyed(mut keyed) => {
+                    let ord = self.stack.force_pop_val()?.as_int()?.cmp(&0);
+                    keyed.compared(ord);
+                    self.resume_keyed(*keyed)?;
+                }
+                Op::ResumeShow(mut showing, methods) => {
+                    match self.stack.force_pop_val()? {
+                        Val::Str(s) => showing.push_str(&s),
+                        v => {
+                            return Err(EvaluationError::Internal(format!(
+                                "expected String, got {v:?}"
+                            )))
+                        }
+                    }
+                    self.resume_show(showing, methods)?;
+                }
+                Op::Show(ctors) => {
+                    let methods = self.pop_methods(&ctors)?;
+                    let v = self.stack.force_pop_val()?;
+                    self.resume_show(Showing::new(v), methods)?;
+                }
+                Op::Primitive(b) => {
+                    let mut args = Vec::with_capacity(b.arity());
+                    for _ in 0..b.arity() {
+                        args.push(self.stack.force_pop_val()?);
+                    }
+                    args.reverse();
+                    self.stack.push(Marker::Val(b.apply(args)?));
+                }
+                Op::Project(idx) => {
+                    let v = self.stack.force_pop_val()?;
+                    self.stack.push(Marker::Val(v.project(idx)?));
+                }
+                Op::Propagate(frames) => {
+                    let v = self.stack.force_pop_val()?;
+                    match &v {
+                        Val::Data { ctor, fields }
+                            if *ctor == *builtins::SOME || *ctor == *builtins::OK =>
+                        {
+                            self.stack.push(Marker::Val(fields[0].clone()));
+                        }
+                        _ => {
+                            // Discard everything the function has pushed,
+                            // including any calls it's in the middle of, and
+                            // then return as usual.
+                            for _ in 0..frames {
+                                self.stack.unwind_to_app_delim()?;
+                                self.stack.force_pop_code()?;
+                                self.stack.force_pop_env()?;
+                            }
+                            self.stack.unwind_to_app_delim()?;
+                            self.code = self.stack.force_pop_code()?;
+                            self.env = self.stack.force_pop_env()?;
+
+                            self.stack.push(Marker::Val(v));
+                        }
+                    }
+                }
                 Op::PushRetAddr(c) => {
                     self.stack.push(Marker::Env(self.env.clone()));
                     self.stack.push(Marker::Code(c));
@@ -173,6 +644,98 @@ impl VirtualMachine {
     }
 }
 
+/// Compare `l` with `r`, or return `None` if they're unordered (e.g. if either
+/// is NaN).
+fn compare(l: Val, r: Val) -> Result<Option<Ordering>, EvaluationError> {
+    match (l, r) {
+        (Val::Int(l), Val::Int(r)) => Ok(Some(l.cmp(&r))),
+        (Val::Num(l), Val::Num(r)) => Ok(l.partial_cmp(&r)),
+        (l, r) => Ok(Some(Key::try_from(l)?.compare(&Key::try_from(r)?)?)),
+    }
+}
+
+/// Whether `v` is of one of the types which `methods` are for.
+fn has_method(methods: &Methods, v: &Val) -> bool {
+    matches!(v, Val::Data { ctor, .. } if methods.contains_key(ctor))
+}
+
+/// Find the method `name` for the type of `v` in `methods`.
+fn find_method(methods: &Methods, v: &Val, name: &str) -> Result<Val, EvaluationError> {
+    match v {
+        Val::Data { ctor, .. } if methods.contains_key(ctor) => {
+            methods[ctor].clone().field(interner::Id::new(name))
+        }
+        v => Err(EvaluationError::Internal(format!(
+            "no method {name} for {v:?}"
+        ))),
+    }
+}
+
+/// Apply an arithmetic or bitwise operator to `Int`s, failing if the result
+/// overflows. Division truncates towards zero, and shifting by a negative
+/// amount or by 64 or more bits overflows.
+fn int_binary(op: BinaryOp, l: i64, r: i64) -> Result<Val, EvaluationError> {
+    let shift = || u32::try_from(r).ok().filter(|r| *r < i64::BITS);
+    let res = match op {
+        BinaryOp::Add => l.checked_add(r),
+        BinaryOp::Sub => l.checked_sub(r),
+        BinaryOp::Mul => l.checked_mul(r),
+        BinaryOp::Div if r == 0 => return Err(EvaluationError::DivisionByZero),
+        BinaryOp::Div => l.checked_div(r),
+        BinaryOp::BitAnd => Some(l & r),
+        BinaryOp::BitOr => Some(l | r),
+        BinaryOp::BitXor => Some(l ^ r),
+        // Bits shifted out of the top overflow, just as they would when
+        // multiplying by a power of two.
+        BinaryOp::Shl => shift()
+            .map(|s| (l << s, s))
+            .filter(|(res, s)| res >> s == l)
+            .map(|(res, _)| res),
+        BinaryOp::Shr => shift().map(|r| l >> r),
+        _ => {
+            return Err(EvaluationError::Internal(format!(
+                "{op:?} isn't an Int operator"
+            )))
+        }
+    };
+    res.map(Val::Int).ok_or(EvaluationError::IntegerOverflow)
+}
+
+/// Apply an arithmetic operator to `BigInt`s. Division truncates towards zero.
+fn big_int_binary(op: BinaryOp, l: &BigInt, r: &BigInt) -> Result<Val, EvaluationError> {
+    let res = match op {
+        BinaryOp::Add => l + r,
+        BinaryOp::Sub => l - r,
+        BinaryOp::Mul => l * r,
+        BinaryOp::Div => match l.checked_div_rem(r) {
+            Some((quotient, _)) => quotient,
+            None => return Err(EvaluationError::DivisionByZero),
+        },
+        _ => {
+            return Err(EvaluationError::Internal(format!(
+                "{op:?} isn't a BigInt operator"
+            )))
+        }
+    };
+    Ok(Val::BigInt(Rc::new(res)))
+}
+
+/// Apply an arithmetic operator to `Rational`s.
+fn rational_binary(op: BinaryOp, l: &Rational, r: &Rational) -> Result<Val, EvaluationError> {
+    let res = match op {
+        BinaryOp::Add => l + r,
+        BinaryOp::Sub => l - r,
+        BinaryOp::Mul => l * r,
+        BinaryOp::Div => l.checked_div(r).ok_or(EvaluationError::DivisionByZero)?,
+        _ => {
+            return Err(EvaluationError::Internal(format!(
+                "{op:?} isn't a Rational operator"
+            )))
+        }
+    };
+    Ok(Val::Rational(Rc::new(res)))
+}
+
 #[derive(Clone, Debug, PartialEq)]
 pub enum Marker {
     AppDelim,
@@ -200,6 +763,12 @@ impl Stack<Marker> {
         }
     }
 
+    /// Pop everything up to & including the topmost `AppDelim`.
+    fn unwind_to_app_delim(&mut self) -> Result<(), EvaluationError> {
+        while !matches!(self.force_pop()?, Marker::AppDelim) {}
+        Ok(())
+    }
+
     fn force_pop_code(&mut self) -> Result<Stack<Op>, EvaluationError> {
         match self.force_pop()? {
             Marker::Code(c) => Ok(c),
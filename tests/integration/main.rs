@@ -1,35 +1,141 @@
 use serde::Deserialize;
 use std::{
-    fs::File,
+    env,
+    fs::{self, File},
     io::{self, BufRead},
     path::PathBuf,
+    process::Command,
 };
 use test_generator::test_resources;
 use untitled_programming_language_project::{
+    check_types, check_types_all,
     error::{Error, EvaluationError, ParseError, Tok},
-    evaluate, typ,
+    evaluate, evaluate_pure, explain_types, parse_typed, typ,
     values::Val,
 };
 
 #[test_resources("./examples/*/*.uplp")]
 pub fn test(p: &str) {
     let test = parse_annotated_test(p);
+    let run = if test.header.pure {
+        evaluate_pure
+    } else {
+        evaluate
+    };
 
-    match test.expectation {
+    match test.header.expectation {
         Expectation::Skip => (),
         Expectation::Value(v) => {
-            let result = evaluate(test.program.as_str()).expect("Program evaluation failed");
+            let result = run(test.program.as_str()).expect("Program evaluation failed");
             assert_eq!(v, result)
         }
         Expectation::Error(e) => {
-            let result = evaluate(test.program.as_str()).expect_err("Nothing went wrong");
+            let result = run(test.program.as_str()).expect_err("Nothing went wrong");
             assert_eq!(e, result)
         }
+        Expectation::Errors(e) => {
+            let errors = match check_types_all(test.program.as_str()) {
+                Err(Error::TypeErrors(errors)) => errors,
+                r => panic!("expected type errors, got {r:?}"),
+            };
+            let lines: Vec<_> = errors
+                .iter()
+                .map(|(location, _)| test.program[..*location].matches('\n').count() + 1)
+                .collect();
+            assert_eq!(e.lines, lines);
+        }
+        Expectation::Type(t) => {
+            let result = check_types(test.program.as_str()).expect("Program typechecking failed");
+            assert_eq!(t.typ, result.to_string());
+            let recovered = check_types_all(test.program.as_str())
+                .expect("Program typechecking with recovery failed");
+            assert_eq!(result, recovered);
+            let explanation = explain_types(test.program.as_str())
+                .expect("Program typechecking with explanation failed");
+            assert_eq!(Ok(&result), explanation.result.as_ref());
+            assert!(!explanation.steps.is_empty());
+            let typed = parse_typed(test.program.as_str()).expect("Program elaboration failed");
+            assert_eq!(result, typed.typ);
+            if let Some(ast) = t.typed_ast {
+                assert_eq!(ast, format!("{typed:?}"));
+            }
+            // Well-typed programs may still fail, e.g. by dividing by zero,
+            // but never because the VM found a value of the wrong type.
+            if let Err(Error::EvaluationError(EvaluationError::Internal(msg))) =
+                evaluate(test.program.as_str())
+            {
+                panic!("well-typed program hit an internal error: {msg}")
+            }
+        }
     }
 }
 
+/// Checking, compiling & showing recurse once per `let`, so programs with long
+/// chains of them must still work on a test thread's small stack, and with
+/// every command of the CLI.
+#[test]
+fn long_let_chain() {
+    let mut program: String = (0..20000).map(|i| format!("let x{i} = {i} in\n")).collect();
+    program.push_str("x19999");
+    assert_eq!("Int", check_types_all(&program).unwrap().to_string());
+    assert_eq!(Val::Int(19999), evaluate(&program).unwrap());
+    let commands: [(&[&str], &str); 6] = [
+        (&["evaluate"], "Int(19999)"),
+        (&["typecheck"], "Int"),
+        (&["typecheck", "--explain"], "Int"),
+        (
+            &["typecheck", "--explain", "--format", "json"],
+            "\"type\": \"Int\"",
+        ),
+        (&["ast-dump"], "Id(0)"),
+        (&["ast-dump", "--typed"], "x19999"),
+    ];
+    for (args, expected) in commands {
+        let out = run_cli("long_let_chain", &program, args);
+        assert!(out.contains(expected), "uplp {args:?} printed {out}");
+    }
+}
+
+#[test]
+fn cli_prints_long_list() {
+    let program = "let rec build = |n, acc| if n == 0 then acc else build (n - 1) (n :: acc) in\n\
+                   build 100000 []";
+    let out = run_cli("long_list", program, &["evaluate"]);
+    assert!(out.starts_with("List([Int(1), Int(2), "));
+    assert!(out.trim_end().ends_with("Int(100000)])"));
+}
+
+#[test]
+fn cli_prints_deep_data() {
+    let program = "type T = L | N T in\n\
+                   let rec go = |n, acc| if n == 0 then acc else go (n - 1) (N acc) in\n\
+                   go 300000 L";
+    let out = run_cli("deep_data", program, &["evaluate"]);
+    let data = "Data { ctor: ";
+    assert_eq!(300001, out.matches(data).count());
+    assert!(out.starts_with(data));
+    assert!(out.contains("fields: [] }] }"));
+    assert!(out.trim_end().ends_with("] }] }"));
+}
+
+/// Run the `uplp` command `args` on `program`, which is written to a file
+/// named after `name`, returning what it printed. It must exit successfully.
+fn run_cli(name: &str, program: &str, args: &[&str]) -> String {
+    let path = env::temp_dir().join(format!("uplp_{name}_{}.uplp", std::process::id()));
+    fs::write(&path, program).expect("failed to write program");
+    let output = Command::new(env!("CARGO_BIN_EXE_uplp"))
+        .args(args)
+        .arg("--file")
+        .arg(&path)
+        .output()
+        .expect("failed to run uplp");
+    fs::remove_file(&path).expect("failed to remove program");
+    assert!(output.status.success(), "uplp {args:?} failed: {output:?}");
+    String::from_utf8(output.stdout).expect("uplp printed invalid UTF-8")
+}
+
 struct AnnotatedTest {
-    expectation: Expectation,
+    header: Header,
     program: String,
 }
 
@@ -67,13 +173,19 @@ fn parse_annotated_test(p: &str) -> AnnotatedTest {
         std::mem::swap(&mut program, &mut preface);
     }
 
-    let expectation: Expectation =
-        toml::from_str(preface.as_str()).expect("Failed to parse toml header");
+    let header: Header = toml::from_str(preface.as_str()).expect("Failed to parse toml header");
 
-    AnnotatedTest {
-        program,
-        expectation,
-    }
+    AnnotatedTest { program, header }
+}
+
+#[derive(Deserialize)]
+struct Header {
+    #[serde(flatten)]
+    expectation: Expectation,
+    /// Whether the program is run with `evaluate_pure` rather than
+    /// `evaluate`.
+    #[serde(default)]
+    pure: bool,
 }
 
 #[derive(Deserialize)]
@@ -83,6 +195,10 @@ enum Expectation {
     Value(ValueExpectation),
     #[serde(rename = "error")]
     Error(ErrorExpectation),
+    #[serde(rename = "errors")]
+    Errors(ErrorsExpectation),
+    #[serde(rename = "type")]
+    Type(TypeExpectation),
     #[serde(rename = "skip")]
     Skip,
 }
@@ -90,8 +206,14 @@ enum Expectation {
 #[derive(Debug, Deserialize)]
 #[serde(tag = "type", content = "value")]
 enum ValueExpectation {
+    /// Written in decimal, since it may not fit in any TOML integer.
+    BigInt(String),
     Bool(bool),
+    Int(i64),
     Num(f64),
+    /// Written as `numerator/denominator`, or as an integer if it's whole.
+    Rational(String),
+    String(String),
     Unit,
     Closure,
 }
@@ -99,8 +221,12 @@ enum ValueExpectation {
 impl PartialEq<Val> for ValueExpectation {
     fn eq(&self, other: &Val) -> bool {
         match (self, other) {
+            (ValueExpectation::BigInt(n1), Val::BigInt(n2)) => *n1 == n2.to_string(),
             (ValueExpectation::Bool(b1), Val::Bool(b2)) => b1 == b2,
+            (ValueExpectation::Int(i1), Val::Int(i2)) => i1 == i2,
             (ValueExpectation::Num(n1), Val::Num(n2)) => n1 == n2,
+            (ValueExpectation::Rational(r1), Val::Rational(r2)) => *r1 == r2.to_string(),
+            (ValueExpectation::String(s1), Val::Str(s2)) => s1.as_str() == s2.as_ref(),
             (ValueExpectation::Unit, Val::Unit) => true,
             (ValueExpectation::Closure, Val::Closure { .. }) => true,
             _ => false,
@@ -108,6 +234,19 @@ impl PartialEq<Val> for ValueExpectation {
     }
 }
 
+/// The lines, in order, of every type error in a program.
+#[derive(Deserialize)]
+struct ErrorsExpectation {
+    lines: Vec<usize>,
+}
+
+#[derive(Deserialize)]
+struct TypeExpectation {
+    typ: String,
+    /// The program with every node annotated with its type.
+    typed_ast: Option<String>,
+}
+
 #[derive(Debug, Deserialize)]
 #[serde(tag = "error", content = "expectation")]
 enum ErrorExpectation {
@@ -117,12 +256,76 @@ enum ErrorExpectation {
     UnexpectedToken { tok: String },
     #[serde(rename = "Parse.invalid_token")]
     InvalidToken { tok: String },
+    #[serde(rename = "Parse.unbound_type")]
+    UnboundType { ident: String },
+    #[serde(rename = "Parse.integer_out_of_range")]
+    IntegerOutOfRange { literal: String },
+    #[serde(rename = "Parse.duplicate_binding")]
+    DuplicateBinding { ident: String },
+    #[serde(rename = "Parse.duplicate_declaration")]
+    DuplicateDeclaration { ident: String },
+    #[serde(rename = "Parse.unbound_class")]
+    UnboundClass { ident: String },
+    #[serde(rename = "Parse.unbound_unit")]
+    UnboundUnit { ident: String },
+    #[serde(rename = "Parse.duplicate_instance")]
+    DuplicateInstance { class: String, ident: String },
     #[serde(rename = "Type.mismatch")]
     TypeMismatch { got: String, expected: String },
     #[serde(rename = "Type.invalid_application")]
     TypeInvalidApplication { applied_type: String },
+    #[serde(rename = "Type.missing_field")]
+    TypeMissingField { field: String, typ: String },
+    #[serde(rename = "Type.duplicate_field")]
+    TypeDuplicateField { field: String },
+    #[serde(rename = "Type.non_exhaustive")]
+    TypeNonExhaustive { missing: Vec<String> },
+    #[serde(rename = "Type.redundant_pattern")]
+    TypeRedundantPattern { pattern: String },
+    #[serde(rename = "Type.pattern_arity")]
+    TypePatternArity {
+        constructor: String,
+        expected: usize,
+        got: usize,
+    },
+    #[serde(rename = "Type.not_comparable")]
+    TypeNotComparable { typ: String },
+    #[serde(rename = "Type.infinite_type")]
+    TypeInfiniteType { var: String, ty: String },
+    #[serde(rename = "Type.no_instance")]
+    TypeNoInstance { class: String, typ: String },
+    #[serde(rename = "Type.unknown_method")]
+    TypeUnknownMethod { class: String, method: String },
+    #[serde(rename = "Type.newtype_instance_within")]
+    TypeNewtypeInstanceWithin { class: String, typ: String },
+    #[serde(rename = "Type.invalid_propagation")]
+    TypeInvalidPropagation { typ: String },
+    #[serde(rename = "Type.propagation_outside_function")]
+    TypePropagationOutsideFunction,
+    #[serde(rename = "Type.recursive_non_function")]
+    TypeRecursiveNonFunction { ident: String },
+    #[serde(rename = "Type.type_escapes")]
+    TypeTypeEscapes { ident: String, typ: String },
+    #[serde(rename = "Type.disallowed_effect")]
+    TypeDisallowedEffect { effect: String, allowed: String },
+    #[serde(rename = "Type.kind_mismatch")]
+    TypeKindMismatch {
+        typ: String,
+        expected: String,
+        got: String,
+    },
+    #[serde(rename = "Evaluation.contract_violation")]
+    ContractViolation { predicate: String, value: String },
     #[serde(rename = "Evaluation.division_by_zero")]
     DivisionByZero,
+    #[serde(rename = "Evaluation.integer_overflow")]
+    IntegerOverflow,
+    #[serde(rename = "Evaluation.index_out_of_bounds")]
+    IndexOutOfBounds { index: i64, len: usize },
+    #[serde(rename = "Evaluation.invalid_size")]
+    InvalidSize { size: i64 },
+    #[serde(rename = "Evaluation.cast_failed")]
+    CastFailed { expected: String, got: String },
 }
 
 impl PartialEq<Error> for ErrorExpectation {
@@ -152,6 +355,42 @@ impl PartialEq<Error> for ErrorExpectation {
                 InvalidToken { tok: tok1 },
                 Error::ParseError(ParseError::InvalidToken { token: tok2, .. }),
             ) => tok1 == tok2,
+            (
+                UnboundType { ident: ident1 },
+                Error::ParseError(ParseError::UnboundType { ident: ident2 }),
+            ) => ident1.as_str() == ident2.name(),
+            (
+                IntegerOutOfRange { literal: literal1 },
+                Error::ParseError(ParseError::IntegerOutOfRange {
+                    literal: literal2, ..
+                }),
+            ) => literal1 == literal2,
+            (
+                DuplicateBinding { ident: ident1 },
+                Error::ParseError(ParseError::DuplicateBinding { ident: ident2 }),
+            ) => ident1.as_str() == ident2.name(),
+            (
+                DuplicateDeclaration { ident: ident1 },
+                Error::ParseError(ParseError::DuplicateDeclaration { ident: ident2 }),
+            ) => ident1.as_str() == ident2.name(),
+            (
+                UnboundClass { ident: ident1 },
+                Error::ParseError(ParseError::UnboundClass { ident: ident2 }),
+            ) => ident1.as_str() == ident2.name(),
+            (
+                UnboundUnit { ident: ident1 },
+                Error::ParseError(ParseError::UnboundUnit { ident: ident2 }),
+            ) => ident1.as_str() == ident2.name(),
+            (
+                DuplicateInstance {
+                    class: class1,
+                    ident: ident1,
+                },
+                Error::ParseError(ParseError::DuplicateInstance {
+                    class: class2,
+                    ident: ident2,
+                }),
+            ) => class1.as_str() == class2.name() && ident1.as_str() == ident2.name(),
             (
                 TypeMismatch {
                     got: got1,
@@ -166,7 +405,171 @@ impl PartialEq<Error> for ErrorExpectation {
                 TypeInvalidApplication { applied_type },
                 Error::TypeError(typ::Error::InvalidApplication(typ)),
             ) => applied_type.as_str() == typ.to_string(),
+            (
+                TypeMissingField {
+                    field: field1,
+                    typ: typ1,
+                },
+                Error::TypeError(typ::Error::MissingField {
+                    field: field2,
+                    typ: typ2,
+                }),
+            ) => field1.as_str() == field2.name() && typ1.as_str() == typ2.to_string(),
+            (
+                TypeDuplicateField { field: field1 },
+                Error::TypeError(typ::Error::DuplicateField(field2)),
+            ) => field1.as_str() == field2.name(),
+            (
+                TypeNonExhaustive { missing: missing1 },
+                Error::TypeError(typ::Error::NonExhaustive { missing: missing2 }),
+            ) => {
+                let missing2: Vec<_> = missing2.iter().map(ToString::to_string).collect();
+                missing1 == &missing2
+            }
+            (
+                TypeRedundantPattern { pattern: pattern1 },
+                Error::TypeError(typ::Error::RedundantPattern(pattern2)),
+            ) => pattern1.as_str() == pattern2.to_string(),
+            (
+                TypePatternArity {
+                    constructor: constructor1,
+                    expected: expected1,
+                    got: got1,
+                },
+                Error::TypeError(typ::Error::PatternArity {
+                    constructor: constructor2,
+                    expected: expected2,
+                    got: got2,
+                }),
+            ) => {
+                constructor1.as_str() == constructor2.name()
+                    && expected1 == expected2
+                    && got1 == got2
+            }
+            (
+                TypeInvalidPropagation { typ: typ1 },
+                Error::TypeError(typ::Error::InvalidPropagation(typ2)),
+            ) => typ1.as_str() == typ2.to_string(),
+            (
+                TypeNotComparable { typ: typ1 },
+                Error::TypeError(typ::Error::NotComparable(typ2)),
+            ) => typ1.as_str() == typ2.to_string(),
+            (
+                TypeInfiniteType { var: var1, ty: ty1 },
+                Error::TypeError(typ::Error::InfiniteType { var: var2, ty: ty2 }),
+            ) => var1.as_str() == var2.to_string() && ty1.as_str() == ty2.to_string(),
+            (
+                TypeNoInstance {
+                    class: class1,
+                    typ: typ1,
+                },
+                Error::TypeError(typ::Error::NoInstance {
+                    class: class2,
+                    typ: typ2,
+                }),
+            ) => class1.as_str() == class2.to_string() && typ1.as_str() == typ2.to_string(),
+            (
+                TypeUnknownMethod {
+                    class: class1,
+                    method: method1,
+                },
+                Error::TypeError(typ::Error::UnknownMethod {
+                    class: class2,
+                    method: method2,
+                }),
+            ) => class1.as_str() == class2.to_string() && method1.as_str() == method2.name(),
+            (
+                TypeNewtypeInstanceWithin {
+                    class: class1,
+                    typ: typ1,
+                },
+                Error::TypeError(typ::Error::NewtypeInstanceWithin {
+                    class: class2,
+                    typ: typ2,
+                }),
+            ) => class1.as_str() == class2.to_string() && typ1.as_str() == typ2.to_string(),
+            (
+                TypePropagationOutsideFunction,
+                Error::TypeError(typ::Error::PropagationOutsideFunction),
+            ) => true,
+            (
+                TypeRecursiveNonFunction { ident: ident1 },
+                Error::TypeError(typ::Error::RecursiveNonFunction(ident2)),
+            ) => ident1.as_str() == ident2.name(),
+            (
+                TypeTypeEscapes {
+                    ident: ident1,
+                    typ: typ1,
+                },
+                Error::TypeError(typ::Error::TypeEscapes {
+                    ident: ident2,
+                    typ: typ2,
+                }),
+            ) => ident1.as_str() == ident2.name() && typ1.as_str() == typ2.to_string(),
+            (
+                TypeDisallowedEffect {
+                    effect: effect1,
+                    allowed: allowed1,
+                },
+                Error::TypeError(typ::Error::DisallowedEffect {
+                    effect: effect2,
+                    allowed: allowed2,
+                }),
+            ) => effect1.as_str() == effect2.name() && allowed1.as_str() == allowed2.to_string(),
+            (
+                TypeKindMismatch {
+                    typ: typ1,
+                    expected: expected1,
+                    got: got1,
+                },
+                Error::TypeError(typ::Error::KindMismatch {
+                    typ: typ2,
+                    expected: expected2,
+                    got: got2,
+                }),
+            ) => {
+                typ1.as_str() == typ2.to_string()
+                    && expected1.as_str() == expected2.to_string()
+                    && got1.as_str() == got2.to_string()
+            }
+            (
+                ContractViolation {
+                    predicate: predicate1,
+                    value: value1,
+                },
+                Error::EvaluationError(EvaluationError::ContractViolation {
+                    predicate: predicate2,
+                    value: value2,
+                    ..
+                }),
+            ) => predicate1 == predicate2 && value1 == value2,
             (DivisionByZero, Error::EvaluationError(EvaluationError::DivisionByZero)) => true,
+            (IntegerOverflow, Error::EvaluationError(EvaluationError::IntegerOverflow)) => true,
+            (
+                IndexOutOfBounds {
+                    index: index1,
+                    len: len1,
+                },
+                Error::EvaluationError(EvaluationError::IndexOutOfBounds {
+                    index: index2,
+                    len: len2,
+                }),
+            ) => index1 == index2 && len1 == len2,
+            (
+                InvalidSize { size: size1 },
+                Error::EvaluationError(EvaluationError::InvalidSize(size2)),
+            ) => size1 == size2,
+            (
+                CastFailed {
+                    expected: expected1,
+                    got: got1,
+                },
+                Error::EvaluationError(EvaluationError::CastFailed {
+                    expected: expected2,
+                    got: got2,
+                    ..
+                }),
+            ) => expected1 == expected2 && got1 == got2,
             _ => false,
         }
     }